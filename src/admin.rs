@@ -0,0 +1,106 @@
+use hyper::{header, Body, Request, Response};
+use ring::hmac;
+use ring::rand::SystemRandom;
+
+use crate::json::{json_error, json_response, CacheFlushResponse};
+use crate::{client, config, server, subreddit, utils};
+
+/// Clear the listing and response caches without restarting the instance.
+/// Requires `Authorization: Bearer <REDLIB_ADMIN_TOKEN>`; without a
+/// configured token the endpoint does not exist.
+pub async fn flush_cache(req: Request<Body>) -> Result<Response<Body>, String> {
+	let Some(token) = config::get_setting("REDLIB_ADMIN_TOKEN").filter(|token| !token.is_empty()) else {
+		return Ok(json_error("Not found".to_string(), 404));
+	};
+
+	let bearer = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+	if !authorized(&token, bearer) {
+		return Ok(json_error("Invalid admin token".to_string(), 401));
+	}
+
+	let listings = client::flush_cache().await + subreddit::flush_cache().await + utils::flush_emojis_cache().await;
+	let responses = server::flush_compression_cache();
+
+	Ok(json_response(CacheFlushResponse { listings, responses }))
+}
+
+/// Developer endpoint answering with what Reddit returns for `?path=`, body
+/// and status verbatim. Only served with `REDLIB_DEBUG=on`. The request made
+/// is echoed in `X-Upstream-Url` and `X-Upstream-Headers`, credentials
+/// redacted.
+pub async fn debug_raw(req: Request<Body>) -> Result<Response<Body>, String> {
+	if config::get_setting("REDLIB_DEBUG").as_deref() != Some("on") {
+		return Ok(json_error("Not found".to_string(), 404));
+	}
+
+	let path = match debug_path(utils::param(&req.uri().to_string(), "path")) {
+		Ok(path) => path,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let raw = match client::raw(path).await {
+		Ok(raw) => raw,
+		Err(msg) => return Ok(json_error(msg, 502)),
+	};
+
+	let headers = raw.headers.iter().map(|(name, value)| format!("{name}: {value}")).collect::<Vec<_>>().join("; ");
+	Ok(
+		Response::builder()
+			.status(raw.status)
+			.header("content-type", raw.content_type.as_deref().unwrap_or("application/octet-stream"))
+			.header("Cache-Control", "no-store")
+			.header("X-Upstream-Url", raw.url)
+			.header("X-Upstream-Headers", headers)
+			.body(raw.body.into())
+			.unwrap_or_default(),
+	)
+}
+
+/// Check the Reddit path a debug request asked for. A path without an
+/// extension gets `.json`, as Reddit would otherwise answer with a page.
+fn debug_path(path: Option<String>) -> Result<String, String> {
+	let path = path.filter(|path| !path.is_empty()).ok_or("path is required")?;
+	if !path.starts_with('/') || path.starts_with("//") || !path.chars().all(|c| c.is_ascii_graphic()) {
+		return Err(format!("\"{path}\" is not a valid Reddit path"));
+	}
+	let (route, query) = path.split_once('?').map_or((path.as_str(), None), |(route, query)| (route, Some(query)));
+	let route = route.trim_end_matches('/');
+	if route.rsplit('/').next().unwrap_or_default().contains('.') {
+		return Ok(path);
+	}
+	Ok(match query {
+		Some(query) => format!("{route}.json?{query}"),
+		None => format!("{route}.json"),
+	})
+}
+
+fn authorized(token: &str, header: Option<&str>) -> bool {
+	let Some(bearer) = header.and_then(|value| value.strip_prefix("Bearer ")) else {
+		return false;
+	};
+	// Compared in constant time through an HMAC, as ring's deprecated
+	// verify_slices_are_equal makes no promise about timing
+	let Ok(key) = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()) else {
+		return false;
+	};
+	hmac::verify(&key, bearer.trim().as_bytes(), hmac::sign(&key, token.as_bytes()).as_ref()).is_ok()
+}
+
+#[test]
+fn test_admin_authorization() {
+	assert!(authorized("s3cret", Some("Bearer s3cret")));
+	assert!(!authorized("s3cret", Some("Bearer wrong")));
+	assert!(!authorized("s3cret", Some("s3cret")));
+	assert!(!authorized("s3cret", None));
+}
+
+#[test]
+fn test_debug_path() {
+	assert_eq!(debug_path(Some("/r/rust/hot.json?raw_json=1".into())).as_deref(), Ok("/r/rust/hot.json?raw_json=1"));
+	assert_eq!(debug_path(Some("/r/rust/hot".into())).as_deref(), Ok("/r/rust/hot.json"));
+	assert_eq!(debug_path(Some("/r/rust/hot/?t=week".into())).as_deref(), Ok("/r/rust/hot.json?t=week"));
+	assert_eq!(debug_path(Some("/api/info.json?id=t3_abc".into())).as_deref(), Ok("/api/info.json?id=t3_abc"));
+	assert_eq!(debug_path(None).unwrap_err(), "path is required");
+	assert!(debug_path(Some("https://evil.example/".into())).is_err());
+	assert!(debug_path(Some("//evil.example/".into())).is_err());
+	assert!(debug_path(Some("/r/rust hot".into())).is_err());
+}
@@ -0,0 +1,354 @@
+//! Archival of a subreddit's posts from a date range, streamed as NDJSON.
+//!
+//! Reddit never pages further back than the 1000 newest items of a listing,
+//! and the same cap applies to every search query. Walking `/new` therefore
+//! only reaches ranges that start among the subreddit's 1000 newest posts;
+//! older ranges go through search, restricted to the range with a timestamp
+//! query. Reddit no longer guarantees that timestamp queries are honoured, so
+//! results are filtered to the range here either way, and a range holding
+//! more than 1000 posts can only be archived in smaller slices.
+
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+
+use crate::cursor;
+use crate::json::{json_error, ndjson_stream, ArchiveSummary, NdjsonWriter};
+use crate::server::RequestExt;
+use crate::subreddit::can_access_quarantine;
+use crate::user::{export_page_cap, fetch_export_page, EXPORT_PAGE_SIZE};
+use crate::utils::{self, param, ListingParams, Post};
+
+/// Most items Reddit lets a listing or search be paged through.
+const LISTING_ITEM_CAP: usize = 1000;
+
+/// How an archive walks back through a subreddit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+	/// Page through `/new` until posts predate the range.
+	Listing,
+	/// Page through a timestamp search of the range, newest first.
+	Search,
+}
+
+impl Strategy {
+	fn parse(value: &str) -> Option<Self> {
+		match value {
+			"listing" => Some(Self::Listing),
+			"search" => Some(Self::Search),
+			_ => None,
+		}
+	}
+
+	fn as_str(self) -> &'static str {
+		match self {
+			Self::Listing => "listing",
+			Self::Search => "search",
+		}
+	}
+
+	/// Listing the pages of an archive are fetched from, and its parameters.
+	fn listing(self, sub: &str, from: u64, to: u64) -> (String, ListingParams) {
+		let mut params = ListingParams::default();
+		match self {
+			Self::Listing => (format!("/r/{sub}/new.json"), params),
+			Self::Search => {
+				params.sort = Some("new".to_string());
+				params.set("q", &format!("timestamp:{from}..{to}"));
+				params.set("syntax", "cloudsearch");
+				params.set("restrict_sr", "on");
+				params.set("include_over_18", "on");
+				(format!("/r/{sub}/search.json"), params)
+			}
+		}
+	}
+}
+
+/// What an archive request asked for.
+#[derive(Debug, PartialEq)]
+struct ArchiveParams {
+	from: u64,
+	to: u64,
+	/// `None` picks a strategy from the first page of `/new`.
+	strategy: Option<Strategy>,
+	after: String,
+	max_pages: usize,
+}
+
+/// Archive endpoint streaming the posts of a subreddit created between
+/// `?from=` and `?to=` (unix timestamps, `to` defaulting to now) as NDJSON,
+/// newest first. The final line is an [`ArchiveSummary`] whose `after`
+/// resumes an archive that stopped at the page cap.
+pub async fn archive_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sub = req.param("sub").unwrap_or_default();
+	if sub.is_empty() || !sub.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+		return Ok(json_error(format!("\"{sub}\" is not a valid subreddit name"), 400));
+	}
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+	let params = match archive_params(&format!("?{}", req.uri().query().unwrap_or_default()), now, export_page_cap()) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let quarantined = can_access_quarantine(&req, &sub);
+
+	let (from, to) = (params.from, params.to);
+	Ok(ndjson_stream(move |writer| {
+		archive_pages(writer, params, move |strategy, after| {
+			let (base, listing) = strategy.listing(&sub, from, to);
+			async move { fetch_export_page(&base, &listing, after, quarantined).await }
+		})
+	}))
+}
+
+/// Stream the posts of the archive `params` asks for to `writer`, ending
+/// with an [`ArchiveSummary`]. `fetch_page` fetches the page of a strategy
+/// after a cursor.
+async fn archive_pages<F, Fut>(mut writer: NdjsonWriter, params: ArchiveParams, mut fetch_page: F)
+where
+	F: FnMut(Strategy, String) -> Fut,
+	Fut: Future<Output = Result<(Vec<Post>, String), String>>,
+{
+	let ArchiveParams {
+		from,
+		to,
+		strategy,
+		mut after,
+		max_pages,
+	} = params;
+	let mut summary = ArchiveSummary {
+		strategy: strategy.unwrap_or(Strategy::Listing),
+		pages: 0,
+		items: 0,
+		reached: None,
+		complete: false,
+		after: None,
+		error: None,
+	};
+
+	while summary.pages < max_pages {
+		let (posts, next) = match fetch_page(summary.strategy, after.clone()).await {
+			Ok(page) => page,
+			Err(msg) => {
+				summary.error = Some(msg);
+				break;
+			}
+		};
+		summary.pages += 1;
+
+		// The first page of /new tells how far back the listing can reach
+		if strategy.is_none() && summary.pages == 1 {
+			summary.strategy = choose_strategy(&posts.iter().map(|post| post.created_ts).collect::<Vec<_>>(), from);
+			if summary.strategy == Strategy::Search {
+				continue;
+			}
+		}
+
+		let oldest = posts.iter().filter(|post| !post.flags.stickied).map(|post| post.created_ts).min();
+		summary.reached = summary.reached.into_iter().chain(oldest).min();
+
+		let (posts, passed) = in_range(posts, from, to);
+		if !writer.write_all(&posts).await {
+			return;
+		}
+		summary.items += posts.len();
+
+		after = next;
+		if passed || after.is_empty() {
+			// Running out of pages before passing the range means Reddit's cap was hit
+			summary.complete = passed || summary.strategy == Strategy::Search;
+			after.clear();
+			break;
+		}
+	}
+
+	summary.after = (!after.is_empty()).then(|| cursor::seal(&format!("{}:{after}", summary.strategy.as_str())));
+	writer.write(&summary).await;
+}
+
+/// Parse and validate an archive request, clamping `max_pages` to `cap`. A
+/// resumed archive keeps the strategy its cursor was issued for.
+fn archive_params(query: &str, now: u64, cap: usize) -> Result<ArchiveParams, String> {
+	let timestamp = |name: &str| {
+		param(query, name)
+			.map(|value| value.parse::<u64>().map_err(|_| format!("{name} must be a unix timestamp")))
+			.transpose()
+	};
+	let from = timestamp("from")?.ok_or("from is required")?;
+	let to = timestamp("to")?.unwrap_or(now);
+	if from >= to {
+		return Err("from must be earlier than to".to_string());
+	}
+
+	let mut strategy = match param(query, "strategy").as_deref() {
+		None | Some("auto") => None,
+		Some(other) => Some(Strategy::parse(other).ok_or_else(|| format!("Unknown strategy {other:?}, expected auto, listing or search"))?),
+	};
+
+	let mut after = String::new();
+	if let Some(token) = param(query, "after") {
+		let cursor = cursor::open(&token).ok_or("Invalid after cursor")?;
+		let (issued_for, cursor) = cursor
+			.split_once(':')
+			.and_then(|(name, cursor)| Some((Strategy::parse(name)?, cursor)))
+			.ok_or("Invalid after cursor")?;
+		strategy = Some(issued_for);
+		after = cursor.to_string();
+	}
+
+	let max_pages = match param(query, "max_pages") {
+		Some(pages) => pages.parse::<usize>().ok().filter(|pages| *pages > 0).ok_or("max_pages must be a positive number")?,
+		None => cap,
+	};
+
+	Ok(ArchiveParams {
+		from,
+		to,
+		strategy,
+		after,
+		max_pages: max_pages.min(cap),
+	})
+}
+
+/// Pick a strategy from the creation times on the first page of `/new`.
+/// Walking the listing wins unless, going by how often the subreddit gets
+/// posts, the range starts further back than the listing reaches.
+fn choose_strategy(created: &[u64], from: u64) -> Strategy {
+	let (Some(&newest), Some(&oldest)) = (created.iter().max(), created.iter().min()) else {
+		return Strategy::Listing;
+	};
+	// Either the range starts on this page or the whole subreddit fits on it
+	if oldest <= from || created.len() < EXPORT_PAGE_SIZE {
+		return Strategy::Listing;
+	}
+
+	let per_second = created.len() as f64 / (newest - oldest).max(1) as f64;
+	if newest.saturating_sub(from) as f64 * per_second <= LISTING_ITEM_CAP as f64 {
+		Strategy::Listing
+	} else {
+		Strategy::Search
+	}
+}
+
+/// Keep the posts of a page created within the range, and tell whether the
+/// page went past its start. Stickied posts are out of order, so they don't
+/// count towards that.
+fn in_range(mut posts: Vec<Post>, from: u64, to: u64) -> (Vec<Post>, bool) {
+	let passed = posts.iter().any(|post| !post.flags.stickied && post.created_ts < from);
+	posts.retain(|post| (from..=to).contains(&post.created_ts) && !(utils::sfw_only() && post.flags.nsfw));
+	(posts, passed)
+}
+
+#[test]
+fn test_archive_params() {
+	let params = archive_params("?from=100&to=200", 1000, 10).unwrap();
+	assert_eq!((params.from, params.to, params.strategy, params.max_pages), (100, 200, None, 10));
+	assert_eq!(archive_params("?from=100&max_pages=50", 1000, 10).unwrap().to, 1000);
+	assert_eq!(archive_params("?from=100&max_pages=50", 1000, 10).unwrap().max_pages, 10);
+	assert_eq!(archive_params("?from=100&strategy=search", 1000, 10).unwrap().strategy, Some(Strategy::Search));
+
+	// Cursors carry the strategy they were issued for
+	let after = cursor::seal("search:t3_abc");
+	let params = archive_params(&format!("?from=100&strategy=listing&after={after}"), 1000, 10).unwrap();
+	assert_eq!((params.strategy, params.after.as_str()), (Some(Strategy::Search), "t3_abc"));
+
+	assert_eq!(archive_params("?to=200", 1000, 10).unwrap_err(), "from is required");
+	assert_eq!(archive_params("?from=yesterday", 1000, 10).unwrap_err(), "from must be a unix timestamp");
+	assert_eq!(archive_params("?from=300&to=200", 1000, 10).unwrap_err(), "from must be earlier than to");
+	assert!(archive_params("?from=100&strategy=fastest", 1000, 10).is_err());
+	let unlabelled = cursor::seal("t3_abc");
+	assert_eq!(archive_params(&format!("?from=100&after={unlabelled}"), 1000, 10).unwrap_err(), "Invalid after cursor");
+}
+
+#[test]
+fn test_archive_listings() {
+	let (base, params) = Strategy::Listing.listing("rust", 100, 200);
+	assert_eq!(params.path(&base), "/r/rust/new.json?raw_json=1");
+	let (base, params) = Strategy::Search.listing("rust", 100, 200);
+	assert_eq!(
+		params.path(&base),
+		"/r/rust/search.json?sort=new&q=timestamp%3A100..200&syntax=cloudsearch&restrict_sr=on&include_over_18=on&raw_json=1"
+	);
+}
+
+#[test]
+fn test_choosing_archive_strategy() {
+	// A full page posted over 100 seconds, one post a second
+	let page: Vec<u64> = (0..100).map(|i| 10_000 - i).collect();
+	assert_eq!(choose_strategy(&page, 9_950), Strategy::Listing);
+	assert_eq!(choose_strategy(&page, 9_500), Strategy::Listing);
+	// Starting 5000 posts back is out of the listing's reach
+	assert_eq!(choose_strategy(&page, 5_000), Strategy::Search);
+	// Quiet subreddits fit on a single page
+	assert_eq!(choose_strategy(&page[..20], 0), Strategy::Listing);
+	assert_eq!(choose_strategy(&[], 0), Strategy::Listing);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_archive_range_filter() {
+	let post = |id: &str, created: u64, stickied: bool| {
+		let post = serde_json::json!({ "kind": "t3", "data": { "id": id, "title": id, "is_self": true, "created_utc": created, "stickied": stickied } });
+		async move { utils::parse_post(&post, true).await }
+	};
+	let page = vec![post("old_sticky", 10, true).await, post("new", 300, false).await, post("in", 150, false).await];
+	let (posts, passed) = in_range(page, 100, 200);
+	assert_eq!(posts.iter().map(|post| post.id.as_str()).collect::<Vec<_>>(), vec!["in"]);
+	assert!(!passed);
+
+	let (posts, passed) = in_range(vec![post("in", 120, false).await, post("before", 50, false).await], 100, 200);
+	assert_eq!(posts.len(), 1);
+	assert!(passed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_archive_stream() {
+	let page = |created: &'static [u64], next: &'static str| async move {
+		let mut posts = Vec::new();
+		for created in created {
+			let post = serde_json::json!({ "kind": "t3", "data": { "id": created.to_string(), "title": "", "is_self": true, "created_utc": created } });
+			posts.push(utils::parse_post(&post, true).await);
+		}
+		Ok((posts, next.to_string()))
+	};
+	let fetch = move |strategy: Strategy, after: String| async move {
+		assert_eq!(strategy, Strategy::Listing);
+		match after.as_str() {
+			"" => page(&[300, 150], "t3_x").await,
+			_ => page(&[120, 50], "t3_y").await,
+		}
+	};
+	let params = |max_pages| ArchiveParams {
+		from: 100,
+		to: 200,
+		strategy: Some(Strategy::Listing),
+		after: String::new(),
+		max_pages,
+	};
+	let lines = |res: Response<Body>| async move {
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		String::from_utf8(body.to_vec())
+			.unwrap()
+			.lines()
+			.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+			.collect::<Vec<_>>()
+	};
+
+	// The archive ends on the page that goes past the start of the range
+	let all = lines(ndjson_stream(move |writer| archive_pages(writer, params(5), fetch))).await;
+	assert_eq!(
+		all.iter().map(|line| line["id"].as_str().unwrap_or("summary")).collect::<Vec<_>>(),
+		vec!["150", "120", "summary"]
+	);
+	assert_eq!(
+		all[2],
+		serde_json::json!({ "strategy": "listing", "pages": 2, "items": 2, "reached": 50, "complete": true })
+	);
+
+	// Stopping at the page cap hands out a cursor for the same strategy
+	let capped = lines(ndjson_stream(move |writer| archive_pages(writer, params(1), fetch))).await;
+	assert_eq!((capped.len(), &capped[1]["complete"]), (2, &serde_json::Value::Bool(false)));
+	assert_eq!(cursor::open(capped[1]["after"].as_str().unwrap()).as_deref(), Some("listing:t3_x"));
+}
@@ -0,0 +1,200 @@
+//! Circuit breaker in front of Reddit, so that an outage makes requests fail
+//! fast instead of every one of them waiting on a timeout.
+
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config;
+
+/// Error returned instead of contacting Reddit while the breaker is open.
+pub const UPSTREAM_UNAVAILABLE: &str = "Reddit is unreachable right now, try again in a little while";
+
+/// Breaker shared by every request to Reddit, configured through the
+/// `REDLIB_CIRCUIT_BREAKER_*` settings.
+pub static UPSTREAM_BREAKER: LazyLock<Breaker> = LazyLock::new(|| {
+	let setting = |name: &str, default: u64| config::get_setting(name).and_then(|value| value.parse().ok()).unwrap_or(default);
+	Breaker::new(
+		setting("REDLIB_CIRCUIT_BREAKER_THRESHOLD", 5) as u32,
+		Duration::from_secs(setting("REDLIB_CIRCUIT_BREAKER_WINDOW", 30)),
+		Duration::from_secs(setting("REDLIB_CIRCUIT_BREAKER_COOLDOWN", 30)),
+	)
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+	/// Requests go through as usual.
+	Closed,
+	/// Requests are refused until the cooldown is over.
+	Open,
+	/// The cooldown is over and the next request tests whether Reddit is back.
+	HalfOpen,
+}
+
+/// Breaker state as reported by `/api/info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerStatus {
+	pub state: State,
+	pub consecutive_failures: u32,
+	/// Seconds until a request is let through again, while open.
+	pub retry_after: Option<u64>,
+}
+
+pub struct Breaker {
+	/// Consecutive failures that open the breaker; zero turns it off.
+	threshold: u32,
+	window: Duration,
+	cooldown: Duration,
+	inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+	failures: u32,
+	first_failure: Option<Instant>,
+	opened_at: Option<Instant>,
+	/// When the request testing recovery was let through, while half-open.
+	probe: Option<Instant>,
+}
+
+impl Breaker {
+	pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+		Self {
+			threshold,
+			window,
+			cooldown,
+			inner: Mutex::default(),
+		}
+	}
+
+	/// Whether a request may go to Reddit now. Once half-open, only one
+	/// request at a time is let through; a probe that never reports back is
+	/// given up on after another cooldown.
+	pub fn allow(&self, now: Instant) -> bool {
+		let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+		let Some(opened_at) = inner.opened_at else {
+			return true;
+		};
+		if now.duration_since(opened_at) < self.cooldown {
+			return false;
+		}
+		if inner.probe.is_some_and(|probe| now.duration_since(probe) < self.cooldown) {
+			return false;
+		}
+		inner.probe = Some(now);
+		true
+	}
+
+	/// Record how a request that was let through went.
+	pub fn record(&self, success: bool, now: Instant) {
+		if self.threshold == 0 {
+			return;
+		}
+
+		let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+		if success {
+			*inner = Inner::default();
+			return;
+		}
+
+		if inner.opened_at.is_some() {
+			// The probe failed, so Reddit gets another full cooldown
+			inner.opened_at = Some(now);
+			inner.probe = None;
+			return;
+		}
+
+		if !inner.first_failure.is_some_and(|first| now.duration_since(first) <= self.window) {
+			inner.failures = 0;
+			inner.first_failure = Some(now);
+		}
+		inner.failures += 1;
+		if inner.failures >= self.threshold {
+			inner.opened_at = Some(now);
+		}
+	}
+
+	pub fn status(&self, now: Instant) -> BreakerStatus {
+		let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+		let (state, retry_after) = match inner.opened_at {
+			None => (State::Closed, None),
+			Some(opened_at) => match self.cooldown.checked_sub(now.duration_since(opened_at)) {
+				Some(left) if !left.is_zero() => (State::Open, Some(left.as_secs().max(1))),
+				_ => (State::HalfOpen, None),
+			},
+		};
+		BreakerStatus {
+			state,
+			consecutive_failures: inner.failures,
+			retry_after,
+		}
+	}
+}
+
+#[test]
+fn test_breaker_transitions() {
+	let breaker = Breaker::new(3, Duration::from_secs(30), Duration::from_secs(10));
+	let start = Instant::now();
+	let at = |secs: u64| start + Duration::from_secs(secs);
+
+	// Failures below the threshold and a success in between keep it closed
+	breaker.record(false, at(0));
+	breaker.record(false, at(1));
+	breaker.record(true, at(2));
+	breaker.record(false, at(3));
+	breaker.record(false, at(4));
+	assert_eq!(breaker.status(at(4)).state, State::Closed);
+	assert_eq!(breaker.status(at(4)).consecutive_failures, 2);
+
+	// The third failure in a row opens it and requests are refused
+	breaker.record(false, at(5));
+	assert_eq!(breaker.status(at(5)).state, State::Open);
+	assert_eq!(breaker.status(at(5)).retry_after, Some(10));
+	assert!(!breaker.allow(at(6)));
+
+	// After the cooldown a single probe goes through
+	assert_eq!(breaker.status(at(15)).state, State::HalfOpen);
+	assert!(breaker.allow(at(15)));
+	assert!(!breaker.allow(at(16)));
+
+	// A failed probe opens it again for a full cooldown
+	breaker.record(false, at(16));
+	assert_eq!(breaker.status(at(20)).state, State::Open);
+	assert!(!breaker.allow(at(25)));
+
+	// A successful probe closes it
+	assert!(breaker.allow(at(26)));
+	breaker.record(true, at(27));
+	assert_eq!(breaker.status(at(27)).state, State::Closed);
+	assert!(breaker.allow(at(27)));
+}
+
+#[test]
+fn test_breaker_window_and_disabling() {
+	let breaker = Breaker::new(3, Duration::from_secs(30), Duration::from_secs(10));
+	let start = Instant::now();
+	let at = |secs: u64| start + Duration::from_secs(secs);
+
+	// Failures spread further apart than the window don't add up
+	breaker.record(false, at(0));
+	breaker.record(false, at(20));
+	breaker.record(false, at(40));
+	assert_eq!(breaker.status(at(40)).state, State::Closed);
+	assert_eq!(breaker.status(at(40)).consecutive_failures, 1);
+
+	// An abandoned probe is given up on after another cooldown
+	breaker.record(false, at(41));
+	breaker.record(false, at(42));
+	assert!(breaker.allow(at(52)));
+	assert!(!breaker.allow(at(55)));
+	assert!(breaker.allow(at(62)));
+
+	let disabled = Breaker::new(0, Duration::from_secs(30), Duration::from_secs(10));
+	for secs in 0..10 {
+		disabled.record(false, at(secs));
+	}
+	assert_eq!(disabled.status(at(10)).state, State::Closed);
+	assert!(disabled.allow(at(10)));
+}
@@ -1,10 +1,11 @@
 use arc_swap::ArcSwap;
 use cached::proc_macro::cached;
+use cached::Cached;
 use futures_lite::future::block_on;
-use futures_lite::{future::Boxed, FutureExt};
+use futures_lite::{future::Boxed, FutureExt, StreamExt};
 use hyper::client::HttpConnector;
 use hyper::header::HeaderValue;
-use hyper::{body, body::Buf, header, Body, Client, Method, Request, Response, Uri};
+use hyper::{body, body::Buf, header, Body, Client, Method, Request, Response, StatusCode, Uri};
 use hyper_rustls::HttpsConnector;
 use libflate::gzip;
 use log::{error, trace, warn};
@@ -14,18 +15,26 @@ use rustls::cipher_suite::{
 	TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
 	TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
 };
-use rustls::{ClientConfig, RootCertStore};
+use rustls::{version, ClientConfig, RootCertStore, SupportedCipherSuite, SupportedProtocolVersion};
+use serde::Serialize;
 use serde_json::Value;
 
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::atomic::Ordering;
-use std::sync::atomic::{AtomicBool, AtomicU16};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize};
 use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 use std::{io, result::Result};
+use tokio::sync::Semaphore;
 
+use crate::breaker::{UPSTREAM_BREAKER, UPSTREAM_UNAVAILABLE};
+use crate::coalesce::SingleFlight;
+use crate::config::get_setting;
 use crate::dbg_msg;
 use crate::oauth::{force_refresh_token, token_daemon, Oauth};
-use crate::server::RequestExt;
-use crate::utils::format_url;
+use crate::server::{RequestContext, RequestExt, REQUEST_CONTEXT};
+use crate::utils::{format_url, param, preview_variant};
 
 const REDDIT_URL_BASE: &str = "https://oauth.reddit.com";
 const REDDIT_URL_BASE_HOST: &str = "oauth.reddit.com";
@@ -36,55 +45,209 @@ const REDDIT_SHORT_URL_BASE_HOST: &str = "redd.it";
 const ALTERNATIVE_REDDIT_URL_BASE: &str = "https://www.reddit.com";
 const ALTERNATIVE_REDDIT_URL_BASE_HOST: &str = "www.reddit.com";
 
-// Firefox-like TLS cipher suites to avoid fingerprinting
-fn create_tls_config() -> ClientConfig {
+/// Oldest TLS version upstream connections may negotiate, set with
+/// `REDLIB_UPSTREAM_MIN_TLS`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MinTls {
+	#[default]
+	V1_2,
+	V1_3,
+}
+
+impl MinTls {
+	pub fn parse(value: Option<String>) -> Result<Self, String> {
+		match value.as_deref().map(str::trim) {
+			None | Some("" | "1.2") => Ok(Self::V1_2),
+			Some("1.3") => Ok(Self::V1_3),
+			Some(other) => Err(format!("\"{other}\" is not a supported TLS version, expected 1.2 or 1.3")),
+		}
+	}
+}
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
+
+// Firefox-like TLS cipher suites to avoid fingerprinting. Requiring TLS 1.3
+// leaves the 1.2 suites out along with the protocol version.
+fn tls_policy(min_tls: MinTls) -> (Vec<SupportedCipherSuite>, &'static [&'static SupportedProtocolVersion]) {
+	let mut cipher_suites = vec![
+		// TLS 1.3 suites
+		TLS13_AES_256_GCM_SHA384,
+		TLS13_AES_128_GCM_SHA256,
+		TLS13_CHACHA20_POLY1305_SHA256,
+	];
+	let versions = match min_tls {
+		MinTls::V1_2 => {
+			cipher_suites.extend([
+				// TLS 1.2 suites
+				TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+				TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+				TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+				TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+				TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+				TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+			]);
+			rustls::ALL_VERSIONS
+		}
+		MinTls::V1_3 => TLS13_ONLY,
+	};
+
+	(cipher_suites, versions)
+}
+
+fn create_tls_config(min_tls: MinTls) -> ClientConfig {
 	let mut root_store = RootCertStore::empty();
 	root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
 		rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
 	}));
 
+	let (cipher_suites, versions) = tls_policy(min_tls);
 	ClientConfig::builder()
-		.with_cipher_suites(&[
-			// TLS 1.3 suites
-			TLS13_AES_256_GCM_SHA384,
-			TLS13_AES_128_GCM_SHA256,
-			TLS13_CHACHA20_POLY1305_SHA256,
-			// TLS 1.2 suites
-			TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
-			TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-			TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-			TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-			TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
-			TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-		])
+		.with_cipher_suites(&cipher_suites)
 		.with_safe_default_kx_groups()
-		.with_safe_default_protocol_versions()
+		.with_protocol_versions(versions)
 		.expect("Failed to set TLS protocol versions")
 		.with_root_certificates(root_store)
 		.with_no_client_auth()
 }
 
-pub static HTTPS_CONNECTOR: LazyLock<HttpsConnector<HttpConnector>> = LazyLock::new(|| {
-	let tls_config = create_tls_config();
+/// Keep-alive settings of the upstream connection pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSettings {
+	/// Idle connections kept open per host.
+	pub max_idle_per_host: usize,
+	/// How long an idle connection is kept before being closed.
+	pub idle_timeout: Duration,
+}
+
+impl PoolSettings {
+	fn parse(size: Option<String>, idle_timeout: Option<String>) -> Self {
+		Self {
+			max_idle_per_host: size.and_then(|size| size.trim().parse().ok()).unwrap_or(DEFAULT_POOL_SIZE),
+			idle_timeout: Duration::from_secs(idle_timeout.and_then(|secs| secs.trim().parse().ok()).unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT)),
+		}
+	}
+}
+
+const DEFAULT_POOL_SIZE: usize = 32;
+const DEFAULT_POOL_IDLE_TIMEOUT: u64 = 90;
+
+/// Effective upstream pool settings, from `REDLIB_UPSTREAM_POOL_SIZE` and
+/// `REDLIB_UPSTREAM_POOL_IDLE_TIMEOUT`.
+pub static UPSTREAM_POOL: LazyLock<PoolSettings> =
+	LazyLock::new(|| PoolSettings::parse(get_setting("REDLIB_UPSTREAM_POOL_SIZE"), get_setting("REDLIB_UPSTREAM_POOL_IDLE_TIMEOUT")));
+
+/// Policy for TLS connections to Reddit. Startup refuses invalid values, so
+/// falling back to the default here is only a formality.
+pub static UPSTREAM_MIN_TLS: LazyLock<MinTls> = LazyLock::new(|| MinTls::parse(get_setting("REDLIB_UPSTREAM_MIN_TLS")).unwrap_or_default());
+
+pub static HTTPS_CONNECTOR: LazyLock<HttpsConnector<HttpConnector>> = LazyLock::new(|| https_connector(*UPSTREAM_MIN_TLS));
+
+fn https_connector(min_tls: MinTls) -> HttpsConnector<HttpConnector> {
+	let tls_config = create_tls_config(min_tls);
+
+	// Probe idle sockets so connections silently dropped by a middlebox are
+	// noticed before a request is sent over them.
+	let mut http = HttpConnector::new();
+	http.enforce_http(false);
+	http.set_keepalive(Some(UPSTREAM_POOL.idle_timeout));
+	http.set_nodelay(true);
+
 	hyper_rustls::HttpsConnectorBuilder::new()
 		.with_tls_config(tls_config)
 		.https_only()
 		.enable_http1()
-		.build()
-});
+		.wrap_connector(http)
+}
 
-pub static CLIENT: LazyLock<Client<HttpsConnector<HttpConnector>>> = LazyLock::new(|| Client::builder().build::<_, Body>(HTTPS_CONNECTOR.clone()));
+pub static CLIENT: LazyLock<Client<HttpsConnector<HttpConnector>>> = LazyLock::new(|| {
+	Client::builder()
+		.pool_max_idle_per_host(UPSTREAM_POOL.max_idle_per_host)
+		.pool_idle_timeout(UPSTREAM_POOL.idle_timeout)
+		.build::<_, Body>(HTTPS_CONNECTOR.clone())
+});
 
 pub static OAUTH_CLIENT: LazyLock<ArcSwap<Oauth>> = LazyLock::new(|| {
-	let client = block_on(Oauth::new());
+	let client = block_on(Oauth::cached_or_new());
 	tokio::spawn(token_daemon());
 	ArcSwap::new(client.into())
 });
 
+/// Slots for requests to Reddit, shared by every route so that endpoints
+/// fanning out to many requests can't flood Reddit. Sized by
+/// `REDLIB_MAX_CONCURRENT_UPSTREAM`; `None` when unlimited.
+static UPSTREAM_SLOTS: LazyLock<Option<(usize, Semaphore)>> = LazyLock::new(|| {
+	get_setting("REDLIB_MAX_CONCURRENT_UPSTREAM")
+		.and_then(|limit| limit.trim().parse().ok())
+		.filter(|limit| *limit > 0)
+		.map(|limit| (limit, Semaphore::new(limit)))
+});
+
+static UPSTREAM_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static UPSTREAM_WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Requests to Reddit under way and queued, as reported by `/api/info` and
+/// `/metrics`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamLoad {
+	pub in_flight: usize,
+	pub waiting: usize,
+	pub limit: Option<usize>,
+}
+
+pub fn upstream_load() -> UpstreamLoad {
+	UpstreamLoad {
+		in_flight: UPSTREAM_IN_FLIGHT.load(Ordering::Relaxed),
+		waiting: UPSTREAM_WAITING.load(Ordering::Relaxed),
+		limit: UPSTREAM_SLOTS.as_ref().map(|(limit, _)| *limit),
+	}
+}
+
+/// Keeps a request counted as in flight until dropped.
+struct InFlight;
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		UPSTREAM_IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// Run `request` once a slot to Reddit is free. Only sending it and
+/// receiving the headers hold the slot, so following a redirect never waits
+/// on a slot its own first request is holding.
+async fn with_upstream_slot<F: Future>(slots: Option<&Semaphore>, request: F) -> F::Output {
+	let _permit = match slots {
+		Some(slots) => {
+			UPSTREAM_WAITING.fetch_add(1, Ordering::Relaxed);
+			let permit = slots.acquire().await.ok();
+			UPSTREAM_WAITING.fetch_sub(1, Ordering::Relaxed);
+			permit
+		}
+		None => None,
+	};
+	UPSTREAM_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+	let _in_flight = InFlight;
+	request.await
+}
+
+/// Send `req` with the shared client once a slot to Reddit is free, for
+/// requests made outside of [`json`]: media, OAuth tokens and peers.
+pub(crate) async fn request_upstream(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+	with_upstream_slot(UPSTREAM_SLOTS.as_ref().map(|(_, slots)| slots), CLIENT.request(req)).await
+}
+
 pub static OAUTH_RATELIMIT_REMAINING: AtomicU16 = AtomicU16::new(99);
 
 pub static OAUTH_IS_ROLLING_OVER: AtomicBool = AtomicBool::new(false);
 
+/// Reddit API hosts to try for GET requests, in order, as `(base URL, host)`
+/// pairs. Configured through `REDLIB_UPSTREAM_HOSTS`.
+static UPSTREAM_HOSTS: LazyLock<Vec<(&'static str, &'static str)>> = LazyLock::new(|| {
+	parse_upstream_hosts(get_setting("REDLIB_UPSTREAM_HOSTS"))
+		.into_iter()
+		.map(|host| (&*format!("https://{host}").leak(), &*host.leak()))
+		.collect()
+});
+
 const URL_PAIRS: [(&str, &str); 2] = [
 	(ALTERNATIVE_REDDIT_URL_BASE, ALTERNATIVE_REDDIT_URL_BASE_HOST),
 	(REDDIT_SHORT_URL_BASE, REDDIT_SHORT_URL_BASE_HOST),
@@ -189,17 +352,74 @@ pub async fn proxy(req: Request<Body>, format: &str) -> Result<Response<Body>, S
 	stream(&url, &req).await
 }
 
+/// Proxy an i.redd.it image. With `?res=<width>`, the preview resolution
+/// closest to that width is served instead, as long as the image was part of
+/// a recently loaded post; otherwise the original is passed through.
+pub async fn proxy_image(mut req: Request<Body>) -> Result<Response<Body>, String> {
+	let query = format!("?{}", req.uri().query().unwrap_or_default());
+	let Some(res) = param(&query, "res") else {
+		return proxy(req, "https://i.redd.it/{path}").await;
+	};
+
+	let variant = res.parse().ok().and_then(|width| preview_variant(&req.param("path").unwrap_or_default(), width));
+	if let Some(url) = variant {
+		return stream(&url, &req).await;
+	}
+
+	// `res` means nothing to i.redd.it
+	if let Ok(uri) = req.uri().path().parse() {
+		*req.uri_mut() = uri;
+	}
+	proxy(req, "https://i.redd.it/{path}").await
+}
+
+/// Proxy a v.redd.it video. With `?download=true` the video is sent as an
+/// attachment named after the `post` param (or the video id) instead of
+/// being displayed inline.
+pub async fn proxy_video(mut req: Request<Body>) -> Result<Response<Body>, String> {
+	let query = format!("?{}", req.uri().query().unwrap_or_default());
+	if param(&query, "download").as_deref() != Some("true") {
+		return proxy(req, "https://v.redd.it/{id}/DASH_{size}").await;
+	}
+
+	let name = param(&query, "post")
+		.or_else(|| req.param("id"))
+		.filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+		.unwrap_or_else(|| "video".to_string());
+
+	// Our download params mean nothing to v.redd.it
+	if let Ok(uri) = req.uri().path().parse() {
+		*req.uri_mut() = uri;
+	}
+
+	let mut res = proxy(req, "https://v.redd.it/{id}/DASH_{size}").await?;
+	if res.status().is_success() {
+		set_attachment(&mut res, &format!("{name}.mp4"));
+	}
+	Ok(res)
+}
+
+/// Mark a proxied video response as a download called `filename`.
+fn set_attachment(res: &mut Response<Body>, filename: &str) {
+	let headers = res.headers_mut();
+	if let Ok(value) = HeaderValue::from_str(&format!("attachment; filename=\"{filename}\"")) {
+		headers.insert(header::CONTENT_DISPOSITION, value);
+	}
+	if !headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|v| v.starts_with("video/")) {
+		headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
+	}
+}
+
 async fn stream(url: &str, req: &Request<Body>) -> Result<Response<Body>, String> {
 	// First parameter is target URL (mandatory).
 	let parsed_uri = url.parse::<Uri>().map_err(|_| "Couldn't parse URL".to_string())?;
 
-	// Build the hyper client from the HTTPS connector.
-	let client: &LazyLock<Client<_, Body>> = &CLIENT;
-
 	let mut builder = Request::get(parsed_uri);
 
-	// Copy useful headers from original request
-	for &key in &["Range", "If-Modified-Since", "Cache-Control"] {
+	// Copy useful headers from original request. Accept-Encoding lets Reddit
+	// pick an encoding the client understands, such as zstd, which we then
+	// pass through untouched.
+	for &key in &["Range", "If-Modified-Since", "Cache-Control", "Accept-Encoding"] {
 		if let Some(value) = req.headers().get(key) {
 			builder = builder.header(key, value);
 		}
@@ -213,8 +433,7 @@ async fn stream(url: &str, req: &Request<Body>) -> Result<Response<Body>, String
 
 	let stream_request = builder.body(Body::empty()).map_err(|_| "Couldn't build empty body in stream".to_string())?;
 
-	client
-		.request(stream_request)
+	request_upstream(stream_request)
 		.await
 		.map(|mut res| {
 			let mut rm = |key: &str| res.headers_mut().remove(key);
@@ -232,20 +451,117 @@ async fn stream(url: &str, req: &Request<Body>) -> Result<Response<Body>, String
 			rm("Nel");
 			rm("Report-To");
 
-			res
+			if req.headers().contains_key(header::ACCEPT_ENCODING) {
+				crate::server::add_vary_accept_encoding(res.headers_mut());
+			}
+			res.extensions_mut().insert(crate::server::Relayed);
+
+			match max_media_bytes() {
+				Some(max_bytes) => limit_media_size(res, max_bytes),
+				None => res,
+			}
 		})
 		.map_err(|e| e.to_string())
 }
 
+/// Returns the value of `REDLIB_MAX_MEDIA_BYTES`, if set to a valid number.
+fn max_media_bytes() -> Option<u64> {
+	get_setting("REDLIB_MAX_MEDIA_BYTES").and_then(|val| val.parse::<u64>().ok())
+}
+
+/// Guards a proxied media response against exceeding `max_bytes`. If Reddit
+/// declares a larger `Content-Length`, the response is replaced with a 413.
+/// Otherwise the body keeps streaming, but is aborted as soon as more than
+/// `max_bytes` have passed through.
+fn limit_media_size(mut res: Response<Body>, max_bytes: u64) -> Response<Body> {
+	let content_length = res
+		.headers()
+		.get(header::CONTENT_LENGTH)
+		.and_then(|val| val.to_str().ok())
+		.and_then(|val| val.parse::<u64>().ok());
+
+	if content_length.is_some_and(|len| len > max_bytes) {
+		return Response::builder()
+			.status(413)
+			.header("content-type", "text/plain")
+			.body("Media exceeds the maximum size allowed by this instance".into())
+			.unwrap_or_default();
+	}
+
+	let mut streamed: u64 = 0;
+	let body = std::mem::take(res.body_mut()).map(move |chunk| {
+		let chunk = chunk.map_err(|e| io::Error::other(e.to_string()))?;
+		streamed += chunk.len() as u64;
+		if streamed > max_bytes {
+			return Err(io::Error::other("Media exceeds the maximum size allowed by this instance"));
+		}
+		Ok(chunk)
+	});
+	*res.body_mut() = Body::wrap_stream(body);
+
+	res
+}
+
 /// Makes a GET request to Reddit at `path`. By default, this will honor HTTP
 /// 3xx codes Reddit returns and will automatically redirect.
-fn reddit_get(path: String, quarantine: bool) -> Boxed<Result<Response<Body>, String>> {
-	request(&Method::GET, path, true, quarantine, REDDIT_URL_BASE, REDDIT_URL_BASE_HOST)
+///
+/// If a host errors out, the request moves on to the next of `UPSTREAM_HOSTS`.
+///
+/// While Reddit keeps failing, `UPSTREAM_BREAKER` turns requests away
+/// without trying any host at all.
+fn reddit_get(path: String, quarantine: bool, over18: bool) -> Boxed<Result<Response<Body>, String>> {
+	async move {
+		if !UPSTREAM_BREAKER.allow(Instant::now()) {
+			RequestContext::with(|context| context.short_circuited.set(true));
+			return Err(UPSTREAM_UNAVAILABLE.to_string());
+		}
+
+		let mut hosts = UPSTREAM_HOSTS.iter().peekable();
+		loop {
+			let Some(&(base_path, host)) = hosts.next() else {
+				return Err("No upstream hosts configured".to_string());
+			};
+
+			let result = request(&Method::GET, path.clone(), true, quarantine, over18, base_path, host).await;
+			match hosts.peek() {
+				Some((_, next_host)) if should_retry(&result) => warn!("{host} failed for {path}, retrying against {next_host}"),
+				_ => {
+					UPSTREAM_BREAKER.record(!should_retry(&result), Instant::now());
+					RequestContext::with(|context| context.upstream_host.set(Some(host)));
+					return result;
+				}
+			}
+		}
+	}
+	.boxed()
+}
+
+/// Whether a failed upstream GET is worth repeating against another host.
+fn should_retry(result: &Result<Response<Body>, String>) -> bool {
+	match result {
+		Ok(response) => response.status().is_server_error(),
+		Err(_) => true,
+	}
+}
+
+fn parse_upstream_hosts(value: Option<String>) -> Vec<String> {
+	let hosts: Vec<String> = value
+		.unwrap_or_default()
+		.split(',')
+		.map(|host| host.trim().trim_start_matches("https://").trim_end_matches('/').to_string())
+		.filter(|host| !host.is_empty())
+		.collect();
+
+	if hosts.is_empty() {
+		vec![REDDIT_URL_BASE_HOST.to_string()]
+	} else {
+		hosts
+	}
 }
 
 /// Makes a HEAD request to Reddit at `path, using the short URL base. This will not follow redirects.
 fn reddit_short_head(path: String, quarantine: bool, base_path: &'static str, host: &'static str) -> Boxed<Result<Response<Body>, String>> {
-	request(&Method::HEAD, path, false, quarantine, base_path, host)
+	request(&Method::HEAD, path, false, quarantine, false, base_path, host)
 }
 
 // /// Makes a HEAD request to Reddit at `path`. This will not follow redirects.
@@ -254,10 +570,30 @@ fn reddit_short_head(path: String, quarantine: bool, base_path: &'static str, ho
 // }
 // Unused - reddit_head is only ever called in the context of a short URL
 
+/// Cookie accepting the gates Reddit puts in front of some communities.
+fn gate_cookie(quarantine: bool, over18: bool) -> &'static str {
+	if quarantine {
+		"_options=%7B%22pref_quarantine_optin%22%3A%20true%2C%20%22pref_gated_sr_optin%22%3A%20true%7D; over18=1"
+	} else if over18 {
+		"over18=1"
+	} else {
+		""
+	}
+}
+
 /// Makes a request to Reddit. If `redirect` is `true`, `request_with_redirect`
 /// will recurse on the URL that Reddit provides in the Location HTTP header
-/// in its response.
-fn request(method: &'static Method, path: String, redirect: bool, quarantine: bool, base_path: &'static str, host: &'static str) -> Boxed<Result<Response<Body>, String>> {
+/// in its response. `quarantine` opts into quarantined and gated
+/// communities, while `over18` only accepts the over-18 gate.
+fn request(
+	method: &'static Method,
+	path: String,
+	redirect: bool,
+	quarantine: bool,
+	over18: bool,
+	base_path: &'static str,
+	host: &'static str,
+) -> Boxed<Result<Response<Body>, String>> {
 	// Build Reddit URL from path.
 	let url = format!("{base_path}{path}");
 
@@ -269,14 +605,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 	let mut headers: Vec<(String, String)> = vec![
 		("Host".into(), host.into()),
 		("Accept-Encoding".into(), if method == Method::GET { "gzip".into() } else { "identity".into() }),
-		(
-			"Cookie".into(),
-			if quarantine {
-				"_options=%7B%22pref_quarantine_optin%22%3A%20true%2C%20%22pref_gated_sr_optin%22%3A%20true%7D".into()
-			} else {
-				"".into()
-			},
-		),
+		("Cookie".into(), gate_cookie(quarantine, over18).into()),
 	];
 
 	{
@@ -299,7 +628,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 
 	async move {
 		match builder {
-			Ok(req) => match client.request(req).await {
+			Ok(req) => match with_upstream_slot(UPSTREAM_SLOTS.as_ref().map(|(_, slots)| slots), client.request(req)).await {
 				Ok(mut response) => {
 					// Reddit may respond with a 3xx. Decide whether or not to
 					// redirect based on caller params.
@@ -328,6 +657,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 									//     2. Percent-encode the path.
 									let new_path = percent_encode(val.as_bytes(), CONTROLS)
 										.to_string()
+										.trim_start_matches(base_path)
 										.trim_start_matches(REDDIT_URL_BASE)
 										.trim_start_matches(ALTERNATIVE_REDDIT_URL_BASE)
 										.to_string();
@@ -337,6 +667,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 								.to_string(),
 							true,
 							quarantine,
+							over18,
 							base_path,
 							host,
 						)
@@ -391,7 +722,7 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 					}
 				}
 				Err(e) => {
-					dbg_msg!("{method} {REDDIT_URL_BASE}{path}: {}", e);
+					dbg_msg!("{method} {base_path}{path}: {}", e);
 
 					Err(e.to_string())
 				}
@@ -402,13 +733,50 @@ fn request(method: &'static Method, path: String, redirect: bool, quarantine: bo
 	.boxed()
 }
 
+/// Result of a JSON request to Reddit, along with what it should tell the
+/// pages waiting on it: the host that answered, and whether the breaker
+/// turned it away.
+type SharedJson = (Result<Value, UpstreamError>, Option<&'static str>, bool);
+
+/// JSON requests to Reddit under way, shared by identical requests made
+/// while they are.
+static JSON_IN_FLIGHT: LazyLock<SingleFlight<SharedJson>> = LazyLock::new(SingleFlight::new);
+
 /// Make a request to a Reddit API and parse the JSON response
+pub async fn json(path: String, quarantine: bool) -> Result<Value, UpstreamError> {
+	json_gated(path, quarantine, false).await
+}
+
+/// Like [`json`], with `over18` accepting Reddit's over-18 gate without
+/// opting into quarantined and gated communities the way `quarantine` does.
 #[cached(size = 100, time = 30, result = true)]
-pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
+pub async fn json_gated(path: String, quarantine: bool, over18: bool) -> Result<Value, UpstreamError> {
+	// Pages missing the cache together get their answer from one request
+	let (result, host, short_circuited) = JSON_IN_FLIGHT
+		.run(&format!("{quarantine}:{over18}:{path}"), || {
+			REQUEST_CONTEXT.scope(RequestContext::inherited(), async move {
+				let result = fetch_json(path, quarantine, over18).await;
+				REQUEST_CONTEXT.with(|context| (result, context.upstream_host.get(), context.short_circuited.get()))
+			})
+		})
+		.await;
+
+	RequestContext::with(|context| {
+		if host.is_some() {
+			context.upstream_host.set(host);
+		}
+		if short_circuited {
+			context.short_circuited.set(true);
+		}
+	});
+	result
+}
+
+async fn fetch_json(path: String, quarantine: bool, over18: bool) -> Result<Value, UpstreamError> {
 	// Closure to quickly build errors
-	let err = |msg: &str, e: String, path: String| -> Result<Value, String> {
+	let err = |msg: &str, e: String, path: String| -> Result<Value, UpstreamError> {
 		// eprintln!("{} - {}: {}", url, msg, e);
-		Err(format!("{msg}: {e} | {path}"))
+		Err(format!("{msg}: {e} | {path}").into())
 	};
 
 	// First, handle rolling over the OAUTH_CLIENT if need be.
@@ -421,7 +789,7 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 	OAUTH_RATELIMIT_REMAINING.fetch_sub(1, Ordering::SeqCst);
 
 	// Fetch the url...
-	match reddit_get(path.clone(), quarantine).await {
+	match reddit_get(path.clone(), quarantine, over18).await {
 		Ok(response) => {
 			let status = response.status();
 
@@ -445,6 +813,8 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 				None
 			};
 
+			let content_type = response.headers().get(header::CONTENT_TYPE).and_then(|val| val.to_str().ok()).map(ToString::to_string);
+
 			// asynchronously aggregate the chunks of the body
 			match hyper::body::aggregate(response).await {
 				Ok(body) => {
@@ -454,14 +824,28 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 						// Rate limited, so spawn a force_refresh_token()
 						tokio::spawn(force_refresh_token());
 						return match reset {
-							Some(val) => Err(format!(
-								"Reddit rate limit exceeded. Try refreshing in a few seconds.\
+							Some(val) => Err(
+								format!(
+									"Reddit rate limit exceeded. Try refreshing in a few seconds.\
 								 Rate limit will reset in: {val}"
-							)),
-							None => Err("Reddit rate limit exceeded".to_string()),
+								)
+								.into(),
+							),
+							None => Err("Reddit rate limit exceeded".into()),
 						};
 					}
 
+					if is_blocked_page(status, content_type.as_deref(), body.chunk()) {
+						// Reddit's anti-bot wall tends to target the token, so roll it over,
+						// once for the whole burst of requests it turns away
+						warn!("Reddit answered {path} with an HTML page instead of JSON");
+						if !OAUTH_IS_ROLLING_OVER.load(Ordering::SeqCst) {
+							warn!("Spawning force_refresh_token()");
+							tokio::spawn(force_refresh_token());
+						}
+						return Err(UpstreamError::Blocked);
+					}
+
 					// Parse the response from Reddit as JSON
 					match serde_json::from_reader(body.reader()) {
 						Ok(value) => {
@@ -482,7 +866,7 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 								if json["message"] == "Unauthorized" {
 									error!("Forcing a token refresh");
 									let () = force_refresh_token().await;
-									return Err("OAuth token has expired. Please refresh the page!".to_string());
+									return Err("OAuth token has expired. Please refresh the page!".into());
 								}
 
 								// Handle quarantined
@@ -493,6 +877,10 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 								if json["reason"] == "gated" {
 									return Err("gated".into());
 								}
+								// Handle NSFW communities behind the over-18 consent gate
+								if json["reason"] == "over18" {
+									return Err("over18".into());
+								}
 								// Handle private subs
 								if json["reason"] == "private" {
 									return Err("private".into());
@@ -502,7 +890,7 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 									return Err("banned".into());
 								}
 
-								Err(format!("Reddit error {} \"{}\": {} | {path}", json["error"], json["reason"], json["message"]))
+								Err(format!("Reddit error {} \"{}\": {} | {path}", json["error"], json["reason"], json["message"]).into())
 							} else {
 								Ok(json)
 							}
@@ -510,7 +898,7 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 						Err(e) => {
 							error!("Got an invalid response from reddit {e}. Status code: {status}");
 							if status.is_server_error() {
-								Err("Reddit is having issues, check if there's an outage".to_string())
+								Err("Reddit is having issues, check if there's an outage".into())
 							} else {
 								err("Failed to parse page JSON data", e.to_string(), path)
 							}
@@ -524,8 +912,153 @@ pub async fn json(path: String, quarantine: bool) -> Result<Value, String> {
 	}
 }
 
+/// What Reddit answered a request with, untouched, for `/api/debug/raw`.
+pub struct RawResponse {
+	pub status: StatusCode,
+	pub content_type: Option<String>,
+	pub body: body::Bytes,
+	/// URL the request was made to.
+	pub url: String,
+	/// Headers it was made with, credentials redacted.
+	pub headers: Vec<(String, String)>,
+}
+
+/// Fetch `path` from Reddit the way [`json`] does, but hand back the body as
+/// it came instead of parsing it. Nothing is cached or shared.
+pub async fn raw(path: String) -> Result<RawResponse, String> {
+	let (response, host) = REQUEST_CONTEXT
+		.scope(RequestContext::inherited(), async {
+			let response = reddit_get(path.clone(), false, false).await;
+			(response, REQUEST_CONTEXT.with(|context| context.upstream_host.get()))
+		})
+		.await;
+	let response = response?;
+
+	let status = response.status();
+	let content_type = response.headers().get(header::CONTENT_TYPE).and_then(|val| val.to_str().ok()).map(ToString::to_string);
+	let body = body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+
+	Ok(RawResponse {
+		status,
+		content_type,
+		body,
+		url: format!("https://{}{path}", host.unwrap_or(REDDIT_URL_BASE_HOST)),
+		headers: redact_headers(OAUTH_CLIENT.load_full().headers_map.clone()),
+	})
+}
+
+/// Headers of a request to Reddit that say nothing about who is making it,
+/// and so may be shown as they are.
+const DISPLAYABLE_HEADERS: [&str; 9] = [
+	"accept",
+	"accept-encoding",
+	"host",
+	"origin",
+	"user-agent",
+	"x-reddit-compression",
+	"x-reddit-media-codecs",
+	"x-reddit-qos",
+	"x-reddit-retry",
+];
+
+/// Sort the headers of a request for display, hiding the value of any that
+/// could identify the instance to Reddit: its credentials, but also the
+/// device and logged-out ids it was handed.
+fn redact_headers(headers: HashMap<String, String>) -> Vec<(String, String)> {
+	let mut headers: Vec<(String, String)> = headers
+		.into_iter()
+		.map(|(name, value)| {
+			let shown = DISPLAYABLE_HEADERS.contains(&name.to_ascii_lowercase().as_str());
+			(name, if shown { value } else { "[redacted]".to_string() })
+		})
+		.collect();
+	headers.sort();
+	headers
+}
+
+/// Message of [`UpstreamError::Blocked`].
+pub const UPSTREAM_BLOCKED: &str = "Reddit blocked the request, try again in a little while";
+
+/// Why a request to Reddit's JSON API failed. It reads as its message, so
+/// it can be compared against and shown like the plain strings handlers pass
+/// around.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamError {
+	/// Reddit answered with an HTML page, which is how its "you've been
+	/// blocked" interstitial shows up
+	Blocked,
+	/// Any other failure, described by its message
+	Failed(String),
+}
+
+impl std::ops::Deref for UpstreamError {
+	type Target = str;
+
+	fn deref(&self) -> &str {
+		match self {
+			Self::Blocked => UPSTREAM_BLOCKED,
+			Self::Failed(msg) => msg,
+		}
+	}
+}
+
+impl std::fmt::Display for UpstreamError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self)
+	}
+}
+
+impl PartialEq<&str> for UpstreamError {
+	fn eq(&self, other: &&str) -> bool {
+		**self == **other
+	}
+}
+
+impl From<String> for UpstreamError {
+	fn from(msg: String) -> Self {
+		Self::Failed(msg)
+	}
+}
+
+impl From<&str> for UpstreamError {
+	fn from(msg: &str) -> Self {
+		Self::Failed(msg.to_string())
+	}
+}
+
+impl From<UpstreamError> for String {
+	fn from(err: UpstreamError) -> Self {
+		err.to_string()
+	}
+}
+
+/// Whether a successful response that should have been JSON is an HTML page
+/// instead, going by its content type or, failing that, its first character.
+/// Reddit's anti-bot wall answers with a 200; HTML error pages that come
+/// with an error status are ordinary upstream failures.
+fn is_blocked_page(status: StatusCode, content_type: Option<&str>, body: &[u8]) -> bool {
+	if !status.is_success() {
+		return false;
+	}
+	if content_type.is_some_and(|content_type| content_type.to_ascii_lowercase().starts_with("text/html")) {
+		return true;
+	}
+	body.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'<')
+}
+
+/// Drop every cached Reddit response and canonical path, returning how many
+/// entries were evicted.
+pub async fn flush_cache() -> usize {
+	let mut json = JSON_GATED.lock().await;
+	let mut canonical = CANONICAL_PATH.lock().await;
+	let evicted = json.cache_size() + canonical.cache_size();
+	json.cache_clear();
+	canonical.cache_clear();
+	evicted
+}
+
 #[cfg(test)]
-use {crate::config::get_setting, sealed_test::prelude::*};
+use sealed_test::prelude::*;
 
 #[test]
 #[sealed_test(env = [("REDLIB_DEFAULT_SUBSCRIPTIONS", "rust")])]
@@ -574,3 +1107,152 @@ async fn test_gated_sub() {
 	assert!(link.is_err());
 	assert_eq!(link, Err("gated".into()));
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_media_size_limit() {
+	// A declared Content-Length over the limit is rejected outright
+	let res = Response::builder().header(header::CONTENT_LENGTH, "2048").body(Body::from(vec![0u8; 2048])).unwrap();
+	assert_eq!(limit_media_size(res, 1024).status(), 413);
+
+	// Without a Content-Length, the stream is aborted once the limit is passed
+	let chunks: Vec<Result<Vec<u8>, io::Error>> = vec![Ok(vec![0u8; 512]), Ok(vec![0u8; 512]), Ok(vec![0u8; 512])];
+	let res = Response::new(Body::wrap_stream(futures_lite::stream::iter(chunks)));
+	let res = limit_media_size(res, 1024);
+	assert_eq!(res.status(), 200);
+	assert!(body::to_bytes(res.into_body()).await.is_err());
+
+	// Responses within the limit pass through untouched
+	let res = limit_media_size(Response::new(Body::from(vec![0u8; 1024])), 1024);
+	assert_eq!(body::to_bytes(res.into_body()).await.unwrap().len(), 1024);
+}
+
+#[test]
+fn test_video_attachment_headers() {
+	let mut res = Response::builder().header("content-type", "binary/octet-stream").body(Body::empty()).unwrap();
+	set_attachment(&mut res, "abc123.mp4");
+	assert_eq!(res.headers()["content-disposition"], "attachment; filename=\"abc123.mp4\"");
+	assert_eq!(res.headers()["content-type"], "video/mp4");
+
+	let mut res = Response::builder().header("content-type", "video/webm").body(Body::empty()).unwrap();
+	set_attachment(&mut res, "abc123.mp4");
+	assert_eq!(res.headers()["content-type"], "video/webm");
+}
+
+#[test]
+fn test_parse_upstream_hosts() {
+	assert_eq!(parse_upstream_hosts(None), vec!["oauth.reddit.com"]);
+	assert_eq!(parse_upstream_hosts(Some(" , ".into())), vec!["oauth.reddit.com"]);
+	assert_eq!(
+		parse_upstream_hosts(Some("https://www.reddit.com/, oauth.reddit.com".into())),
+		vec!["www.reddit.com", "oauth.reddit.com"]
+	);
+}
+
+#[test]
+fn test_upstream_retry_conditions() {
+	let response = |status: u16| Ok(Response::builder().status(status).body(Body::empty()).unwrap());
+	assert!(should_retry(&response(502)));
+	assert!(should_retry(&Err("connection reset".to_string())));
+	assert!(!should_retry(&response(200)));
+	assert!(!should_retry(&response(404)));
+}
+
+#[test]
+fn test_pool_settings() {
+	assert_eq!(
+		PoolSettings::parse(None, None),
+		PoolSettings {
+			max_idle_per_host: 32,
+			idle_timeout: Duration::from_secs(90)
+		}
+	);
+	assert_eq!(PoolSettings::parse(Some("8".into()), Some(" 30 ".into())).idle_timeout, Duration::from_secs(30));
+	assert_eq!(PoolSettings::parse(Some("lots".into()), None).max_idle_per_host, 32);
+}
+
+#[test]
+fn test_redacting_debug_headers() {
+	let headers = HashMap::from([
+		("Authorization".to_string(), "Bearer abc".to_string()),
+		("User-Agent".to_string(), "Redlib".to_string()),
+		("x-reddit-session".to_string(), "xyz".to_string()),
+		("x-reddit-loid".to_string(), "loid".to_string()),
+		("X-Reddit-Device-Id".to_string(), "uuid".to_string()),
+		("x-reddit-qos".to_string(), "down-rate-mbps=300".to_string()),
+	]);
+	assert_eq!(
+		redact_headers(headers),
+		vec![
+			("Authorization".to_string(), "[redacted]".to_string()),
+			("User-Agent".to_string(), "Redlib".to_string()),
+			("X-Reddit-Device-Id".to_string(), "[redacted]".to_string()),
+			("x-reddit-loid".to_string(), "[redacted]".to_string()),
+			("x-reddit-qos".to_string(), "down-rate-mbps=300".to_string()),
+			("x-reddit-session".to_string(), "[redacted]".to_string()),
+		]
+	);
+}
+
+#[test]
+fn test_upstream_tls_policy() {
+	assert_eq!(MinTls::parse(None), Ok(MinTls::V1_2));
+	assert_eq!(MinTls::parse(Some("1.3".into())), Ok(MinTls::V1_3));
+	assert!(MinTls::parse(Some("1.1".into())).is_err());
+
+	let (suites, versions) = tls_policy(MinTls::V1_2);
+	assert_eq!((suites.len(), versions.len()), (9, 2));
+	let (suites, versions) = tls_policy(MinTls::V1_3);
+	assert_eq!((suites.len(), versions.len()), (3, 1));
+	assert!(suites.iter().all(|suite| suite.version() == &version::TLS13));
+
+	// Either policy makes a usable connector
+	let _ = https_connector(MinTls::V1_2);
+	let _ = https_connector(MinTls::V1_3);
+}
+
+#[test]
+fn test_detecting_blocked_pages() {
+	let blocked = "\n  <!DOCTYPE html><html><body>You've been blocked by network security.</body></html>";
+	assert!(is_blocked_page(StatusCode::OK, Some("text/html; charset=utf-8"), blocked.as_bytes()));
+	assert!(is_blocked_page(StatusCode::OK, Some("application/json"), blocked.as_bytes()));
+	assert!(is_blocked_page(StatusCode::OK, None, b"<html>"));
+	assert!(!is_blocked_page(StatusCode::OK, Some("application/json; charset=UTF-8"), br#"{"kind": "Listing"}"#));
+	assert!(!is_blocked_page(StatusCode::OK, None, b" [{\"kind\": \"Listing\"}]"));
+	// Reddit's own error pages are outages, not blocks
+	assert!(!is_blocked_page(StatusCode::SERVICE_UNAVAILABLE, Some("text/html"), b"<html>Service Unavailable</html>"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_upstream_slots_limit_concurrency() {
+	use std::sync::Arc;
+
+	let slots = Arc::new(Semaphore::new(2));
+	let running = Arc::new(AtomicUsize::new(0));
+	let most = Arc::new(AtomicUsize::new(0));
+	let mut requests = tokio::task::JoinSet::new();
+	for _ in 0..8 {
+		let (slots, running, most) = (slots.clone(), running.clone(), most.clone());
+		requests.spawn(async move {
+			with_upstream_slot(Some(&slots), async {
+				let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+				most.fetch_max(now, Ordering::SeqCst);
+				tokio::time::sleep(Duration::from_millis(20)).await;
+				running.fetch_sub(1, Ordering::SeqCst);
+			})
+			.await
+		});
+	}
+	while requests.join_next().await.is_some() {}
+
+	assert_eq!(most.load(Ordering::SeqCst), 2);
+	assert_eq!(slots.available_permits(), 2);
+}
+
+#[test]
+fn test_gate_cookie() {
+	assert!(gate_cookie(true, false).contains("pref_quarantine_optin"));
+	assert!(gate_cookie(true, false).ends_with("over18=1"));
+	// Acknowledging the over-18 gate doesn't opt into quarantined communities
+	assert_eq!(gate_cookie(false, true), "over18=1");
+	assert_eq!(gate_cookie(false, false), "");
+}
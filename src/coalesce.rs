@@ -0,0 +1,87 @@
+//! Coalescing of identical requests to Reddit made at the same time, so that
+//! a burst of cache misses for one page costs a single upstream request.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+/// Requests under way, keyed by what they fetch. Whoever asks for a key
+/// while it is in flight waits for the same result instead of fetching it
+/// again; once it has landed, the key is free for the next fetch.
+pub struct SingleFlight<T> {
+	in_flight: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+	pub fn new() -> Self {
+		Self { in_flight: Mutex::default() }
+	}
+
+	/// Get the result of `fetch` for `key`, sharing the fetch of anyone else
+	/// asking for it at the same time. Should the caller doing the fetch give
+	/// up on it, one of those waiting carries on with its own `fetch`.
+	pub async fn run<F, Fut>(&self, key: &str, fetch: F) -> T
+	where
+		F: FnOnce() -> Fut,
+		Fut: Future<Output = T>,
+	{
+		let cell = self.in_flight.lock().unwrap_or_else(|e| e.into_inner()).entry(key.to_string()).or_default().clone();
+		let value = cell.get_or_init(fetch).await.clone();
+
+		let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+		if in_flight.get(key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+			in_flight.remove(key);
+		}
+		value
+	}
+
+	/// Keys being fetched right now.
+	pub fn len(&self) -> usize {
+		self.in_flight.lock().unwrap_or_else(|e| e.into_inner()).len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl<T: Clone> Default for SingleFlight<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_single_flight() {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::time::Duration;
+
+	let flight = Arc::new(SingleFlight::new());
+	let calls = Arc::new(AtomicUsize::new(0));
+	let fetch = |flight: Arc<SingleFlight<String>>, calls: Arc<AtomicUsize>, key: &'static str| {
+		tokio::spawn(async move {
+			flight
+				.run(key, || async {
+					calls.fetch_add(1, Ordering::SeqCst);
+					tokio::time::sleep(Duration::from_millis(100)).await;
+					format!("{key} page")
+				})
+				.await
+		})
+	};
+
+	// Identical requests made together share one fetch
+	let requests: Vec<_> = (0..20).map(|_| fetch(flight.clone(), calls.clone(), "/r/rust")).collect();
+	for request in requests {
+		assert_eq!(request.await.unwrap(), "/r/rust page");
+	}
+	assert_eq!(calls.load(Ordering::SeqCst), 1);
+	assert!(flight.is_empty());
+
+	// Different keys don't wait on each other, and a finished key is fetched afresh
+	let (a, b) = (fetch(flight.clone(), calls.clone(), "/r/rust"), fetch(flight.clone(), calls.clone(), "/r/golang"));
+	assert_eq!((a.await.unwrap(), b.await.unwrap()), ("/r/rust page".to_string(), "/r/golang page".to_string()));
+	assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
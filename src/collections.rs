@@ -22,7 +22,7 @@ pub fn all() -> Vec<Collection> {
 			target: target.to_string(),
 		})
 		.collect();
-	entries.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+	entries.sort_by_key(|a| a.name.to_lowercase());
 	entries
 }
 
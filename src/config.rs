@@ -111,6 +111,114 @@ pub struct Config {
 
 	#[serde(rename = "REDLIB_COLLECTIONS")]
 	pub(crate) collections: Option<String>,
+
+	#[serde(rename = "REDLIB_MAX_MEDIA_BYTES")]
+	pub(crate) max_media_bytes: Option<String>,
+
+	#[serde(rename = "REDLIB_TLS_CERT")]
+	pub(crate) tls_cert: Option<String>,
+
+	#[serde(rename = "REDLIB_TLS_KEY")]
+	pub(crate) tls_key: Option<String>,
+
+	#[serde(rename = "REDLIB_HSTS")]
+	pub(crate) hsts: Option<String>,
+
+	#[serde(rename = "REDLIB_SECURITY_HEADERS")]
+	pub(crate) security_headers: Option<String>,
+
+	#[serde(rename = "REDLIB_REFERRER_POLICY")]
+	pub(crate) referrer_policy: Option<String>,
+
+	#[serde(rename = "REDLIB_CSP")]
+	pub(crate) csp: Option<String>,
+
+	#[serde(rename = "REDLIB_DEFAULT_SUBREDDIT")]
+	pub(crate) default_subreddit: Option<String>,
+
+	#[serde(rename = "REDLIB_DEFAULT_COLLECTION")]
+	pub(crate) default_collection: Option<String>,
+
+	#[serde(rename = "REDLIB_CURSOR_SECRET")]
+	pub(crate) cursor_secret: Option<String>,
+
+	#[serde(rename = "REDLIB_UPSTREAM_HOSTS")]
+	pub(crate) upstream_hosts: Option<String>,
+
+	#[serde(rename = "REDLIB_DISABLE_SITEMAP")]
+	pub(crate) disable_sitemap: Option<String>,
+
+	#[serde(rename = "REDLIB_ROBOTS_TXT")]
+	pub(crate) robots_txt: Option<String>,
+
+	#[serde(rename = "REDLIB_BLUR_KEYWORDS")]
+	pub(crate) blur_keywords: Option<String>,
+
+	#[serde(rename = "REDLIB_ADMIN_TOKEN")]
+	pub(crate) admin_token: Option<String>,
+
+	#[serde(rename = "REDLIB_UPSTREAM_POOL_SIZE")]
+	pub(crate) upstream_pool_size: Option<String>,
+
+	#[serde(rename = "REDLIB_UPSTREAM_POOL_IDLE_TIMEOUT")]
+	pub(crate) upstream_pool_idle_timeout: Option<String>,
+
+	#[serde(rename = "REDLIB_TRUST_PROXY")]
+	pub(crate) trust_proxy: Option<String>,
+
+	#[serde(rename = "REDLIB_TRUSTED_PROXIES")]
+	pub(crate) trusted_proxies: Option<String>,
+
+	#[serde(rename = "REDLIB_EXPORT_MAX_PAGES")]
+	pub(crate) export_max_pages: Option<String>,
+
+	#[serde(rename = "REDLIB_CIRCUIT_BREAKER_THRESHOLD")]
+	pub(crate) circuit_breaker_threshold: Option<String>,
+
+	#[serde(rename = "REDLIB_CIRCUIT_BREAKER_WINDOW")]
+	pub(crate) circuit_breaker_window: Option<String>,
+
+	#[serde(rename = "REDLIB_CIRCUIT_BREAKER_COOLDOWN")]
+	pub(crate) circuit_breaker_cooldown: Option<String>,
+
+	#[serde(rename = "REDLIB_USER_AGENTS")]
+	pub(crate) user_agents: Option<String>,
+
+	#[serde(rename = "REDLIB_MEDIA_BASE_URL")]
+	pub(crate) media_base_url: Option<String>,
+
+	#[serde(rename = "REDLIB_SUBREDDIT_SUGGESTION_THRESHOLD")]
+	pub(crate) subreddit_suggestion_threshold: Option<String>,
+
+	#[serde(rename = "REDLIB_ERROR_TEMPLATE")]
+	pub(crate) error_template: Option<String>,
+
+	#[serde(rename = "REDLIB_ERROR_INSTANCE")]
+	pub(crate) error_instance: Option<String>,
+
+	#[serde(rename = "REDLIB_MAX_CONCURRENT_UPSTREAM")]
+	pub(crate) max_concurrent_upstream: Option<String>,
+
+	#[serde(rename = "REDLIB_TOKEN_CACHE_FILE")]
+	pub(crate) token_cache_file: Option<String>,
+
+	#[serde(rename = "REDLIB_COMMENT_BODY_LIMIT")]
+	pub(crate) comment_body_limit: Option<String>,
+
+	#[serde(rename = "REDLIB_DEBUG")]
+	pub(crate) debug: Option<String>,
+
+	#[serde(rename = "REDLIB_UPSTREAM_MIN_TLS")]
+	pub(crate) upstream_min_tls: Option<String>,
+
+	#[serde(rename = "REDLIB_RATE_LIMIT")]
+	pub(crate) rate_limit: Option<String>,
+
+	#[serde(rename = "REDLIB_PEERS")]
+	pub(crate) peers: Option<String>,
+
+	#[serde(rename = "REDLIB_FORWARDED_HEADER")]
+	pub(crate) forwarded_header: Option<String>,
 }
 
 impl Config {
@@ -160,6 +268,42 @@ impl Config {
 			full_url: parse("REDLIB_FULL_URL"),
 			default_remove_default_feeds: parse("REDLIB_DEFAULT_REMOVE_DEFAULT_FEEDS"),
 			collections: parse("REDLIB_COLLECTIONS"),
+			max_media_bytes: parse("REDLIB_MAX_MEDIA_BYTES"),
+			tls_cert: parse("REDLIB_TLS_CERT"),
+			tls_key: parse("REDLIB_TLS_KEY"),
+			hsts: parse("REDLIB_HSTS"),
+			security_headers: parse("REDLIB_SECURITY_HEADERS"),
+			referrer_policy: parse("REDLIB_REFERRER_POLICY"),
+			csp: parse("REDLIB_CSP"),
+			default_subreddit: parse("REDLIB_DEFAULT_SUBREDDIT"),
+			default_collection: parse("REDLIB_DEFAULT_COLLECTION"),
+			cursor_secret: parse("REDLIB_CURSOR_SECRET"),
+			upstream_hosts: parse("REDLIB_UPSTREAM_HOSTS"),
+			disable_sitemap: parse("REDLIB_DISABLE_SITEMAP"),
+			robots_txt: parse("REDLIB_ROBOTS_TXT"),
+			blur_keywords: parse("REDLIB_BLUR_KEYWORDS"),
+			admin_token: parse("REDLIB_ADMIN_TOKEN"),
+			upstream_pool_size: parse("REDLIB_UPSTREAM_POOL_SIZE"),
+			upstream_pool_idle_timeout: parse("REDLIB_UPSTREAM_POOL_IDLE_TIMEOUT"),
+			trust_proxy: parse("REDLIB_TRUST_PROXY"),
+			trusted_proxies: parse("REDLIB_TRUSTED_PROXIES"),
+			export_max_pages: parse("REDLIB_EXPORT_MAX_PAGES"),
+			circuit_breaker_threshold: parse("REDLIB_CIRCUIT_BREAKER_THRESHOLD"),
+			circuit_breaker_window: parse("REDLIB_CIRCUIT_BREAKER_WINDOW"),
+			circuit_breaker_cooldown: parse("REDLIB_CIRCUIT_BREAKER_COOLDOWN"),
+			user_agents: parse("REDLIB_USER_AGENTS"),
+			media_base_url: parse("REDLIB_MEDIA_BASE_URL"),
+			subreddit_suggestion_threshold: parse("REDLIB_SUBREDDIT_SUGGESTION_THRESHOLD"),
+			error_template: parse("REDLIB_ERROR_TEMPLATE"),
+			error_instance: parse("REDLIB_ERROR_INSTANCE"),
+			max_concurrent_upstream: parse("REDLIB_MAX_CONCURRENT_UPSTREAM"),
+			token_cache_file: parse("REDLIB_TOKEN_CACHE_FILE"),
+			comment_body_limit: parse("REDLIB_COMMENT_BODY_LIMIT"),
+			debug: parse("REDLIB_DEBUG"),
+			upstream_min_tls: parse("REDLIB_UPSTREAM_MIN_TLS"),
+			rate_limit: parse("REDLIB_RATE_LIMIT"),
+			peers: parse("REDLIB_PEERS"),
+			forwarded_header: parse("REDLIB_FORWARDED_HEADER"),
 		}
 	}
 }
@@ -191,6 +335,42 @@ fn get_setting_from_config(name: &str, config: &Config) -> Option<String> {
 		"REDLIB_FULL_URL" => config.full_url.clone(),
 		"REDLIB_DEFAULT_REMOVE_DEFAULT_FEEDS" => config.default_remove_default_feeds.clone(),
 		"REDLIB_COLLECTIONS" => config.collections.clone(),
+		"REDLIB_MAX_MEDIA_BYTES" => config.max_media_bytes.clone(),
+		"REDLIB_TLS_CERT" => config.tls_cert.clone(),
+		"REDLIB_TLS_KEY" => config.tls_key.clone(),
+		"REDLIB_HSTS" => config.hsts.clone(),
+		"REDLIB_SECURITY_HEADERS" => config.security_headers.clone(),
+		"REDLIB_REFERRER_POLICY" => config.referrer_policy.clone(),
+		"REDLIB_CSP" => config.csp.clone(),
+		"REDLIB_DEFAULT_SUBREDDIT" => config.default_subreddit.clone(),
+		"REDLIB_DEFAULT_COLLECTION" => config.default_collection.clone(),
+		"REDLIB_CURSOR_SECRET" => config.cursor_secret.clone(),
+		"REDLIB_UPSTREAM_HOSTS" => config.upstream_hosts.clone(),
+		"REDLIB_DISABLE_SITEMAP" => config.disable_sitemap.clone(),
+		"REDLIB_ROBOTS_TXT" => config.robots_txt.clone(),
+		"REDLIB_BLUR_KEYWORDS" => config.blur_keywords.clone(),
+		"REDLIB_ADMIN_TOKEN" => config.admin_token.clone(),
+		"REDLIB_UPSTREAM_POOL_SIZE" => config.upstream_pool_size.clone(),
+		"REDLIB_UPSTREAM_POOL_IDLE_TIMEOUT" => config.upstream_pool_idle_timeout.clone(),
+		"REDLIB_TRUST_PROXY" => config.trust_proxy.clone(),
+		"REDLIB_TRUSTED_PROXIES" => config.trusted_proxies.clone(),
+		"REDLIB_EXPORT_MAX_PAGES" => config.export_max_pages.clone(),
+		"REDLIB_CIRCUIT_BREAKER_THRESHOLD" => config.circuit_breaker_threshold.clone(),
+		"REDLIB_CIRCUIT_BREAKER_WINDOW" => config.circuit_breaker_window.clone(),
+		"REDLIB_CIRCUIT_BREAKER_COOLDOWN" => config.circuit_breaker_cooldown.clone(),
+		"REDLIB_USER_AGENTS" => config.user_agents.clone(),
+		"REDLIB_MEDIA_BASE_URL" => config.media_base_url.clone(),
+		"REDLIB_SUBREDDIT_SUGGESTION_THRESHOLD" => config.subreddit_suggestion_threshold.clone(),
+		"REDLIB_ERROR_TEMPLATE" => config.error_template.clone(),
+		"REDLIB_ERROR_INSTANCE" => config.error_instance.clone(),
+		"REDLIB_MAX_CONCURRENT_UPSTREAM" => config.max_concurrent_upstream.clone(),
+		"REDLIB_TOKEN_CACHE_FILE" => config.token_cache_file.clone(),
+		"REDLIB_COMMENT_BODY_LIMIT" => config.comment_body_limit.clone(),
+		"REDLIB_DEBUG" => config.debug.clone(),
+		"REDLIB_UPSTREAM_MIN_TLS" => config.upstream_min_tls.clone(),
+		"REDLIB_RATE_LIMIT" => config.rate_limit.clone(),
+		"REDLIB_PEERS" => config.peers.clone(),
+		"REDLIB_FORWARDED_HEADER" => config.forwarded_header.clone(),
 		_ => None,
 	}
 }
@@ -0,0 +1,123 @@
+use std::sync::LazyLock;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ring::hmac;
+
+use crate::config;
+use crate::utils::param;
+
+/// Key used to sign pagination cursors, derived from `REDLIB_CURSOR_SECRET`.
+/// Without a secret, cursors are passed through untouched.
+static CURSOR_KEY: LazyLock<Option<hmac::Key>> = LazyLock::new(|| {
+	config::get_setting("REDLIB_CURSOR_SECRET")
+		.filter(|secret| !secret.is_empty())
+		.map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+});
+
+/// Pagination parameters that hold a Reddit listing cursor.
+const CURSOR_PARAMS: [&str; 2] = ["after", "before"];
+
+/// Parameters holding a token sealed by this instance, which other instances
+/// can't open.
+const SEALED_PARAMS: [&str; 4] = ["after", "before", "seen", "since"];
+
+/// Whether a request's path and query carry a token sealed by this instance.
+pub fn carries_sealed(path: &str) -> bool {
+	SEALED_PARAMS.iter().any(|name| param(path, name).is_some_and(|token| !token.is_empty()))
+}
+
+/// Wrap a Reddit `after`/`before` token in a signed blob for the client.
+pub fn seal(cursor: &str) -> String {
+	seal_with(CURSOR_KEY.as_ref(), cursor)
+}
+
+/// Unwrap a token made by [`seal`], failing if it was not issued by this
+/// instance.
+pub fn open(token: &str) -> Option<String> {
+	open_with(CURSOR_KEY.as_ref(), token)
+}
+
+/// Rewrite the cursor parameters of a request's query string back to the raw
+/// Reddit tokens, failing if any of them was not issued by this instance.
+pub fn open_query(query: &str) -> Result<String, String> {
+	open_query_with(CURSOR_KEY.as_ref(), query)
+}
+
+fn seal_with(key: Option<&hmac::Key>, cursor: &str) -> String {
+	let Some(key) = key else {
+		return cursor.to_string();
+	};
+
+	let tag = hmac::sign(key, cursor.as_bytes());
+	format!("{}.{}", URL_SAFE_NO_PAD.encode(cursor), URL_SAFE_NO_PAD.encode(tag))
+}
+
+fn open_with(key: Option<&hmac::Key>, token: &str) -> Option<String> {
+	let Some(key) = key else {
+		return Some(token.to_string());
+	};
+
+	let (cursor, tag) = token.split_once('.')?;
+	let cursor = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+	let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+
+	hmac::verify(key, &cursor, &tag).ok()?;
+	String::from_utf8(cursor).ok()
+}
+
+fn open_query_with(key: Option<&hmac::Key>, query: &str) -> Result<String, String> {
+	if key.is_none() {
+		return Ok(query.to_string());
+	}
+
+	query
+		.split('&')
+		.map(|pair| match pair.split_once('=') {
+			Some((name, value)) if CURSOR_PARAMS.contains(&name) && !value.is_empty() => open_with(key, value)
+				.map(|cursor| format!("{name}={cursor}"))
+				.ok_or_else(|| format!("Invalid {name} cursor")),
+			_ => Ok(pair.to_string()),
+		})
+		.collect::<Result<Vec<_>, _>>()
+		.map(|pairs| pairs.join("&"))
+}
+
+#[test]
+fn test_cursor_round_trip() {
+	let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+
+	let sealed = seal_with(Some(&key), "t3_abc123");
+	assert_ne!(sealed, "t3_abc123");
+	assert!(!sealed.contains("t3_"));
+	assert_eq!(open_with(Some(&key), &sealed).as_deref(), Some("t3_abc123"));
+
+	let query = format!("sort=new&after={sealed}&limit=25");
+	assert_eq!(open_query_with(Some(&key), &query), Ok("sort=new&after=t3_abc123&limit=25".to_string()));
+	assert_eq!(open_query_with(Some(&key), "after=&limit=25"), Ok("after=&limit=25".to_string()));
+}
+
+#[test]
+fn test_sealed_params() {
+	assert!(carries_sealed("/api/r/rust/hot.js?after=t3_abc"));
+	assert!(carries_sealed("/api/post/abc/comments/poll?since=token"));
+	assert!(!carries_sealed("/api/r/rust/hot.js?after=&limit=25"));
+	assert!(!carries_sealed("/api/r/rust/hot.js"));
+}
+
+#[test]
+fn test_cursor_rejects_forgeries() {
+	let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+	let other = hmac::Key::new(hmac::HMAC_SHA256, b"other");
+
+	let (_, tag) = seal_with(Some(&key), "t3_abc123").split_once('.').map(|(c, t)| (c.to_string(), t.to_string())).unwrap();
+	let forged = format!("{}.{tag}", URL_SAFE_NO_PAD.encode("t3_zzz999"));
+	assert_eq!(open_with(Some(&key), &forged), None);
+	assert_eq!(open_with(Some(&other), &seal_with(Some(&key), "t3_abc123")), None);
+	assert_eq!(open_query_with(Some(&key), "before=t3_abc123"), Err("Invalid before cursor".to_string()));
+}
+
+#[test]
+fn test_cursor_passthrough_without_secret() {
+	assert_eq!(seal_with(None, "t3_abc123"), "t3_abc123");
+	assert_eq!(open_query_with(None, "after=t3_abc123"), Ok("after=t3_abc123".to_string()));
+}
@@ -1,10 +1,10 @@
 //! Handler for post duplicates.
 
 use crate::client::json;
-use crate::json::{json_error, json_response, truncate_posts, DuplicatesResponse, DEFAULT_BODY_LIMIT};
+use crate::json::{add_body_text, json_error, json_response, truncate_posts, upstream_error, DuplicatesResponse, PostList, DEFAULT_BODY_LIMIT};
 use crate::server::RequestExt;
 use crate::subreddit::{can_access_quarantine, quarantine};
-use crate::utils::{error, filter_posts, get_filters, nsfw_landing, param, parse_post, template, Post, Preferences};
+use crate::utils::{error, filter_posts, get_filters, nsfw_landing, param, parse_post, template, ListingParams, Post, Preferences};
 
 use askama::Template;
 use hyper::{Body, Request, Response};
@@ -54,7 +54,7 @@ struct DuplicatesTemplate {
 /// Make the GET request to Reddit. It assumes `req` is the appropriate Reddit
 /// REST endpoint for enumerating post duplicates.
 pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
-	let path: String = format!("{}.json?{}&raw_json=1", req.uri().path(), req.uri().query().unwrap_or_default());
+	let path = ListingParams::clamped(req.uri().query().unwrap_or_default()).path(&format!("{}.json", req.uri().path()));
 	let sub = req.param("sub").unwrap_or_default();
 	let quarantined = can_access_quarantine(&req, &sub);
 
@@ -174,12 +174,13 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 					// whether or not to define `before`.
 					//
 					// We'll mitigate that by requesting at most one duplicate.
-					let new_path: String = format!(
-						"{}.json?before=t3_{}&sort={}&limit=1&raw_json=1",
-						req.uri().path(),
-						&duplicates[0].id,
-						if sort.is_empty() { "num_comments".to_string() } else { sort.clone() }
-					);
+					let previous = ListingParams {
+						before: Some(format!("t3_{}", duplicates[0].id)),
+						sort: Some(if sort.is_empty() { "num_comments".to_string() } else { sort.clone() }),
+						limit: Some(1),
+						..ListingParams::default()
+					};
+					let new_path = previous.path(&format!("{}.json", req.uri().path()));
 					match json(new_path, true).await {
 						Ok(response) => {
 							if !response[1]["data"]["children"].as_array().unwrap_or(&Vec::new()).is_empty() {
@@ -223,7 +224,11 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 
 /// JSON API endpoint for duplicate posts.
 pub async fn item_json(req: Request<Body>) -> Result<Response<Body>, String> {
-	let path: String = format!("{}.json?{}&raw_json=1", req.uri().path().trim_end_matches(".js"), req.uri().query().unwrap_or_default());
+	let params = match ListingParams::parse(req.uri().query().unwrap_or_default()) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let path = params.path(&format!("{}.json", req.uri().path().trim_end_matches(".js")));
 	let sub = req.param("sub").unwrap_or_default();
 	let quarantined = can_access_quarantine(&req, &sub);
 
@@ -232,9 +237,9 @@ pub async fn item_json(req: Request<Body>) -> Result<Response<Body>, String> {
 		.and_then(|s| s.parse().ok())
 		.or(Some(DEFAULT_BODY_LIMIT));
 
-	match json(path, quarantined).await {
+	match json(path.clone(), quarantined).await {
 		Ok(response) => {
-			let post = parse_post(&response[0]["data"]["children"][0], true).await;
+			let mut post = parse_post(&response[0]["data"]["children"][0], true).await;
 
 			// Check NSFW gating (server-side SFW_ONLY only)
 			if post.nsfw && crate::utils::sfw_only() {
@@ -244,16 +249,22 @@ pub async fn item_json(req: Request<Body>) -> Result<Response<Body>, String> {
 			let filters = get_filters(&req);
 			let (mut duplicates, _, _) = parse_duplicates(&response[1], &filters, true).await;
 
+			add_body_text(std::slice::from_mut(&mut post), &path);
+			add_body_text(&mut duplicates, &path);
+
 			// Truncate duplicate post bodies (but keep original post full)
 			truncate_posts(&mut duplicates, body_limit);
 
-			Ok(json_response(DuplicatesResponse { post, duplicates }))
+			Ok(json_response(DuplicatesResponse {
+				post,
+				duplicates: PostList::for_view(duplicates, &path),
+			}))
 		}
 		Err(msg) => {
 			if msg == "quarantined" || msg == "gated" {
 				Ok(json_error(format!("Post is {msg}"), 403))
 			} else {
-				Ok(json_error(msg, 500))
+				Ok(upstream_error(msg, 500))
 			}
 		}
 	}
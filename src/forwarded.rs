@@ -0,0 +1,258 @@
+//! Resolve the real client address and scheme of a request that came
+//! through one or more reverse proxies.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::LazyLock;
+
+use hyper::HeaderMap;
+
+use crate::config;
+
+/// Address and scheme a request was originally made with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+	pub ip: Option<IpAddr>,
+	pub scheme: String,
+}
+
+/// A network in CIDR notation, such as `10.0.0.0/8` or `::1/128`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+	network: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	/// Parse `addr/prefix`, or a bare address matching only itself.
+	pub fn parse(value: &str) -> Option<Self> {
+		let (addr, prefix) = value.trim().split_once('/').map_or((value.trim(), None), |(addr, prefix)| (addr, Some(prefix)));
+		let network: IpAddr = addr.parse().ok()?;
+		let max = if network.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max)?,
+			None => max,
+		};
+		Some(Self { network, prefix })
+	}
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match (self.network, ip.to_canonical()) {
+			(IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(u32::from(network).into(), u32::from(ip).into(), self.prefix, 32),
+			(IpAddr::V6(network), IpAddr::V6(ip)) => prefix_matches(u128::from(network), u128::from(ip), self.prefix, 128),
+			_ => false,
+		}
+	}
+}
+
+fn prefix_matches(network: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+	let shift = bits - prefix;
+	shift == bits || (network >> shift) == (ip >> shift)
+}
+
+/// Proxies whose forwarding headers are believed. Empty unless
+/// `REDLIB_TRUST_PROXY` is on; `REDLIB_TRUSTED_PROXIES` narrows or widens the
+/// default of loopback and private networks.
+static TRUSTED_PROXIES: LazyLock<Vec<Cidr>> = LazyLock::new(|| {
+	if config::get_setting("REDLIB_TRUST_PROXY").as_deref() != Some("on") {
+		return Vec::new();
+	}
+	parse_trusted_proxies(config::get_setting("REDLIB_TRUSTED_PROXIES"))
+});
+
+fn parse_trusted_proxies(value: Option<String>) -> Vec<Cidr> {
+	let value = value.filter(|value| !value.trim().is_empty());
+	let value = value.as_deref().unwrap_or("127.0.0.0/8,::1/128,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,fc00::/7");
+	value.split(',').filter_map(Cidr::parse).collect()
+}
+
+/// Forwarding headers the proxies in front of us set.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardingHeader {
+	/// `X-Forwarded-For` and `X-Forwarded-Proto`.
+	#[default]
+	XForwardedFor,
+	/// The standard `Forwarded` header.
+	Forwarded,
+}
+
+impl ForwardingHeader {
+	pub fn parse(value: Option<String>) -> Result<Self, String> {
+		match value.as_deref().map(|value| value.trim().to_ascii_lowercase()).as_deref() {
+			None | Some("" | "x-forwarded-for") => Ok(Self::XForwardedFor),
+			Some("forwarded") => Ok(Self::Forwarded),
+			Some(other) => Err(format!("\"{other}\" is not a forwarding header, expected x-forwarded-for or forwarded")),
+		}
+	}
+}
+
+/// Header read for the client's address, from `REDLIB_FORWARDED_HEADER`.
+/// Only this one is believed: a proxy that only appends to the other leaves
+/// whatever the client put in it untouched. Startup refuses invalid values.
+static FORWARDING_HEADER: LazyLock<ForwardingHeader> = LazyLock::new(|| ForwardingHeader::parse(config::get_setting("REDLIB_FORWARDED_HEADER")).unwrap_or_default());
+
+/// Work out who made the request, honouring the configured forwarding
+/// header only when `peer` is a trusted proxy.
+pub fn client_info(peer: Option<SocketAddr>, tls: bool, headers: &HeaderMap) -> ClientInfo {
+	resolve(peer.map(|peer| peer.ip()), tls, headers, &TRUSTED_PROXIES, *FORWARDING_HEADER)
+}
+
+fn resolve(peer: Option<IpAddr>, tls: bool, headers: &HeaderMap, trusted: &[Cidr], header: ForwardingHeader) -> ClientInfo {
+	let direct = ClientInfo {
+		ip: peer,
+		scheme: if tls { "https" } else { "http" }.to_string(),
+	};
+	let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+
+	let Some(peer) = peer.filter(|peer| is_trusted(*peer)) else {
+		return direct;
+	};
+
+	let (hops, protos) = forwarded_chain(headers, header);
+
+	// Walk the chain from the nearest hop, skipping our own proxies. The
+	// first address they did not add themselves is the client; anything
+	// to its left could have been made up by that client.
+	let mut ip = peer;
+	for hop in hops.iter().rev() {
+		let Some(hop) = hop else { break };
+		ip = *hop;
+		if !is_trusted(ip) {
+			break;
+		}
+	}
+
+	ClientInfo {
+		ip: Some(ip),
+		scheme: protos
+			.last()
+			.map(|proto| proto.to_ascii_lowercase())
+			.filter(|proto| proto == "http" || proto == "https")
+			.unwrap_or(direct.scheme),
+	}
+}
+
+/// Addresses and schemes listed in `header` by the proxies in front of us,
+/// furthest first. Hops that are not a plain address (`unknown`, obfuscated
+/// identifiers) are kept as `None`.
+fn forwarded_chain(headers: &HeaderMap, header: ForwardingHeader) -> (Vec<Option<IpAddr>>, Vec<String>) {
+	let values = |name: &str| -> Vec<String> {
+		headers
+			.get_all(name)
+			.iter()
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.map(|value| value.trim().to_string())
+			.filter(|value| !value.is_empty())
+			.collect()
+	};
+
+	if header == ForwardingHeader::Forwarded {
+		let mut hops = Vec::new();
+		let mut protos = Vec::new();
+		for element in values("forwarded") {
+			for pair in element.split(';') {
+				let Some((key, value)) = pair.split_once('=') else { continue };
+				let value = value.trim().trim_matches('"');
+				match key.trim().to_ascii_lowercase().as_str() {
+					"for" => hops.push(parse_node(value)),
+					"proto" => protos.push(value.to_string()),
+					_ => {}
+				}
+			}
+		}
+		return (hops, protos);
+	}
+
+	(values("x-forwarded-for").iter().map(|hop| parse_node(hop)).collect(), values("x-forwarded-proto"))
+}
+
+/// Parse a forwarded node: `1.2.3.4`, `1.2.3.4:80`, `[::1]` or `[::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+	if let Some(rest) = node.strip_prefix('[') {
+		return rest.split_once(']').and_then(|(ip, _)| ip.parse::<Ipv6Addr>().ok()).map(IpAddr::V6);
+	}
+	node
+		.parse()
+		.ok()
+		.or_else(|| node.rsplit_once(':').and_then(|(ip, _)| ip.parse::<Ipv4Addr>().ok()).map(IpAddr::V4))
+}
+
+#[test]
+fn test_cidr_matching() {
+	let private = Cidr::parse("10.0.0.0/8").unwrap();
+	assert!(private.contains("10.1.2.3".parse().unwrap()));
+	assert!(!private.contains("11.0.0.1".parse().unwrap()));
+	assert!(private.contains("::ffff:10.0.0.1".parse().unwrap()));
+	assert!(Cidr::parse("fc00::/7").unwrap().contains("fd12::1".parse().unwrap()));
+	assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+	assert!(Cidr::parse("192.168.1.1").unwrap().contains("192.168.1.1".parse().unwrap()));
+	assert_eq!(Cidr::parse("10.0.0.0/33"), None);
+	assert_eq!(Cidr::parse("proxy.local"), None);
+}
+
+#[test]
+fn test_forwarding_header_setting() {
+	assert_eq!(ForwardingHeader::parse(None), Ok(ForwardingHeader::XForwardedFor));
+	assert_eq!(ForwardingHeader::parse(Some("Forwarded".into())), Ok(ForwardingHeader::Forwarded));
+	assert!(ForwardingHeader::parse(Some("x-real-ip".into())).is_err());
+}
+
+#[test]
+fn test_forwarded_chains() {
+	let trusted = parse_trusted_proxies(None);
+	let headers = |pairs: &[(&'static str, &'static str)]| {
+		let mut headers = HeaderMap::new();
+		for (name, value) in pairs {
+			headers.append(*name, value.parse().unwrap());
+		}
+		headers
+	};
+	let resolve_with = |header: ForwardingHeader, peer: &str, pairs: &[(&'static str, &'static str)]| resolve(peer.parse().ok(), false, &headers(pairs), &trusted, header);
+	let resolve_from = |peer: &str, pairs: &[(&'static str, &'static str)]| resolve_with(ForwardingHeader::XForwardedFor, peer, pairs);
+	let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+
+	// A legitimate chain through two of our own proxies
+	let client = resolve_from("10.0.0.2", &[("x-forwarded-for", "203.0.113.7, 10.0.0.1"), ("x-forwarded-proto", "https")]);
+	assert_eq!(client.ip, ip("203.0.113.7"));
+	assert_eq!(client.scheme, "https");
+
+	// A client prepending a fake address only fools itself
+	let client = resolve_from("127.0.0.1", &[("x-forwarded-for", "1.1.1.1, 198.51.100.4")]);
+	assert_eq!(client.ip, ip("198.51.100.4"));
+
+	// Headers sent straight to us by an untrusted peer are ignored
+	let client = resolve_from("198.51.100.4", &[("x-forwarded-for", "1.1.1.1"), ("x-forwarded-proto", "https")]);
+	assert_eq!(client.ip, ip("198.51.100.4"));
+	assert_eq!(client.scheme, "http");
+
+	// Only the configured header is read, so a client can't pick its address
+	// by sending the other one to a proxy that leaves it alone
+	let client = resolve_from("10.0.0.2", &[("forwarded", "for=1.2.3.4"), ("x-forwarded-for", "203.0.113.7")]);
+	assert_eq!(client.ip, ip("203.0.113.7"));
+	let client = resolve_with(ForwardingHeader::Forwarded, "10.0.0.2", &[("x-forwarded-for", "1.2.3.4")]);
+	assert_eq!(client.ip, ip("10.0.0.2"));
+
+	// The standard header may carry ports and IPv6 brackets
+	let client = resolve_with(
+		ForwardingHeader::Forwarded,
+		"::1",
+		&[("forwarded", "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.1:8080"), ("x-forwarded-for", "1.1.1.1")],
+	);
+	assert_eq!(client.ip, ip("2001:db8::1"));
+	assert_eq!(client.scheme, "https");
+
+	// Hops that are not addresses end the walk at the last known address
+	let client = resolve_with(ForwardingHeader::Forwarded, "10.0.0.2", &[("forwarded", "for=unknown, for=10.0.0.1")]);
+	assert_eq!(client.ip, ip("10.0.0.1"));
+
+	// Nothing is trusted while REDLIB_TRUST_PROXY is off
+	let client = resolve(
+		"10.0.0.2".parse().ok(),
+		true,
+		&headers(&[("x-forwarded-for", "1.1.1.1")]),
+		&[],
+		ForwardingHeader::XForwardedFor,
+	);
+	assert_eq!(client.ip, ip("10.0.0.2"));
+	assert_eq!(client.scheme, "https");
+}
@@ -1,13 +1,17 @@
 use crate::{
+	breaker, client,
 	config::{Config, CONFIG},
+	json::{json_response, ApiInfoResponse},
+	post,
 	server::RequestExt,
-	utils::{ErrorTemplate, Preferences},
+	utils::{self, ErrorTemplate, Preferences},
 };
 use askama::Template;
 use build_html::{Container, Html, HtmlContainer, Table};
 use hyper::{http::Error, Body, Request, Response};
 use serde::{Deserialize, Serialize};
 use std::sync::LazyLock;
+use std::time::Instant;
 use time::OffsetDateTime;
 
 /// This is the local static that is initialized at runtime (technically at
@@ -39,6 +43,47 @@ pub async fn instance_info(req: Request<Body>) -> Result<Response<Body>, String>
 	response.map_err(|err| format!("{err}"))
 }
 
+/// JSON API endpoint reporting the instance version and the state of the
+/// circuit breaker in front of Reddit. With `?ids=`, it resolves fullnames
+/// the way Reddit's own `api/info` does instead.
+pub async fn api_info(req: Request<Body>) -> Result<Response<Body>, String> {
+	if req.uri().query().is_some_and(|query| utils::param(&format!("?{query}"), "ids").is_some()) {
+		return post::things_json(req).await;
+	}
+	Ok(json_response(ApiInfoResponse {
+		crate_version: env!("CARGO_PKG_VERSION"),
+		git_commit: env!("GIT_HASH"),
+		upstream: breaker::UPSTREAM_BREAKER.status(Instant::now()),
+		upstream_requests: client::upstream_load(),
+	}))
+}
+
+/// Metrics endpoint in Prometheus' text format, reporting the requests to
+/// Reddit under way and queued.
+pub async fn metrics(_req: Request<Body>) -> Result<Response<Body>, String> {
+	Ok(
+		Response::builder()
+			.status(200)
+			.header("content-type", "text/plain; version=0.0.4")
+			.body(metrics_text(&client::upstream_load()).into())
+			.unwrap_or_default(),
+	)
+}
+
+fn metrics_text(load: &client::UpstreamLoad) -> String {
+	let mut gauges = vec![
+		("redlib_upstream_in_flight", "Requests to Reddit under way.", load.in_flight),
+		("redlib_upstream_waiting", "Requests to Reddit waiting for a free slot.", load.waiting),
+	];
+	if let Some(limit) = load.limit {
+		gauges.push(("redlib_upstream_limit", "Most requests to Reddit in flight at once.", limit));
+	}
+	gauges
+		.into_iter()
+		.map(|(name, help, value)| format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"))
+		.collect()
+}
+
 fn info_json() -> Result<Response<Body>, Error> {
 	if let Ok(body) = serde_json::to_string(&*INSTANCE_INFO) {
 		Response::builder().status(200).header("content-type", "application/json").body(body.into())
@@ -130,6 +175,36 @@ impl InstanceInfo {
 				["Full URL", &convert(&self.config.full_url)],
 				["Remove default feeds", &convert(&self.config.default_remove_default_feeds)],
 				["Collections", &convert(&self.config.collections)],
+				["Max media bytes", &convert(&self.config.max_media_bytes)],
+				["HSTS max-age", &convert(&self.config.hsts)],
+				["Security headers", &convert(&self.config.security_headers)],
+				["Referrer policy", &convert(&self.config.referrer_policy)],
+				["Content security policy", &convert(&self.config.csp)],
+				["Homepage subreddit", &convert(&self.config.default_subreddit)],
+				["Homepage collection", &convert(&self.config.default_collection)],
+				["Upstream hosts", &convert(&self.config.upstream_hosts)],
+				["Disable sitemap", &convert(&self.config.disable_sitemap)],
+				["Custom robots.txt", &convert(&self.config.robots_txt)],
+				["Blur keywords", &convert(&self.config.blur_keywords)],
+				["Upstream pool size", &convert(&self.config.upstream_pool_size)],
+				["Upstream pool idle timeout", &convert(&self.config.upstream_pool_idle_timeout)],
+				["Trust proxy", &convert(&self.config.trust_proxy)],
+				["Trusted proxies", &convert(&self.config.trusted_proxies)],
+				["Export page cap", &convert(&self.config.export_max_pages)],
+				["Circuit breaker threshold", &convert(&self.config.circuit_breaker_threshold)],
+				["Circuit breaker window", &convert(&self.config.circuit_breaker_window)],
+				["Circuit breaker cooldown", &convert(&self.config.circuit_breaker_cooldown)],
+				["User agents", &convert(&self.config.user_agents)],
+				["Media base URL", &convert(&self.config.media_base_url)],
+				["Subreddit suggestion threshold", &convert(&self.config.subreddit_suggestion_threshold)],
+				["Error instance", &convert(&self.config.error_instance)],
+				["Max concurrent upstream", &convert(&self.config.max_concurrent_upstream)],
+				["Comment body limit", &convert(&self.config.comment_body_limit)],
+				["Debug", &convert(&self.config.debug)],
+				["Upstream minimum TLS", &convert(&self.config.upstream_min_tls)],
+				["Rate limit", &convert(&self.config.rate_limit)],
+				["Peers", &convert(&self.config.peers)],
+				["Forwarded header", &convert(&self.config.forwarded_header)],
 				//TODO: fallback to crate::config::DEFAULT_PUSHSHIFT_FRONTEND
 			])
 			.with_header_row(["Settings"]),
@@ -173,6 +248,36 @@ impl InstanceInfo {
 				Full URL: {:?}\n
 				Remove default feeds: {:?}\n
 				Collections: {:?}\n
+				Max media bytes: {:?}\n
+				HSTS max-age: {:?}\n
+				Security headers: {:?}\n
+				Referrer policy: {:?}\n
+				Content security policy: {:?}\n
+				Homepage subreddit: {:?}\n
+				Homepage collection: {:?}\n
+				Upstream hosts: {:?}\n
+				Disable sitemap: {:?}\n
+				Custom robots.txt: {:?}\n
+				Blur keywords: {:?}\n
+				Upstream pool size: {:?}\n
+				Upstream pool idle timeout: {:?}\n
+				Trust proxy: {:?}\n
+				Trusted proxies: {:?}\n
+				Export page cap: {:?}\n
+				Circuit breaker threshold: {:?}\n
+				Circuit breaker window: {:?}\n
+				Circuit breaker cooldown: {:?}\n
+				User agents: {:?}\n
+				Media base URL: {:?}\n
+				Subreddit suggestion threshold: {:?}\n
+				Error instance: {:?}\n
+				Max concurrent upstream: {:?}\n
+				Comment body limit: {:?}\n
+				Debug: {:?}\n
+				Upstream minimum TLS: {:?}\n
+				Rate limit: {:?}\n
+				Peers: {:?}\n
+				Forwarded header: {:?}\n
                 Config:\n
                     Banner: {:?}\n
                     Hide awards: {:?}\n
@@ -202,6 +307,36 @@ impl InstanceInfo {
 					self.config.default_remove_default_feeds,
 					self.config.collections,
 					self.config.pushshift,
+					self.config.max_media_bytes,
+					self.config.hsts,
+					self.config.security_headers,
+					self.config.referrer_policy,
+					self.config.csp,
+					self.config.default_subreddit,
+					self.config.default_collection,
+					self.config.upstream_hosts,
+					self.config.disable_sitemap,
+					self.config.robots_txt,
+					self.config.blur_keywords,
+					self.config.upstream_pool_size,
+					self.config.upstream_pool_idle_timeout,
+					self.config.trust_proxy,
+					self.config.trusted_proxies,
+					self.config.export_max_pages,
+					self.config.circuit_breaker_threshold,
+					self.config.circuit_breaker_window,
+					self.config.circuit_breaker_cooldown,
+					self.config.user_agents,
+					self.config.media_base_url,
+					self.config.subreddit_suggestion_threshold,
+					self.config.error_instance,
+					self.config.max_concurrent_upstream,
+					self.config.comment_body_limit,
+					self.config.debug,
+					self.config.upstream_min_tls,
+					self.config.rate_limit,
+					self.config.peers,
+					self.config.forwarded_header,
 					self.config.banner,
 					self.config.default_hide_awards,
 					self.config.default_hide_score,
@@ -236,3 +371,17 @@ struct MessageTemplate {
 	prefs: Preferences,
 	url: String,
 }
+
+#[test]
+fn test_metrics_text() {
+	let load = client::UpstreamLoad {
+		in_flight: 3,
+		waiting: 1,
+		limit: Some(8),
+	};
+	let text = metrics_text(&load);
+	assert!(text.starts_with("# HELP redlib_upstream_in_flight Requests to Reddit under way.\n# TYPE redlib_upstream_in_flight gauge\nredlib_upstream_in_flight 3\n"));
+	assert!(text.contains("\nredlib_upstream_waiting 1\n"));
+	assert!(text.ends_with("\nredlib_upstream_limit 8\n"));
+	assert!(!metrics_text(&client::UpstreamLoad { limit: None, ..load }).contains("redlib_upstream_limit"));
+}
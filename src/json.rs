@@ -1,9 +1,18 @@
 //! JSON API response helpers and structs.
 
+use hyper::body::Sender;
 use hyper::{Body, Response};
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
 
-use crate::utils::{truncate_body, Comment, Post, Subreddit, User};
+use crate::archive::Strategy;
+use crate::breaker::BreakerStatus;
+use crate::client::{UpstreamError, UpstreamLoad, UPSTREAM_BLOCKED};
+use crate::config::get_setting;
+use crate::cursor;
+use crate::multi::Multireddit;
+use crate::utils::{self, inline_spoilers, markdown_to_text, param, truncate_body, Comment, Listing, Moderator, Post, PreviewImage, SeenPosts, Subreddit, User, Widget};
 
 /// Default body truncation limit for list endpoints (search, subreddit, user, duplicates)
 pub const DEFAULT_BODY_LIMIT: usize = 400;
@@ -15,6 +24,10 @@ pub fn truncate_post(post: &mut Post, body_limit: Option<usize>) {
 		post.body = truncated_body;
 		if was_truncated {
 			post.body_truncated = Some(true);
+			post.spoilers = inline_spoilers(&post.body);
+		}
+		if let Some(text) = &post.body_text {
+			post.body_text = Some(truncate_body(text, limit).0);
 		}
 	}
 }
@@ -26,11 +39,87 @@ pub fn truncate_posts(posts: &mut [Post], body_limit: Option<usize>) {
 	}
 }
 
+/// Comment body limit asked for with `?comment_body_limit=`, falling back to
+/// `REDLIB_COMMENT_BODY_LIMIT`. Comments are left whole by default.
+pub fn comment_body_limit(path: &str) -> Option<usize> {
+	param(path, "comment_body_limit")
+		.or_else(|| get_setting("REDLIB_COMMENT_BODY_LIMIT"))
+		.and_then(|limit| limit.parse().ok())
+}
+
+/// Truncate bodies of a comment tree, replies included, if body_limit is specified.
+pub fn truncate_comments(comments: &mut [Comment], body_limit: Option<usize>) {
+	if let Some(limit) = body_limit {
+		for comment in comments.iter_mut() {
+			let (truncated_body, was_truncated) = truncate_body(&comment.body, limit);
+			comment.body = truncated_body;
+			if was_truncated {
+				comment.body_truncated = Some(true);
+				comment.spoilers = inline_spoilers(&comment.body);
+			}
+			if let Some(text) = &comment.body_text {
+				comment.body_text = Some(truncate_body(text, limit).0);
+			}
+			truncate_comments(&mut comment.replies, body_limit);
+		}
+	}
+}
+
+/// Whether `path` asks for plain-text bodies with `?translate_markdown=false`.
+fn wants_body_text(path: &str) -> bool {
+	param(path, "translate_markdown").is_some_and(|translate| translate == "false")
+}
+
+/// Fill in the plain-text `body_text` of posts when `path` asks for it.
+pub fn add_body_text(posts: &mut [Post], path: &str) {
+	if wants_body_text(path) {
+		for post in posts.iter_mut() {
+			post.body_text = Some(markdown_to_text(&post.body));
+		}
+	}
+}
+
+/// Fill in the plain-text `body_text` of a comment tree when `path` asks for it.
+pub fn add_comments_body_text(comments: &mut [Comment], path: &str) {
+	if wants_body_text(path) {
+		for comment in comments.iter_mut() {
+			comment.body_text = Some(markdown_to_text(&comment.body));
+			add_comments_body_text(&mut comment.replies, path);
+		}
+	}
+}
+
+/// Machine-readable causes of API errors, sent as `reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiErrorCode {
+	/// The subreddit doesn't exist; `suggestion` may name the one meant.
+	NotFound,
+	/// The community is age-restricted and needs `?sfw_acknowledged=true`.
+	#[serde(rename = "over_18_gate")]
+	Over18Gate,
+	/// Reddit can't be reached right now.
+	Upstream,
+	/// Reddit answered with its anti-bot page instead of JSON.
+	UpstreamBlocked,
+	/// The client used up its `REDLIB_RATE_LIMIT` budget.
+	RateLimited,
+}
+
 /// Wrapper for all JSON API responses.
 #[derive(Serialize)]
 pub struct JsonResponse<T: Serialize> {
 	pub data: Option<T>,
 	pub error: Option<String>,
+	/// Machine-readable cause for errors that clients can act on
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub reason: Option<ApiErrorCode>,
+	/// Subreddit the client probably meant, for `not_found` errors
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub suggestion: Option<String>,
+	/// Instance that answered, from `REDLIB_ERROR_INSTANCE`, on errors
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub instance: Option<String>,
 }
 
 /// Build a successful JSON response.
@@ -38,6 +127,9 @@ pub fn json_response<T: Serialize>(data: T) -> Response<Body> {
 	let response = JsonResponse {
 		data: Some(data),
 		error: None,
+		reason: None,
+		suggestion: None,
+		instance: None,
 	};
 	Response::builder()
 		.status(200)
@@ -46,11 +138,101 @@ pub fn json_response<T: Serialize>(data: T) -> Response<Body> {
 		.unwrap_or_default()
 }
 
+/// Build an NDJSON response streamed by `produce`, which runs in a task of
+/// its own and writes one line per item, followed by a summary line.
+pub fn ndjson_stream<F, Fut>(produce: F) -> Response<Body>
+where
+	F: FnOnce(NdjsonWriter) -> Fut,
+	Fut: Future<Output = ()> + Send + 'static,
+{
+	let (sender, body) = Body::channel();
+	tokio::spawn(utils::with_request_settings(produce(NdjsonWriter { sender })));
+	Response::builder()
+		.status(200)
+		.header("content-type", "application/x-ndjson")
+		.body(body)
+		.unwrap_or_default()
+}
+
+/// Writing end of an [`ndjson_stream`].
+pub struct NdjsonWriter {
+	sender: Sender,
+}
+
+impl NdjsonWriter {
+	/// Write `item` as a line. Returns false once the client has gone away,
+	/// after which there's no point in producing more.
+	pub async fn write(&mut self, item: &impl Serialize) -> bool {
+		let line = serde_json::to_string(item).unwrap_or_default() + "\n";
+		self.sender.send_data(line.into()).await.is_ok()
+	}
+
+	/// Write every one of `items`, stopping early if the client goes away.
+	pub async fn write_all<T: Serialize>(&mut self, items: &[T]) -> bool {
+		for item in items {
+			if !self.write(item).await {
+				return false;
+			}
+		}
+		true
+	}
+}
+
 /// Build an error JSON response.
 pub fn json_error(msg: String, status: u16) -> Response<Body> {
+	error_response(msg, status, None, None)
+}
+
+/// Build the error JSON response for a request to Reddit that failed,
+/// answering with `status` unless Reddit blocked it.
+pub fn upstream_error(err: UpstreamError, status: u16) -> Response<Body> {
+	match err {
+		// Being blocked by Reddit is worth telling apart from a failure of our own
+		UpstreamError::Blocked => json_error_with_reason(UPSTREAM_BLOCKED.to_string(), 502, ApiErrorCode::UpstreamBlocked),
+		UpstreamError::Failed(msg) => json_error(msg, status),
+	}
+}
+
+/// Build an error JSON response carrying a machine-readable `reason`.
+pub fn json_error_with_reason(msg: String, status: u16, reason: ApiErrorCode) -> Response<Body> {
+	error_response(msg, status, Some(reason), None)
+}
+
+/// Build the error JSON response for a subreddit that doesn't exist, naming
+/// the one that was probably meant if there is one.
+pub fn subreddit_not_found(sub: &str, suggestion: Option<String>) -> Response<Body> {
+	let msg = match &suggestion {
+		Some(suggestion) => format!("r/{sub} does not exist; did you mean r/{suggestion}?"),
+		None => format!("r/{sub} does not exist"),
+	};
+	error_response(msg, 404, Some(ApiErrorCode::NotFound), suggestion)
+}
+
+/// Build the error JSON response for a listing Reddit wouldn't serve.
+/// `subject` names what the listing belongs to, such as `r/rust`.
+pub fn listing_error(err: UpstreamError, subject: &str) -> Response<Body> {
+	match &*err {
+		msg @ ("quarantined" | "gated") => json_error(format!("{subject} is {msg}"), 403),
+		"over18" if crate::utils::sfw_only() => json_error("NSFW content is disabled on this instance".to_string(), 403),
+		"over18" => json_error_with_reason(
+			format!("{subject} is age-restricted; pass ?sfw_acknowledged=true to continue"),
+			400,
+			ApiErrorCode::Over18Gate,
+		),
+		"private" => json_error(format!("{subject} is a private community"), 403),
+		"banned" => json_error(format!("{subject} has been banned from Reddit"), 404),
+		"suspended" => json_error(format!("{subject} has been suspended"), 404),
+		_ => upstream_error(err, 500),
+	}
+}
+
+fn error_response(msg: String, status: u16, reason: Option<ApiErrorCode>, suggestion: Option<String>) -> Response<Body> {
 	let response: JsonResponse<()> = JsonResponse {
 		data: None,
 		error: Some(msg),
+		reason,
+		suggestion,
+		instance: crate::config::get_setting("REDLIB_ERROR_INSTANCE").filter(|instance| !instance.is_empty()),
 	};
 	Response::builder()
 		.status(status)
@@ -59,32 +241,190 @@ pub fn json_error(msg: String, status: u16) -> Response<Body> {
 		.unwrap_or_default()
 }
 
+/// Reduced post representation returned by listing endpoints with `?view=compact`.
+#[derive(Serialize)]
+pub struct CompactPost {
+	pub id: String,
+	pub title: String,
+	pub author: String,
+	pub subreddit: String,
+	pub score: i64,
+	pub num_comments: i64,
+	pub created_utc: u64,
+	pub thumbnail: Option<String>,
+	pub permalink: String,
+	pub nsfw: bool,
+}
+
+impl From<Post> for CompactPost {
+	fn from(post: Post) -> Self {
+		Self {
+			score: post.score.1.parse().unwrap_or_default(),
+			num_comments: post.comments.1.parse().unwrap_or_default(),
+			thumbnail: Some(post.thumbnail.url).filter(|url| !url.is_empty()),
+			id: post.id,
+			title: post.title,
+			author: post.author.name,
+			subreddit: post.community,
+			created_utc: post.created_ts,
+			permalink: post.permalink,
+			nsfw: post.nsfw,
+		}
+	}
+}
+
+/// Preview-only post representation returned by listing endpoints with `?preview_only=true`.
+#[derive(Serialize)]
+pub struct PostPreview {
+	pub id: String,
+	pub preview_images: Vec<PreviewImage>,
+}
+
+/// Posts of a listing response, either in full, as `CompactPost`s or as `PostPreview`s.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum PostList {
+	Full(Vec<Post>),
+	Compact(Vec<CompactPost>),
+	Previews(Vec<PostPreview>),
+}
+
+impl PostList {
+	/// Pick the representation requested by the `view` or `preview_only` params of `path`.
+	pub fn for_view(posts: Vec<Post>, path: &str) -> Self {
+		if param(path, "preview_only").is_some_and(|preview_only| preview_only == "true") {
+			Self::Previews(
+				posts
+					.into_iter()
+					.filter(|post| !post.preview_images.is_empty())
+					.map(|post| PostPreview {
+						id: post.id,
+						preview_images: post.preview_images,
+					})
+					.collect(),
+			)
+		} else if param(path, "view").is_some_and(|view| view == "compact") {
+			Self::Compact(posts.into_iter().map(CompactPost::from).collect())
+		} else {
+			Self::Full(posts)
+		}
+	}
+}
+
 // --- Response structs for each endpoint ---
 
+/// One page of posts of a listing, flattened into the response of each
+/// endpoint serving listings.
+#[derive(Serialize)]
+pub struct ListingPage {
+	pub posts: PostList,
+	/// Cursor for the next page; opaque when `REDLIB_CURSOR_SECRET` is set
+	pub after: Option<String>,
+	/// Items seen up to and including this page; send it as `count` along with `after`
+	pub count: u64,
+	/// Items of the page left out because Reddit sent them malformed
+	pub skipped: usize,
+	/// Token to pass back as `seen` with the next page, with `?dedup_seen=true`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub seen: Option<String>,
+}
+
+impl ListingPage {
+	/// Page of `listing` in the view `path` asks for, with the cursors sealed.
+	pub fn new(listing: Listing, seen: Option<SeenPosts>, path: &str) -> Self {
+		Self {
+			posts: PostList::for_view(listing.posts, path),
+			after: listing.after.as_deref().map(cursor::seal),
+			count: listing.count,
+			skipped: listing.skipped,
+			seen: seen.map(|seen| seen.seal()),
+		}
+	}
+}
+
 #[derive(Serialize)]
 pub struct SubredditResponse {
 	pub subreddit: Subreddit,
-	pub posts: Vec<Post>,
-	pub after: Option<String>,
+	#[serde(flatten)]
+	pub page: ListingPage,
+}
+
+/// Listing of a public multireddit, along with what it is made of.
+#[derive(Serialize)]
+pub struct MultiredditResponse {
+	pub multireddit: Multireddit,
+	#[serde(flatten)]
+	pub page: ListingPage,
 }
 
 #[derive(Serialize)]
 pub struct PostResponse {
 	pub post: Post,
 	pub comments: Vec<Comment>,
+	/// Id of the first comment left out when `?max_comments=` cut the thread
+	/// short; pass it back as `?comments_after=` to get the comments that follow
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub more_comments: Option<String>,
+	/// Comment the thread was focused on, with its parents and replies
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub focused_comment: Option<String>,
+}
+
+/// Comments that appeared since the last poll of a thread, oldest first and
+/// without their replies; `parent_id` tells where each one goes. Pass `since`
+/// back on the next poll.
+#[derive(Serialize)]
+pub struct CommentPollResponse {
+	pub comments: Vec<Comment>,
+	pub since: String,
 }
 
 #[derive(Serialize)]
 pub struct UserResponse {
 	pub user: User,
-	pub posts: Vec<Post>,
-	pub after: Option<String>,
+	#[serde(flatten)]
+	pub page: ListingPage,
+}
+
+/// Outcome for a single requested item of the batch post endpoint.
+#[derive(Serialize)]
+pub struct BatchPostResult {
+	pub input: String,
+	pub post: Option<Post>,
+	pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchPostsResponse {
+	pub results: Vec<BatchPostResult>,
+}
+
+/// Anything a fullname can stand for, tagged with its `kind`.
+#[derive(Serialize)]
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum Thing {
+	Post(Box<Post>),
+	Comment(Box<Comment>),
+	Subreddit(Box<Subreddit>),
+}
+
+/// Outcome for a single requested fullname of `/api/info?ids=`.
+#[derive(Serialize)]
+pub struct ResolvedThing {
+	pub input: String,
+	pub thing: Option<Thing>,
+	pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ResolvedThingsResponse {
+	pub results: Vec<ResolvedThing>,
 }
 
 #[derive(Serialize)]
 pub struct SearchResponse {
-	pub posts: Vec<Post>,
-	pub after: Option<String>,
+	#[serde(flatten)]
+	pub page: ListingPage,
 }
 
 #[derive(Serialize)]
@@ -97,5 +437,202 @@ pub struct WikiResponse {
 #[derive(Serialize)]
 pub struct DuplicatesResponse {
 	pub post: Post,
-	pub duplicates: Vec<Post>,
+	pub duplicates: PostList,
+}
+
+/// About info for several subreddits at once, keyed by the requested name.
+/// Names that could not be fetched are listed in `errors` instead.
+#[derive(Serialize)]
+pub struct SubredditsAboutResponse {
+	pub subreddits: BTreeMap<String, Subreddit>,
+	pub errors: BTreeMap<String, String>,
+}
+
+/// Number of entries evicted by `POST /api/admin/flush-cache`. Media is
+/// proxied straight through, so the only response cache is the compressed
+/// body cache.
+#[derive(Serialize)]
+pub struct CacheFlushResponse {
+	pub listings: usize,
+	pub responses: usize,
+}
+
+/// Posts made to a subreddit since a given time, as reported by
+/// `/api/r/:sub/new_count`. Only one page of `/new` is looked at, so past
+/// that `overflow` is set and `label` reads like `100+`.
+#[derive(Serialize)]
+pub struct NewPostCountResponse {
+	pub subreddit: String,
+	pub since: u64,
+	pub count: usize,
+	pub overflow: bool,
+	pub label: String,
+}
+
+/// Whether a subreddit can be viewed, as reported by `/api/r/:sub/status`:
+/// `public`, `private`, `banned`, `quarantined` or `not_found`.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SubredditStatusResponse {
+	pub status: &'static str,
+	pub over_18: bool,
+}
+
+#[derive(Serialize)]
+pub struct WidgetsResponse {
+	pub subreddit: String,
+	pub widgets: Vec<Widget>,
+}
+
+#[derive(Serialize)]
+pub struct ModeratorsResponse {
+	pub subreddit: String,
+	pub moderators: Vec<Moderator>,
+}
+
+/// Approximate activity statistics for a user. The comment/post split only
+/// covers the sampled page of recent public activity, not the full history.
+#[derive(Serialize)]
+pub struct UserStatsResponse {
+	pub name: String,
+	pub link_karma: i64,
+	pub comment_karma: i64,
+	pub account_age_days: i64,
+	pub sampled_posts: u64,
+	pub sampled_comments: u64,
+	pub comment_to_post_ratio: Option<f64>,
+	pub note: String,
+}
+
+/// Response of `/api/info`.
+#[derive(Serialize)]
+pub struct ApiInfoResponse {
+	pub crate_version: &'static str,
+	pub git_commit: &'static str,
+	pub upstream: BreakerStatus,
+	pub upstream_requests: UpstreamLoad,
+}
+
+/// Last line of a `/api/u/:name/export` stream. `more` is set when the export
+/// stopped at the page cap or on an error before reaching the end of the
+/// history; `after` then resumes it.
+#[derive(Serialize)]
+pub struct ExportSummary {
+	pub pages: usize,
+	pub items: usize,
+	pub more: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub after: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+/// Last line of an archive stream. `reached` is the creation time of the
+/// oldest post looked at so far, and `complete` whether the whole range was
+/// covered. An archive that stopped at the page cap or on an error is
+/// resumed with `after`.
+#[derive(Serialize)]
+pub struct ArchiveSummary {
+	pub strategy: Strategy,
+	pub pages: usize,
+	pub items: usize,
+	pub reached: Option<u64>,
+	pub complete: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub after: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+#[tokio::test]
+async fn test_error_reason_serialization() {
+	let body = hyper::body::to_bytes(json_error_with_reason("gated".to_string(), 400, ApiErrorCode::Over18Gate).into_body())
+		.await
+		.unwrap();
+	assert_eq!(body, r#"{"data":null,"error":"gated","reason":"over_18_gate"}"#);
+
+	let body = hyper::body::to_bytes(json_error("oops".to_string(), 500).into_body()).await.unwrap();
+	assert_eq!(body, r#"{"data":null,"error":"oops"}"#);
+
+	let blocked = upstream_error(UpstreamError::Blocked, 500);
+	assert_eq!(blocked.status(), 502);
+	let body = hyper::body::to_bytes(blocked.into_body()).await.unwrap();
+	assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["reason"], "upstream_blocked");
+	assert_eq!(listing_error(UpstreamError::Blocked, "r/rust").status(), 502);
+	// Other failures keep the handler's status, even when they read the same
+	assert_eq!(json_error(UPSTREAM_BLOCKED.to_string(), 500).status(), 500);
+	assert_eq!(listing_error("private".into(), "r/rust").status(), 403);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_compact_view() {
+	let post: serde_json::Value = serde_json::from_str(
+		r#"{"kind":"t3","data":{"id":"abc","title":"Hello","author":"someone","subreddit":"rust","score":1234,"num_comments":56,"created_utc":1700000000.0,"permalink":"/r/rust/comments/abc/hello/","is_self":true,"thumbnail":"self"}}"#,
+	)
+	.unwrap();
+	let posts = vec![crate::utils::parse_post(&post, true).await];
+
+	let compact = serde_json::to_value(PostList::for_view(posts, "/r/rust.json?view=compact")).unwrap();
+	assert_eq!(compact[0]["id"], "abc");
+	assert_eq!(compact[0]["author"], "someone");
+	assert_eq!(compact[0]["subreddit"], "rust");
+	assert_eq!(compact[0]["score"], 1234);
+	assert_eq!(compact[0]["num_comments"], 56);
+	assert_eq!(compact[0]["created_utc"], 1700000000);
+	assert!(compact[0].get("body").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_preview_only_view() {
+	let image: serde_json::Value = serde_json::from_str(
+		r#"{"kind":"t3","data":{"id":"img","title":"Photo","url":"https://i.redd.it/photo.jpg","preview":{"images":[{"source":{"url":"https://preview.redd.it/photo.jpg?width=1920&s=c","width":1920,"height":1080},"resolutions":[{"url":"https://preview.redd.it/photo.jpg?width=108&s=a","width":108,"height":60},{"url":"https://preview.redd.it/photo.jpg?width=640&s=b","width":640,"height":360}]}]}}}"#,
+	)
+	.unwrap();
+	let text: serde_json::Value = serde_json::from_str(r#"{"kind":"t3","data":{"id":"txt","title":"Hello","is_self":true}}"#).unwrap();
+	let posts = vec![crate::utils::parse_post(&image, true).await, crate::utils::parse_post(&text, true).await];
+
+	let previews = serde_json::to_value(PostList::for_view(posts, "/r/pics.json?preview_only=true")).unwrap();
+	assert_eq!(previews.as_array().unwrap().len(), 1);
+	assert_eq!(previews[0]["id"], "img");
+	assert!(previews[0].get("title").is_none());
+
+	let images = previews[0]["preview_images"].as_array().unwrap();
+	assert_eq!(images.len(), 3);
+	assert_eq!(images[0]["url"], "/preview/pre/photo.jpg?width=108&s=a");
+	assert_eq!(images[0]["width"], 108);
+	assert_eq!(images[2]["height"], 1080);
+}
+
+/// One of a user's public multireddits, listed by `/api/u/<name>/multis`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct UserMulti {
+	pub name: String,
+	pub title: String,
+	pub subreddit_count: usize,
+	pub description: String,
+}
+
+/// Public multireddits of a user; the posts of each one are at
+/// `/api/multi/<user>/<name>`.
+#[derive(Serialize)]
+pub struct UserMultisResponse {
+	pub name: String,
+	pub multireddits: Vec<UserMulti>,
+}
+
+/// Posts and comments a user made in one subreddit within the sampled activity.
+#[derive(Serialize)]
+pub struct SubredditActivity {
+	pub subreddit: String,
+	pub posts: u64,
+	pub comments: u64,
+}
+
+/// Subreddits a user was recently active in, most frequent first. Only the
+/// sampled pages of recent public activity are considered.
+#[derive(Serialize)]
+pub struct UserSubredditsResponse {
+	pub name: String,
+	pub subreddits: Vec<SubredditActivity>,
+	pub sampled_items: usize,
+	pub note: String,
 }
@@ -1,15 +1,25 @@
+pub mod admin;
+pub mod archive;
+pub mod breaker;
 pub mod client;
+pub mod coalesce;
 pub mod collections;
 pub mod config;
+pub mod cursor;
 pub mod duplicates;
+pub mod forwarded;
 pub mod instance_info;
 pub mod json;
+pub mod multi;
 pub mod oauth;
 pub mod oauth_resources;
+pub mod peers;
 pub mod post;
+pub mod ratelimit;
 pub mod search;
 pub mod server;
 pub mod settings;
+pub mod sitemap;
 pub mod subreddit;
 pub mod user;
 pub mod utils;
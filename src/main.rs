@@ -4,17 +4,18 @@
 
 use cached::proc_macro::cached;
 use clap::{Arg, ArgAction, Command};
+use std::collections::HashMap;
 use std::str::FromStr;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
 
 use futures_lite::FutureExt;
 use hyper::Uri;
-use hyper::{header::HeaderValue, Body, Request, Response};
+use hyper::{Body, Request, Response};
 use log::info;
-use redlib::client::{canonical_path, proxy, CLIENT};
-use redlib::server::{self, RequestExt};
+use redlib::client::{canonical_path, proxy, proxy_image, proxy_video, MinTls, CLIENT, UPSTREAM_POOL};
+use redlib::server::{self, Precompressed, RequestExt};
 use redlib::utils::{error, redirect, ThemeAssets};
-use redlib::{config, duplicates, headers, instance_info, post, search, settings, subreddit, user};
+use redlib::{admin, archive, config, duplicates, forwarded, instance_info, multi, oauth, post, search, settings, sitemap, subreddit, user, utils};
 
 use redlib::client::OAUTH_CLIENT;
 
@@ -75,37 +76,35 @@ async fn opensearch() -> Result<Response<Body>, String> {
 	)
 }
 
-async fn resource(body: &str, content_type: &str, cache: bool) -> Result<Response<Body>, String> {
-	let mut res = Response::builder()
-		.status(200)
-		.header("content-type", content_type)
-		.body(body.to_string().into())
-		.unwrap_or_default();
-
-	if cache {
-		if let Ok(val) = HeaderValue::from_str("public, max-age=1209600, s-maxage=86400") {
-			res.headers_mut().insert("Cache-Control", val);
-		}
-	}
-
-	Ok(res)
-}
-
-async fn style() -> Result<Response<Body>, String> {
+/// Stylesheet with every theme appended.
+fn stylesheet() -> String {
 	let mut res = include_str!("../static/style.css").to_string();
 	for file in ThemeAssets::iter() {
 		res.push('\n');
 		let theme = ThemeAssets::get(file.as_ref()).unwrap();
 		res.push_str(std::str::from_utf8(theme.data.as_ref()).unwrap());
 	}
-	Ok(
-		Response::builder()
-			.status(200)
-			.header("content-type", "text/css")
-			.header("Cache-Control", "public, max-age=1209600, s-maxage=86400")
-			.body(res.to_string().into())
-			.unwrap_or_default(),
-	)
+	res
+}
+
+/// Text assets by path, compressed once at startup.
+static STATIC_ASSETS: LazyLock<HashMap<&'static str, Precompressed>> = LazyLock::new(|| {
+	HashMap::from([
+		("/style.css", Precompressed::new("text/css", stylesheet(), true)),
+		("/manifest.json", Precompressed::new("application/json", include_str!("../static/manifest.json"), false)),
+		("/playHLSVideo.js", Precompressed::new("text/javascript", include_str!("../static/playHLSVideo.js"), false)),
+		("/hls.min.js", Precompressed::new("text/javascript", include_str!("../static/hls.min.js"), false)),
+		("/highlighted.js", Precompressed::new("text/javascript", include_str!("../static/highlighted.js"), false)),
+		("/check_update.js", Precompressed::new("text/javascript", include_str!("../static/check_update.js"), false)),
+		("/copy.js", Precompressed::new("text/javascript", include_str!("../static/copy.js"), false)),
+	])
+});
+
+async fn static_asset(req: Request<Body>) -> Result<Response<Body>, String> {
+	match STATIC_ASSETS.get(req.uri().path()) {
+		Some(asset) => Ok(asset.response(req.headers())),
+		None => error(req, "Nothing here").await,
+	}
 }
 
 #[tokio::main]
@@ -153,15 +152,14 @@ async fn main() {
 				.short('H')
 				.long("hsts")
 				.value_name("EXPIRE_TIME")
-				.help("HSTS header to tell browsers that this site should only be accessed over HTTPS")
-				.default_value("604800")
+				.help("HSTS header to tell browsers that this site should only be accessed over HTTPS [default: REDLIB_HSTS or 604800]")
 				.num_args(1),
 		)
 		.get_matches();
 
 	let address = matches.get_one::<String>("address").unwrap();
 	let port = matches.get_one::<String>("port").unwrap();
-	let hsts = matches.get_one("hsts").map(|m: &String| m.as_str());
+	let hsts = matches.get_one::<String>("hsts").cloned();
 
 	let ipv4_only = std::env::var("IPV4_ONLY").is_ok() || matches.get_flag("ipv4-only");
 	let ipv6_only = std::env::var("IPV6_ONLY").is_ok() || matches.get_flag("ipv6-only");
@@ -179,6 +177,53 @@ async fn main() {
 	// Begin constructing a server
 	let mut app = server::Server::new();
 
+	// Refuse to start with an ambiguous or broken homepage redirect
+	if let Err(e) = subreddit::homepage() {
+		eprintln!("Invalid homepage configuration: {e}");
+		std::process::exit(1);
+	}
+
+	// Media URLs are handed to browsers as is, so they must be absolute
+	if let Err(e) = utils::parse_media_base_url(config::get_setting("REDLIB_MEDIA_BASE_URL")) {
+		eprintln!("Invalid REDLIB_MEDIA_BASE_URL: {e}");
+		std::process::exit(1);
+	}
+
+	if let Err(e) = forwarded::ForwardingHeader::parse(config::get_setting("REDLIB_FORWARDED_HEADER")) {
+		eprintln!("Invalid REDLIB_FORWARDED_HEADER: {e}");
+		std::process::exit(1);
+	}
+
+	if let Err(e) = MinTls::parse(config::get_setting("REDLIB_UPSTREAM_MIN_TLS")) {
+		eprintln!("Invalid REDLIB_UPSTREAM_MIN_TLS: {e}");
+		std::process::exit(1);
+	}
+
+	if let Err(e) = utils::load_error_template(config::get_setting("REDLIB_ERROR_TEMPLATE")) {
+		eprintln!("Failed to read REDLIB_ERROR_TEMPLATE: {e}");
+		std::process::exit(1);
+	}
+
+	// Serve HTTPS directly when a certificate and key are configured. This runs
+	// before anything touches the network so a bad certificate fails fast.
+	let tls_enabled = match (config::get_setting("REDLIB_TLS_CERT"), config::get_setting("REDLIB_TLS_KEY")) {
+		(Some(cert), Some(key)) => match server::load_tls_config(&cert, &key) {
+			Ok(tls_config) => {
+				app.tls_config = Some(Arc::new(tls_config));
+				true
+			}
+			Err(e) => {
+				eprintln!("Failed to load TLS configuration: {e}");
+				std::process::exit(1);
+			}
+		},
+		(None, None) => false,
+		_ => {
+			eprintln!("REDLIB_TLS_CERT and REDLIB_TLS_KEY must be set together");
+			std::process::exit(1);
+		}
+	};
+
 	// Force evaluation of statics. In instance_info case, we need to evaluate
 	// the timestamp so deploy date is accurate - in config case, we need to
 	// evaluate the configuration to avoid paying penalty at first request -
@@ -191,79 +236,61 @@ async fn main() {
 	LazyLock::force(&instance_info::INSTANCE_INFO);
 	info!("Creating OAUTH client.");
 	LazyLock::force(&OAUTH_CLIENT);
+	info!(
+		"Upstream pool: {} idle connections per host, {}s idle timeout",
+		UPSTREAM_POOL.max_idle_per_host,
+		UPSTREAM_POOL.idle_timeout.as_secs()
+	);
+	match oauth::USER_AGENTS.len() {
+		0 => info!("User agents: generated for each token"),
+		count => info!("User agents: picking from a pool of {count}"),
+	}
 
 	// Define default headers (added to all responses)
-	app.default_headers = headers! {
-		"Referrer-Policy" => "no-referrer",
-		"X-Content-Type-Options" => "nosniff",
-		"X-Frame-Options" => "DENY",
-		"Content-Security-Policy" => "default-src 'none'; font-src 'self'; script-src 'self' blob:; manifest-src 'self'; media-src 'self' data: blob: about:; style-src 'self' 'unsafe-inline'; base-uri 'none'; img-src 'self' data:; form-action 'self'; frame-ancestors 'none'; connect-src 'self'; worker-src blob:;"
-	};
+	let hsts = hsts.or_else(|| config::get_setting("REDLIB_HSTS")).unwrap_or_else(|| "604800".to_string());
+	app.default_headers = server::security_headers(&hsts);
 
-	if let Some(expire_time) = hsts {
-		if let Ok(val) = HeaderValue::from_str(&format!("max-age={expire_time}")) {
-			app.default_headers.insert("Strict-Transport-Security", val);
-		}
+	// Read static files. Like media, these aren't rate limited
+	LazyLock::force(&STATIC_ASSETS);
+	for path in STATIC_ASSETS.keys() {
+		app.at(path).unlimited().get(|r| static_asset(r).boxed());
 	}
-
-	// Read static files
-	app.at("/style.css").get(|_| style().boxed());
-	app
-		.at("/manifest.json")
-		.get(|_| resource(include_str!("../static/manifest.json"), "application/json", false).boxed());
-	app.at("/robots.txt").get(|_| {
-		resource(
-			if match config::get_setting("REDLIB_ROBOTS_DISABLE_INDEXING") {
-				Some(val) => val == "on",
-				None => false,
-			} {
-				"User-agent: *\nDisallow: /"
-			} else {
-				"User-agent: *\nDisallow: /u/\nDisallow: /user/"
-			},
-			"text/plain",
-			true,
-		)
-		.boxed()
-	});
-	app.at("/favicon.ico").get(|_| favicon().boxed());
-	app.at("/logo.png").get(|_| pwa_logo().boxed());
-	app.at("/Inter.var.woff2").get(|_| font().boxed());
-	app.at("/touch-icon-iphone.png").get(|_| iphone_logo().boxed());
-	app.at("/apple-touch-icon.png").get(|_| iphone_logo().boxed());
-	app.at("/opensearch.xml").get(|_| opensearch().boxed());
-	app
-		.at("/playHLSVideo.js")
-		.get(|_| resource(include_str!("../static/playHLSVideo.js"), "text/javascript", false).boxed());
-	app
-		.at("/hls.min.js")
-		.get(|_| resource(include_str!("../static/hls.min.js"), "text/javascript", false).boxed());
-	app
-		.at("/highlighted.js")
-		.get(|_| resource(include_str!("../static/highlighted.js"), "text/javascript", false).boxed());
-	app
-		.at("/check_update.js")
-		.get(|_| resource(include_str!("../static/check_update.js"), "text/javascript", false).boxed());
-	app.at("/copy.js").get(|_| resource(include_str!("../static/copy.js"), "text/javascript", false).boxed());
+	app.at("/robots.txt").unlimited().get(|r| sitemap::robots(r).boxed());
+	app.at("/sitemap.xml").unlimited().get(|r| sitemap::sitemap(r).boxed());
+	app.at("/favicon.ico").unlimited().get(|_| favicon().boxed());
+	app.at("/logo.png").unlimited().get(|_| pwa_logo().boxed());
+	app.at("/Inter.var.woff2").unlimited().get(|_| font().boxed());
+	app.at("/touch-icon-iphone.png").unlimited().get(|_| iphone_logo().boxed());
+	app.at("/apple-touch-icon.png").unlimited().get(|_| iphone_logo().boxed());
+	app.at("/opensearch.xml").unlimited().get(|_| opensearch().boxed());
 
 	app.at("/commits.atom").get(|_| async move { proxy_commit_info().await }.boxed());
 	app.at("/instances.json").get(|_| async move { proxy_instances().await }.boxed());
 
-	// Proxy media through Redlib
-	app.at("/vid/:id/:size").get(|r| proxy(r, "https://v.redd.it/{id}/DASH_{size}").boxed());
-	app.at("/hls/:id/*path").get(|r| proxy(r, "https://v.redd.it/{id}/{path}").boxed());
-	app.at("/img/*path").get(|r| proxy(r, "https://i.redd.it/{path}").boxed());
-	app.at("/thumb/:point/:id").get(|r| proxy(r, "https://{point}.thumbs.redditmedia.com/{id}").boxed());
-	app.at("/emoji/:id/:name").get(|r| proxy(r, "https://emoji.redditmedia.com/{id}/{name}").boxed());
+	// Proxy media through Redlib. A page asks for a lot of media, none of
+	// which touches Reddit's API, so it isn't rate limited
+	app.at("/vid/:id/:size").unlimited().get(|r| proxy_video(r).boxed());
+	app.at("/hls/:id/*path").unlimited().get(|r| proxy(r, "https://v.redd.it/{id}/{path}").boxed());
+	app.at("/img/*path").unlimited().get(|r| proxy_image(r).boxed());
+	app
+		.at("/thumb/:point/:id")
+		.unlimited()
+		.get(|r| proxy(r, "https://{point}.thumbs.redditmedia.com/{id}").boxed());
+	app
+		.at("/emoji/:id/:name")
+		.unlimited()
+		.get(|r| proxy(r, "https://emoji.redditmedia.com/{id}/{name}").boxed());
 	app
 		.at("/emote/:subreddit_id/:filename")
+		.unlimited()
 		.get(|r| proxy(r, "https://reddit-econ-prod-assets-permanent.s3.amazonaws.com/asset-manager/{subreddit_id}/{filename}").boxed());
 	app
 		.at("/preview/:loc/award_images/:fullname/:id")
+		.unlimited()
 		.get(|r| proxy(r, "https://{loc}view.redd.it/award_images/{fullname}/{id}").boxed());
-	app.at("/preview/:loc/:id").get(|r| proxy(r, "https://{loc}view.redd.it/{id}").boxed());
-	app.at("/style/*path").get(|r| proxy(r, "https://styles.redditmedia.com/{path}").boxed());
-	app.at("/static/*path").get(|r| proxy(r, "https://www.redditstatic.com/{path}").boxed());
+	app.at("/preview/:loc/:id").unlimited().get(|r| proxy(r, "https://{loc}view.redd.it/{id}").boxed());
+	app.at("/style/*path").unlimited().get(|r| proxy(r, "https://styles.redditmedia.com/{path}").boxed());
+	app.at("/static/*path").unlimited().get(|r| proxy(r, "https://www.redditstatic.com/{path}").boxed());
 
 	// Browse user profile
 	app
@@ -280,6 +307,12 @@ async fn main() {
 	// JSON API routes for users
 	app.at("/user/:name.js").get(|r| user::profile_json(r).boxed());
 	app.at("/user/:name/:listing.js").get(|r| user::profile_json(r).boxed());
+	app.at("/api/u/:name/stats").get(|r| user::stats_json(r).boxed());
+	app.at("/api/u/:name/subreddits").get(|r| user::subreddits_json(r).boxed());
+	app.at("/api/u/:name/export").get(|r| user::export_json(r).boxed());
+	app.at("/api/u/:name/multis").get(|r| user::multis_json(r).boxed());
+	app.at("/api/multi/:user/:name").get(|r| multi::multi_json(r).boxed());
+	app.at("/api/multi/:user/:name/:sort").get(|r| multi::multi_json(r).boxed());
 
 	app.at("/user/:name/comments/:id").get(|r| post::item(r).boxed());
 	app.at("/user/:name/comments/:id/:title").get(|r| post::item(r).boxed());
@@ -297,12 +330,25 @@ async fn main() {
 	// Subreddit services
 	app.at("/c/:collection").get(|r| subreddit::community(r).boxed());
 	app.at("/c/:collection/:sort").get(|r| subreddit::community(r).boxed());
+	app.at("/c/:collection/search").get(|r| search::find_collection(r).boxed());
 
 	// JSON API routes for subreddits
 	app.at("/r/:sub.js").get(|r| subreddit::community_json(r).boxed());
 	app.at("/r/:sub/:sort.js").get(|r| subreddit::community_json(r).boxed());
 	app.at("/c/:collection.js").get(|r| subreddit::community_json(r).boxed());
 	app.at("/c/:collection/:sort.js").get(|r| subreddit::community_json(r).boxed());
+	app.at("/c/:collection/search.js").get(|r| search::find_collection_json(r).boxed());
+	app.at("/api/r/:sub/moderators").get(|r| subreddit::moderators_json(r).boxed());
+	app.at("/api/r/:sub/status").get(|r| subreddit::status_json(r).boxed());
+	app.at("/api/r/:sub/new_count").get(|r| subreddit::new_count_json(r).boxed());
+	app.at("/api/r/:sub/widgets").get(|r| subreddit::widgets_json(r).boxed());
+	app.at("/api/r/:sub/archive").get(|r| archive::archive_json(r).boxed());
+	app.at("/api/posts").get(|r| post::batch_json(r).boxed());
+	app.at("/api/comment/:id").get(|r| post::comment_json(r).boxed());
+	app.at("/api/post/:id/comments/poll").get(|r| post::poll_comments_json(r).boxed());
+	app.at("/api/subreddits/about").get(|r| subreddit::about_batch_json(r).boxed());
+	app.at("/api/admin/flush-cache").post(|r| admin::flush_cache(r).boxed());
+	app.at("/api/debug/raw").get(|r| admin::debug_raw(r).boxed());
 
 	app
 		.at("/r/:sub")
@@ -390,6 +436,8 @@ async fn main() {
 
 	// Instance info page
 	app.at("/info").get(|r| instance_info::instance_info(r).boxed());
+	app.at("/api/info").get(|r| instance_info::api_info(r).boxed());
+	app.at("/metrics").unlimited().get(|r| instance_info::metrics(r).boxed());
 	app.at("/info.:extension").get(|r| instance_info::instance_info(r).boxed());
 
 	// Handle obfuscated share links.
@@ -435,7 +483,7 @@ async fn main() {
 	// Default service in case no routes match
 	app.at("/*").get(|req| error(req, "Nothing here").boxed());
 
-	println!("Running Redlib v{} on {listener}!", env!("CARGO_PKG_VERSION"));
+	println!("Running Redlib v{} on {}{listener}!", env!("CARGO_PKG_VERSION"), if tls_enabled { "https://" } else { "" });
 
 	let server = app.listen(&listener);
 
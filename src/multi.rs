@@ -0,0 +1,160 @@
+//! Public multireddits, the lists of subreddits users curate on Reddit.
+
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::client::json;
+use crate::cursor;
+use crate::json::{add_body_text, json_error, json_response, listing_error, truncate_posts, upstream_error, ListingPage, MultiredditResponse, DEFAULT_BODY_LIMIT};
+use crate::server::RequestExt;
+use crate::utils::{self, fetch_listing, filter_listing, format_url, param, Listing, ListingParams, SeenPosts};
+
+/// Sorts Reddit serves multireddit listings in.
+const MULTI_SORTS: [&str; 5] = ["hot", "new", "top", "rising", "controversial"];
+
+/// What a multireddit is made of, as described by its owner.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Multireddit {
+	pub name: String,
+	pub title: String,
+	pub owner: String,
+	pub description: String,
+	pub subreddits: Vec<String>,
+	pub icon: String,
+	pub nsfw: bool,
+	pub created_ts: u64,
+}
+
+/// JSON API endpoint for the listing of a public multireddit, along with
+/// the subreddits it is made of.
+pub async fn multi_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let user = req.param("user").unwrap_or_default();
+	let name = req.param("name").unwrap_or_default();
+	let sort = req.param("sort").unwrap_or_else(|| "hot".to_string());
+	for part in [&user, &name] {
+		if part.is_empty() || !part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+			return Ok(json_error(format!("\"{part}\" is not a valid multireddit path"), 400));
+		}
+	}
+	if !MULTI_SORTS.contains(&sort.as_str()) {
+		return Ok(json_error(format!("Multireddits can't be sorted by {sort}"), 400));
+	}
+	let subject = format!("u/{user}/m/{name}");
+
+	let multireddit = match json(format!("/api/multi/user/{user}/m/{name}.json?raw_json=1"), false).await {
+		Ok(res) => match parse_multireddit(&res, &subject) {
+			Ok(multireddit) => multireddit,
+			Err((msg, status)) => return Ok(json_error(msg, status)),
+		},
+		Err(msg) if msg.starts_with("Reddit error 404") => return Ok(json_error(format!("{subject} does not exist"), 404)),
+		Err(msg) if msg.starts_with("Reddit error 403") => return Ok(json_error(format!("{subject} is private"), 403)),
+		Err(err) => return Ok(upstream_error(err, 500)),
+	};
+
+	// Check NSFW gating (server-side SFW_ONLY only)
+	if multireddit.nsfw && utils::sfw_only() {
+		return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
+	}
+
+	let query = match cursor::open_query(req.uri().query().unwrap_or_default()) {
+		Ok(query) => query,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let params = match ListingParams::parse(&query) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let base = format!("/user/{user}/m/{name}/{sort}.json");
+	let path = params.path(&base);
+
+	// Parse body_limit param (default: 400 chars)
+	let body_limit: Option<usize> = param(&path, "body_limit").and_then(|s| s.parse().ok()).or(Some(DEFAULT_BODY_LIMIT));
+
+	let mut seen = match SeenPosts::from_query(&path) {
+		Ok(seen) => seen,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	match fetch_listing(&base, &params, false, false, true).await {
+		Ok(Listing { mut posts, after, count, skipped }) => {
+			// A multireddit may mix NSFW subreddits in with the rest
+			posts.retain(|post| !(utils::sfw_only() && post.flags.nsfw));
+			filter_listing(&mut posts, &path);
+			if let Some(seen) = seen.as_mut() {
+				seen.dedup(&mut posts);
+			}
+			add_body_text(&mut posts, &path);
+			// Truncate post bodies for list response
+			truncate_posts(&mut posts, body_limit);
+			if utils::wants_emojis(&path) {
+				utils::replace_listing_emojis(&mut posts, true).await;
+			}
+			Ok(json_response(MultiredditResponse {
+				multireddit,
+				page: ListingPage::new(Listing { posts, after, count, skipped }, seen, &path),
+			}))
+		}
+		Err(msg) => Ok(listing_error(msg, &subject)),
+	}
+}
+
+/// Read a multireddit off `/api/multi`. Reddit describes missing
+/// multireddits, and those private to someone else, with an error object
+/// instead, which becomes the message and status to answer with.
+fn parse_multireddit(res: &Value, subject: &str) -> Result<Multireddit, (String, u16)> {
+	let data = &res["data"];
+	if res["kind"] != "LabeledMulti" || !data["name"].as_str().is_some_and(|name| !name.is_empty()) {
+		return Err(match res["reason"].as_str() {
+			Some("MULTI_NOT_FOUND") | None => (format!("{subject} does not exist"), 404),
+			Some(_) => (format!("{subject} is private"), 403),
+		});
+	}
+	if data["visibility"] == "private" {
+		return Err((format!("{subject} is private"), 403));
+	}
+
+	let text = |field: &str| data[field].as_str().unwrap_or_default().to_string();
+	Ok(Multireddit {
+		name: text("name"),
+		title: text("display_name"),
+		owner: text("owner"),
+		description: text("description_md"),
+		subreddits: data["subreddits"]
+			.as_array()
+			.map(|subs| subs.iter().filter_map(|sub| sub["name"].as_str()).map(ToString::to_string).collect())
+			.unwrap_or_default(),
+		icon: format_url(data["icon_url"].as_str().unwrap_or_default()),
+		nsfw: data["over_18"].as_bool().unwrap_or_default(),
+		created_ts: data["created_utc"].as_f64().unwrap_or_default().round() as u64,
+	})
+}
+
+#[test]
+fn test_parse_multireddit() {
+	let res = serde_json::json!({
+		"kind": "LabeledMulti",
+		"data": {
+			"name": "langs",
+			"display_name": "Languages",
+			"owner": "someone",
+			"description_md": "Programming **languages**",
+			"subreddits": [{ "name": "rust" }, { "name": "golang" }],
+			"visibility": "public",
+			"over_18": false,
+			"created_utc": 1700000000.0
+		}
+	});
+	let multireddit = parse_multireddit(&res, "u/someone/m/langs").unwrap();
+	assert_eq!((multireddit.name.as_str(), multireddit.title.as_str()), ("langs", "Languages"));
+	assert_eq!(multireddit.subreddits, vec!["rust", "golang"]);
+	assert_eq!(multireddit.created_ts, 1700000000);
+
+	let missing = serde_json::json!({ "explanation": "that multireddit doesn't exist", "message": "Not Found", "reason": "MULTI_NOT_FOUND" });
+	assert_eq!(parse_multireddit(&missing, "u/someone/m/gone"), Err(("u/someone/m/gone does not exist".to_string(), 404)));
+	let forbidden = serde_json::json!({ "explanation": "you are not allowed to do that", "message": "Forbidden", "reason": "MULTI_CANNOT_EDIT" });
+	assert_eq!(parse_multireddit(&forbidden, "u/someone/m/mine").unwrap_err().1, 403);
+	let mut private = res.clone();
+	private["data"]["visibility"] = "private".into();
+	assert_eq!(parse_multireddit(&private, "u/someone/m/langs").unwrap_err().1, 403);
+}
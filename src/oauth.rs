@@ -1,12 +1,21 @@
-use std::{collections::HashMap, sync::atomic::Ordering, time::Duration};
+use std::{
+	collections::HashMap,
+	fs,
+	io::Write,
+	path::{Path, PathBuf},
+	sync::{atomic::Ordering, LazyLock},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-	client::{CLIENT, OAUTH_CLIENT, OAUTH_IS_ROLLING_OVER, OAUTH_RATELIMIT_REMAINING},
+	client::{request_upstream, OAUTH_CLIENT, OAUTH_IS_ROLLING_OVER, OAUTH_RATELIMIT_REMAINING},
+	config::get_setting,
 	oauth_resources::ANDROID_APP_VERSION_LIST,
 };
 use base64::{engine::general_purpose, Engine as _};
-use hyper::{client, Body, Method, Request};
+use hyper::{Body, Method, Request};
 use log::{error, info, trace, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tegen::tegen::TextGenerator;
 use tokio::time::{error::Elapsed, timeout};
@@ -17,6 +26,54 @@ const AUTH_ENDPOINT: &str = "https://www.reddit.com";
 
 const OAUTH_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// File the current token is kept in across restarts, from
+/// `REDLIB_TOKEN_CACHE_FILE`.
+static TOKEN_CACHE_FILE: LazyLock<Option<PathBuf>> = LazyLock::new(|| get_setting("REDLIB_TOKEN_CACHE_FILE").filter(|path| !path.trim().is_empty()).map(PathBuf::from));
+
+/// Least lifetime a cached token needs left to be reused, so that the token
+/// daemon still has time to replace it.
+const MIN_CACHED_TOKEN_LIFETIME: u64 = 300;
+
+/// What is kept of a token on disk: everything requests are made with.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedToken {
+	headers: HashMap<String, String>,
+	user_agent: String,
+	generic_web: bool,
+	/// Unix time the token expires at
+	expires_at: u64,
+}
+
+fn unix_now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+/// User agents configured through `REDLIB_USER_AGENTS`. One is picked for
+/// each new token and kept for its whole lifetime, so that requests made with
+/// the same token look like they come from the same client.
+pub static USER_AGENTS: LazyLock<Vec<String>> = LazyLock::new(|| parse_user_agents(get_setting("REDLIB_USER_AGENTS")));
+
+/// Split the user agent list on newlines. User agents commonly contain `;`
+/// themselves, so nothing else separates them.
+fn parse_user_agents(value: Option<String>) -> Vec<String> {
+	value
+		.unwrap_or_default()
+		.lines()
+		.map(str::trim)
+		.filter(|agent| !agent.is_empty())
+		.map(ToString::to_string)
+		.collect()
+}
+
+/// A user agent from the configured pool, or `generated` when there is none.
+fn pick_user_agent(generated: String) -> String {
+	if USER_AGENTS.is_empty() {
+		generated
+	} else {
+		USER_AGENTS[fastrand::usize(..USER_AGENTS.len())].clone()
+	}
+}
+
 // Response from OAuth backend authentication
 #[derive(Debug, Clone)]
 pub struct OauthResponse {
@@ -71,6 +128,93 @@ pub struct Oauth {
 }
 
 impl Oauth {
+	/// Create the OAuth client to start with, reusing the token cached in
+	/// `REDLIB_TOKEN_CACHE_FILE` while it is still good.
+	pub(crate) async fn cached_or_new() -> Self {
+		if let Some(path) = TOKEN_CACHE_FILE.as_deref() {
+			match Self::load_cached(path, unix_now()) {
+				Ok(oauth) => {
+					info!("[✅] Reusing the OAuth token cached in {}", path.display());
+					return oauth;
+				}
+				Err(msg) => info!("[🔄] Not reusing a cached OAuth token: {msg}"),
+			}
+		}
+		Self::new().await
+	}
+
+	fn load_cached(path: &Path, now: u64) -> Result<Self, String> {
+		let contents = fs::read_to_string(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+		let cached: CachedToken = serde_json::from_str(&contents).map_err(|e| format!("{} is corrupt: {e}", path.display()))?;
+		if cached.expires_at < now + MIN_CACHED_TOKEN_LIFETIME || !cached.headers.contains_key("Authorization") {
+			return Err("it has expired".to_string());
+		}
+
+		let backend = if cached.generic_web {
+			OauthBackendImpl::GenericWeb(GenericWebAuth {
+				device_id: String::new(),
+				user_agent: cached.user_agent,
+				additional_headers: HashMap::new(),
+			})
+		} else {
+			OauthBackendImpl::MobileSpoof(MobileSpoofAuth {
+				device: Device {
+					user_agent: cached.user_agent,
+					..Device::default()
+				},
+				additional_headers: HashMap::new(),
+			})
+		};
+		Ok(Self {
+			headers_map: cached.headers,
+			expires_in: cached.expires_at - now,
+			backend,
+		})
+	}
+
+	/// Write the token to `path`, readable by its owner only. The file is
+	/// replaced in one go so a crash never leaves half a token behind.
+	fn save_cached(&self, path: &Path, now: u64) -> std::io::Result<()> {
+		let cached = CachedToken {
+			headers: self.headers_map.clone(),
+			user_agent: self.user_agent().to_string(),
+			generic_web: matches!(self.backend, OauthBackendImpl::GenericWeb(_)),
+			expires_at: now + self.expires_in,
+		};
+
+		// A partial file left behind by an earlier attempt would keep whatever
+		// permissions it had, so it is replaced rather than reused
+		let partial = PathBuf::from(format!("{}.tmp", path.display()));
+		match fs::remove_file(&partial) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+			_ => {}
+		}
+		let mut options = fs::OpenOptions::new();
+		options.write(true).create_new(true);
+		#[cfg(unix)]
+		std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+		let mut file = options.open(&partial)?;
+		file.write_all(serde_json::to_string(&cached)?.as_bytes())?;
+		file.sync_all()?;
+		fs::rename(partial, path)
+	}
+
+	/// Cache the token in `REDLIB_TOKEN_CACHE_FILE`. Should that fail, the
+	/// token cached before is thrown away too, so that a restart doesn't pick
+	/// up the token this one replaced.
+	fn cache(&self) {
+		let Some(path) = TOKEN_CACHE_FILE.as_deref() else {
+			return;
+		};
+		if let Err(e) = self.save_cached(path, unix_now()) {
+			warn!("[⚠️] Failed to cache the OAuth token in {}: {e}", path.display());
+			match fs::remove_file(path) {
+				Err(e) if e.kind() != std::io::ErrorKind::NotFound => error!("[⛔] Failed to remove the outdated OAuth token cached in {}: {e}", path.display()),
+				_ => {}
+			}
+		}
+	}
+
 	/// Create a new OAuth client
 	pub(crate) async fn new() -> Self {
 		// Try MobileSpoofAuth first, then fall back to GenericWebAuth
@@ -82,6 +226,7 @@ impl Oauth {
 			match attempt {
 				Ok(Ok(oauth)) => {
 					info!("[✅] Successfully created OAuth client");
+					oauth.cache();
 					return oauth;
 				}
 				Ok(Err(e)) => {
@@ -247,8 +392,7 @@ impl OauthBackend for MobileSpoofAuth {
 		trace!("Sending token request...\n\n{request:?}");
 
 		// Send request
-		let client: &std::sync::LazyLock<client::Client<_, Body>> = &CLIENT;
-		let resp = client.request(request).await?;
+		let resp = request_upstream(request).await?;
 
 		trace!("Received response with status {} and length {:?}", resp.status(), resp.headers().get("content-length"));
 		trace!("OAuth headers: {:#?}", resp.headers());
@@ -331,7 +475,7 @@ impl GenericWebAuth {
 
 		Self {
 			device_id,
-			user_agent: fake_user_agent::get_rua().to_owned(),
+			user_agent: pick_user_agent(fake_user_agent::get_rua().to_owned()),
 			additional_headers: HashMap::new(),
 		}
 	}
@@ -364,8 +508,7 @@ impl OauthBackend for GenericWebAuth {
 		trace!("Sending GenericWebAuth token request...\n\n{request:?}");
 
 		// Send request
-		let client: &std::sync::LazyLock<client::Client<_, Body>> = &CLIENT;
-		let resp = client.request(request).await?;
+		let resp = request_upstream(request).await?;
 
 		trace!("Received response with status {} and length {:?}", resp.status(), resp.headers().get("content-length"));
 		trace!("GenericWebAuth headers: {:#?}", resp.headers());
@@ -440,7 +583,7 @@ impl Device {
 		let android_app_version = choose(ANDROID_APP_VERSION_LIST).to_string();
 		let android_version = fastrand::u8(9..=14);
 
-		let android_user_agent = format!("Reddit/{android_app_version}/Android {android_version}");
+		let android_user_agent = pick_user_agent(format!("Reddit/{android_app_version}/Android {android_version}"));
 
 		let qos = fastrand::u32(1000..=100_000);
 		let qos: f32 = qos as f32 / 1000.0;
@@ -538,3 +681,46 @@ fn test_creating_backends() {
 	MobileSpoofAuth::new();
 	GenericWebAuth::new();
 }
+
+#[test]
+fn test_token_cache() {
+	let path = std::env::temp_dir().join(format!("redlib-test-token-{}.json", uuid::Uuid::new_v4()));
+	let oauth = Oauth {
+		headers_map: HashMap::from([("Authorization".to_string(), "Bearer secret".to_string())]),
+		expires_in: 3600,
+		backend: OauthBackendImpl::GenericWeb(GenericWebAuth::new()),
+	};
+	// A world-readable partial file left behind doesn't leak into the cache
+	let partial = PathBuf::from(format!("{}.tmp", path.display()));
+	fs::write(&partial, "stale").unwrap();
+	#[cfg(unix)]
+	fs::set_permissions(&partial, std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+	oauth.save_cached(&path, 1000).unwrap();
+	assert!(!partial.exists());
+	#[cfg(unix)]
+	assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+
+	// Reused with the lifetime it has left
+	let cached = Oauth::load_cached(&path, 2000).unwrap();
+	assert_eq!(cached.headers_map, oauth.headers_map);
+	assert_eq!(cached.expires_in, 2600);
+	assert_eq!(cached.user_agent(), oauth.user_agent());
+	assert!(matches!(cached.backend, OauthBackendImpl::GenericWeb(_)));
+
+	// Tokens about to expire and corrupt files make way for a fresh token
+	assert!(Oauth::load_cached(&path, 4400).is_err());
+	fs::write(&path, "{\"headers\":").unwrap();
+	assert!(Oauth::load_cached(&path, 2000).unwrap_err().contains("corrupt"));
+	fs::remove_file(&path).unwrap();
+	assert!(Oauth::load_cached(&path, 2000).is_err());
+}
+
+#[test]
+fn test_parse_user_agents() {
+	assert!(parse_user_agents(None).is_empty());
+	assert_eq!(parse_user_agents(Some("Mozilla/5.0 (X11; Linux x86_64)".into())), vec!["Mozilla/5.0 (X11; Linux x86_64)"]);
+	assert_eq!(
+		parse_user_agents(Some("Mozilla/5.0 (X11; Linux x86_64)\n\n  Mozilla/5.0 (Windows NT 10.0; Win64; x64)\n".into())),
+		vec!["Mozilla/5.0 (X11; Linux x86_64)", "Mozilla/5.0 (Windows NT 10.0; Win64; x64)"]
+	);
+}
@@ -0,0 +1,165 @@
+//! Fallback to peer Redlib instances, which answer JSON API requests this
+//! instance couldn't get out of Reddit itself.
+
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use log::warn;
+
+use crate::client::request_upstream;
+use crate::{config, cursor};
+
+/// Header counting how many instances a request went through, so that peers
+/// falling back on each other don't pass it around forever.
+pub const FORWARDED_HEADER: &str = "X-Redlib-Forwarded";
+
+/// Hops a request may already have made and still be passed on to a peer.
+/// A request coming from a peer is never passed on again.
+const MAX_PEER_HOPS: u32 = 1;
+
+/// How long a peer gets to answer before the next one is tried.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Base URLs of the instances to fall back on, from `REDLIB_PEERS`.
+pub static PEERS: LazyLock<Vec<String>> = LazyLock::new(|| parse_peers(config::get_setting("REDLIB_PEERS")));
+
+fn parse_peers(value: Option<String>) -> Vec<String> {
+	value
+		.unwrap_or_default()
+		.split(',')
+		.map(|peer| peer.trim().trim_end_matches('/').to_string())
+		// Upstream connections are HTTPS only
+		.filter(|peer| peer.starts_with("https://") && peer.parse::<Uri>().is_ok_and(|uri| uri.host().is_some()))
+		.collect()
+}
+
+/// Instances a request went through before reaching this one.
+pub fn hops(headers: &HeaderMap) -> u32 {
+	headers
+		.get(FORWARDED_HEADER)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.trim().parse().ok())
+		.unwrap_or_default()
+}
+
+/// Whether an API request for `path` that ended with `status` should be
+/// tried against a peer: when Reddit couldn't be reached or the handler
+/// failed to get an answer out of it, and the request didn't come from a
+/// peer already. Requests paging on with cursors this instance sealed are
+/// kept here, as peers would only turn them away.
+pub fn wants_fallback(path: &str, status: Option<StatusCode>, short_circuited: bool, hops: u32) -> bool {
+	!PEERS.is_empty() && fallback_needed(path, status, short_circuited, hops)
+}
+
+fn fallback_needed(path: &str, status: Option<StatusCode>, short_circuited: bool, hops: u32) -> bool {
+	hops < MAX_PEER_HOPS && !cursor::carries_sealed(path) && (short_circuited || !status.is_some_and(|status| !status.is_server_error()))
+}
+
+/// Ask each peer in turn for `path`, relaying the first answer that isn't a
+/// server error. Only the status, content type and body are passed on, with
+/// `X-Served-By` naming the peer.
+pub async fn fetch(path: &str, hops: u32) -> Option<Response<Body>> {
+	fetch_from(&PEERS, path, hops, request_upstream).await
+}
+
+async fn fetch_from<F, Fut>(peers: &[String], path: &str, hops: u32, send: F) -> Option<Response<Body>>
+where
+	F: Fn(Request<Body>) -> Fut,
+	Fut: Future<Output = Result<Response<Body>, hyper::Error>>,
+{
+	for peer in peers {
+		let Ok(req) = Request::get(format!("{peer}{path}"))
+			.header(FORWARDED_HEADER, hops + 1)
+			.header(header::ACCEPT_ENCODING, "identity")
+			.body(Body::empty())
+		else {
+			continue;
+		};
+
+		match tokio::time::timeout(PEER_TIMEOUT, send(req)).await {
+			Ok(Ok(res)) if !res.status().is_server_error() => {
+				let content_type = res.headers().get(header::CONTENT_TYPE).cloned().unwrap_or(HeaderValue::from_static("application/json"));
+				let mut relayed = Response::builder()
+					.status(res.status())
+					.header(header::CONTENT_TYPE, content_type)
+					.body(res.into_body())
+					.unwrap_or_default();
+				relayed.extensions_mut().insert(crate::server::Relayed);
+				if let Ok(served_by) = HeaderValue::from_str(peer) {
+					relayed.headers_mut().insert("X-Served-By", served_by);
+				}
+				return Some(relayed);
+			}
+			Ok(Ok(res)) => warn!("Peer {peer} answered {path} with {}", res.status()),
+			Ok(Err(e)) => warn!("Peer {peer} failed for {path}: {e}"),
+			Err(_) => warn!("Peer {peer} timed out for {path}"),
+		}
+	}
+	None
+}
+
+#[test]
+fn test_parse_peers() {
+	let peers = parse_peers(Some(" https://redlib.example/ ,http://plain.example,https://other.example:8443,not a url,".to_string()));
+	assert_eq!(peers, vec!["https://redlib.example", "https://other.example:8443"]);
+	assert!(parse_peers(None).is_empty());
+}
+
+#[test]
+fn test_peer_fallback_conditions() {
+	let path = "/api/r/rust/hot.js?limit=25";
+	assert!(fallback_needed(path, Some(StatusCode::INTERNAL_SERVER_ERROR), false, 0));
+	assert!(fallback_needed(path, None, false, 0));
+	assert!(fallback_needed(path, Some(StatusCode::OK), true, 0));
+	assert!(!fallback_needed(path, Some(StatusCode::OK), false, 0));
+	assert!(!fallback_needed(path, Some(StatusCode::NOT_FOUND), false, 0));
+	// Requests from a peer are never passed on again
+	assert!(!fallback_needed(path, Some(StatusCode::BAD_GATEWAY), true, 1));
+	// Nor are cursors only this instance can open
+	assert!(!fallback_needed("/api/r/rust/hot.js?after=sealed", Some(StatusCode::BAD_GATEWAY), true, 0));
+}
+
+#[test]
+fn test_peer_hops() {
+	let mut headers = HeaderMap::new();
+	assert_eq!(hops(&headers), 0);
+	headers.insert(FORWARDED_HEADER, HeaderValue::from_static("1"));
+	assert_eq!(hops(&headers), 1);
+	headers.insert(FORWARDED_HEADER, HeaderValue::from_static("lots"));
+	assert_eq!(hops(&headers), 0);
+}
+
+#[tokio::test]
+async fn test_fetch_from_peers() {
+	let peers = vec!["https://down.example".to_string(), "https://up.example".to_string()];
+	// Stub peers: the first one is failing, the second answers with the hops it was told about
+	let send = |req: Request<Body>| async move {
+		let res = if req.uri().host() == Some("down.example") {
+			Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::empty())
+		} else {
+			Response::builder()
+				.header(header::CONTENT_TYPE, "application/json")
+				.header("Set-Cookie", "session=peer")
+				.body(Body::from(format!("{{\"path\":\"{}\",\"hops\":{}}}", req.uri().path(), hops(req.headers()))))
+		};
+		Ok(res.unwrap())
+	};
+
+	let res = fetch_from(&peers, "/api/r/rust/hot.js", 0, send).await.unwrap();
+	assert_eq!(res.headers()["X-Served-By"], "https://up.example");
+	assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+	assert!(!res.headers().contains_key("Set-Cookie"));
+	assert!(res.extensions().get::<crate::server::Relayed>().is_some());
+	let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+	assert_eq!(body, r#"{"path":"/api/r/rust/hot.js","hops":1}"#);
+
+	// A peer that got the request from another one wouldn't pass it on
+	let mut headers = HeaderMap::new();
+	headers.insert(FORWARDED_HEADER, HeaderValue::from(1));
+	assert!(!fallback_needed("/api/r/rust/hot.js", None, true, hops(&headers)));
+
+	assert!(fetch_from(&peers[..1], "/api/r/rust/hot.js", 0, send).await.is_none());
+}
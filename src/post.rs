@@ -1,25 +1,38 @@
 #![allow(clippy::cmp_owned)]
 
 // CRATES
-use crate::client::json;
+use crate::client::{json, UpstreamError};
 use crate::config::get_setting;
-use crate::json::{json_error, json_response, PostResponse};
+use crate::json::{
+	add_body_text, add_comments_body_text, comment_body_limit, json_error, json_response, truncate_comments, truncate_posts, upstream_error, BatchPostResult,
+	BatchPostsResponse, CommentPollResponse, PostResponse, ResolvedThing, ResolvedThingsResponse, Thing, DEFAULT_BODY_LIMIT,
+};
 
 /// Default maximum comment depth for JSON API
 pub const DEFAULT_COMMENT_DEPTH: usize = 5;
 /// Default maximum number of top-level comments for JSON API
 pub const DEFAULT_COMMENT_LIMIT: usize = 30;
-use crate::server::RequestExt;
+/// Maximum number of posts accepted by the batch endpoint (Reddit's by_id limit)
+pub const MAX_BATCH_POSTS: usize = 100;
+/// Maximum number of fullnames resolved at once (Reddit's api/info limit)
+pub const MAX_INFO_IDS: usize = 100;
+use crate::cursor;
+use crate::server::{RequestContext, RequestExt, REQUEST_CONTEXT};
 use crate::subreddit::{can_access_quarantine, quarantine};
 use crate::utils::{
-	error, format_num, get_filters, nsfw_landing, param, parse_post, rewrite_emotes, setting, template, time, val, Author, Awards, Comment, Flair, FlairPart, Post, Preferences,
+	error, format_num, get_filters, inline_spoilers, instance_url, local_time, nsfw_landing, param, parse_post, replace_comment_emojis, replace_post_emojis, rewrite_emotes,
+	setting, subreddit_emojis, template, time, val, wants_emojis, Author, Awards, Comment, Flair, FlairPart, MoreComments, Post, Preferences, Subreddit,
 };
 use hyper::{Body, Request, Response};
+use url::Url;
 
 use askama::Template;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use cached::proc_macro::cached;
 use regex::Regex;
 use std::collections::{HashMap, HashSet};
 use std::sync::LazyLock;
+use time::OffsetDateTime;
 
 // STRUCTS
 #[derive(Template)]
@@ -37,6 +50,60 @@ struct PostTemplate {
 
 static COMMENT_SEARCH_CAPTURE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\?q=(.*)&type=comment").unwrap());
 
+/// Matches a bare post id or a `t3_` fullname.
+static POST_ID_MATCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(?:t3_)?([0-9a-z]{1,13})$").unwrap());
+static COMMENT_ID_MATCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(?:t1_)?([0-9a-z]{1,13})$").unwrap());
+/// Matches the fullname of a comment, post or subreddit.
+static FULLNAME_MATCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^t[135]_[0-9a-z]{1,13}$").unwrap());
+
+/// Most parent comments Reddit returns as context for a single comment.
+const MAX_COMMENT_CONTEXT: usize = 8;
+
+/// Shortest time clients have to wait between two polls of a thread, and for
+/// how long `poll_thread` holds on to a fetch.
+const MIN_POLL_INTERVAL: u64 = 10;
+
+/// Comments and reply depth fetched for each poll, newest first.
+const POLL_COMMENT_LIMIT: usize = 500;
+const POLL_COMMENT_DEPTH: usize = 10;
+
+/// Threads with at least this many comments are streamed to the browser.
+/// Streamed pages skip response compression, so small threads are better
+/// off rendered in one piece.
+const STREAMING_COMMENT_THRESHOLD: u64 = 500;
+
+/// Where the comment threads go in post.html.
+const COMMENTS_MARKER: &str = "<!-- COMMENTS -->";
+
+/// A single top-level comment thread of a post page, as streamed on its own.
+#[derive(Template)]
+#[template(path = "thread.html")]
+struct ThreadTemplate<'a> {
+	c: &'a Comment,
+	post: &'a Post,
+	single_thread: bool,
+}
+
+/// Render a post page without its comments, split into what comes before
+/// and after the threads.
+fn split_page(page: &PostTemplate) -> (String, String) {
+	let html = page.render().unwrap_or_default();
+	match html.split_once(COMMENTS_MARKER) {
+		Some((head, tail)) => (format!("{head}{COMMENTS_MARKER}"), tail.to_string()),
+		None => (html, String::new()),
+	}
+}
+
+fn render_thread(page: &PostTemplate, comment: &Comment) -> String {
+	ThreadTemplate {
+		c: comment,
+		post: &page.post,
+		single_thread: page.single_thread,
+	}
+	.render()
+	.unwrap_or_default()
+}
+
 pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 	// Build Reddit API path
 	let mut path: String = format!("{}.json?{}&raw_json=1", req.uri().path(), req.uri().query().unwrap_or_default());
@@ -45,7 +112,7 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 	let url = req.uri().to_string();
 
 	// Set sort to sort query parameter
-	let sort = param(&path, "sort").unwrap_or_else(|| {
+	let mut sort = param(&path, "sort").unwrap_or_else(|| {
 		// Grab default comment sort method from Cookies
 		let default_sort = setting(&req, "comment_sort");
 
@@ -70,7 +137,14 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 		// Otherwise, grab the JSON output from the request
 		Ok(response) => {
 			// Parse the JSON into Post and Comment structs
-			let post = parse_post(&response[0]["data"]["children"][0], false).await;
+			let mut post = parse_post(&response[0]["data"]["children"][0], false).await;
+
+			let emojis = if wants_emojis(&url) {
+				subreddit_emojis(post.community.clone()).await.unwrap_or_default()
+			} else {
+				HashMap::new()
+			};
+			replace_post_emojis(&mut post, &emojis, false);
 
 			let req_url = req.uri().to_string();
 			// Return landing page if this post if this Reddit deems this post
@@ -80,6 +154,12 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 				return Ok(nsfw_landing(req, req_url).await.unwrap_or_default());
 			}
 
+			// Without a sort from the user or the instance, Reddit orders the
+			// comments by the subreddit's suggested sort, so reflect that.
+			if sort.is_empty() {
+				sort = post.suggested_sort.clone().unwrap_or_default();
+			}
+
 			let query_body = match COMMENT_SEARCH_CAPTURE.captures(&url) {
 				Some(captures) => captures.get(1).unwrap().as_str().replace("%20", " ").replace('+', " "),
 				None => String::new(),
@@ -89,22 +169,59 @@ pub async fn item(req: Request<Body>) -> Result<Response<Body>, String> {
 			let form = url::form_urlencoded::parse(query_string.as_bytes()).collect::<HashMap<_, _>>();
 			let query = form.get("q").unwrap().clone().to_string();
 
-			let comments = match query.as_str() {
-				"" => parse_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &req),
-				_ => query_comments(&response[1], &post.permalink, &post.author.name, highlighted_comment, &get_filters(&req), &query, &req),
-			};
-
-			// Use the Post and Comment structs to generate a website to show users
-			Ok(template(&PostTemplate {
-				comments,
+			let streamed = post.comments.1.parse::<u64>().unwrap_or_default() >= STREAMING_COMMENT_THRESHOLD;
+			let (permalink, post_author) = (post.permalink.clone(), post.author.name.clone());
+			let mut page = PostTemplate {
+				comments: Vec::new(),
 				post,
 				url_without_query: url.clone().trim_end_matches(&format!("?q={query}&type=comment")).to_string(),
 				sort,
 				prefs: Preferences::new(&req),
 				single_thread,
 				url: req_url,
-				comment_query: query,
-			}))
+				comment_query: query.clone(),
+			};
+
+			let highlighted_comment = highlighted_comment.clone();
+			let parse = move || {
+				let mut comments = match query.as_str() {
+					"" => parse_comments(&response[1], &permalink, &post_author, &highlighted_comment, &get_filters(&req), &req),
+					_ => query_comments(&response[1], &permalink, &post_author, &highlighted_comment, &get_filters(&req), &query, &req),
+				};
+				for comment in &mut comments {
+					replace_comment_emojis(comment, &emojis, false);
+				}
+				comments
+			};
+
+			// Send the post straight away on huge threads rather than making
+			// the browser wait for every comment to be parsed and rendered
+			if streamed {
+				let (mut sender, body) = Body::channel();
+				let context = RequestContext::inherited();
+				tokio::spawn(async move {
+					let (head, tail) = split_page(&page);
+					if sender.send_data(head.into()).await.is_err() {
+						return;
+					}
+					// Parsing thousands of comments would hold up a runtime worker
+					let Ok(comments) = tokio::task::spawn_blocking(move || REQUEST_CONTEXT.sync_scope(context, parse)).await else {
+						return;
+					};
+					for comment in comments {
+						if sender.send_data(render_thread(&page, &comment).into()).await.is_err() {
+							return;
+						}
+					}
+					let _ = sender.send_data(tail.into()).await;
+				});
+
+				return Ok(Response::builder().status(200).header("content-type", "text/html").body(body).unwrap_or_default());
+			}
+
+			// Use the Post and Comment structs to generate a website to show users
+			page.comments = parse();
+			Ok(template(&page))
 		}
 		// If the Reddit API returns an error, exit and send error page to user
 		Err(msg) => {
@@ -126,26 +243,53 @@ pub async fn item_json(req: Request<Body>) -> Result<Response<Body>, String> {
 	let max_depth: usize = param(&format!("?{query}"), "depth")
 		.and_then(|s| s.parse().ok())
 		.unwrap_or(DEFAULT_COMMENT_DEPTH);
-	let limit: usize = param(&format!("?{query}"), "limit")
+	// ?comment_limit= is the clearer name for the comments Reddit sends before "load more" stubs
+	let limit: usize = param(&format!("?{query}"), "comment_limit")
+		.or_else(|| param(&format!("?{query}"), "limit"))
 		.and_then(|s| s.parse().ok())
 		.unwrap_or(DEFAULT_COMMENT_LIMIT);
 
+	// ?comment_sort=old returns comments in a stable chronological order
+	let chronological = param(&format!("?{query}"), "comment_sort").is_some_and(|s| s == "old");
+
+	// ?by_author= (or ?op_only=true) keeps only that user's comments, ?with_parents=true adds what they replied to
+	let by_author = param(&format!("?{query}"), "by_author").filter(|author| !author.is_empty());
+	let op_only = param(&format!("?{query}"), "op_only").is_some_and(|s| s == "true");
+	let with_parents = param(&format!("?{query}"), "with_parents").is_some_and(|s| s == "true");
+
+	// ?top_threads=N keeps only the N best-scored top-level threads expanded
+	let top_threads: Option<usize> = param(&format!("?{query}"), "top_threads").and_then(|s| s.parse().ok());
+
+	// ?comment_score_threshold=N collapses comments scored below N
+	let score_threshold: Option<i64> = param(&format!("?{query}"), "comment_score_threshold").and_then(|s| s.parse().ok());
+
+	// ?max_comments= caps the thread, ?comments_after= picks up where a capped one stopped
+	let max_comments: usize = param(&format!("?{query}"), "max_comments").and_then(|s| s.parse().ok()).unwrap_or(usize::MAX);
+	let comments_after = match param(&format!("?{query}"), "comments_after").filter(|after| !after.is_empty()) {
+		Some(after) => match COMMENT_ID_MATCH.captures(&after.to_lowercase()) {
+			Some(caps) => Some(caps[1].to_string()),
+			None => return Ok(json_error("Invalid comments_after token".to_string(), 400)),
+		},
+		None => None,
+	};
+
+	// Pasted Reddit links focus on a comment with an extra path segment, the same as ?comment=
+	let focus = focused_comment(req.param("comment_id"), &format!("?{query}"));
+	let max_depth = max_depth + focus.as_ref().map_or(0, |(_, context)| *context);
+
 	// Build path with depth and limit for Reddit API
-	let path: String = format!(
-		"{}.json?{}&raw_json=1&depth={}&limit={}",
+	let path = format!(
+		"{}.json?{}",
 		req.uri().path().trim_end_matches(".js"),
-		query,
-		max_depth,
-		limit
+		thread_query(query, focus.as_ref(), max_depth, limit, chronological)
 	);
+	let highlighted_comment = focus.map(|(id, _)| id).unwrap_or_default();
 	let sub = req.param("sub").unwrap_or_default();
 	let quarantined = can_access_quarantine(&req, &sub);
 
-	let highlighted_comment = &req.param("comment_id").unwrap_or_default();
-
 	match json(path, quarantined).await {
 		Ok(response) => {
-			let post = parse_post(&response[0]["data"]["children"][0], true).await;
+			let mut post = parse_post(&response[0]["data"]["children"][0], true).await;
 
 			// Check NSFW gating (server-side SFW_ONLY only)
 			if post.nsfw && crate::utils::sfw_only() {
@@ -154,20 +298,516 @@ pub async fn item_json(req: Request<Body>) -> Result<Response<Body>, String> {
 
 			let filters = get_filters(&req);
 			// Use depth-limited parsing for JSON API (with markdown bodies)
-			let comments = parse_comments_with_depth(&response[1], &post.permalink, &post.author.name, highlighted_comment, &filters, &req, 0, max_depth, true);
+			let mut comments = parse_comments_with_depth(&response[1], &post.permalink, &post.author.name, &highlighted_comment, &filters, &req, 0, max_depth, true);
+			if chronological {
+				sort_comments_chronologically(&mut comments);
+			}
+			if let Some(top) = top_threads {
+				comments = trim_to_top_threads(comments, top);
+			}
+			if let Some(threshold) = score_threshold {
+				collapse_below_score(&mut comments, threshold);
+			}
+			if let Some(author) = by_author.or_else(|| op_only.then(|| post.author.name.clone())) {
+				comments = comments_by_author(comments, &author, with_parents);
+			}
+			let Some((mut comments, next)) = page_comments(comments, comments_after.as_deref(), max_comments) else {
+				return Ok(json_error("The comment comments_after names is no longer in the thread".to_string(), 404));
+			};
+			add_body_text(std::slice::from_mut(&mut post), &format!("?{query}"));
+			add_comments_body_text(&mut comments, &format!("?{query}"));
+			truncate_comments(&mut comments, comment_body_limit(&format!("?{query}")));
+			if wants_emojis(&format!("?{query}")) {
+				let emojis = subreddit_emojis(post.community.clone()).await.unwrap_or_default();
+				replace_post_emojis(&mut post, &emojis, true);
+				for comment in &mut comments {
+					replace_comment_emojis(comment, &emojis, true);
+				}
+			}
 
-			Ok(json_response(PostResponse { post, comments }))
+			Ok(json_response(PostResponse {
+				post,
+				comments,
+				more_comments: next,
+				focused_comment: Some(highlighted_comment).filter(|id| !id.is_empty()),
+			}))
 		}
 		Err(msg) => {
 			if msg == "quarantined" || msg == "gated" {
 				Ok(json_error(format!("Post is {msg}"), 403))
 			} else {
-				Ok(json_error(msg, 500))
+				Ok(upstream_error(msg, 500))
+			}
+		}
+	}
+}
+
+/// Comment a post request focuses on, taken from the extra path segment of
+/// `/comments/<id>/<slug>/<comment>` links or from `?comment=`, along with
+/// how many of its parents to show (`?context=`, 3 by default).
+fn focused_comment(path_id: Option<String>, query: &str) -> Option<(String, usize)> {
+	let input = path_id.filter(|id| !id.is_empty()).or_else(|| param(query, "comment"))?.to_lowercase();
+	let id = COMMENT_ID_MATCH.captures(&input)?[1].to_string();
+	let context = param(query, "context").and_then(|s| s.parse().ok()).unwrap_or(3).min(MAX_COMMENT_CONTEXT);
+	Some((id, context))
+}
+
+/// Query asking Reddit for the thread of a post JSON request: the client's
+/// own parameters, less those set here, and the comment it focuses on.
+fn thread_query(query: &str, focus: Option<&(String, usize)>, depth: usize, limit: usize, chronological: bool) -> String {
+	// Parameters set below aren't passed on twice
+	let forwarded = query
+		.split('&')
+		.filter(|pair| !["comment", "context", "limit", "comment_limit"].contains(&pair.split('=').next().unwrap_or_default()));
+	let focus_query = focus.map_or_else(String::new, |(id, context)| format!("&comment={id}&context={context}"));
+
+	format!(
+		"{}&raw_json=1&depth={depth}&limit={limit}{focus_query}{}",
+		forwarded.collect::<Vec<_>>().join("&"),
+		if chronological { "&sort=old" } else { "" }
+	)
+}
+
+/// JSON API endpoint resolving a comment id or `t1_` fullname to its post,
+/// returning the thread around that comment: up to `?context=` parent
+/// comments (3 by default) and its replies. `?comment_score_threshold=`
+/// collapses comments the way it does for whole posts.
+pub async fn comment_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let query = format!("?{}", req.uri().query().unwrap_or_default());
+	let input = req.param("id").unwrap_or_default().to_lowercase();
+	let Some(id) = COMMENT_ID_MATCH.captures(&input).map(|caps| caps[1].to_string()) else {
+		return Ok(json_error(format!("{input} is not a comment id"), 400));
+	};
+	let context: usize = param(&query, "context").and_then(|s| s.parse().ok()).unwrap_or(3).min(MAX_COMMENT_CONTEXT);
+	let score_threshold: Option<i64> = param(&query, "comment_score_threshold").and_then(|s| s.parse().ok());
+
+	// Comment ids alone don't say which post they belong to
+	let info = match json(format!("/api/info.json?id=t1_{id}&raw_json=1"), false).await {
+		Ok(info) => info,
+		Err(err) => return Ok(upstream_error(err, 500)),
+	};
+	let (post_id, sub) = match locate_comment(&info, &id) {
+		Ok(location) => location,
+		Err((msg, status)) => return Ok(json_error(msg, status)),
+	};
+
+	let path = format!("/comments/{post_id}/_/{id}.json?context={context}&depth={}&raw_json=1", context + DEFAULT_COMMENT_DEPTH);
+	match json(path, can_access_quarantine(&req, &sub)).await {
+		Ok(response) => {
+			let mut post = parse_post(&response[0]["data"]["children"][0], true).await;
+			if post.nsfw && crate::utils::sfw_only() {
+				return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
+			}
+
+			let mut comments = parse_comments_with_depth(
+				&response[1],
+				&post.permalink,
+				&post.author.name,
+				&id,
+				&get_filters(&req),
+				&req,
+				0,
+				context + DEFAULT_COMMENT_DEPTH,
+				true,
+			);
+			if let Some(threshold) = score_threshold {
+				collapse_below_score(&mut comments, threshold);
 			}
+			add_body_text(std::slice::from_mut(&mut post), &query);
+			add_comments_body_text(&mut comments, &query);
+			truncate_comments(&mut comments, comment_body_limit(&query));
+
+			Ok(json_response(PostResponse {
+				post,
+				comments,
+				more_comments: None,
+				focused_comment: Some(id),
+			}))
+		}
+		Err(err) => match &*err {
+			msg @ ("quarantined" | "gated" | "private") => Ok(json_error(format!("The post of comment {id} is in a {msg} community"), 403)),
+			_ => Ok(upstream_error(err, 500)),
+		},
+	}
+}
+
+/// JSON API endpoint for long-polling a thread. Returns the comments posted
+/// since the `?since=` token handed out by the previous poll, or every loaded
+/// comment when there is none, along with the token for the next poll.
+pub async fn poll_comments_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let query = format!("?{}", req.uri().query().unwrap_or_default());
+	let input = req.param("id").unwrap_or_default().to_lowercase();
+	let Some(id) = POST_ID_MATCH.captures(&input).map(|caps| caps[1].to_string()) else {
+		return Ok(json_error(format!("{input} is not a post id"), 400));
+	};
+
+	let now = OffsetDateTime::now_utc().unix_timestamp().max(0) as u64;
+	let since = match param(&query, "since").filter(|token| !token.is_empty()) {
+		Some(token) => match PollPosition::open(&token) {
+			Some(position) => position,
+			None => return Ok(json_error("Invalid since token".to_string(), 400)),
+		},
+		None => PollPosition::default(),
+	};
+	let wait = (since.issued + MIN_POLL_INTERVAL).saturating_sub(now);
+	if wait > 0 {
+		let mut res = json_error(format!("Polled too soon, try again in {wait} seconds"), 429);
+		res.headers_mut().insert("Retry-After", wait.into());
+		return Ok(res);
+	}
+
+	match poll_thread(id).await {
+		Ok(response) => {
+			let post = parse_post(&response[0]["data"]["children"][0], true).await;
+			if post.nsfw && crate::utils::sfw_only() {
+				return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
+			}
+
+			let thread = parse_comments_with_depth(&response[1], &post.permalink, &post.author.name, "", &get_filters(&req), &req, 0, POLL_COMMENT_DEPTH, true);
+			let (mut comments, mut next) = comments_since(thread, &since);
+			next.issued = now;
+			add_comments_body_text(&mut comments, &query);
+
+			Ok(json_response(CommentPollResponse { comments, since: next.seal() }))
+		}
+		Err(err) => match &*err {
+			msg @ ("quarantined" | "gated" | "private") => Ok(json_error(format!("Post is {msg}"), 403)),
+			_ => Ok(upstream_error(err, 500)),
+		},
+	}
+}
+
+/// Newest comments of a thread, fetched at most once per `MIN_POLL_INTERVAL`
+/// whatever the tokens of the clients polling it say.
+#[cached(size = 500, time = 10, result = true)]
+async fn poll_thread(id: String) -> Result<serde_json::Value, UpstreamError> {
+	let path = format!("/comments/{id}.json?sort=new&limit={POLL_COMMENT_LIMIT}&depth={POLL_COMMENT_DEPTH}&raw_json=1");
+	json(path, false).await
+}
+
+/// How far a client has read a thread: the creation time of the newest
+/// comment it was sent, the ids of the comments made that same second, and
+/// when it last polled.
+#[derive(Debug, Default, PartialEq)]
+struct PollPosition {
+	created: u64,
+	seen: Vec<String>,
+	issued: u64,
+}
+
+impl PollPosition {
+	fn seal(&self) -> String {
+		cursor::seal(&URL_SAFE_NO_PAD.encode(format!("{}:{}:{}", self.created, self.issued, self.seen.join(","))))
+	}
+
+	fn open(token: &str) -> Option<Self> {
+		let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor::open(token)?).ok()?).ok()?;
+		let mut parts = decoded.splitn(3, ':');
+		let created = parts.next()?.parse().ok()?;
+		let issued = parts.next()?.parse().ok()?;
+		let seen = parts.next()?.split(',').filter(|id| !id.is_empty()).map(ToString::to_string).collect();
+		Some(Self { created, seen, issued })
+	}
+}
+
+/// Move every comment of a thread into `into` in reading order, without
+/// their replies and leaving out "load more" stubs.
+fn flatten_comments(comments: Vec<Comment>, into: &mut Vec<Comment>) {
+	for mut comment in comments {
+		let replies = std::mem::take(&mut comment.replies);
+		if comment.kind != "more" {
+			into.push(comment);
 		}
+		flatten_comments(replies, into);
 	}
 }
 
+/// Pick the comments of a thread written by `author`, flattened in reading
+/// order. With `with_parents`, each one is preceded by the comment it
+/// replies to, unless that was already included.
+fn comments_by_author(thread: Vec<Comment>, author: &str, with_parents: bool) -> Vec<Comment> {
+	let mut all = Vec::new();
+	flatten_comments(thread, &mut all);
+
+	let wanted: HashSet<String> = all
+		.iter()
+		.filter(|comment| comment.author.name.eq_ignore_ascii_case(author))
+		.flat_map(|comment| {
+			let parent = (with_parents && comment.parent_kind == "t1").then(|| comment.parent_id.clone());
+			std::iter::once(comment.id.clone()).chain(parent)
+		})
+		.collect();
+	all.retain(|comment| wanted.contains(&comment.id));
+	all
+}
+
+/// Collapse the comments of a tree scored below `threshold`. They stay in
+/// place so their replies, which are judged on their own score, keep a
+/// parent. Hidden scores and "load more" stubs are left alone.
+fn collapse_below_score(comments: &mut [Comment], threshold: i64) {
+	for comment in comments.iter_mut() {
+		if comment.kind != "more" && comment.score.1.parse::<i64>().is_ok_and(|score| score < threshold) {
+			comment.collapsed = true;
+		}
+		collapse_below_score(&mut comment.replies, threshold);
+	}
+}
+
+/// Keep the `top` highest-scored top-level comments of a thread with all
+/// their replies, in the order they came in. Every other top-level comment
+/// becomes a "load more" stub in its place, counting the comments it stood
+/// for; its id still gets the thread from `/api/comment/:id`.
+fn trim_to_top_threads(mut thread: Vec<Comment>, top: usize) -> Vec<Comment> {
+	// Hidden scores don't parse and rank last
+	let mut ranked: Vec<(i64, usize)> = thread
+		.iter()
+		.enumerate()
+		.filter(|(_, comment)| comment.kind != "more")
+		.map(|(index, comment)| (comment.score.1.parse().unwrap_or(i64::MIN), index))
+		.collect();
+	ranked.sort_by_key(|&(score, index)| (std::cmp::Reverse(score), index));
+	let kept: HashSet<usize> = ranked.into_iter().take(top).map(|(_, index)| index).collect();
+
+	for (index, comment) in thread.iter_mut().enumerate() {
+		if comment.kind == "more" || kept.contains(&index) {
+			continue;
+		}
+		comment.more_count = thread_size(comment);
+		comment.kind = "more".to_string();
+		comment.load_more = Some(MoreComments {
+			count: comment.more_count,
+			children: vec![comment.id.clone()],
+		});
+		comment.replies.clear();
+		comment.body.clear();
+		comment.author.name.clear();
+	}
+	thread
+}
+
+/// Comments in a thread, counting those behind its "load more" stubs.
+fn thread_size(comment: &Comment) -> i64 {
+	if comment.kind == "more" {
+		return comment.more_count;
+	}
+	1 + comment.replies.iter().map(thread_size).sum::<i64>()
+}
+
+/// Pick the comments of a thread that are newer than `since`, flattened and
+/// oldest first, along with the position after them.
+fn comments_since(thread: Vec<Comment>, since: &PollPosition) -> (Vec<Comment>, PollPosition) {
+	let mut comments = Vec::new();
+	flatten_comments(thread, &mut comments);
+
+	comments.retain(|comment| comment.created_ts > since.created || (comment.created_ts == since.created && !since.seen.contains(&comment.id)));
+	comments.sort_by(|a, b| (a.created_ts, &a.id).cmp(&(b.created_ts, &b.id)));
+
+	let mut next = PollPosition {
+		created: since.created,
+		seen: since.seen.clone(),
+		issued: since.issued,
+	};
+	if let Some(newest) = comments.last().map(|comment| comment.created_ts) {
+		if newest > next.created {
+			next.created = newest;
+			next.seen.clear();
+		}
+		let newest_ids = comments.iter().filter(|comment| comment.created_ts == newest).map(|comment| comment.id.clone());
+		next.seen.extend(newest_ids);
+	}
+	(comments, next)
+}
+
+/// Find the post id and subreddit of comment `id` in an `api/info` response.
+fn locate_comment(info: &serde_json::Value, id: &str) -> Result<(String, String), (String, u16)> {
+	let comment = &info["data"]["children"][0]["data"];
+	let Some(post_id) = comment["link_id"].as_str().and_then(|link| link.strip_prefix("t3_")) else {
+		return Err((format!("Comment {id} was not found"), 404));
+	};
+	if comment["author"] == "[deleted]" && (comment["body"] == "[deleted]" || comment["body"] == "[removed]") {
+		return Err((format!("Comment {id} has been deleted"), 410));
+	}
+
+	Ok((post_id.to_string(), comment["subreddit"].as_str().unwrap_or_default().to_string()))
+}
+
+/// JSON API endpoint fetching several posts at once. `?ids=` takes a
+/// comma-separated list of fullnames, bare ids, permalinks or short links.
+pub async fn batch_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let query = format!("?{}", req.uri().query().unwrap_or_default());
+	let inputs: Vec<String> = param(&query, "ids")
+		.unwrap_or_default()
+		.split(',')
+		.map(str::trim)
+		.filter(|input| !input.is_empty())
+		.map(ToString::to_string)
+		.collect();
+
+	if inputs.is_empty() {
+		return Ok(json_error("At least one post is required in ?ids=".to_string(), 400));
+	}
+	if inputs.len() > MAX_BATCH_POSTS {
+		return Ok(json_error(format!("At most {MAX_BATCH_POSTS} posts can be fetched at once"), 400));
+	}
+
+	let ids: Vec<Option<String>> = inputs.iter().map(|input| extract_post_id(input)).collect();
+	let mut fullnames: Vec<String> = ids.iter().flatten().map(|id| format!("t3_{id}")).collect();
+	fullnames.sort_unstable();
+	fullnames.dedup();
+
+	let mut posts: HashMap<String, Post> = HashMap::new();
+	if !fullnames.is_empty() {
+		match Post::fetch(&format!("/by_id/{}.json?raw_json=1", fullnames.join(",")), false, true).await {
+			Ok((mut fetched, _)) => {
+				add_body_text(&mut fetched, &query);
+				truncate_posts(&mut fetched, param(&query, "body_limit").and_then(|s| s.parse().ok()).or(Some(DEFAULT_BODY_LIMIT)));
+				posts.extend(fetched.into_iter().map(|post| (post.id.clone(), post)));
+			}
+			Err(err) => return Ok(upstream_error(err, 500)),
+		}
+	}
+
+	Ok(json_response(BatchPostsResponse {
+		results: batch_results(inputs, ids, &posts),
+	}))
+}
+
+/// Match each requested post with the fetched post its id names. An id given
+/// more than once gets the post each time.
+fn batch_results(inputs: Vec<String>, ids: Vec<Option<String>>, posts: &HashMap<String, Post>) -> Vec<BatchPostResult> {
+	inputs
+		.into_iter()
+		.zip(ids)
+		.map(|(input, id)| {
+			let (post, error) = match id {
+				None => (None, Some("Not a post id, fullname or permalink".to_string())),
+				Some(id) => match posts.get(&id).cloned() {
+					Some(post) if post.nsfw && crate::utils::sfw_only() => (None, Some("NSFW content is disabled on this instance".to_string())),
+					Some(post) => (Some(post), None),
+					None => (None, Some(format!("Post {id} was not found"))),
+				},
+			};
+			BatchPostResult { input, post, error }
+		})
+		.collect()
+}
+
+/// JSON API endpoint resolving a comma-separated list of `t1_`, `t3_` and
+/// `t5_` fullnames in `?ids=` to the comments, posts and subreddits they
+/// stand for, in the order they were given.
+pub async fn things_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let query = format!("?{}", req.uri().query().unwrap_or_default());
+	let inputs: Vec<String> = param(&query, "ids")
+		.unwrap_or_default()
+		.split(',')
+		.map(str::trim)
+		.filter(|input| !input.is_empty())
+		.map(ToString::to_string)
+		.collect();
+
+	if inputs.is_empty() {
+		return Ok(json_error("At least one fullname is required in ?ids=".to_string(), 400));
+	}
+	if inputs.len() > MAX_INFO_IDS {
+		return Ok(json_error(format!("At most {MAX_INFO_IDS} fullnames can be resolved at once"), 400));
+	}
+
+	let mut fullnames: Vec<String> = inputs.iter().map(|input| input.to_lowercase()).filter(|input| FULLNAME_MATCH.is_match(input)).collect();
+	fullnames.sort_unstable();
+	fullnames.dedup();
+
+	let info = if fullnames.is_empty() {
+		serde_json::Value::Null
+	} else {
+		match json(format!("/api/info.json?id={}&raw_json=1", fullnames.join(",")), false).await {
+			Ok(info) => info,
+			Err(err) => return Ok(upstream_error(err, 500)),
+		}
+	};
+
+	let mut results = resolve_things(inputs, &info, &req).await;
+	for thing in results.iter_mut().filter_map(|result| result.thing.as_mut()) {
+		match thing {
+			Thing::Post(post) => add_body_text(std::slice::from_mut(post), &query),
+			Thing::Comment(comment) => add_comments_body_text(std::slice::from_mut(comment), &query),
+			Thing::Subreddit(_) => {}
+		}
+	}
+	Ok(json_response(ResolvedThingsResponse { results }))
+}
+
+/// Match each requested fullname with the thing of an api/info response
+/// it names.
+async fn resolve_things(inputs: Vec<String>, info: &serde_json::Value, req: &Request<Body>) -> Vec<ResolvedThing> {
+	let mut found: HashMap<String, &serde_json::Value> = HashMap::new();
+	for child in info["data"]["children"].as_array().into_iter().flatten() {
+		if let (Some(kind), Some(id)) = (child["kind"].as_str(), child["data"]["id"].as_str()) {
+			found.insert(format!("{kind}_{id}"), child);
+		}
+	}
+
+	let filters = get_filters(req);
+	let mut results = Vec::new();
+	for input in inputs {
+		let fullname = input.to_lowercase();
+		let (thing, error) = if !FULLNAME_MATCH.is_match(&fullname) {
+			(None, Some("Not a t1_, t3_ or t5_ fullname".to_string()))
+		} else {
+			match (fullname.get(..2), found.get(&fullname)) {
+				(_, None) => (None, Some(format!("{fullname} was not found"))),
+				(Some("t3"), Some(child)) => match parse_post(child, true).await {
+					post if post.nsfw && crate::utils::sfw_only() => (None, Some("NSFW content is disabled on this instance".to_string())),
+					post if filters.contains(&post.community) || filters.contains(&["u_", &post.author.name].concat()) => {
+						(None, Some(format!("{fullname} is filtered out by your settings")))
+					}
+					post => (Some(Thing::Post(Box::new(post))), None),
+				},
+				(Some("t1"), Some(child)) => {
+					let data = &child["data"];
+					// Comment permalinks are the post's with the comment id appended
+					let permalink = val(child, "permalink");
+					let post_link = permalink.trim_end_matches('/').rsplit_once('/').map_or(String::new(), |(post, _)| format!("{post}/"));
+					let comment = build_comment(child, data, Vec::new(), &post_link, "", "", &filters, req, true);
+					(Some(Thing::Comment(Box::new(comment))), None)
+				}
+				(_, Some(child)) => match Subreddit::parse(&child["data"]) {
+					sub if sub.nsfw && crate::utils::sfw_only() => (None, Some("NSFW content is disabled on this instance".to_string())),
+					sub => (Some(Thing::Subreddit(Box::new(sub))), None),
+				},
+			}
+		};
+		results.push(ResolvedThing { input, thing, error });
+	}
+	results
+}
+
+/// Normalize a `t3_` fullname, bare id, permalink or redd.it short link to
+/// the post's id.
+pub fn extract_post_id(input: &str) -> Option<String> {
+	let input = input.trim();
+	if let Some(caps) = POST_ID_MATCH.captures(&input.to_lowercase()) {
+		return Some(caps[1].to_string());
+	}
+
+	let url = if input.starts_with("http://") || input.starts_with("https://") {
+		Url::parse(input).ok()?
+	} else if input.starts_with('/') {
+		Url::parse(&format!("https://reddit.com{input}")).ok()?
+	} else {
+		return None;
+	};
+
+	let mut segments = url.path_segments()?.filter(|segment| !segment.is_empty());
+	let id = if url.host_str() == Some("redd.it") {
+		segments.next()?
+	} else {
+		segments.find(|segment| *segment == "comments")?;
+		segments.next()?
+	};
+
+	POST_ID_MATCH.captures(&id.to_lowercase()).map(|caps| caps[1].to_string())
+}
+
 // COMMENTS
 
 fn parse_comments(json: &serde_json::Value, post_link: &str, post_author: &str, highlighted_comment: &str, filters: &HashSet<String>, req: &Request<Body>) -> Vec<Comment> {
@@ -221,6 +861,65 @@ fn parse_comments_with_depth(
 		.collect()
 }
 
+/// Sort comments and their replies oldest first, breaking ties by id, so the
+/// same thread always comes back in the same order. "Load more" stubs have no
+/// timestamp and are kept at the end of their level.
+fn sort_comments_chronologically(comments: &mut [Comment]) {
+	comments.sort_by(|a, b| (a.kind == "more", a.created_ts, &a.id).cmp(&(b.kind == "more", b.created_ts, &b.id)));
+	for comment in comments.iter_mut() {
+		sort_comments_chronologically(&mut comment.replies);
+	}
+}
+
+/// Where comment `id` is in the thread, as its index at each level of
+/// replies.
+fn comment_position(comments: &[Comment], id: &str) -> Option<Vec<usize>> {
+	comments.iter().enumerate().find_map(|(index, comment)| {
+		let mut position = if comment.id == id { Vec::new() } else { comment_position(&comment.replies, id)? };
+		position.insert(0, index);
+		Some(position)
+	})
+}
+
+/// Keep at most `max` comments in reading order, starting at comment
+/// `after`, and return the id of the first comment left out, if any. Replies
+/// to comments that came before `after` are moved up to the top level, where
+/// their `parent_id` tells where they belong. `None` when `after` is no
+/// longer in the thread.
+fn page_comments(comments: Vec<Comment>, after: Option<&str>, max: usize) -> Option<(Vec<Comment>, Option<String>)> {
+	fn take(comments: Vec<Comment>, position: &mut Vec<usize>, start: &[usize], budget: &mut usize, next: &mut Option<String>) -> Vec<Comment> {
+		let mut kept = Vec::new();
+		for (index, mut comment) in comments.into_iter().enumerate() {
+			if next.is_some() {
+				break;
+			}
+			position.push(index);
+			if start.len() > position.len() && start.starts_with(position) {
+				// Sent already, but some of its replies were not
+				kept.extend(take(std::mem::take(&mut comment.replies), position, start, budget, next));
+			} else if position.as_slice() >= start {
+				if *budget == 0 {
+					*next = Some(comment.id);
+				} else {
+					*budget -= 1;
+					comment.replies = take(std::mem::take(&mut comment.replies), position, start, budget, next);
+					kept.push(comment);
+				}
+			}
+			position.pop();
+		}
+		kept
+	}
+
+	let start = match after {
+		Some(id) => comment_position(&comments, id)?,
+		None => Vec::new(),
+	};
+	let mut next = None;
+	let kept = take(comments, &mut Vec::new(), &start, &mut { max }, &mut next);
+	Some((kept, next))
+}
+
 fn query_comments(
 	json: &serde_json::Value,
 	post_link: &str,
@@ -293,6 +992,12 @@ fn build_comment(
 	// shows how many more (sub-)comments exist in the respective nesting level.
 	// Note that in certain (seemingly random) cases, the count is simply wrong.
 	let more_count = data["count"].as_i64().unwrap_or_default();
+	let load_more = (kind == "more").then(|| MoreComments {
+		count: more_count,
+		children: data["children"]
+			.as_array()
+			.map_or_else(Vec::new, |ids| ids.iter().filter_map(|id| id.as_str()).map(ToString::to_string).collect()),
+	});
 
 	let awards: Awards = Awards::parse(&data["all_awardings"]);
 
@@ -323,31 +1028,494 @@ fn build_comment(
 	// collapse stickied moderator comments.
 	let is_moderator_comment = data["distinguished"].as_str().unwrap_or_default() == "moderator";
 	let is_stickied = data["stickied"].as_bool().unwrap_or_default();
-	let collapsed = (is_moderator_comment && is_stickied) || is_filtered;
+	let collapsed = (is_moderator_comment && is_stickied) || is_filtered || data["collapsed"].as_bool().unwrap_or_default();
+	let collapsed_reason = ["collapsed_reason", "collapsed_reason_code"]
+		.iter()
+		.find_map(|key| data[*key].as_str().filter(|reason| !reason.is_empty()))
+		.map(ToString::to_string);
+	let score_hidden = data["score_hidden"].as_bool().unwrap_or_default();
+	let distinguished = data["distinguished"].as_str().filter(|role| !role.is_empty()).map(ToString::to_string);
+
+	let instance_permalink = instance_url(&format!("{post_link}{id}/"));
+	let spoilers = inline_spoilers(&body);
 
 	Comment {
 		id,
 		kind,
 		parent_id: parent_info[1].to_string(),
 		parent_kind: parent_info[0].to_string(),
+		instance_permalink,
 		post_link: post_link.to_string(),
 		post_author: post_author.to_string(),
 		body,
+		body_truncated: None,
+		body_text: None,
 		author,
-		score: if data["score_hidden"].as_bool().unwrap_or_default() {
+		score: if score_hidden {
 			("\u{2022}".to_string(), "Hidden".to_string())
 		} else {
 			format_num(score)
 		},
 		rel_time,
 		created,
+		created_local: local_time(unix_time.round() as u64),
+		created_ts: unix_time.round() as u64,
 		edited,
 		replies,
 		highlighted,
 		awards,
 		collapsed,
+		collapsed_reason,
+		score_hidden,
+		is_submitter: data["is_submitter"].as_bool().unwrap_or_default(),
+		distinguished,
+		author_premium: data["author_premium"].as_bool().unwrap_or_default(),
 		is_filtered,
+		spoilers,
 		more_count,
+		load_more,
 		prefs: Preferences::new(req),
 	}
 }
+
+#[test]
+fn test_chronological_comment_order() {
+	let req = Request::new(Body::empty());
+	let listing = |order: &[(&str, u64)]| {
+		let children: Vec<serde_json::Value> = order
+			.iter()
+			.map(|(id, created)| {
+				serde_json::json!({
+					"kind": if *id == "more" { "more" } else { "t1" },
+					"data": { "id": id, "parent_id": "t3_post", "created_utc": created, "body": id }
+				})
+			})
+			.collect();
+		serde_json::json!({ "data": { "children": children } })
+	};
+	let sorted_ids = |json: &serde_json::Value| {
+		let mut comments = parse_comments_with_depth(json, "/r/test/comments/post/", "op", "", &HashSet::new(), &req, 0, 1, true);
+		sort_comments_chronologically(&mut comments);
+		comments.into_iter().map(|c| c.id).collect::<Vec<_>>()
+	};
+
+	let a = listing(&[("more", 0), ("c", 20), ("b", 10), ("a", 10)]);
+	let b = listing(&[("a", 10), ("c", 20), ("more", 0), ("b", 10)]);
+	assert_eq!(sorted_ids(&a), vec!["a", "b", "c", "more"]);
+	assert_eq!(sorted_ids(&a), sorted_ids(&b));
+}
+
+#[test]
+fn test_comment_collapse_metadata() {
+	let req = Request::new(Body::empty());
+	let listing = serde_json::json!({ "data": { "children": [
+		{ "kind": "t1", "data": {
+			"id": "automod", "parent_id": "t3_post", "author": "AutoModerator", "body": "Please read the rules",
+			"collapsed": true, "collapsed_reason_code": "LOW_SCORE", "score_hidden": true, "score": 1
+		} },
+		{ "kind": "t1", "data": { "id": "regular", "parent_id": "t3_post", "author": "someone", "body": "hi", "collapsed": false, "score": 5 } }
+	] } });
+	let comments = parse_comments_with_depth(&listing, "/r/test/comments/post/", "op", "", &HashSet::new(), &req, 0, 1, true);
+
+	assert!(comments[0].collapsed);
+	assert_eq!(comments[0].collapsed_reason.as_deref(), Some("LOW_SCORE"));
+	assert!(comments[0].score_hidden);
+	assert_eq!(comments[0].score.1, "Hidden");
+
+	assert!(!comments[1].collapsed);
+	assert_eq!(comments[1].collapsed_reason, None);
+	assert!(!comments[1].score_hidden);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_author_flags() {
+	let req = Request::new(Body::empty());
+	let listing = serde_json::json!({ "data": { "children": [
+		{ "kind": "t1", "data": { "id": "mod", "parent_id": "t3_post", "author": "a_mod", "body": "Locked", "distinguished": "moderator", "score": 1 } },
+		{ "kind": "t1", "data": {
+			"id": "op", "parent_id": "t3_post", "author": "op", "body": "Thanks", "is_submitter": true, "author_premium": true, "distinguished": null, "score": 3
+		} }
+	] } });
+	let comments = parse_comments_with_depth(&listing, "/r/test/comments/post/", "op", "", &HashSet::new(), &req, 0, 1, true);
+
+	assert_eq!(comments[0].distinguished.as_deref(), Some("moderator"));
+	assert!(!comments[0].is_submitter && !comments[0].author_premium);
+	assert_eq!(comments[1].distinguished, None);
+	assert!(comments[1].is_submitter && comments[1].author_premium);
+
+	let post = serde_json::json!({ "kind": "t3", "data": { "id": "post", "title": "Rules", "is_self": true, "distinguished": "admin" } });
+	assert_eq!(parse_post(&post, true).await.distinguished.as_deref(), Some("admin"));
+}
+
+#[test]
+fn test_comments_by_author() {
+	let req = Request::new(Body::empty());
+	let comment = |id: &str, author: &str, replies: Vec<serde_json::Value>| {
+		serde_json::json!({
+			"kind": "t1",
+			"data": { "id": id, "parent_id": "t3_post", "author": author, "body": id, "replies": { "data": { "children": replies } } }
+		})
+	};
+	let reply = |id: &str, parent: &str, author: &str| {
+		let parent_id = format!("t1_{parent}");
+		serde_json::json!({ "kind": "t1", "data": { "id": id, "parent_id": parent_id, "author": author, "body": id } })
+	};
+	let thread = || {
+		let listing = serde_json::json!({ "data": { "children": [
+			comment("q1", "asker", vec![reply("a1", "q1", "op"), reply("f1", "q1", "asker")]),
+			comment("q2", "someone", vec![]),
+			comment("o1", "OP", vec![reply("a2", "o1", "op")]),
+		] } });
+		parse_comments_with_depth(&listing, "/r/iama/comments/post/", "op", "", &HashSet::new(), &req, 0, 5, true)
+	};
+	let ids = |comments: Vec<Comment>| comments.into_iter().map(|comment| comment.id).collect::<Vec<_>>();
+
+	assert_eq!(ids(comments_by_author(thread(), "op", false)), vec!["a1", "o1", "a2"]);
+	// Parents are added once, in reading order
+	assert_eq!(ids(comments_by_author(thread(), "op", true)), vec!["q1", "a1", "o1", "a2"]);
+	assert!(comments_by_author(thread(), "nobody", true).is_empty());
+}
+
+#[test]
+fn test_top_threads() {
+	let req = Request::new(Body::empty());
+	let comment = |id: &str, score: i64, replies: Vec<serde_json::Value>| {
+		serde_json::json!({
+			"kind": "t1",
+			"data": { "id": id, "parent_id": "t3_post", "author": "someone", "body": id, "score": score, "replies": { "data": { "children": replies } } }
+		})
+	};
+	let reply = |id: &str, parent: &str| {
+		let parent_id = format!("t1_{parent}");
+		serde_json::json!({ "kind": "t1", "data": { "id": id, "parent_id": parent_id, "author": "someone", "body": id, "score": 1 } })
+	};
+	let listing = serde_json::json!({ "data": { "children": [
+		comment("a", 10, vec![reply("a1", "a")]),
+		comment("b", 50, vec![reply("b1", "b"), reply("b2", "b")]),
+		comment("c", 5, vec![]),
+		comment("d", 30, vec![reply("d1", "d")]),
+		{ "kind": "more", "data": { "id": "rest", "parent_id": "t3_post", "count": 40 } },
+	] } });
+	let thread = parse_comments_with_depth(&listing, "/r/x/comments/post/", "op", "", &HashSet::new(), &req, 0, 5, true);
+
+	let trimmed = trim_to_top_threads(thread, 2);
+	let shape: Vec<(&str, &str, i64, usize)> = trimmed
+		.iter()
+		.map(|comment| (comment.id.as_str(), comment.kind.as_str(), comment.more_count, comment.replies.len()))
+		.collect();
+	// The two best threads stay where they were, the others become stubs
+	assert_eq!(
+		shape,
+		vec![("a", "more", 2, 0), ("b", "t1", 0, 2), ("c", "more", 1, 0), ("d", "t1", 0, 1), ("rest", "more", 40, 0)]
+	);
+	assert!(trimmed[0].body.is_empty());
+}
+
+#[test]
+fn test_collapsing_low_scores() {
+	let req = Request::new(Body::empty());
+	let listing = serde_json::json!({ "data": { "children": [
+		{ "kind": "t1", "data": { "id": "low", "parent_id": "t3_post", "author": "someone", "body": "meh", "score": -4, "replies": { "data": { "children": [
+			{ "kind": "t1", "data": { "id": "high", "parent_id": "t1_low", "author": "someone", "body": "great", "score": 120 } }
+		] } } } },
+		{ "kind": "t1", "data": { "id": "hidden", "parent_id": "t3_post", "author": "someone", "body": "new", "score": 0, "score_hidden": true } },
+		{ "kind": "more", "data": { "id": "rest", "parent_id": "t3_post", "count": 12, "children": ["x", "y"] } },
+	] } });
+	let mut comments = parse_comments_with_depth(&listing, "/r/x/comments/post/", "op", "", &HashSet::new(), &req, 0, 5, true);
+
+	collapse_below_score(&mut comments, 1);
+	assert!(comments[0].collapsed);
+	assert_eq!(comments[0].replies.len(), 1);
+	assert_eq!(comments[0].replies[0].id, "high");
+	assert!(!comments[0].replies[0].collapsed);
+	assert!(!comments[1].collapsed);
+	// Stubs have no score of their own to judge
+	assert_eq!(comments[2].kind, "more");
+	assert!(!comments[2].collapsed);
+}
+
+#[test]
+fn test_focused_comment_links() {
+	// The focused form of a post link names its comment in the last path segment
+	let focus = focused_comment(Some("KX9Z2".into()), "?context=2&comment=other");
+	assert_eq!(focus, Some(("kx9z2".to_string(), 2)));
+	assert_eq!(
+		thread_query("context=2&comment=other&sort=top&limit=500", focus.as_ref(), DEFAULT_COMMENT_DEPTH + 2, 30, false),
+		"sort=top&raw_json=1&depth=7&limit=30&comment=kx9z2&context=2"
+	);
+
+	assert_eq!(focused_comment(Some(String::new()), "?comment=t1_kx9z2"), Some(("kx9z2".to_string(), 3)));
+	assert_eq!(focused_comment(Some("kx9z2".into()), "?context=50"), Some(("kx9z2".to_string(), MAX_COMMENT_CONTEXT)));
+	assert_eq!(focused_comment(None, "?sort=top"), None);
+	assert_eq!(focused_comment(Some("not-a-comment".into()), ""), None);
+	assert_eq!(
+		thread_query("sort=top", None, DEFAULT_COMMENT_DEPTH, 30, true),
+		"sort=top&raw_json=1&depth=5&limit=30&sort=old"
+	);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_batch_results_repeat_posts() {
+	let post = parse_post(&serde_json::json!({ "kind": "t3", "data": { "id": "abc", "title": "Post", "is_self": true } }), true).await;
+	let posts = HashMap::from([("abc".to_string(), post)]);
+	let inputs = ["t3_abc", "https://redd.it/abc", "t3_gone", "???"].map(ToString::to_string).to_vec();
+	let ids = inputs.iter().map(|input| extract_post_id(input)).collect();
+	let results = batch_results(inputs, ids, &posts);
+
+	// The same post asked for twice is there both times
+	let found: Vec<Option<&str>> = results.iter().map(|result| result.post.as_ref().map(|post| post.id.as_str())).collect();
+	assert_eq!(found, vec![Some("abc"), Some("abc"), None, None]);
+	assert_eq!(results[2].error.as_deref(), Some("Post gone was not found"));
+	assert_eq!(results[3].error.as_deref(), Some("Not a post id, fullname or permalink"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resolving_things() {
+	let req = Request::new(Body::empty());
+	let info = serde_json::json!({ "kind": "Listing", "data": { "children": [
+		{ "kind": "t5", "data": { "id": "2s7lj", "display_name": "rust", "title": "Rust" } },
+		{ "kind": "t1", "data": { "id": "kx9z2", "parent_id": "t3_abc", "author": "ferris", "body": "Hi", "permalink": "/r/rust/comments/abc/title/kx9z2/" } },
+		{ "kind": "t3", "data": { "id": "abc", "title": "Post", "subreddit": "rust", "is_self": true } },
+	] } });
+	let inputs = ["t3_abc", "T1_kx9z2", "t5_2s7lj", "t3_gone", "abc"].map(ToString::to_string).to_vec();
+	let results = resolve_things(inputs, &info, &req).await;
+
+	let kinds: Vec<Option<&str>> = results
+		.iter()
+		.map(|result| match &result.thing {
+			Some(Thing::Post(post)) => Some(post.id.as_str()),
+			Some(Thing::Comment(comment)) => Some(comment.post_link.as_str()),
+			Some(Thing::Subreddit(sub)) => Some(sub.name.as_str()),
+			None => None,
+		})
+		.collect();
+	// Results follow the order of the request, whatever order Reddit answered in
+	assert_eq!(kinds, vec![Some("abc"), Some("/r/rust/comments/abc/title/"), Some("rust"), None, None]);
+	assert_eq!(results[1].input, "T1_kx9z2");
+	assert_eq!(results[3].error.as_deref(), Some("t3_gone was not found"));
+	assert_eq!(results[4].error.as_deref(), Some("Not a t1_, t3_ or t5_ fullname"));
+
+	let json = serde_json::to_value(&results[0]).unwrap();
+	assert_eq!(json["thing"]["kind"], "post");
+	assert_eq!(json["thing"]["data"]["id"], "abc");
+
+	// Posts from filtered subreddits or users are held back like in listings
+	let filtered = Request::builder().header("Cookie", "filters=rust").body(Body::empty()).unwrap();
+	let results = resolve_things(vec!["t3_abc".to_string()], &info, &filtered).await;
+	assert!(results[0].thing.is_none());
+	assert_eq!(results[0].error.as_deref(), Some("t3_abc is filtered out by your settings"));
+}
+
+#[test]
+fn test_extract_post_id() {
+	assert_eq!(extract_post_id("t3_1abc2d"), Some("1abc2d".to_string()));
+	assert_eq!(extract_post_id("1abc2d"), Some("1abc2d".to_string()));
+	assert_eq!(extract_post_id("https://www.reddit.com/r/rust/comments/1abc2d/some_title/"), Some("1abc2d".to_string()));
+	assert_eq!(extract_post_id("/r/rust/comments/1abc2d/some_title/kxyz12/"), Some("1abc2d".to_string()));
+	assert_eq!(extract_post_id("https://old.reddit.com/comments/1abc2d"), Some("1abc2d".to_string()));
+	assert_eq!(extract_post_id("https://redd.it/1abc2d"), Some("1abc2d".to_string()));
+	assert_eq!(extract_post_id("t1_1abc2d"), None);
+	assert_eq!(extract_post_id("/r/rust/"), None);
+	assert_eq!(extract_post_id("not a post"), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_streamed_page_matches_rendered_page() {
+	let req = Request::new(Body::empty());
+	let post = serde_json::json!({
+		"kind": "t3",
+		"data": { "id": "post", "title": "Huge thread", "is_self": true, "permalink": "/r/test/comments/post/huge_thread/", "num_comments": 2 }
+	});
+	let listing = serde_json::json!({ "data": { "children": [
+		{ "kind": "t1", "data": { "id": "a", "parent_id": "t3_post", "body_html": "<p>first</p>", "author": "someone" } },
+		{ "kind": "t1", "data": { "id": "b", "parent_id": "t3_post", "body_html": "<p>second</p>", "author": "someone" } }
+	] } });
+
+	let mut page = PostTemplate {
+		comments: Vec::new(),
+		post: parse_post(&post, false).await,
+		sort: String::new(),
+		prefs: Preferences::new(&req),
+		single_thread: false,
+		url: "/r/test/comments/post/huge_thread/".to_string(),
+		url_without_query: "/r/test/comments/post/huge_thread/".to_string(),
+		comment_query: String::new(),
+	};
+	let comments = parse_comments(&listing, &page.post.permalink, "op", "", &HashSet::new(), &req);
+
+	let (head, tail) = split_page(&page);
+	let streamed = [head, comments.iter().map(|comment| render_thread(&page, comment)).collect(), tail].concat();
+
+	page.comments = comments;
+	let rendered = page.render().unwrap();
+
+	let normalize = |html: &str| html.split_whitespace().collect::<String>();
+	assert!(rendered.contains("<p>second</p>"));
+	assert_eq!(normalize(&streamed), normalize(&rendered));
+}
+
+#[test]
+fn test_paging_comments() {
+	let req = Request::new(Body::empty());
+	// a { b { c }, d }, e { f }
+	let comment = |id: &str, replies: Vec<serde_json::Value>| {
+		serde_json::json!({
+			"kind": "t1",
+			"data": { "id": id, "parent_id": "t3_post", "body": id, "replies": { "data": { "children": replies } } }
+		})
+	};
+	let thread = serde_json::json!({ "data": { "children": [
+		comment("a", vec![comment("b", vec![comment("c", vec![])]), comment("d", vec![])]),
+		comment("e", vec![comment("f", vec![])]),
+	] } });
+	let comments = || parse_comments_with_depth(&thread, "/r/test/comments/post/", "op", "", &HashSet::new(), &req, 0, 5, true);
+	fn ids(comments: &[Comment]) -> String {
+		comments
+			.iter()
+			.map(|c| format!("{}{}", c.id, if c.replies.is_empty() { String::new() } else { format!("({})", ids(&c.replies)) }))
+			.collect::<Vec<_>>()
+			.join(",")
+	}
+
+	let (all, next) = page_comments(comments(), None, usize::MAX).unwrap();
+	assert_eq!((ids(&all), next), ("a(b(c),d),e(f)".to_string(), None));
+
+	let (first, next) = page_comments(comments(), None, 2).unwrap();
+	assert_eq!((ids(&first), next.clone()), ("a(b)".to_string(), Some("c".to_string())));
+
+	let (second, next) = page_comments(comments(), next.as_deref(), 2).unwrap();
+	assert_eq!((ids(&second), next.clone()), ("c,d".to_string(), Some("e".to_string())));
+
+	let (third, next) = page_comments(comments(), next.as_deref(), 2).unwrap();
+	assert_eq!((ids(&third), next), ("e(f)".to_string(), None));
+
+	// The continuation holds up when comments before it come and go
+	let (shifted, _) = page_comments(comments().split_off(1), Some("e"), 2).unwrap();
+	assert_eq!(ids(&shifted), "e(f)");
+	assert!(page_comments(comments(), Some("gone"), 2).is_none());
+}
+
+#[test]
+fn test_locating_comments() {
+	let info = |comment: serde_json::Value| serde_json::json!({ "data": { "children": [{ "kind": "t1", "data": comment }] } });
+
+	let found = info(serde_json::json!({ "id": "c1", "link_id": "t3_p1", "subreddit": "rust", "author": "ferris", "body": "Hi" }));
+	assert_eq!(locate_comment(&found, "c1"), Ok(("p1".to_string(), "rust".to_string())));
+
+	let deleted = info(serde_json::json!({ "id": "c1", "link_id": "t3_p1", "subreddit": "rust", "author": "[deleted]", "body": "[removed]" }));
+	assert_eq!(locate_comment(&deleted, "c1").unwrap_err().1, 410);
+
+	let missing = serde_json::json!({ "data": { "children": [] } });
+	assert_eq!(locate_comment(&missing, "c1").unwrap_err(), ("Comment c1 was not found".to_string(), 404));
+}
+
+#[test]
+fn test_polling_new_comments() {
+	let req = Request::new(Body::empty());
+	let comment = |id: &str, created: u64, replies: Vec<serde_json::Value>| {
+		serde_json::json!({
+			"kind": "t1",
+			"data": { "id": id, "parent_id": "t3_post", "body": id, "created_utc": created, "replies": { "data": { "children": replies } } }
+		})
+	};
+	let thread = |comments: Vec<serde_json::Value>| {
+		let thread = serde_json::json!({ "data": { "children": comments } });
+		parse_comments_with_depth(&thread, "/r/test/comments/post/", "op", "", &HashSet::new(), &req, 0, 5, true)
+	};
+	let ids = |comments: &[Comment]| comments.iter().map(|comment| comment.id.clone()).collect::<Vec<_>>();
+
+	// The first poll sends everything, flattened and oldest first
+	let (comments, position) = comments_since(
+		thread(vec![comment("b", 200, vec![comment("c", 300, vec![])]), comment("a", 100, vec![])]),
+		&PollPosition::default(),
+	);
+	assert_eq!(ids(&comments), vec!["a", "b", "c"]);
+	assert_eq!(position.created, 300);
+	assert_eq!(position.seen, vec!["c"]);
+
+	// Later polls only send what is new, including replies to old comments
+	// and comments made in the same second as the newest one seen
+	let (comments, position) = comments_since(
+		thread(vec![
+			comment("d", 300, vec![]),
+			comment("b", 200, vec![comment("e", 400, vec![]), comment("c", 300, vec![])]),
+			comment("a", 100, vec![]),
+		]),
+		&position,
+	);
+	assert_eq!(ids(&comments), vec!["d", "e"]);
+	assert!(comments.iter().all(|comment| comment.replies.is_empty()));
+
+	// Nothing new keeps the position
+	let (comments, unchanged) = comments_since(thread(vec![comment("e", 400, vec![])]), &position);
+	assert!(comments.is_empty());
+	assert_eq!(unchanged, position);
+}
+
+#[test]
+fn test_poll_tokens() {
+	let position = PollPosition {
+		created: 1700000000,
+		seen: vec!["abc".to_string(), "def".to_string()],
+		issued: 1700000030,
+	};
+	let token = position.seal();
+	assert!(!token.contains("abc"));
+	assert_eq!(PollPosition::open(&token), Some(position));
+	assert_eq!(PollPosition::open("not a token"), None);
+}
+
+#[test]
+fn test_truncating_comments() {
+	let req = Request::new(Body::empty());
+	let long = "word ".repeat(40);
+	let listing = serde_json::json!({ "data": { "children": [{
+		"kind": "t1",
+		"data": { "id": "a", "parent_id": "t3_post", "author": "someone", "body": long, "score": 1, "replies": { "data": { "children": [
+			{ "kind": "t1", "data": { "id": "a1", "parent_id": "t1_a", "author": "someone", "body": format!("{long} >!spoiler!<"), "score": 1 } },
+			{ "kind": "t1", "data": { "id": "a2", "parent_id": "t1_a", "author": "someone", "body": "short", "score": 1 } },
+		] } } }
+	}] } });
+	let thread = || parse_comments_with_depth(&listing, "/r/x/comments/post/", "op", "", &HashSet::new(), &req, 0, 5, true);
+
+	// Comments are left whole unless a limit is given
+	let mut comments = thread();
+	truncate_comments(&mut comments, None);
+	assert_eq!(comments[0].body, long);
+	assert_eq!(comments[0].body_truncated, None);
+
+	let mut comments = thread();
+	truncate_comments(&mut comments, Some(20));
+	assert_eq!(comments[0].body, "word word word word...");
+	assert_eq!(comments[0].body_truncated, Some(true));
+	// Replies are truncated too, and lose the spoilers that were cut off
+	let replies = &comments[0].replies;
+	assert_eq!((replies[0].body_truncated, replies[0].spoilers.len()), (Some(true), 0));
+	assert_eq!((replies[1].body.as_str(), replies[1].body_truncated), ("short", None));
+
+	assert_eq!(comment_body_limit("?comment_body_limit=100"), Some(100));
+	assert_eq!(comment_body_limit("?comment_body_limit=lots"), None);
+}
+
+#[test]
+fn test_more_stubs() {
+	let req = Request::new(Body::empty());
+	let listing = serde_json::json!({ "data": { "children": [
+		{ "kind": "t1", "data": { "id": "a", "parent_id": "t3_post", "author": "someone", "body": "first", "score": 3, "replies": { "data": { "children": [
+			{ "kind": "more", "data": { "id": "a2", "parent_id": "t1_a", "count": 4, "children": ["a2", "a3", "a4"] } },
+		] } } } },
+		{ "kind": "more", "data": { "id": "b", "parent_id": "t3_post", "count": 120, "children": ["b", "c"] } },
+	] } });
+	let thread = parse_comments_with_depth(&listing, "/r/x/comments/post/", "op", "", &HashSet::new(), &req, 0, 5, true);
+
+	assert_eq!(thread[0].load_more, None);
+	let nested = thread[0].replies[0].load_more.as_ref().unwrap();
+	assert_eq!((nested.count, nested.children.as_slice()), (4, ["a2", "a3", "a4"].map(String::from).as_slice()));
+	let stub = serde_json::to_value(&thread[1]).unwrap();
+	assert_eq!(stub["load_more"], serde_json::json!({ "count": 120, "children": ["b", "c"] }));
+
+	// Threads folded away by ?top_threads= can be loaded the same way
+	let trimmed = trim_to_top_threads(thread, 0);
+	let folded = trimmed[0].load_more.as_ref().unwrap();
+	assert_eq!((folded.count, folded.children.as_slice()), (5, ["a".to_string()].as_slice()));
+}
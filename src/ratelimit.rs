@@ -0,0 +1,138 @@
+//! Per-client rate limiting of requests to the instance, so that a single
+//! client can't use up the instance's Reddit budget for everyone else.
+
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use cached::{Cached, SizedCache};
+use hyper::header::{HeaderMap, HeaderValue};
+
+use crate::config;
+
+/// Limiter applied to requests for routes that reach Reddit, allowing
+/// `REDLIB_RATE_LIMIT` of them a minute per client address. `None` when
+/// unlimited.
+pub static CLIENT_LIMITER: LazyLock<Option<RateLimiter>> = LazyLock::new(|| {
+	config::get_setting("REDLIB_RATE_LIMIT")
+		.and_then(|limit| limit.trim().parse().ok())
+		.filter(|limit| *limit > 0)
+		.map(|limit| RateLimiter::new(limit, Duration::from_secs(60)))
+});
+
+/// Clients tracked at once. The one heard from least recently is forgotten
+/// first, and starts over with a whole budget should it come back.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Where a client stands with the limiter after a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+	pub limit: u32,
+	pub remaining: u32,
+	/// Seconds until the budget is whole again.
+	pub reset: u64,
+	/// Seconds until the next request is let through, when this one wasn't.
+	pub retry_after: Option<u64>,
+}
+
+impl Budget {
+	/// `X-RateLimit-*` headers describing the budget, sent with every response.
+	pub fn headers(&self) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert("X-RateLimit-Limit", HeaderValue::from(self.limit));
+		headers.insert("X-RateLimit-Remaining", HeaderValue::from(self.remaining));
+		headers.insert("X-RateLimit-Reset", HeaderValue::from(self.reset));
+		headers
+	}
+}
+
+/// Token bucket per client address, holding up to `limit` requests and
+/// refilling at `limit` per `period`.
+pub struct RateLimiter {
+	limit: u32,
+	period: Duration,
+	buckets: Mutex<SizedCache<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+impl RateLimiter {
+	pub fn new(limit: u32, period: Duration) -> Self {
+		Self {
+			limit,
+			period,
+			buckets: Mutex::new(SizedCache::with_size(MAX_TRACKED_CLIENTS)),
+		}
+	}
+
+	/// Where `ip` stands, taking a request out of its budget if `charge` is
+	/// set. Requests that aren't charged are never refused.
+	pub fn check(&self, ip: IpAddr, charge: bool, now: Instant) -> Budget {
+		let limit = f64::from(self.limit);
+		let per_second = limit / self.period.as_secs_f64();
+
+		let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+		let bucket = buckets.cache_get_or_set_with(ip.to_canonical(), || Bucket { tokens: limit, updated: now });
+		bucket.tokens = (bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * per_second).min(limit);
+		bucket.updated = now;
+		let refused = charge && bucket.tokens < 1.0;
+		if charge && !refused {
+			bucket.tokens -= 1.0;
+		}
+
+		Budget {
+			limit: self.limit,
+			remaining: bucket.tokens.floor() as u32,
+			reset: ((limit - bucket.tokens) / per_second).ceil() as u64,
+			retry_after: refused.then(|| ((1.0 - bucket.tokens) / per_second).ceil().max(1.0) as u64),
+		}
+	}
+}
+
+#[test]
+fn test_rate_limit_budget() {
+	let limiter = RateLimiter::new(3, Duration::from_secs(60));
+	let client: IpAddr = "203.0.113.7".parse().unwrap();
+	let start = Instant::now();
+	let at = |secs: u64| start + Duration::from_secs(secs);
+
+	// Each request takes one out of the budget, which refills a request every 20 seconds
+	let remaining: Vec<(u32, u64)> = (0..3).map(|_| limiter.check(client, true, at(0))).map(|budget| (budget.remaining, budget.reset)).collect();
+	assert_eq!(remaining, vec![(2, 20), (1, 40), (0, 60)]);
+	let refused = limiter.check(client, true, at(5));
+	assert_eq!((refused.remaining, refused.retry_after), (0, Some(15)));
+
+	// Other clients have their own budget
+	let other = limiter.check("198.51.100.4".parse().unwrap(), true, at(5));
+	assert_eq!((other.remaining, other.retry_after), (2, None));
+
+	// Requests that aren't charged only report the budget
+	let peeked = limiter.check(client, false, at(25));
+	assert_eq!((peeked.remaining, peeked.retry_after), (1, None));
+	let refilled = limiter.check(client, true, at(25));
+	assert_eq!((refilled.remaining, refilled.retry_after), (0, None));
+
+	let headers = limiter.check(client, true, at(120)).headers();
+	let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+	assert_eq!(
+		(header("X-RateLimit-Limit"), header("X-RateLimit-Remaining"), header("X-RateLimit-Reset")),
+		("3".to_string(), "2".to_string(), "20".to_string())
+	);
+}
+
+#[test]
+fn test_rate_limit_forgets_idle_clients() {
+	let limiter = RateLimiter::new(1, Duration::from_secs(60));
+	let now = Instant::now();
+	let first: IpAddr = "10.0.0.0".parse().unwrap();
+	assert_eq!(limiter.check(first, true, now).remaining, 0);
+	for i in 1..=MAX_TRACKED_CLIENTS as u32 {
+		limiter.check(IpAddr::V4(std::net::Ipv4Addr::from(0x0a00_0000 + i)), true, now);
+	}
+	// The least recently seen client made room for the others
+	assert_eq!(limiter.buckets.lock().unwrap().cache_size(), MAX_TRACKED_CLIENTS);
+	assert_eq!(limiter.check(first, true, now).retry_after, None);
+}
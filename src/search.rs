@@ -1,10 +1,14 @@
 #![allow(clippy::cmp_owned)]
 
 // CRATES
-use crate::json::{json_error, json_response, truncate_posts, SearchResponse, DEFAULT_BODY_LIMIT};
-use crate::utils::{self, catch_random, error, filter_posts, format_num, format_url, get_filters, param, redirect, setting, template, val, Post, Preferences};
+use crate::json::{add_body_text, json_error, json_response, listing_error, truncate_posts, ListingPage, SearchResponse, DEFAULT_BODY_LIMIT};
+use crate::utils::{
+	self, catch_random, error, fetch_listing, filter_listing, filter_posts, format_num, format_url, get_filters, param, redirect, setting, template, val, Listing,
+	ListingParams, Post, Preferences, SeenPosts,
+};
 use crate::{
 	client::json,
+	collections, cursor,
 	server::RequestExt,
 	subreddit::{can_access_quarantine, quarantine},
 };
@@ -58,13 +62,12 @@ static REDDIT_URL_MATCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^https?
 // SERVICES
 pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 	// This ensures that during a search, no NSFW posts are fetched at all
-	let nsfw_results = if setting(&req, "show_nsfw") == "on" && !utils::sfw_only() {
-		"&include_over_18=on"
-	} else {
-		""
-	};
-	let uri_path = req.uri().path().replace("+", "%2B");
-	let path = format!("{}.json?{}{}&raw_json=1", uri_path, req.uri().query().unwrap_or_default(), nsfw_results);
+	let base = format!("{}.json", req.uri().path().replace("+", "%2B"));
+	let mut params = ListingParams::clamped(req.uri().query().unwrap_or_default());
+	if setting(&req, "show_nsfw") == "on" && !utils::sfw_only() {
+		params.set("include_over_18", "on");
+	}
+	let path = params.path(&base);
 	let mut query = param(&path, "q").unwrap_or_default();
 	query = REDDIT_URL_MATCH.replace(&query, "").to_string();
 
@@ -130,8 +133,9 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 			no_posts: false,
 		}))
 	} else {
-		match Post::fetch(&path, quarantined, false).await {
-			Ok((mut posts, after)) => {
+		match fetch_listing(&base, &params, quarantined, false, false).await {
+			Ok(Listing { mut posts, after, .. }) => {
+				filter_listing(&mut posts, &path);
 				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
@@ -144,7 +148,7 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 						sort,
 						t: param(&path, "t").unwrap_or_default(),
 						before: param(&path, "after").unwrap_or_default(),
-						after,
+						after: after.unwrap_or_default(),
 						restrict_sr: param(&path, "restrict_sr").unwrap_or_default(),
 						typed,
 					},
@@ -170,41 +174,127 @@ pub async fn find(req: Request<Body>) -> Result<Response<Body>, String> {
 
 /// JSON API endpoint for search results.
 pub async fn find_json(req: Request<Body>) -> Result<Response<Body>, String> {
-	let nsfw_results = if !utils::sfw_only() { "&include_over_18=on" } else { "" };
-	let uri_path = req.uri().path().replace("+", "%2B").trim_end_matches(".js").to_string();
-	let path = format!("{}.json?{}{}&raw_json=1", uri_path, req.uri().query().unwrap_or_default(), nsfw_results);
-	let query = param(&path, "q").unwrap_or_default();
+	let base = format!("{}.json", req.uri().path().replace("+", "%2B").trim_end_matches(".js"));
+	let mut params = match cursor::open_query(req.uri().query().unwrap_or_default()).and_then(|query| ListingParams::parse(&query)) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	if let Err(msg) = search_safety(&mut params, utils::sfw_only()) {
+		return Ok(json_error(msg, 400));
+	}
+	let sub = req.param("sub").unwrap_or_default();
+
+	search_posts_json(&req, &sub, &base, &params).await
+}
+
+/// Search restricted to the subreddits of a configured collection.
+pub async fn find_collection(req: Request<Body>) -> Result<Response<Body>, String> {
+	let alias = req.param("collection").unwrap_or_default();
+	let Some(target) = collections::resolve(&alias) else {
+		return error(req, &format!("Collection \"{alias}\" is not configured")).await;
+	};
+
+	let mut params = ListingParams::clamped(req.uri().query().unwrap_or_default());
+	params.set("restrict_sr", "on");
+	Ok(redirect(&format!("/r/{}/search?{}", target.replace('+', "%2B"), params.page_query())))
+}
+
+/// JSON API endpoint for searching across the subreddits of a configured collection.
+pub async fn find_collection_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let alias = req.param("collection").unwrap_or_default();
+	let Some(target) = collections::resolve(&alias) else {
+		return Ok(json_error(format!("Collection \"{alias}\" is not configured"), 404));
+	};
+
+	let mut params = match cursor::open_query(req.uri().query().unwrap_or_default()).and_then(|query| ListingParams::parse(&query)) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	params.set("restrict_sr", "on");
+	if let Err(msg) = search_safety(&mut params, utils::sfw_only()) {
+		return Ok(json_error(msg, 400));
+	}
+	let base = format!("/r/{}/search.json", target.replace('+', "%2B"));
+
+	search_posts_json(&req, &target, &base, &params).await
+}
+
+/// Settle what a JSON search may return. NSFW results are included unless
+/// the client asks for `?include_over_18=off`, and never on SFW-only
+/// instances, whatever the client asks for. `?include_categories=` is passed
+/// on to Reddit, which only honours it for searches across all of Reddit.
+fn search_safety(params: &mut ListingParams, sfw_only: bool) -> Result<(), String> {
+	let value = |name: &str| params.extra.iter().find(|(existing, _)| existing == name).map(|(_, value)| value.clone());
+
+	let include_over_18 = match value("include_over_18").as_deref() {
+		None | Some("" | "on" | "true") => true,
+		Some("off" | "false") => false,
+		Some(other) => return Err(format!("include_over_18 must be on or off, not \"{other}\"")),
+	};
+	match value("include_categories").as_deref() {
+		None | Some("" | "false") => {}
+		Some("true") if value("restrict_sr").as_deref() == Some("on") => {
+			return Err("include_categories only applies to searches across all of Reddit, not restricted ones".to_string());
+		}
+		Some("true") => {}
+		Some(other) => return Err(format!("include_categories must be true or false, not \"{other}\"")),
+	}
+
+	params.set("include_over_18", if include_over_18 && !sfw_only { "on" } else { "off" });
+	Ok(())
+}
+
+/// Fetch post search results for the search at `base` and wrap them in a
+/// `SearchResponse`.
+async fn search_posts_json(req: &Request<Body>, sub: &str, base: &str, params: &ListingParams) -> Result<Response<Body>, String> {
+	let path = &params.path(base);
+	let query = param(path, "q").unwrap_or_default();
 
 	if query.is_empty() {
 		return Ok(json_error("Search query is required".to_string(), 400));
 	}
 
 	// Parse body_limit param (default: 400 chars)
-	let body_limit: Option<usize> = param(&path, "body_limit")
+	let body_limit: Option<usize> = param(path, "body_limit")
 		.and_then(|s| s.parse().ok())
 		.or(Some(DEFAULT_BODY_LIMIT));
 
-	let sub = req.param("sub").unwrap_or_default();
-	let quarantined = can_access_quarantine(&req, &sub);
+	let quarantined = can_access_quarantine(req, sub);
 
-	let typed = param(&path, "type").unwrap_or_default();
+	let typed = param(path, "type").unwrap_or_default();
 
 	// Only return posts for JSON API (not subreddit suggestions)
 	if typed == "sr" {
 		return Ok(json_error("Subreddit search not supported in JSON API, use post search".to_string(), 400));
 	}
 
-	match Post::fetch(&path, quarantined, true).await {
-		Ok((mut posts, after)) => {
+	let mut seen = match SeenPosts::from_query(path) {
+		Ok(seen) => seen,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	match fetch_listing(base, params, quarantined, false, true).await {
+		Ok(Listing { mut posts, after, count, skipped }) => {
+			// Reddit's own filtering of NSFW results isn't relied upon
+			if utils::sfw_only() {
+				posts.retain(|post| !post.flags.nsfw);
+			}
+			filter_listing(&mut posts, path);
+			if let Some(seen) = seen.as_mut() {
+				seen.dedup(&mut posts);
+			}
+			add_body_text(&mut posts, path);
 			// Truncate post bodies for list response
 			truncate_posts(&mut posts, body_limit);
+			if utils::wants_emojis(path) {
+				utils::replace_listing_emojis(&mut posts, true).await;
+			}
 			let response = SearchResponse {
-				posts,
-				after: if after.is_empty() { None } else { Some(after) },
+				page: ListingPage::new(Listing { posts, after, count, skipped }, seen, path),
 			};
 			Ok(json_response(response))
 		}
-		Err(msg) => Ok(json_error(msg, 500)),
+		Err(msg) => Ok(listing_error(msg, &format!("r/{}", if sub.is_empty() { "all" } else { sub }))),
 	}
 }
 
@@ -233,3 +323,55 @@ async fn search_subreddits(q: &str, typed: &str) -> Vec<Subreddit> {
 		})
 		.collect::<Vec<Subreddit>>()
 }
+
+#[test]
+fn test_search_safety() {
+	let settle = |query: &str, sfw_only: bool| {
+		let mut params = ListingParams::parse(query).unwrap();
+		search_safety(&mut params, sfw_only).map(|()| params.to_query())
+	};
+
+	assert_eq!(settle("q=rust", false).unwrap(), "q=rust&include_over_18=on&raw_json=1");
+	assert_eq!(settle("q=rust&include_over_18=off", false).unwrap(), "q=rust&include_over_18=off&raw_json=1");
+	// SFW-only instances override whatever the client asked for
+	assert_eq!(settle("q=rust&include_over_18=on", true).unwrap(), "q=rust&include_over_18=off&raw_json=1");
+	assert_eq!(settle("q=rust", true).unwrap(), "q=rust&include_over_18=off&raw_json=1");
+
+	assert!(settle("q=rust&include_categories=true", false).unwrap().contains("include_categories=true"));
+	assert!(settle("q=rust&include_over_18=maybe", false).is_err());
+	assert!(settle("q=rust&include_categories=1", false).is_err());
+	assert!(settle("q=rust&restrict_sr=on&include_categories=true", false).is_err());
+}
+
+#[cfg(test)]
+use sealed_test::prelude::*;
+
+#[test]
+#[sealed_test(env = [("REDLIB_COLLECTIONS", "langs=rust+golang")])]
+fn test_collection_search() {
+	tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap().block_on(async {
+		let request = |uri: &str, collection: &str| {
+			let mut req = Request::get(uri).body(Body::empty()).unwrap();
+			let mut params = route_recognizer::Params::new();
+			params.insert("collection".to_string(), collection.to_string());
+			req.set_params(params);
+			req
+		};
+
+		// Searches from the page are Reddit's own, restricted to the collection's subreddits
+		let res = find_collection(request("/c/langs/search?q=async", "langs")).await.unwrap();
+		assert_eq!(res.status(), 302);
+		assert_eq!(res.headers()["Location"], "/r/rust%2Bgolang/search?q=async&restrict_sr=on");
+		// Whatever the client passed, only one restrict_sr is sent on, and an empty query adds no stray separator
+		let res = find_collection(request("/c/langs/search?restrict_sr=off&q=async&sort=new", "langs")).await.unwrap();
+		assert_eq!(res.headers()["Location"], "/r/rust%2Bgolang/search?sort=new&q=async&restrict_sr=on");
+		let res = find_collection(request("/c/langs/search", "langs")).await.unwrap();
+		assert_eq!(res.headers()["Location"], "/r/rust%2Bgolang/search?restrict_sr=on");
+
+		// The JSON API turns away unknown collections and empty queries before asking Reddit
+		let res = find_collection_json(request("/c/other/search.js?q=async", "other")).await.unwrap();
+		assert_eq!(res.status(), 404);
+		let res = find_collection_json(request("/c/langs/search.js", "langs")).await.unwrap();
+		assert_eq!(res.status(), 400);
+	});
+}
@@ -1,11 +1,14 @@
 #![allow(dead_code)]
 #![allow(clippy::cmp_owned)]
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use brotli::enc::{BrotliCompress, BrotliEncoderParams};
 use cached::proc_macro::cached;
+use cached::Cached;
+use chrono_tz::Tz;
 use cookie::Cookie;
 use core::f64;
-use futures_lite::{future::Boxed, Future, FutureExt};
+use futures_lite::{future::Boxed, stream, Future, FutureExt};
 use hyper::{
 	body,
 	body::HttpBody,
@@ -13,21 +16,38 @@ use hyper::{
 	service::{make_service_fn, service_fn},
 	HeaderMap,
 };
-use hyper::{Body, Method, Request, Response, Server as HyperServer};
+use hyper::{
+	server::{
+		accept::{self, Accept},
+		conn::AddrStream,
+	},
+	Body, Method, Request, Response, Server as HyperServer, StatusCode, Uri,
+};
 use libflate::gzip;
 use route_recognizer::{Params, Router};
+use rustls::{Certificate, PrivateKey, SignatureScheme};
 use std::{
+	cell::Cell,
 	cmp::Ordering,
 	fmt::Display,
-	io,
+	fs::File,
+	io::{self, BufReader},
+	net::SocketAddr,
 	pin::Pin,
 	result::Result,
 	str::{from_utf8, Split},
 	string::ToString,
+	sync::Arc,
+	time::{Duration, Instant},
 };
 use time::OffsetDateTime;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use url::Url;
 
-use crate::{config, dbg_msg};
+use crate::forwarded::{self, ClientInfo};
+use crate::json::{json_error, json_error_with_reason, ApiErrorCode};
+use crate::{breaker, config, dbg_msg, peers, ratelimit, utils};
 
 const BANNED_USER_AGENTS: &[&str] = &[
 	"AI2Bot",
@@ -121,6 +141,8 @@ const BANNED_USER_AGENTS: &[&str] = &[
 type BoxResponse = Pin<Box<dyn Future<Output = Result<Response<Body>, String>> + Send>>;
 
 /// Compressors for the response Body, in ascending order of preference.
+/// zstd is not among them: it only reaches clients on proxied bodies Reddit
+/// encoded with it, which are passed through as they are.
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 enum CompressionType {
 	Passthrough,
@@ -168,14 +190,28 @@ impl Display for CompressionType {
 	}
 }
 
+/// How long a client may take to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct Route<'a> {
-	router: &'a mut Router<fn(Request<Body>) -> BoxResponse>,
+	router: &'a mut Router<Endpoint>,
 	path: String,
+	rate_limited: bool,
+}
+
+/// Handler of a route, and whether requests for it take from the client's
+/// `REDLIB_RATE_LIMIT` budget.
+#[derive(Clone, Copy)]
+struct Endpoint {
+	handler: fn(Request<Body>) -> BoxResponse,
+	rate_limited: bool,
 }
 
 pub struct Server {
 	pub default_headers: HeaderMap,
-	router: Router<fn(Request<Body>) -> BoxResponse>,
+	/// When set, connections are served over HTTPS using this configuration
+	pub tls_config: Option<Arc<rustls::ServerConfig>>,
+	router: Router<Endpoint>,
 }
 
 #[macro_export]
@@ -199,6 +235,7 @@ pub trait RequestExt {
 	fn set_params(&mut self, params: Params) -> Option<Params>;
 	fn cookies(&self) -> Vec<Cookie<'_>>;
 	fn cookie(&self, name: &str) -> Option<Cookie<'_>>;
+	fn client(&self) -> ClientInfo;
 }
 
 pub trait ResponseExt {
@@ -238,6 +275,15 @@ impl RequestExt for Request<Body> {
 	fn cookie(&self, name: &str) -> Option<Cookie<'_>> {
 		self.cookies().into_iter().find(|c| c.name() == name)
 	}
+
+	/// Real address and scheme of the client, seen through trusted proxies.
+	fn client(&self) -> ClientInfo {
+		self
+			.extensions()
+			.get::<ClientInfo>()
+			.cloned()
+			.unwrap_or_else(|| forwarded::client_info(None, false, self.headers()))
+	}
 }
 
 impl ResponseExt for Response<Body> {
@@ -268,7 +314,18 @@ impl ResponseExt for Response<Body> {
 
 impl Route<'_> {
 	fn method(&mut self, method: &Method, dest: fn(Request<Body>) -> BoxResponse) -> &mut Self {
-		self.router.add(&format!("/{}{}", method.as_str(), self.path), dest);
+		let endpoint = Endpoint {
+			handler: dest,
+			rate_limited: self.rate_limited,
+		};
+		self.router.add(&format!("/{}{}", method.as_str(), self.path), endpoint);
+		self
+	}
+
+	/// Leave requests for this route out of the rate limit, for static files
+	/// and media that never reach Reddit's API. Call before adding endpoints.
+	pub fn unlimited(&mut self) -> &mut Self {
+		self.rate_limited = false;
 		self
 	}
 
@@ -293,6 +350,7 @@ impl Server {
 	pub fn new() -> Self {
 		Self {
 			default_headers: HeaderMap::new(),
+			tls_config: None,
 			router: Router::new(),
 		}
 	}
@@ -301,11 +359,13 @@ impl Server {
 		Route {
 			path: path.to_owned(),
 			router: &mut self.router,
+			rate_limited: true,
 		}
 	}
 
 	pub fn listen(self, addr: &str) -> Boxed<Result<(), hyper::Error>> {
-		let make_svc = make_service_fn(move |_conn| {
+		let tls_config = self.tls_config.clone();
+		let new_service = move |peer: Option<SocketAddr>, tls: bool| {
 			// For correct borrowing, these values need to be borrowed
 			let router = self.router.clone();
 			let default_headers = self.default_headers.clone();
@@ -315,9 +375,14 @@ impl Server {
 			// returns a Response into a `Service`.
 			// let shared_router = router.clone();
 			async move {
-				Ok::<_, String>(service_fn(move |req: Request<Body>| {
+				Ok::<_, String>(service_fn(move |mut req: Request<Body>| {
+					let client = forwarded::client_info(peer, tls, req.headers());
+					log::debug!("{} {} {}", client.ip.map_or_else(|| "-".to_string(), |ip| ip.to_string()), req.method(), req.uri());
+					let client_ip = client.ip;
+					req.extensions_mut().insert(client);
+
 					let req_headers = req.headers().clone();
-					let def_headers = default_headers.clone();
+					let mut def_headers = default_headers.clone();
 
 					// Catch robots.txt-disrespecful bots who still identify themselves
 					// Typically justified as "human triggered" actions.
@@ -351,60 +416,275 @@ impl Server {
 					};
 
 					// Match the visited path with an added route
-					match router.recognize(&format!("/{}{}", method.as_str(), path)) {
+					let route = router.recognize(&format!("/{}{}", method.as_str(), path));
+
+					// Every response tells the client how much of its budget is left,
+					// but only routes that reach Reddit take from it
+					let charge = route.as_ref().is_ok_and(|found| found.handler().rate_limited);
+					if let Some(budget) = client_ip
+						.zip(ratelimit::CLIENT_LIMITER.as_ref())
+						.map(|(ip, limiter)| limiter.check(ip, charge, Instant::now()))
+					{
+						def_headers.extend(budget.headers());
+						if let Some(retry_after) = budget.retry_after {
+							return rate_limited(def_headers, retry_after).boxed();
+						}
+					}
+
+					match route {
 						// If a route was configured for this path
 						Ok(found) => {
 							let mut parammed = req;
 							parammed.set_params(found.params().clone());
 
-							// Run the route's function
-							let func = (found.handler().to_owned().to_owned())(parammed);
+							// JSON API clients may ask for media straight from Reddit, for
+							// absolute links and for times in their own timezone
+							let is_api = path.ends_with(".js") || path.starts_with("/api/");
+							let query = format!("?{}", parammed.uri().query().unwrap_or_default());
+							let api_param = |name: &str| is_api.then(|| utils::param(&query, name)).flatten();
+							let absolute_urls = api_param("absolute_urls").as_deref() == Some("true");
+							let context = RequestContext {
+								raw_media_urls: api_param("raw_url").as_deref() == Some("true"),
+								// The Host header is the client's to make up, so only a configured origin will do
+								instance_origin: absolute_urls.then(utils::configured_origin).flatten(),
+								display_tz: api_param("tz").map(|tz| utils::parse_timezone(&tz)),
+								..RequestContext::default()
+							};
+							if absolute_urls && context.instance_origin.is_none() {
+								let mut res = json_error("absolute_urls needs REDLIB_FULL_URL to be set on this instance".to_string(), 400);
+								res.headers_mut().extend(def_headers);
+								return async { Ok(res) }.boxed();
+							}
+
+							let path_and_query = parammed.uri().path_and_query().map(ToString::to_string).unwrap_or_default();
+							let uri = parammed.uri().clone();
+							let hops = peers::hops(&req_headers);
+
+							// Run the route's function, noting which Reddit host it ended up talking to
+							let func = (found.handler().handler)(parammed);
 							async move {
-								match func.await {
+								let (result, upstream, short_circuited) = REQUEST_CONTEXT
+									.scope(context, async move {
+										let result = func.await;
+										REQUEST_CONTEXT.with(|context| (result, context.upstream_host.get(), context.short_circuited.get()))
+									})
+									.await;
+								// As a last resort, a peer instance may still get an answer out of Reddit
+								let status = result.as_ref().ok().map(Response::status);
+								let from_peer = if is_api && peers::wants_fallback(&path_and_query, status, short_circuited, hops) {
+									peers::fetch(&path_and_query, hops).await
+								} else {
+									None
+								};
+								let result = match from_peer {
+									Some(res) => Ok(res),
+									// Whatever the handler made of it, Reddit being cut off is a 503
+									None if short_circuited => result.map(|res| short_circuit(res, is_api)),
+									None => result,
+								};
+								match result {
 									Ok(mut res) => {
 										res.headers_mut().extend(def_headers);
-										if is_head {
-											*res.body_mut() = Body::empty();
-										} else {
-											let _ = compress_response(&req_headers, &mut res).await;
+										if let Some(host) = upstream {
+											res.headers_mut().insert("X-Redlib-Upstream", header::HeaderValue::from_static(host));
 										}
+										finish_response(&req_headers, &mut res, is_head).await;
 
 										Ok(res)
 									}
-									Err(msg) => new_boilerplate(def_headers, req_headers, 500, if is_head { Body::empty() } else { Body::from(msg) }).await,
+									Err(msg) => failure_response(uri, req_headers, def_headers, 500, msg, is_head).await,
 								}
 							}
 							.boxed()
 						}
 						// If there was a routing error
-						Err(e) => new_boilerplate(def_headers, req_headers, 404, if is_head { Body::empty() } else { e.into() }).boxed(),
+						Err(e) => failure_response(req.uri().clone(), req_headers, def_headers, 404, e, is_head).boxed(),
 					}
 				}))
 			}
-		});
+		};
 
 		// Build SocketAddr from provided address
 		let address = &addr.parse().unwrap_or_else(|_| panic!("Cannot parse {addr} as address (example format: 0.0.0.0:8080)"));
 
 		// Bind server to address specified above. Gracefully shut down if CTRL+C is pressed
-		let server = HyperServer::bind(address).serve(make_svc).with_graceful_shutdown(async {
-			#[cfg(windows)]
-			// Wait for the CTRL+C signal
-			tokio::signal::ctrl_c().await.expect("Failed to install CTRL+C signal handler");
-
-			#[cfg(unix)]
-			{
-				// Wait for CTRL+C or SIGTERM signals
-				let mut signal_terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Failed to install SIGTERM signal handler");
-				tokio::select! {
-					_ = tokio::signal::ctrl_c() => (),
-					_ = signal_terminate.recv() => ()
+		match tls_config {
+			// Terminate TLS in-process when a certificate and key were configured
+			Some(tls_config) => HyperServer::builder(tls_incoming(address, tls_config))
+				.serve(make_service_fn(move |conn: &TlsStream<TcpStream>| new_service(conn.get_ref().0.peer_addr().ok(), true)))
+				.with_graceful_shutdown(shutdown_signal())
+				.boxed(),
+			None => HyperServer::bind(address)
+				.serve(make_service_fn(move |conn: &AddrStream| new_service(Some(conn.remote_addr()), false)))
+				.with_graceful_shutdown(shutdown_signal())
+				.boxed(),
+		}
+	}
+}
+
+/// Resolves once CTRL+C (or SIGTERM on unix) is received.
+async fn shutdown_signal() {
+	#[cfg(windows)]
+	// Wait for the CTRL+C signal
+	tokio::signal::ctrl_c().await.expect("Failed to install CTRL+C signal handler");
+
+	#[cfg(unix)]
+	{
+		// Wait for CTRL+C or SIGTERM signals
+		let mut signal_terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Failed to install SIGTERM signal handler");
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => (),
+			_ = signal_terminate.recv() => ()
+		}
+	}
+}
+
+/// Accept TCP connections on `address` and hand them to hyper once the TLS
+/// handshake has completed. Handshakes run in their own tasks so a slow
+/// client cannot hold up other connections.
+fn tls_incoming(address: &SocketAddr, tls_config: Arc<rustls::ServerConfig>) -> impl Accept<Conn = TlsStream<TcpStream>, Error = io::Error> {
+	let listener = std::net::TcpListener::bind(address).unwrap_or_else(|e| panic!("Cannot bind to {address}: {e}"));
+	listener.set_nonblocking(true).expect("Failed to set listener to non-blocking");
+	let listener = TcpListener::from_std(listener).expect("Failed to register listener with the runtime");
+	let acceptor = TlsAcceptor::from(tls_config);
+	let (tx, rx) = tokio::sync::mpsc::channel(128);
+
+	tokio::spawn(async move {
+		while !tx.is_closed() {
+			let stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					log::warn!("Failed to accept connection: {e}");
+					tokio::time::sleep(Duration::from_millis(100)).await;
+					continue;
 				}
-			}
-		});
+			};
+
+			let acceptor = acceptor.clone();
+			let tx = tx.clone();
+			tokio::spawn(async move {
+				match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+					Ok(Ok(stream)) => {
+						let _ = tx.send(stream).await;
+					}
+					Ok(Err(e)) => log::debug!("TLS handshake failed: {e}"),
+					Err(_) => log::debug!("TLS handshake timed out"),
+				}
+			});
+		}
+	});
+
+	accept::from_stream(stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|stream| (Ok(stream), rx)) }))
+}
+
+/// Build a rustls server configuration from a PEM certificate chain and
+/// private key, making sure the key actually belongs to the certificate.
+pub fn load_tls_config(cert_path: &str, key_path: &str) -> Result<rustls::ServerConfig, String> {
+	let cert_file = File::open(cert_path).map_err(|e| format!("Cannot read certificate {cert_path}: {e}"))?;
+	let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+		.map_err(|e| format!("Cannot parse certificate {cert_path}: {e}"))?
+		.into_iter()
+		.map(Certificate)
+		.collect();
+	if certs.is_empty() {
+		return Err(format!("No certificates found in {cert_path}"));
+	}
+
+	let key_file = File::open(key_path).map_err(|e| format!("Cannot read private key {key_path}: {e}"))?;
+	let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+		.map_err(|e| format!("Cannot parse private key {key_path}: {e}"))?
+		.into_iter()
+		.find_map(|item| match item {
+			rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+			_ => None,
+		})
+		.ok_or_else(|| format!("No private key found in {key_path}"))?;
+
+	verify_key_matches(&certs[0], &key).map_err(|e| format!("Private key {key_path} does not match certificate {cert_path}: {e}"))?;
+
+	let mut config = rustls::ServerConfig::builder()
+		.with_safe_defaults()
+		.with_no_client_auth()
+		.with_single_cert(certs, key)
+		.map_err(|e| format!("Invalid TLS certificate or key: {e}"))?;
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	Ok(config)
+}
+
+/// Sign a probe message with `key` and check the signature against the
+/// public key of `cert`. rustls itself does not catch a mismatched pair
+/// until the first handshake fails.
+fn verify_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<(), String> {
+	const PROBE: &[u8] = b"redlib tls key check";
+	let schemes = [
+		(SignatureScheme::ED25519, &webpki::ED25519),
+		(SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+		(SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+		(SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256),
+	];
+
+	let signing_key = rustls::sign::any_supported_type(key).map_err(|e| e.to_string())?;
+	let offered: Vec<SignatureScheme> = schemes.iter().map(|(scheme, _)| *scheme).collect();
+	let signer = signing_key.choose_scheme(&offered).ok_or("unsupported key type")?;
+	let signature = signer.sign(PROBE).map_err(|e| e.to_string())?;
+	let algorithm = schemes
+		.iter()
+		.find(|(scheme, _)| *scheme == signer.scheme())
+		.map(|(_, alg)| *alg)
+		.ok_or("unsupported key type")?;
+
+	webpki::EndEntityCert::try_from(cert.0.as_slice())
+		.and_then(|cert| cert.verify_signature(algorithm, PROBE, &signature))
+		.map_err(|e| e.to_string())
+}
+
+/// Default Content-Security-Policy. Every source is limited to the instance's
+/// own origin, plus the origin of `full_url` when the instance is served under
+/// a separate public URL, so proxied media loads while third-party
+/// connections stay blocked. Images, video and the HLS playlists fetched by
+/// the player may also come from `media_base`.
+pub fn content_security_policy(full_url: Option<&str>, media_base: Option<&str>) -> String {
+	let origin_of = |url: Option<&str>| {
+		url
+			.and_then(|url| Url::parse(url).ok())
+			.map(|url| url.origin())
+			.filter(|origin| origin.is_tuple())
+			.map(|origin| format!(" {}", origin.ascii_serialization()))
+			.unwrap_or_default()
+	};
+	let origin = origin_of(full_url);
+	let media = Some(origin_of(media_base)).filter(|media| *media != origin).unwrap_or_default();
+
+	format!(
+		"default-src 'none'; font-src 'self'{origin}; script-src 'self'{origin} blob:; manifest-src 'self'{origin}; media-src 'self'{origin}{media} data: blob: about:; style-src 'self'{origin} 'unsafe-inline'; base-uri 'none'; img-src 'self'{origin}{media} data:; form-action 'self'{origin}; frame-ancestors 'none'; connect-src 'self'{origin}{media}; worker-src blob:;"
+	)
+}
+
+/// Security headers sent with every response. `hsts` is the HSTS max-age in
+/// seconds, or `off` to leave the header out.
+pub fn security_headers(hsts: &str) -> HeaderMap {
+	let mut headers = HeaderMap::new();
+
+	if config::get_setting("REDLIB_SECURITY_HEADERS").as_deref() != Some("off") {
+		let referrer_policy = config::get_setting("REDLIB_REFERRER_POLICY").unwrap_or_else(|| "no-referrer".to_string());
+		let csp = config::get_setting("REDLIB_CSP")
+			.unwrap_or_else(|| content_security_policy(config::get_setting("REDLIB_FULL_URL").as_deref(), config::get_setting("REDLIB_MEDIA_BASE_URL").as_deref()));
+		headers = headers! {
+			"Referrer-Policy" => &referrer_policy,
+			"X-Content-Type-Options" => "nosniff",
+			"X-Frame-Options" => "DENY",
+			"Content-Security-Policy" => &csp
+		};
+	}
 
-		server.boxed()
+	if hsts != "off" {
+		if let Ok(val) = header::HeaderValue::from_str(&format!("max-age={hsts}")) {
+			headers.insert("Strict-Transport-Security", val);
+		}
 	}
+
+	headers
 }
 
 /// Create a boilerplate Response for error conditions. This response will be
@@ -426,6 +706,30 @@ async fn new_boilerplate(
 	}
 }
 
+/// Answer a request its handler failed on, or that no route matched, with
+/// `status`: in JSON for the API, and with the error page, custom template
+/// included, for everything else.
+async fn failure_response(
+	uri: Uri,
+	req_headers: HeaderMap<header::HeaderValue>,
+	default_headers: HeaderMap<header::HeaderValue>,
+	status: u16,
+	msg: String,
+	is_head: bool,
+) -> Result<Response<Body>, String> {
+	let mut res = if uri.path().ends_with(".js") || uri.path().starts_with("/api/") {
+		json_error(msg, status)
+	} else {
+		let mut req = Request::new(Body::empty());
+		*req.uri_mut() = uri;
+		*req.headers_mut() = req_headers.clone();
+		utils::error_page(req, &msg, status)?
+	};
+	res.headers_mut().extend(default_headers);
+	finish_response(&req_headers, &mut res, is_head).await;
+	Ok(res)
+}
+
 /// Determines the desired compressor based on the Accept-Encoding header.
 ///
 /// This function will honor the [q-value](https://developer.mozilla.org/en-US/docs/Glossary/Quality_values)
@@ -624,6 +928,16 @@ async fn compress_response(req_headers: &HeaderMap<header::HeaderValue>, res: &m
 		return Ok(());
 	};
 
+	// Whether this body is compressed depends on what the client accepts,
+	// so caches must keep one copy per Accept-Encoding.
+	add_vary_accept_encoding(res.headers_mut());
+
+	// Proxied bodies may already be encoded by Reddit (say, with zstd), in
+	// which case they are passed on as they are rather than encoded twice.
+	if res.headers().contains_key(header::CONTENT_ENCODING) {
+		return Ok(());
+	}
+
 	// Don't bother if the size of the size of the response body will fit
 	// within an IP frame (less the bytes that make up the TCP/IP and HTTP
 	// headers).
@@ -683,6 +997,177 @@ async fn compress_response(req_headers: &HeaderMap<header::HeaderValue>, res: &m
 	Ok(())
 }
 
+/// Turn the response of a handler whose Reddit requests were refused by the
+/// open circuit breaker into a `503`. API clients get the usual JSON error
+/// with an `upstream` reason; pages keep the error page the handler rendered.
+fn short_circuit(mut res: Response<Body>, is_api: bool) -> Response<Body> {
+	if is_api {
+		res = json_error_with_reason(breaker::UPSTREAM_UNAVAILABLE.to_string(), 503, ApiErrorCode::Upstream);
+	}
+	*res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+	if let Some(retry_after) = breaker::UPSTREAM_BREAKER.status(Instant::now()).retry_after {
+		res.headers_mut().insert(header::RETRY_AFTER, retry_after.into());
+	}
+	res
+}
+
+/// Turn away a client that has used up its `REDLIB_RATE_LIMIT` budget.
+async fn rate_limited(default_headers: HeaderMap<header::HeaderValue>, retry_after: u64) -> Result<Response<Body>, String> {
+	let mut res = json_error_with_reason(format!("Too many requests, try again in {retry_after} seconds"), 429, ApiErrorCode::RateLimited);
+	res.headers_mut().extend(default_headers);
+	res.headers_mut().insert(header::RETRY_AFTER, retry_after.into());
+	Ok(res)
+}
+
+/// What the request being handled asked for, and what handling it ran into.
+/// Everything done on its behalf reaches it through [`REQUEST_CONTEXT`].
+#[derive(Debug, Default)]
+pub struct RequestContext {
+	/// Set for JSON responses requested with `?raw_url=true`, which keep
+	/// media URLs pointing at Reddit instead of this instance's proxy.
+	pub raw_media_urls: bool,
+	/// Origin of this instance for JSON responses requested with
+	/// `?absolute_urls=true`, which link to posts and comments with absolute
+	/// URLs next to Reddit's relative permalinks.
+	pub instance_origin: Option<String>,
+	/// Timezone of JSON responses requested with `?tz=`, which show the
+	/// creation time of posts and comments in it next to the UTC one.
+	pub display_tz: Option<Tz>,
+	/// Upstream host that answered the last Reddit request made while
+	/// handling the request, reported in the `X-Redlib-Upstream` header.
+	pub upstream_host: Cell<Option<&'static str>>,
+	/// Set when a request to Reddit made while handling the request was
+	/// turned away by the open breaker.
+	pub short_circuited: Cell<bool>,
+}
+
+impl RequestContext {
+	/// Read the context of the request being handled, if there is one.
+	pub fn with<T>(f: impl FnOnce(&Self) -> T) -> Option<T> {
+		REQUEST_CONTEXT.try_with(f).ok()
+	}
+
+	/// A context for work done on behalf of the current request, with its
+	/// settings but keeping what it runs into to itself.
+	pub fn inherited() -> Self {
+		Self::with(|context| Self {
+			raw_media_urls: context.raw_media_urls,
+			instance_origin: context.instance_origin.clone(),
+			display_tz: context.display_tz,
+			..Self::default()
+		})
+		.unwrap_or_default()
+	}
+}
+
+tokio::task_local! {
+	pub static REQUEST_CONTEXT: RequestContext;
+}
+
+/// Marks a response whose body is relayed from elsewhere, such as proxied
+/// media, so that it is streamed on rather than read into memory first.
+#[derive(Debug, Clone, Copy)]
+pub struct Relayed;
+
+/// Largest body buffered to settle its `Content-Length` and `ETag`.
+const MAX_BUFFERED_BODY: u64 = 4 * 1024 * 1024;
+
+/// Compress a handler's response and settle its headers so that `HEAD` gets
+/// exactly what `GET` would. Bodies this instance made in full get an
+/// explicit `Content-Length` and an `ETag`; relayed and streamed ones keep
+/// whatever the handler set. `HEAD` responses then drop the body itself.
+async fn finish_response(req_headers: &HeaderMap<header::HeaderValue>, res: &mut Response<Body>, is_head: bool) {
+	let _ = compress_response(req_headers, res).await;
+
+	// Hyper knows the exact size of relayed bodies that came with a
+	// Content-Length too, so that alone doesn't mean the body is at hand
+	let buffer = res.extensions().get::<Relayed>().is_none() && res.body().size_hint().exact().is_some_and(|size| size <= MAX_BUFFERED_BODY);
+	if buffer {
+		if let Ok(bytes) = body::to_bytes(res.body_mut()).await {
+			let headers = res.headers_mut();
+			headers.insert(header::CONTENT_LENGTH, bytes.len().into());
+			if !headers.contains_key(header::ETAG) {
+				let digest = ring::digest::digest(&ring::digest::SHA256, &bytes);
+				if let Ok(etag) = header::HeaderValue::from_str(&format!("\"{}\"", URL_SAFE_NO_PAD.encode(&digest.as_ref()[..16]))) {
+					headers.insert(header::ETAG, etag);
+				}
+			}
+			*res.body_mut() = Body::from(bytes);
+		}
+	}
+
+	if is_head {
+		*res.body_mut() = Body::empty();
+	}
+}
+
+/// Add `Accept-Encoding` to the response's `Vary` header, unless it is
+/// already listed.
+pub fn add_vary_accept_encoding(headers: &mut HeaderMap<header::HeaderValue>) {
+	let listed = headers
+		.get_all(header::VARY)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.any(|name| name.trim().eq_ignore_ascii_case("accept-encoding") || name.trim() == "*");
+	if !listed {
+		headers.append(header::VARY, header::HeaderValue::from_static("Accept-Encoding"));
+	}
+}
+
+/// Static asset compressed ahead of time with every compressor we support,
+/// so that serving it costs no compression. Variants that don't come out
+/// smaller than the asset itself are left out.
+pub struct Precompressed {
+	content_type: &'static str,
+	cache: bool,
+	identity: body::Bytes,
+	encoded: Vec<(CompressionType, body::Bytes)>,
+}
+
+impl Precompressed {
+	pub fn new(content_type: &'static str, body: impl Into<Vec<u8>>, cache: bool) -> Self {
+		let identity: Vec<u8> = body.into();
+		let encoded = [CompressionType::Brotli, CompressionType::Gzip]
+			.into_iter()
+			.filter_map(|compressor| {
+				let compressed = compress_body_no_cache(compressor, identity.clone()).ok()?;
+				(compressed.len() < identity.len()).then(|| (compressor, compressed.into()))
+			})
+			.collect();
+		Self {
+			content_type,
+			cache,
+			identity: identity.into(),
+			encoded,
+		}
+	}
+
+	/// Respond with the variant the client prefers out of those it accepts,
+	/// or the asset as it is when there is none.
+	pub fn response(&self, req_headers: &HeaderMap<header::HeaderValue>) -> Response<Body> {
+		let compressor = req_headers
+			.get(header::ACCEPT_ENCODING)
+			.and_then(|accept_encoding| accept_encoding.to_str().ok())
+			.and_then(|accept_encoding| determine_compressor(accept_encoding.to_string()));
+		let variant = compressor.and_then(|compressor| self.encoded.iter().find(|(encoded, _)| *encoded == compressor));
+
+		let mut res = Response::new(Body::from(variant.map_or_else(|| self.identity.clone(), |(_, body)| body.clone())));
+		let headers = res.headers_mut();
+		headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static(self.content_type));
+		if let Some((compressor, _)) = variant {
+			if let Ok(encoding) = header::HeaderValue::from_str(&compressor.to_string()) {
+				headers.insert(header::CONTENT_ENCODING, encoding);
+			}
+		}
+		if self.cache {
+			headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("public, max-age=1209600, s-maxage=86400"));
+		}
+		add_vary_accept_encoding(headers);
+		res
+	}
+}
+
 /// Compresses a `Vec<u8>` given a [`CompressionType`].
 ///
 /// This is a helper function for [`compress_response`] and should not be
@@ -749,6 +1234,14 @@ fn compress_body(compressor: CompressionType, body_bytes: Vec<u8>) -> Result<Vec
 	Ok(compressed)
 }
 
+/// Drop every cached compressed body, returning how many were evicted.
+pub fn flush_compression_cache() -> usize {
+	let mut cache = COMPRESS_BODY.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+	let evicted = cache.cache_size();
+	cache.cache_clear();
+	evicted
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -871,3 +1364,192 @@ mod tests {
 		}
 	}
 }
+
+#[cfg(test)]
+use sealed_test::prelude::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_already_encoded_responses_pass_through() {
+	let mut req_headers = HeaderMap::new();
+	req_headers.insert(header::ACCEPT_ENCODING, header::HeaderValue::from_static("zstd, br, gzip"));
+
+	let payload = "x".repeat(4096);
+	let mut res = Response::builder()
+		.header(header::CONTENT_TYPE, "text/css")
+		.header(header::CONTENT_ENCODING, "zstd")
+		.header(header::VARY, "Accept-Encoding")
+		.body(Body::from(payload.clone()))
+		.unwrap();
+	compress_response(&req_headers, &mut res).await.unwrap();
+	assert_eq!(res.headers()[header::CONTENT_ENCODING], "zstd");
+	assert_eq!(res.headers().get_all(header::VARY).iter().count(), 1);
+	assert_eq!(body::to_bytes(res.into_body()).await.unwrap(), payload.as_bytes());
+
+	let mut res = Response::builder().header(header::CONTENT_TYPE, "application/json").body(Body::from(payload)).unwrap();
+	compress_response(&req_headers, &mut res).await.unwrap();
+	assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+	assert_eq!(res.headers()[header::VARY], "Accept-Encoding");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failure_responses() {
+	let page = failure_response("/r/rust/nowhere".parse().unwrap(), HeaderMap::new(), HeaderMap::new(), 404, "Not found".to_string(), false)
+		.await
+		.unwrap();
+	// Pages get the error page, which the custom template replaces when set
+	assert_eq!(page.status(), 404);
+	assert_eq!(page.headers()[header::CONTENT_TYPE], "text/html");
+	assert!(page.headers().contains_key("X-Request-Id"));
+
+	let api = failure_response("/api/nowhere".parse().unwrap(), HeaderMap::new(), HeaderMap::new(), 500, "oops".to_string(), false)
+		.await
+		.unwrap();
+	assert_eq!(api.status(), 500);
+	assert_eq!(api.headers()[header::CONTENT_TYPE], "application/json");
+	assert_eq!(body::to_bytes(api.into_body()).await.unwrap(), r#"{"data":null,"error":"oops"}"#);
+
+	let head = failure_response("/r/rust/nowhere".parse().unwrap(), HeaderMap::new(), HeaderMap::new(), 404, "Not found".to_string(), true)
+		.await
+		.unwrap();
+	assert!(body::to_bytes(head.into_body()).await.unwrap().is_empty());
+}
+
+#[test]
+fn test_content_security_policy() {
+	let csp = content_security_policy(None, None);
+	assert!(csp.contains("connect-src 'self';"));
+	assert!(csp.contains("img-src 'self' data:;"));
+
+	let csp = content_security_policy(Some("https://redlib.example.com/some/path"), None);
+	assert!(csp.contains("connect-src 'self' https://redlib.example.com;"));
+	assert!(csp.contains("media-src 'self' https://redlib.example.com data: blob: about:;"));
+
+	// Media served from a CDN host may be loaded, but nothing else from it
+	let csp = content_security_policy(Some("https://redlib.example.com"), Some("https://cdn.example.com/media"));
+	assert!(csp.contains("img-src 'self' https://redlib.example.com https://cdn.example.com data:;"));
+	assert!(csp.contains("script-src 'self' https://redlib.example.com blob:;"));
+
+	// Unparseable URLs fall back to the same-origin policy
+	assert_eq!(content_security_policy(Some("not a url"), None), content_security_policy(None, None));
+}
+
+#[test]
+#[sealed_test(env = [("REDLIB_SECURITY_HEADERS", "off")])]
+fn test_security_headers_toggle() {
+	let headers = security_headers("off");
+	assert!(headers.is_empty());
+
+	let headers = security_headers("31536000");
+	assert_eq!(headers.len(), 1);
+	assert_eq!(headers["Strict-Transport-Security"], "max-age=31536000");
+}
+
+#[test]
+fn test_loading_tls_config_errors() {
+	let err = load_tls_config("/nonexistent/cert.pem", "/nonexistent/key.pem").unwrap_err();
+	assert!(err.starts_with("Cannot read certificate /nonexistent/cert.pem"));
+
+	let empty = std::env::temp_dir().join("redlib-test-empty-cert.pem");
+	std::fs::write(&empty, "").unwrap();
+	let empty = empty.to_str().unwrap();
+	assert_eq!(load_tls_config(empty, empty).unwrap_err(), format!("No certificates found in {empty}"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_head_matches_get() {
+	let mut req_headers = HeaderMap::new();
+	req_headers.insert(header::ACCEPT_ENCODING, header::HeaderValue::from_static("gzip"));
+	let respond = || crate::json::json_response(vec!["rust"; 1000]);
+
+	let (mut get, mut head) = (respond(), respond());
+	finish_response(&req_headers, &mut get, false).await;
+	finish_response(&req_headers, &mut head, true).await;
+
+	assert_eq!(get.status(), head.status());
+	assert_eq!(get.headers(), head.headers());
+	assert!(head.headers().contains_key(header::ETAG));
+	assert_eq!(head.headers()[header::CONTENT_ENCODING], "gzip");
+
+	let get_body = body::to_bytes(get.into_body()).await.unwrap();
+	assert_eq!(head.headers()[header::CONTENT_LENGTH], get_body.len().to_string().as_str());
+	assert!(body::to_bytes(head.into_body()).await.unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relayed_bodies_stream() {
+	let req_headers = HeaderMap::new();
+	let respond = || Response::builder().header(header::CONTENT_TYPE, "image/png").body(Body::from("media")).unwrap();
+
+	// Both bodies have an exact size, but only the one made here is buffered
+	let mut made = respond();
+	finish_response(&req_headers, &mut made, false).await;
+	assert!(made.headers().contains_key(header::ETAG));
+
+	let mut relayed = respond();
+	relayed.extensions_mut().insert(Relayed);
+	finish_response(&req_headers, &mut relayed, false).await;
+	assert!(!relayed.headers().contains_key(header::ETAG));
+	assert_eq!(body::to_bytes(relayed.into_body()).await.unwrap(), "media");
+}
+
+#[test]
+fn test_unlimited_routes() {
+	let mut server = Server::new();
+	server.at("/r/:sub").get(|_| async { Ok(Response::new(Body::empty())) }.boxed());
+	server.at("/img/*path").unlimited().get(|_| async { Ok(Response::new(Body::empty())) }.boxed());
+	let limited = |path: &str| server.router.recognize(path).map(|found| found.handler().rate_limited).ok();
+	assert_eq!(limited("/GET/r/rust"), Some(true));
+	assert_eq!(limited("/GET/img/abc.png"), Some(false));
+}
+
+#[tokio::test]
+async fn test_short_circuited_responses() {
+	let page = Response::builder().status(500).header("content-type", "text/html").body(Body::from("<html>")).unwrap();
+	let page = short_circuit(page, false);
+	assert_eq!(page.status(), StatusCode::SERVICE_UNAVAILABLE);
+	assert_eq!(body::to_bytes(page.into_body()).await.unwrap(), "<html>");
+
+	let api = short_circuit(Response::new(Body::from("{}")), true);
+	assert_eq!(api.status(), StatusCode::SERVICE_UNAVAILABLE);
+	let body = body::to_bytes(api.into_body()).await.unwrap();
+	assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["reason"], "upstream");
+}
+
+#[tokio::test]
+async fn test_precompressed_assets() {
+	let css = "body { color: black; }\n".repeat(200);
+	let asset = Precompressed::new("text/css", css.clone(), true);
+	let request = |accept_encoding: Option<&'static str>| {
+		let mut headers = HeaderMap::new();
+		if let Some(accept_encoding) = accept_encoding {
+			headers.insert(header::ACCEPT_ENCODING, header::HeaderValue::from_static(accept_encoding));
+		}
+		asset.response(&headers)
+	};
+
+	let res = request(Some("gzip, br"));
+	assert_eq!(res.headers()[header::CONTENT_ENCODING], "br");
+	assert_eq!(res.headers()[header::VARY], "Accept-Encoding");
+	assert_eq!(res.headers()[header::CONTENT_TYPE], "text/css");
+	assert!(res.headers().contains_key(header::CACHE_CONTROL));
+
+	let mut res = request(Some("gzip"));
+	assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+	let gzipped = body::to_bytes(res.body_mut()).await.unwrap();
+	let mut decoded = String::new();
+	io::Read::read_to_string(&mut gzip::Decoder::new(&gzipped[..]).unwrap(), &mut decoded).unwrap();
+	assert_eq!(decoded, css);
+
+	// Clients accepting nothing we have get the asset as it is
+	for accept_encoding in [None, Some("zstd")] {
+		let mut res = request(accept_encoding);
+		assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+		assert_eq!(body::to_bytes(res.body_mut()).await.unwrap(), css);
+	}
+
+	// Compressing a tiny asset only makes it bigger
+	let tiny = Precompressed::new("text/javascript", "x();", false);
+	let mut headers = HeaderMap::new();
+	headers.insert(header::ACCEPT_ENCODING, header::HeaderValue::from_static("br"));
+	assert!(!tiny.response(&headers).headers().contains_key(header::CONTENT_ENCODING));
+}
@@ -0,0 +1,109 @@
+use hyper::{Body, Request, Response};
+
+use crate::utils::{error, instance_origin};
+use crate::{collections, config, subreddit};
+
+/// Whether `/sitemap.xml` is served. Instances hidden from search engines
+/// have nothing to advertise, so indexing being disabled turns it off too.
+fn sitemap_enabled() -> bool {
+	config::get_setting("REDLIB_DISABLE_SITEMAP").as_deref() != Some("on") && config::get_setting("REDLIB_ROBOTS_DISABLE_INDEXING").as_deref() != Some("on")
+}
+
+/// Serve `robots.txt`, either the operator's own or the generated default.
+pub async fn robots(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sitemap_url = sitemap_enabled()
+		.then(|| instance_origin(&req))
+		.filter(|base| !base.is_empty())
+		.map(|base| format!("{base}/sitemap.xml"));
+	let body = render_robots(
+		config::get_setting("REDLIB_ROBOTS_TXT"),
+		config::get_setting("REDLIB_ROBOTS_DISABLE_INDEXING").as_deref() == Some("on"),
+		sitemap_url,
+	);
+
+	Ok(
+		Response::builder()
+			.status(200)
+			.header("content-type", "text/plain")
+			.header("Cache-Control", "public, max-age=1209600, s-maxage=86400")
+			.body(body.into())
+			.unwrap_or_default(),
+	)
+}
+
+/// Serve a sitemap of the instance's entry points: the front page, every
+/// configured collection and the default subreddits.
+pub async fn sitemap(req: Request<Body>) -> Result<Response<Body>, String> {
+	if !sitemap_enabled() {
+		return error(req, "The sitemap is disabled on this instance").await;
+	}
+
+	let mut paths = vec!["/".to_string()];
+	paths.extend(subreddit::homepage().ok().flatten());
+	paths.extend(collections::all().into_iter().map(|collection| format!("/c/{}", collection.name)));
+	paths.extend(
+		config::get_setting("REDLIB_DEFAULT_SUBSCRIPTIONS")
+			.unwrap_or_default()
+			.split('+')
+			.filter(|sub| !sub.is_empty())
+			.map(|sub| format!("/r/{sub}")),
+	);
+
+	Ok(
+		Response::builder()
+			.status(200)
+			.header("content-type", "application/xml")
+			.header("Cache-Control", "public, max-age=86400")
+			.body(render_sitemap(&instance_origin(&req), paths).into())
+			.unwrap_or_default(),
+	)
+}
+
+fn render_robots(custom: Option<String>, disable_indexing: bool, sitemap_url: Option<String>) -> String {
+	let mut body = match custom.filter(|custom| !custom.trim().is_empty()) {
+		Some(custom) => custom.trim_end().to_string(),
+		None if disable_indexing => "User-agent: *\nDisallow: /".to_string(),
+		None => "User-agent: *\nDisallow: /u/\nDisallow: /user/".to_string(),
+	};
+
+	if let Some(url) = sitemap_url {
+		body.push_str(&format!("\n\nSitemap: {url}"));
+	}
+
+	body
+}
+
+fn render_sitemap(base: &str, paths: Vec<String>) -> String {
+	let mut seen = Vec::new();
+	let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+	for path in paths {
+		if seen.contains(&path) {
+			continue;
+		}
+		let loc = format!("{base}{path}").replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+		xml.push_str(&format!("\t<url><loc>{loc}</loc></url>\n"));
+		seen.push(path);
+	}
+	xml.push_str("</urlset>\n");
+	xml
+}
+
+#[test]
+fn test_render_robots() {
+	assert_eq!(render_robots(None, false, None), "User-agent: *\nDisallow: /u/\nDisallow: /user/");
+	assert_eq!(render_robots(None, true, None), "User-agent: *\nDisallow: /");
+	assert_eq!(
+		render_robots(Some("User-agent: *\nAllow: /\n".into()), false, Some("https://example.com/sitemap.xml".into())),
+		"User-agent: *\nAllow: /\n\nSitemap: https://example.com/sitemap.xml"
+	);
+}
+
+#[test]
+fn test_render_sitemap() {
+	let paths = vec!["/".to_string(), "/c/news".to_string(), "/r/rust".to_string(), "/r/rust".to_string()];
+	let xml = render_sitemap("https://example.com", paths);
+	assert!(xml.starts_with("<?xml"));
+	assert_eq!(xml.matches("<url>").count(), 3);
+	assert!(xml.contains("<loc>https://example.com/c/news</loc>"));
+	assert!(xml.contains("<loc>https://example.com/r/rust</loc>"));
+}
@@ -1,20 +1,31 @@
 #![allow(clippy::cmp_owned)]
 
-use crate::{collections, config, utils};
+use crate::{collections, config, cursor, utils};
 // CRATES
-use crate::json::{json_error, json_response, truncate_posts, SubredditResponse, WikiResponse, DEFAULT_BODY_LIMIT};
+use crate::json::{
+	add_body_text, json_error, json_response, listing_error, subreddit_not_found, truncate_posts, upstream_error, ListingPage, ModeratorsResponse, NewPostCountResponse,
+	SubredditResponse, SubredditStatusResponse, SubredditsAboutResponse, WidgetsResponse, WikiResponse, DEFAULT_BODY_LIMIT,
+};
 use crate::utils::{
-	catch_random, error, filter_posts, format_num, format_url, get_filters, info, nsfw_landing, param, redirect, rewrite_urls, setting, template, val, Post, Preferences,
-	Subreddit,
+	bad_request, catch_random, error, fetch_listing, filter_listing, filter_posts, format_url, get_filters, info, nsfw_landing, param, redirect, replace_listing_emojis,
+	rewrite_urls, setting, template, val, wants_emojis, Listing, ListingParams, Moderator, Post, Preferences, SeenPosts, Subreddit, Widget, WidgetButton,
+};
+use crate::{
+	client::{json, json_gated, UpstreamError},
+	server::RequestExt,
+	server::ResponseExt,
 };
-use crate::{client::json, server::RequestExt, server::ResponseExt};
 use askama::Template;
+use cached::proc_macro::cached;
+use cached::Cached;
 use cookie::Cookie;
 use htmlescape::decode_html;
 use hyper::{Body, Request, Response};
 
 use chrono::DateTime;
 use regex::Regex;
+use serde_json::Value;
+use std::collections::HashSet;
 use std::sync::LazyLock;
 use time::{Duration, OffsetDateTime};
 
@@ -26,6 +37,8 @@ struct SubredditTemplate {
 	posts: Vec<Post>,
 	sort: (String, String),
 	ends: (String, String),
+	/// Listing items seen so far, passed back to Reddit as `count`
+	count: u64,
 	prefs: Preferences,
 	url: String,
 	redirect_url: String,
@@ -61,15 +74,96 @@ struct WallTemplate {
 	url: String,
 }
 
-static GEO_FILTER_MATCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"geo_filter=(?<region>\w+)").unwrap());
+static GEO_FILTER_MATCH: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?:^|&)geo(?:_filter)?=(?<region>[^&]*)").unwrap());
+
+/// Region codes Reddit accepts for the `geo_filter` parameter of r/popular and r/all.
+const GEO_REGIONS: &[&str] = &[
+	"GLOBAL", "AR", "AU", "BG", "CA", "CL", "CO", "CZ", "DE", "ES", "FI", "FR", "GB", "GR", "HR", "HU", "IE", "IN", "IS", "IT", "JP", "MX", "MY", "NL", "NZ", "PH", "PL", "PR",
+	"PT", "RO", "RS", "SE", "SG", "TH", "TR", "TW", "US", "US_AK", "US_AL", "US_AR", "US_AZ", "US_CA", "US_CO", "US_CT", "US_DC", "US_DE", "US_FL", "US_GA", "US_HI", "US_IA",
+	"US_ID", "US_IL", "US_IN", "US_KS", "US_KY", "US_LA", "US_MA", "US_MD", "US_ME", "US_MI", "US_MN", "US_MO", "US_MS", "US_MT", "US_NC", "US_ND", "US_NE", "US_NH", "US_NJ",
+	"US_NM", "US_NV", "US_NY", "US_OH", "US_OK", "US_OR", "US_PA", "US_RI", "US_SC", "US_SD", "US_TN", "US_TX", "US_UT", "US_VA", "US_VT", "US_WA", "US_WI", "US_WV", "US_WY",
+];
+
+/// Upstream listing path for `sub_name` sorted by `sort`. Reddit only serves
+/// `best` for the anonymous front page, which is r/popular here.
+fn listing_path(sub_name: &str, sort: &str) -> Result<String, String> {
+	match (sub_name, sort) {
+		("popular", "best") => Ok("/best.json".to_string()),
+		(_, "best") => Err(format!("Sorting by best is only available on the front page, not r/{sub_name}")),
+		_ => Ok(format!("/r/{}/{sort}.json", sub_name.replace('+', "%2B"))),
+	}
+}
+
+/// Whether the listing was requested with `?hide_stickied=true`, dropping
+/// moderator announcements from the results.
+fn hide_stickied(path: &str) -> bool {
+	param(path, "hide_stickied").is_some_and(|v| v == "true")
+}
+
+/// Resolve the `geo` (or legacy `geo_filter`) query parameter for a listing.
+/// r/popular defaults to the global feed; other listings only get a filter
+/// when one is requested. Unknown regions are rejected.
+fn geo_filter(sub_name: &str, query: &str) -> Result<Option<String>, String> {
+	if sub_name != "popular" && sub_name != "all" {
+		return Ok(None);
+	}
+
+	match GEO_FILTER_MATCH.captures(query) {
+		Some(caps) => {
+			let region = caps["region"].to_uppercase();
+			if GEO_REGIONS.contains(&region.as_str()) {
+				Ok(Some(region))
+			} else {
+				Err(format!("Unknown region \"{}\"", &caps["region"]))
+			}
+		}
+		None if sub_name == "popular" => Ok(Some("GLOBAL".to_owned())),
+		None => Ok(None),
+	}
+}
 
 // SERVICES
+/// The listing `/` redirects to, configured through `REDLIB_DEFAULT_SUBREDDIT`
+/// or `REDLIB_DEFAULT_COLLECTION`.
+pub fn homepage() -> Result<Option<String>, String> {
+	homepage_path(config::get_setting("REDLIB_DEFAULT_SUBREDDIT"), config::get_setting("REDLIB_DEFAULT_COLLECTION"))
+}
+
+fn homepage_path(subreddit: Option<String>, collection: Option<String>) -> Result<Option<String>, String> {
+	let subreddit = subreddit.map(|sub| sub.trim().trim_start_matches("r/").to_string()).filter(|sub| !sub.is_empty());
+	let collection = collection.map(|name| name.trim().to_string()).filter(|name| !name.is_empty());
+
+	match (subreddit, collection) {
+		(Some(_), Some(_)) => Err("REDLIB_DEFAULT_SUBREDDIT and REDLIB_DEFAULT_COLLECTION cannot both be set".to_string()),
+		(Some(sub), None) => {
+			if sub.split('+').all(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+				Ok(Some(format!("/r/{sub}")))
+			} else {
+				Err(format!("REDLIB_DEFAULT_SUBREDDIT \"{sub}\" is not a valid subreddit name"))
+			}
+		}
+		(None, Some(name)) => match collections::resolve(&name) {
+			Some(_) => Ok(Some(format!("/c/{name}"))),
+			None => Err(format!("REDLIB_DEFAULT_COLLECTION \"{name}\" is not listed in REDLIB_COLLECTIONS")),
+		},
+		(None, None) => Ok(None),
+	}
+}
+
 pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 	// Build Reddit API path
 	let root = req.uri().path() == "/";
 	let query = req.uri().query().unwrap_or_default().to_string();
 	let subscribed = setting(&req, "subscriptions");
 	let front_page = setting(&req, "front_page");
+
+	// Operators can point the homepage at a listing of their choice, as long as
+	// the visitor hasn't picked a front page of their own
+	if root && (front_page == "default" || front_page.is_empty()) && subscribed.is_empty() {
+		if let Ok(Some(home)) = homepage() {
+			return Ok(redirect(&if query.is_empty() { home } else { format!("{home}?{query}") }));
+		}
+	}
 	let remove_default_feeds = setting(&req, "remove_default_feeds") == "on";
 	let post_sort = req.cookie("post_sort").map_or_else(|| "hot".to_string(), |c| c.value().to_string());
 	let sort = req.param("sort").unwrap_or_else(|| req.param("id").unwrap_or(post_sort));
@@ -143,12 +237,19 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 
 	// Request subreddit metadata
 	let sub = if !sub_name.contains('+') && sub_name != subscribed && sub_name != "popular" && sub_name != "all" {
-		// Regular subreddit
-		subreddit(&sub_name, quarantined).await.unwrap_or_default()
+		// Regular subreddit, taking the user to the one they probably meant if it doesn't exist
+		let about = subreddit(&sub_name, quarantined, false).await;
+		if is_missing(&about) {
+			if let Some(suggestion) = suggest_subreddit(sub_name.clone()).await {
+				let url = req.uri().path_and_query().map_or("", |url| url.as_str());
+				return Ok(redirect(&url.replacen(&format!("/r/{sub_name}"), &format!("/r/{suggestion}"), 1)));
+			}
+		}
+		about.unwrap_or_default()
 	} else if sub_name == subscribed {
 		// Subscription feed
 		if req.uri().path().starts_with("/r/") {
-			subreddit(&sub_name, quarantined).await.unwrap_or_default()
+			subreddit(&sub_name, quarantined, false).await.unwrap_or_default()
 		} else {
 			Subreddit::default()
 		}
@@ -167,16 +268,21 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 		return Ok(nsfw_landing(req, req_url).await.unwrap_or_default());
 	}
 
-	let mut params = String::from("&raw_json=1");
-	if sub_name == "popular" {
-		let geo_filter = match GEO_FILTER_MATCH.captures(&query) {
-			Some(geo_filter) => geo_filter["region"].to_string(),
-			None => "GLOBAL".to_owned(),
-		};
-		params.push_str(&format!("&geo_filter={geo_filter}"));
+	let region = match geo_filter(&sub_name, &query) {
+		Ok(region) => region,
+		Err(msg) => return bad_request(req, &msg).await,
+	};
+
+	let mut params = ListingParams::clamped(req.uri().query().unwrap_or_default());
+	if let Some(region) = region {
+		params.set("geo_filter", &region);
 	}
 
-	let path = format!("/r/{}/{sort}.json?{}{params}", sub_name.replace('+', "%2B"), req.uri().query().unwrap_or_default());
+	let listing = match listing_path(&sub_name, &sort) {
+		Ok(listing) => listing,
+		Err(msg) => return bad_request(req, &msg).await,
+	};
+	let path = params.path(&listing);
 	let url = String::from(req.uri().path_and_query().map_or("", |val| val.as_str()));
 	let redirect_url = url[1..].replace('?', "%3F").replace('&', "%26").replace('+', "%2B");
 	let filters = get_filters(&req);
@@ -188,6 +294,7 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 			posts: Vec::new(),
 			sort: (sort, param(&path, "t").unwrap_or_default()),
 			ends: (param(&path, "after").unwrap_or_default(), String::new()),
+			count: params.count,
 			prefs: Preferences::new(&req),
 			url,
 			redirect_url,
@@ -199,20 +306,28 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 			collection_subreddits: collection_subreddits.clone(),
 		}))
 	} else {
-		match Post::fetch(&path, quarantined, false).await {
-			Ok((mut posts, after)) => {
+		match fetch_listing(&listing, &params, quarantined, false, false).await {
+			Ok(Listing { mut posts, after, count, .. }) => {
+				if hide_stickied(&path) {
+					posts.retain(|p| !p.flags.stickied);
+				}
+				filter_listing(&mut posts, &path);
 				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
+				if wants_emojis(&path) {
+					replace_listing_emojis(&mut posts, false).await;
+				}
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
 				if sort == "new" {
-					posts.sort_by(|a, b| b.created_ts.cmp(&a.created_ts));
-					posts.sort_by(|a, b| b.flags.stickied.cmp(&a.flags.stickied));
+					posts.sort_by_key(|p| std::cmp::Reverse(p.created_ts));
+					posts.sort_by_key(|p| std::cmp::Reverse(p.flags.stickied));
 				}
 			Ok(template(&SubredditTemplate {
 				sub,
 				posts,
 					sort: (sort, param(&path, "t").unwrap_or_default()),
-					ends: (param(&path, "after").unwrap_or_default(), after),
+					ends: (param(&path, "after").unwrap_or_default(), after.unwrap_or_default()),
+					count,
 					prefs: Preferences::new(&req),
 					url,
 					redirect_url,
@@ -224,8 +339,10 @@ pub async fn community(req: Request<Body>) -> Result<Response<Body>, String> {
 				collection_subreddits: collection_subreddits.clone(),
 			}))
 			}
-			Err(msg) => match msg.as_str() {
+			Err(msg) => match &*msg {
 				"quarantined" | "gated" => Ok(quarantine(&req, sub_name, &msg)),
+				"over18" if crate::utils::should_be_nsfw_gated(&req, &req_url) => Ok(nsfw_landing(req, req_url).await.unwrap_or_default()),
+				"over18" => Ok(quarantine(&req, sub_name, "age-restricted")),
 				"private" => error(req, &format!("r/{sub_name} is a private community")).await,
 				"banned" => error(req, &format!("r/{sub_name} has been banned from Reddit")).await,
 				_ => error(req, &msg).await,
@@ -293,6 +410,8 @@ pub async fn community_json(req: Request<Body>) -> Result<Response<Body>, String
 		default_front
 	};
 
+	// Passing ?sfw_acknowledged=true accepts Reddit's over-18 gate, unless the instance is SFW-only
+	let sfw_acknowledged = !crate::utils::sfw_only() && param(&format!("?{query}"), "sfw_acknowledged").is_some_and(|v| v == "true");
 	let quarantined = can_access_quarantine(&req, &sub_name);
 
 	// Handle random subreddits - return error for JSON API
@@ -302,7 +421,11 @@ pub async fn community_json(req: Request<Body>) -> Result<Response<Body>, String
 
 	// Request subreddit metadata
 	let sub = if !sub_name.contains('+') && sub_name != subscribed && sub_name != "popular" && sub_name != "all" {
-		subreddit(&sub_name, quarantined).await.unwrap_or_default()
+		let about = subreddit(&sub_name, quarantined, sfw_acknowledged).await;
+		if is_missing(&about) {
+			return Ok(subreddit_not_found(&sub_name, suggest_subreddit(sub_name.clone()).await));
+		}
+		about.unwrap_or_default()
 	} else if sub_name == subscribed {
 		Subreddit::default()
 	} else {
@@ -317,39 +440,77 @@ pub async fn community_json(req: Request<Body>) -> Result<Response<Body>, String
 		return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
 	}
 
-	let mut params = String::from("&raw_json=1");
-	if sub_name == "popular" {
-		let geo_filter = match GEO_FILTER_MATCH.captures(&query) {
-			Some(geo_filter) => geo_filter["region"].to_string(),
-			None => "GLOBAL".to_owned(),
-		};
-		params.push_str(&format!("&geo_filter={geo_filter}"));
+	let region = match geo_filter(&sub_name, &query) {
+		Ok(region) => region,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	let query = match cursor::open_query(&query) {
+		Ok(query) => query,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	let mut params = match ListingParams::parse(&query) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	if let Some(region) = region {
+		params.set("geo_filter", &region);
 	}
 
-	let path = format!("/r/{}/{sort}.json?{}{params}", sub_name.replace('+', "%2B"), req.uri().query().unwrap_or_default());
+	let listing = match listing_path(&sub_name, &sort) {
+		Ok(listing) => listing,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let path = params.path(&listing);
 
 	// Parse body_limit param (default: 400 chars)
 	let body_limit: Option<usize> = param(&path, "body_limit")
 		.and_then(|s| s.parse().ok())
 		.or(Some(DEFAULT_BODY_LIMIT));
 
-	match Post::fetch(&path, quarantined, true).await {
-		Ok((mut posts, after)) => {
+	let mut seen = match SeenPosts::from_query(&path) {
+		Ok(seen) => seen,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	match fetch_listing(&listing, &params, quarantined, sfw_acknowledged, true).await {
+		Ok(Listing { mut posts, after, count, skipped }) => {
+			if hide_stickied(&path) {
+				posts.retain(|p| !p.flags.stickied);
+			}
+			filter_listing(&mut posts, &path);
+			if let Some(seen) = seen.as_mut() {
+				seen.dedup(&mut posts);
+			}
+			add_body_text(&mut posts, &path);
 			// Truncate post bodies for list response
 			truncate_posts(&mut posts, body_limit);
+			if wants_emojis(&path) {
+				replace_listing_emojis(&mut posts, true).await;
+			}
+			if param(&path, "include_subreddit").is_some_and(|include| include == "false") {
+				trim_subreddit_details(&mut posts, &sub);
+			}
 			let response = SubredditResponse {
 				subreddit: sub,
-				posts,
-				after: if after.is_empty() { None } else { Some(after) },
+				page: ListingPage::new(Listing { posts, after, count, skipped }, seen, &path),
 			};
 			Ok(json_response(response))
 		}
-		Err(msg) => match msg.as_str() {
-			"quarantined" | "gated" => Ok(json_error(format!("r/{sub_name} is {msg}"), 403)),
-			"private" => Ok(json_error(format!("r/{sub_name} is a private community"), 403)),
-			"banned" => Ok(json_error(format!("r/{sub_name} has been banned from Reddit"), 404)),
-			_ => Ok(json_error(msg, 500)),
-		},
+		Err(msg) => Ok(listing_error(msg, &format!("r/{sub_name}"))),
+	}
+}
+
+/// Leave out the subreddit that posts embed with `?sr_detail=true` wherever
+/// it is the listing's own, which the response already carries once. The
+/// posts keep its name in `community`.
+fn trim_subreddit_details(posts: &mut [Post], subreddit: &Subreddit) {
+	if subreddit.name.is_empty() {
+		return;
+	}
+	for post in posts.iter_mut().filter(|post| post.community.eq_ignore_ascii_case(&subreddit.name)) {
+		post.subreddit_detail = None;
 	}
 }
 
@@ -641,7 +802,7 @@ pub async fn wiki_json(req: Request<Body>) -> Result<Response<Body>, String> {
 			if msg == "quarantined" || msg == "gated" {
 				Ok(json_error(format!("r/{sub} is {msg}"), 403))
 			} else {
-				Ok(json_error(msg, 500))
+				Ok(upstream_error(msg, 500))
 			}
 		}
 	}
@@ -696,57 +857,406 @@ pub async fn sidebar(req: Request<Body>) -> Result<Response<Body>, String> {
 // 	)
 // }
 
-// async fn moderators_list(sub: &str, quarantined: bool) -> Result<Vec<String>, String> {
-// 	// Build the moderator list URL
-// 	let path: String = format!("/r/{}/about/moderators.json?raw_json=1", sub);
+/// JSON API endpoint listing the moderators of a subreddit.
+pub async fn moderators_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sub = req.param("sub").unwrap_or_default();
+	let quarantined = can_access_quarantine(&req, &sub);
 
-// 	// Retrieve response
-// 	json(path, quarantined).await.map(|response| {
-// 		// Traverse json tree and format into list of strings
-// 		response["data"]["children"]
-// 			.as_array()
-// 			.unwrap_or(&Vec::new())
-// 			.iter()
-// 			.filter_map(|moderator| {
-// 				let name = moderator["name"].as_str().unwrap_or_default();
-// 				if name.is_empty() {
-// 					None
-// 				} else {
-// 					Some(name.to_string())
-// 				}
-// 			})
-// 			.collect::<Vec<_>>()
-// 	})
-// }
+	// Handle random subreddits - return error for JSON API
+	if sub == "random" || sub == "randnsfw" {
+		return Ok(json_error("Random subreddits not supported in JSON API".to_string(), 400));
+	}
+
+	match moderators_list(sub.clone(), quarantined).await {
+		Ok(moderators) => Ok(json_response(ModeratorsResponse { subreddit: sub, moderators })),
+		Err(err) => match &*err {
+			msg @ ("quarantined" | "gated") => Ok(json_error(format!("r/{sub} is {msg}"), 403)),
+			"private" => Ok(json_error(format!("r/{sub} is a private community; its moderator list requires authentication"), 403)),
+			"banned" => Ok(json_error(format!("r/{sub} has been banned from Reddit"), 404)),
+			// Reddit answers with a bare 403 when the list is hidden from anonymous users
+			msg if msg.starts_with("Reddit error 403") => Ok(json_error(format!("The moderator list of r/{sub} requires authentication"), 403)),
+			_ => Ok(upstream_error(err, 500)),
+		},
+	}
+}
+
+/// Most posts a new-post count looks at: a single page of `/new`.
+const NEW_COUNT_PAGE_SIZE: usize = 100;
+
+/// JSON API endpoint counting the posts made to a subreddit after `?since=`
+/// (a unix timestamp), for "12 new" badges. The user's filters and the
+/// instance's SFW setting apply.
+pub async fn new_count_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sub = req.param("sub").unwrap_or_default();
+
+	if sub == "random" || sub == "randnsfw" {
+		return Ok(json_error("Random subreddits not supported in JSON API".to_string(), 400));
+	}
+
+	let since = match param(&format!("?{}", req.uri().query().unwrap_or_default()), "since").map(|since| since.parse::<u64>()) {
+		Some(Ok(since)) => since,
+		Some(Err(_)) => return Ok(json_error("since must be a unix timestamp".to_string(), 400)),
+		None => return Ok(json_error("since is required".to_string(), 400)),
+	};
+
+	let quarantined = can_access_quarantine(&req, &sub);
+	let params = ListingParams {
+		limit: Some(NEW_COUNT_PAGE_SIZE as u32),
+		..ListingParams::default()
+	};
+	match fetch_listing(&format!("/r/{sub}/new.json"), &params, quarantined, false, true).await {
+		Ok(listing) => {
+			let (count, overflow) = count_new_posts(listing.posts, since, &get_filters(&req), utils::sfw_only());
+			Ok(json_response(NewPostCountResponse {
+				subreddit: sub,
+				since,
+				count,
+				overflow,
+				label: if overflow { format!("{count}+") } else { count.to_string() },
+			}))
+		}
+		Err(msg) => Ok(listing_error(msg, &format!("r/{sub}"))),
+	}
+}
+
+/// Count the posts of a page of `/new` made after `since`, and tell whether
+/// there are more than the page holds. That is decided before filtering, as
+/// filtered posts still take up room on the page.
+fn count_new_posts(mut posts: Vec<Post>, since: u64, filters: &HashSet<String>, sfw_only: bool) -> (usize, bool) {
+	let oldest = posts.iter().filter(|post| !post.flags.stickied).map(|post| post.created_ts).min();
+	let overflow = posts.len() >= NEW_COUNT_PAGE_SIZE && oldest.is_some_and(|oldest| oldest > since);
+
+	filter_posts(&mut posts, filters);
+	let count = posts.iter().filter(|post| post.created_ts > since && !(sfw_only && post.flags.nsfw)).count();
+	(count, overflow)
+}
+
+/// JSON API endpoint reporting whether a subreddit exists and can be viewed,
+/// without fetching any of its posts.
+pub async fn status_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sub = req.param("sub").unwrap_or_default();
+
+	if sub == "random" || sub == "randnsfw" {
+		return Ok(json_error("Random subreddits not supported in JSON API".to_string(), 400));
+	}
+
+	match subreddit_status(sub.to_lowercase()).await {
+		Ok(status) => Ok(json_response(status)),
+		Err(err) => Ok(upstream_error(err, 502)),
+	}
+}
+
+// Statuses are cheap to re-check but get asked for in bursts when a page
+// full of links is rendered, so hold on to them for a minute.
+#[cached(size = 500, time = 60, result = true)]
+async fn subreddit_status(sub: String) -> Result<SubredditStatusResponse, UpstreamError> {
+	classify_status(json(format!("/r/{sub}/about.json?raw_json=1"), false).await)
+}
+
+/// Turn the outcome of an about request into a subreddit status. Failures
+/// that say nothing about the subreddit itself are passed on as errors.
+fn classify_status(about: Result<Value, UpstreamError>) -> Result<SubredditStatusResponse, UpstreamError> {
+	let status = |status, over_18| Ok(SubredditStatusResponse { status, over_18 });
+	match about {
+		// Unknown names get redirected to a subreddit search listing
+		Ok(about) if about["kind"] == "t5" => status("public", about["data"]["over18"].as_bool().unwrap_or_default()),
+		Ok(_) => status("not_found", false),
+		Err(msg) => match &*msg {
+			"private" => status("private", false),
+			"banned" => status("banned", false),
+			// Both need an explicit opt-in before Reddit shows anything
+			"quarantined" | "gated" => status("quarantined", false),
+			"over18" => status("public", true),
+			_ if msg.starts_with("Reddit error 404") => status("not_found", false),
+			_ => Err(msg),
+		},
+	}
+}
+
+/// JSON API endpoint for the sidebar widgets of a subreddit, in the order
+/// they are shown.
+pub async fn widgets_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let sub = req.param("sub").unwrap_or_default();
+	let quarantined = can_access_quarantine(&req, &sub);
+
+	if sub == "random" || sub == "randnsfw" {
+		return Ok(json_error("Random subreddits not supported in JSON API".to_string(), 400));
+	}
+
+	match json(format!("/r/{sub}/api/widgets?raw_json=1"), quarantined).await {
+		Ok(response) => Ok(json_response(WidgetsResponse {
+			subreddit: sub,
+			widgets: parse_widgets(&response),
+		})),
+		Err(err @ UpstreamError::Blocked) => Ok(upstream_error(err, 500)),
+		Err(msg) => {
+			let status = match &*msg {
+				"quarantined" | "gated" | "private" => 403,
+				"banned" => 404,
+				_ => 500,
+			};
+			Ok(json_error(about_error(&sub, &msg), status))
+		}
+	}
+}
+
+/// Pick the sidebar widgets out of Reddit's widget listing. `items` holds
+/// every widget by id, while `layout.sidebar.order` says which ones are on
+/// the sidebar and in what order.
+fn parse_widgets(response: &Value) -> Vec<Widget> {
+	let items = &response["items"];
+	let order: Vec<&str> = response["layout"]["sidebar"]["order"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+
+	order.into_iter().filter_map(|id| items.get(id)).map(parse_widget).collect()
+}
+
+fn parse_widget(widget: &Value) -> Widget {
+	let field = |name: &str| widget[name].as_str().unwrap_or_default().to_string();
+
+	match widget["kind"].as_str().unwrap_or_default() {
+		"textarea" => Widget::Text {
+			title: field("shortName"),
+			text: field("text"),
+		},
+		"button" => Widget::Button {
+			title: field("shortName"),
+			description: field("description"),
+			buttons: widget["buttons"]
+				.as_array()
+				.into_iter()
+				.flatten()
+				.filter_map(|button| {
+					Some(WidgetButton {
+						text: button["text"].as_str()?.to_string(),
+						url: format_url(button["url"].as_str().unwrap_or_default()),
+					})
+				})
+				.collect(),
+		},
+		kind => Widget::Unknown {
+			kind: kind.to_string(),
+			title: field("shortName"),
+		},
+	}
+}
+
+/// Most subreddits `/api/subreddits/about` looks up in one request.
+const MAX_BATCH_SUBREDDITS: usize = 50;
+
+/// How many of those lookups run against Reddit at the same time.
+const BATCH_SUBREDDIT_CONCURRENCY: usize = 5;
+
+/// JSON API endpoint for the about info of several subreddits at once.
+pub async fn about_batch_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let names = match batch_subreddit_names(&format!("?{}", req.uri().query().unwrap_or_default())) {
+		Ok(names) => names,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	let permits = std::sync::Arc::new(tokio::sync::Semaphore::new(BATCH_SUBREDDIT_CONCURRENCY));
+	let mut lookups = tokio::task::JoinSet::new();
+	for name in names {
+		let quarantined = can_access_quarantine(&req, &name);
+		let permits = permits.clone();
+		lookups.spawn(utils::with_request_settings(async move {
+			let _permit = permits.acquire_owned().await;
+			let about = subreddit(&name, quarantined, false).await;
+			(name, about)
+		}));
+	}
+
+	let mut response = SubredditsAboutResponse {
+		subreddits: Default::default(),
+		errors: Default::default(),
+	};
+	while let Some(lookup) = lookups.join_next().await {
+		let Ok((name, about)) = lookup else { continue };
+		match batch_about(&name, about) {
+			Ok(sub) => {
+				response.subreddits.insert(name, sub);
+			}
+			Err(msg) => {
+				response.errors.insert(name, msg);
+			}
+		}
+	}
+
+	Ok(json_response(response))
+}
+
+/// Parse and validate the comma-separated `names` of a bulk about request.
+fn batch_subreddit_names(query: &str) -> Result<Vec<String>, String> {
+	let mut names: Vec<String> = Vec::new();
+	for name in param(query, "names").unwrap_or_default().split(',').map(|name| name.trim().trim_start_matches("r/")) {
+		if name.is_empty() || names.iter().any(|seen| seen.eq_ignore_ascii_case(name)) {
+			continue;
+		}
+		if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+			return Err(format!("\"{name}\" is not a valid subreddit name"));
+		}
+		names.push(name.to_string());
+	}
+
+	match names.len() {
+		0 => Err("At least one subreddit is required in ?names=".to_string()),
+		n if n > MAX_BATCH_SUBREDDITS => Err(format!("At most {MAX_BATCH_SUBREDDITS} subreddits can be fetched at once")),
+		_ => Ok(names),
+	}
+}
+
+/// Settle the entry of `name` in a bulk about response, turning unknown and
+/// unservable subreddits into errors.
+fn batch_about(name: &str, about: Result<Subreddit, String>) -> Result<Subreddit, String> {
+	if is_missing(&about) {
+		return Err(format!("r/{name} does not exist"));
+	}
+	match about {
+		Ok(sub) if sub.nsfw && crate::utils::sfw_only() => Err("NSFW content is disabled on this instance".to_string()),
+		Ok(sub) => Ok(sub),
+		Err(msg) => Err(about_error(name, &msg)),
+	}
+}
+
+/// Explain why the about info of `sub` could not be fetched.
+fn about_error(sub: &str, msg: &str) -> String {
+	match msg {
+		"quarantined" | "gated" => format!("r/{sub} is {msg}"),
+		"private" => format!("r/{sub} is a private community"),
+		"banned" => format!("r/{sub} has been banned from Reddit"),
+		_ => msg.to_string(),
+	}
+}
+
+// Moderator lists rarely change, so keep them around a little longer than
+// client::json's 30 second cache.
+#[cached(size = 100, time = 300, result = true)]
+async fn moderators_list(sub: String, quarantined: bool) -> Result<Vec<Moderator>, UpstreamError> {
+	// Build the moderator list URL
+	let path: String = format!("/r/{sub}/about/moderators.json?raw_json=1");
+
+	// Retrieve response
+	json(path, quarantined).await.map(|response| parse_moderators(&response))
+}
+
+/// Drop every cached moderator list and status, returning how many entries
+/// were evicted.
+pub async fn flush_cache() -> usize {
+	let mut moderators = MODERATORS_LIST.lock().await;
+	let mut statuses = SUBREDDIT_STATUS.lock().await;
+	let evicted = moderators.cache_size() + statuses.cache_size();
+	moderators.cache_clear();
+	statuses.cache_clear();
+	evicted
+}
+
+/// Traverse the `about/moderators` JSON tree into a list of moderators.
+fn parse_moderators(response: &Value) -> Vec<Moderator> {
+	response["data"]["children"]
+		.as_array()
+		.unwrap_or(&Vec::new())
+		.iter()
+		.filter_map(|moderator| {
+			let name = moderator["name"].as_str().unwrap_or_default();
+			if name.is_empty() {
+				None
+			} else {
+				Some(Moderator {
+					name: name.to_string(),
+					permissions: moderator["mod_permissions"]
+						.as_array()
+						.map(|permissions| permissions.iter().filter_map(|p| p.as_str().map(ToString::to_string)).collect())
+						.unwrap_or_default(),
+				})
+			}
+		})
+		.collect()
+}
 
 // SUBREDDIT
-async fn subreddit(sub: &str, quarantined: bool) -> Result<Subreddit, String> {
+async fn subreddit(sub: &str, quarantined: bool, over18: bool) -> Result<Subreddit, String> {
 	// Build the Reddit JSON API url
 	let path: String = format!("/r/{sub}/about.json?raw_json=1");
 
 	// Send a request to the url
-	let res = json(path, quarantined).await?;
-
-	// Metadata regarding the subreddit
-	let members: i64 = res["data"]["subscribers"].as_u64().unwrap_or_default() as i64;
-	let active: i64 = res["data"]["accounts_active"].as_u64().unwrap_or_default() as i64;
-
-	// Fetch subreddit icon either from the community_icon or icon_img value
-	let community_icon: &str = res["data"]["community_icon"].as_str().unwrap_or_default();
-	let icon = if community_icon.is_empty() { val(&res, "icon_img") } else { community_icon.to_string() };
-
-	Ok(Subreddit {
-		name: val(&res, "display_name"),
-		title: val(&res, "title"),
-		description: val(&res, "public_description"),
-		info: rewrite_urls(&val(&res, "description_html")),
-		// moderators: moderators_list(sub, quarantined).await.unwrap_or_default(),
-		icon: format_url(&icon),
-		members: format_num(members),
-		active: format_num(active),
-		wiki: res["data"]["wiki_enabled"].as_bool().unwrap_or_default(),
-		nsfw: res["data"]["over18"].as_bool().unwrap_or_default(),
-	})
+	let res = json_gated(path, quarantined, over18).await?;
+
+	Ok(Subreddit::parse(&res["data"]))
+}
+
+/// Whether an about request found that the subreddit doesn't exist. Reddit
+/// answers unknown names with a subreddit search listing or a 404.
+fn is_missing(about: &Result<Subreddit, String>) -> bool {
+	match about {
+		Ok(sub) => sub.name.is_empty(),
+		Err(msg) => msg.starts_with("Reddit error 404"),
+	}
+}
+
+/// How similar a suggestion must be to the name that was asked for, from
+/// `REDLIB_SUBREDDIT_SUGGESTION_THRESHOLD`. `None` turns suggestions off.
+fn suggestion_threshold() -> Option<f64> {
+	match config::get_setting("REDLIB_SUBREDDIT_SUGGESTION_THRESHOLD") {
+		Some(value) if value == "off" => None,
+		Some(value) => value.parse().ok().filter(|threshold| (0.0..=1.0).contains(threshold)).or(Some(0.8)),
+		None => Some(0.8),
+	}
+}
+
+/// Find the subreddit someone most likely meant when asking for `sub`, which
+/// doesn't exist, through Reddit's autocomplete. Only a single match that is
+/// close enough counts, so an ambiguous typo is left alone.
+#[cached(size = 500, time = 600)]
+async fn suggest_subreddit(sub: String) -> Option<String> {
+	let threshold = suggestion_threshold()?;
+	if sub.is_empty() || !sub.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+		return None;
+	}
+
+	let path = format!(
+		"/api/subreddit_autocomplete_v2.json?query={sub}&include_over_18={}&include_profiles=false&limit=10&raw_json=1",
+		!crate::utils::sfw_only()
+	);
+	let response = json(path, false).await.ok()?;
+	let names: Vec<&str> = response["data"]["children"]
+		.as_array()
+		.into_iter()
+		.flatten()
+		.filter(|child| child["kind"] == "t5")
+		.filter_map(|child| child["data"]["display_name"].as_str())
+		.collect();
+	pick_suggestion(&sub, &names, threshold)
+}
+
+/// The only one of `names` at least `threshold` similar to `sub`.
+fn pick_suggestion(sub: &str, names: &[&str], threshold: f64) -> Option<String> {
+	let mut close = names.iter().filter(|name| !name.eq_ignore_ascii_case(sub) && similarity(sub, name) >= threshold);
+	match (close.next(), close.next()) {
+		(Some(name), None) => Some(name.to_string()),
+		_ => None,
+	}
+}
+
+/// Case-insensitive similarity of two names between 0 and 1, from their
+/// edit distance.
+fn similarity(a: &str, b: &str) -> f64 {
+	let a: Vec<char> = a.to_lowercase().chars().collect();
+	let b: Vec<char> = b.to_lowercase().chars().collect();
+	let longest = a.len().max(b.len());
+	if longest == 0 {
+		return 1.0;
+	}
+
+	let mut row: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.iter().enumerate() {
+		let mut diagonal = row[0];
+		row[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let substituted = diagonal + usize::from(ca != cb);
+			diagonal = row[j + 1];
+			row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+		}
+	}
+	1.0 - row[b.len()] as f64 / longest as f64
 }
 
 pub async fn rss(req: Request<Body>) -> Result<Response<Body>, String> {
@@ -766,7 +1276,7 @@ pub async fn rss(req: Request<Body>) -> Result<Response<Body>, String> {
 	let path = format!("/r/{sub}/{sort}.json?{}", req.uri().query().unwrap_or_default());
 
 	// Get subreddit data
-	let subreddit = subreddit(&sub, false).await?;
+	let subreddit = subreddit(&sub, false, false).await?;
 
 	// Get posts
 	let (posts, _) = Post::fetch(&path, false, false).await?;
@@ -780,15 +1290,12 @@ pub async fn rss(req: Request<Body>) -> Result<Response<Body>, String> {
 				.into_iter()
 				.map(|post| Item {
 					title: Some(post.title.to_string()),
+					description: Some(utils::rss_description(&post, &path)),
+					enclosure: utils::rss_enclosure(&post),
 					link: Some(format_url(&utils::get_post_url(&post))),
 					author: Some(post.author.name),
 					content: Some(rewrite_urls(&decode_html(&post.body).unwrap())),
 					pub_date: Some(DateTime::from_timestamp(post.created_ts as i64, 0).unwrap_or_default().to_rfc2822()),
-					description: Some(format!(
-						"<a href='{}{}'>Comments</a>",
-						config::get_setting("REDLIB_FULL_URL").unwrap_or_default(),
-						post.permalink
-					)),
 					..Default::default()
 				})
 				.collect::<Vec<_>>(),
@@ -807,14 +1314,189 @@ pub async fn rss(req: Request<Body>) -> Result<Response<Body>, String> {
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_fetching_subreddit() {
-	let subreddit = subreddit("rust", false).await;
+	let subreddit = subreddit("rust", false, false).await;
 	assert!(subreddit.is_ok());
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn test_gated_and_quarantined() {
-	let quarantined = subreddit("edgy", true).await;
+	let quarantined = subreddit("edgy", true, false).await;
 	assert!(quarantined.is_ok());
-	let gated = subreddit("drugs", true).await;
+	let gated = subreddit("drugs", true, false).await;
 	assert!(gated.is_ok());
 }
+
+#[test]
+fn test_parsing_moderators() {
+	let response: Value = serde_json::from_str(
+		r#"{"kind":"UserList","data":{"children":[{"name":"spez","mod_permissions":["all"]},{"name":"AutoModerator","mod_permissions":["posts","wiki"]},{"name":""}]}}"#,
+	)
+	.unwrap();
+	let moderators = parse_moderators(&response);
+	assert_eq!(moderators.len(), 2);
+	assert_eq!(moderators[0].name, "spez");
+	assert_eq!(moderators[0].permissions, vec!["all".to_string()]);
+	assert_eq!(moderators[1].permissions, vec!["posts".to_string(), "wiki".to_string()]);
+}
+
+#[test]
+fn test_geo_filter() {
+	assert_eq!(geo_filter("popular", ""), Ok(Some("GLOBAL".to_owned())));
+	assert_eq!(geo_filter("all", ""), Ok(None));
+	assert_eq!(geo_filter("popular", "t=day&geo=us"), Ok(Some("US".to_owned())));
+	assert_eq!(geo_filter("all", "geo_filter=US_CA"), Ok(Some("US_CA".to_owned())));
+	assert_eq!(geo_filter("rust", "geo=US"), Ok(None));
+	assert!(geo_filter("popular", "geo=ZZ").is_err());
+}
+
+#[test]
+fn test_best_sort_routing() {
+	assert_eq!(listing_path("popular", "best"), Ok("/best.json".to_string()));
+	assert_eq!(listing_path("rust+linux", "top"), Ok("/r/rust%2Blinux/top.json".to_string()));
+	assert!(listing_path("rust", "best").is_err());
+}
+
+#[test]
+fn test_homepage_path() {
+	let some = |value: &str| Some(value.to_string());
+	assert_eq!(homepage_path(None, None), Ok(None));
+	assert_eq!(homepage_path(some("rust"), None), Ok(some("/r/rust")));
+	assert_eq!(homepage_path(some(" r/rust+linux "), some("")), Ok(some("/r/rust+linux")));
+	assert!(homepage_path(some("../settings"), None).is_err());
+	assert!(homepage_path(some("rust"), some("news")).is_err());
+	assert!(homepage_path(None, some("missing")).unwrap_err().contains("REDLIB_COLLECTIONS"));
+}
+
+#[test]
+fn test_batch_subreddit_names() {
+	assert_eq!(batch_subreddit_names("?names=rust,%20r/linux,Rust,,"), Ok(vec!["rust".to_string(), "linux".to_string()]));
+	assert!(batch_subreddit_names("?names=").is_err());
+	assert!(batch_subreddit_names("?names=rust,../settings").unwrap_err().contains("not a valid"));
+
+	let too_many = (0..=MAX_BATCH_SUBREDDITS).map(|i| format!("sub{i}")).collect::<Vec<_>>().join(",");
+	assert!(batch_subreddit_names(&format!("?names={too_many}")).unwrap_err().starts_with("At most"));
+
+	assert_eq!(about_error("pics", "banned"), "r/pics has been banned from Reddit");
+	assert_eq!(about_error("secret", "private"), "r/secret is a private community");
+
+	// Reddit answers unknown names with an empty about page or a 404
+	assert_eq!(batch_about("nosuchsub", Ok(Subreddit::default())).err().as_deref(), Some("r/nosuchsub does not exist"));
+	assert_eq!(
+		batch_about("gone", Err("Reddit error 404 \"Not Found\"".to_string())).err().as_deref(),
+		Some("r/gone does not exist")
+	);
+	assert_eq!(batch_about("secret", Err("private".to_string())).err().as_deref(), Some("r/secret is a private community"));
+	let rust = Subreddit {
+		name: "rust".to_string(),
+		..Subreddit::default()
+	};
+	assert_eq!(batch_about("rust", Ok(rust)).map(|sub| sub.name), Ok("rust".to_string()));
+}
+
+#[test]
+fn test_classify_subreddit_status() {
+	let status = |about| classify_status(about).map(|status| (status.status, status.over_18));
+	let about = serde_json::json!({ "kind": "t5", "data": { "display_name": "rust", "over18": false } });
+	assert_eq!(status(Ok(about)), Ok(("public", false)));
+	assert_eq!(status(Ok(serde_json::json!({ "kind": "Listing", "data": { "children": [] } }))), Ok(("not_found", false)));
+	assert_eq!(status(Err("Reddit error 404 \"\": \"Not Found\" | /r/x/about.json".into())), Ok(("not_found", false)));
+	assert_eq!(status(Err("private".into())), Ok(("private", false)));
+	assert_eq!(status(Err("banned".into())), Ok(("banned", false)));
+	assert_eq!(status(Err("quarantined".into())), Ok(("quarantined", false)));
+	assert_eq!(status(Err("over18".into())), Ok(("public", true)));
+	assert!(status(Err("Reddit rate limit exceeded".into())).is_err());
+}
+
+#[test]
+fn test_parse_widgets() {
+	let response = serde_json::json!({
+		"items": {
+			"widget_rules": { "kind": "subreddit-rules", "shortName": "Rules", "data": [] },
+			"widget_about": { "kind": "textarea", "shortName": "About", "text": "Welcome to **r/rust**", "textHtml": "<p>...</p>" },
+			"widget_links": {
+				"kind": "button",
+				"shortName": "Links",
+				"description": "Useful links",
+				"buttons": [
+					{ "kind": "text", "text": "Book", "url": "https://doc.rust-lang.org/book/" },
+					{ "kind": "text", "text": "Wiki", "url": "https://www.reddit.com/r/rust/wiki" },
+					{ "kind": "image", "url": "https://example.com" }
+				]
+			},
+			"widget_hidden": { "kind": "textarea", "shortName": "Not on the sidebar", "text": "" }
+		},
+		"layout": { "sidebar": { "order": ["widget_about", "widget_links", "widget_rules", "widget_missing"] } }
+	});
+
+	let widgets = serde_json::to_value(parse_widgets(&response)).unwrap();
+	assert_eq!(widgets.as_array().unwrap().len(), 3);
+	assert_eq!(widgets[0], serde_json::json!({ "type": "text", "title": "About", "text": "Welcome to **r/rust**" }));
+	assert_eq!(widgets[1]["type"], "button");
+	assert_eq!(
+		widgets[1]["buttons"],
+		serde_json::json!([{ "text": "Book", "url": "https://doc.rust-lang.org/book/" }, { "text": "Wiki", "url": "/r/rust/wiki" }])
+	);
+	assert_eq!(widgets[2], serde_json::json!({ "type": "unknown", "kind": "subreddit-rules", "title": "Rules" }));
+}
+
+#[test]
+fn test_subreddit_suggestions() {
+	assert!((similarity("programing", "programming") - 10.0 / 11.0).abs() < 1e-9);
+	assert_eq!(similarity("Rust", "rust"), 1.0);
+	assert_eq!(similarity("abc", "xyz"), 0.0);
+
+	let candidates = ["programming", "ProgrammerHumor", "prog"];
+	assert_eq!(pick_suggestion("programing", &candidates, 0.8), Some("programming".to_string()));
+	// Nothing close enough, or more than one candidate, suggests nothing
+	assert_eq!(pick_suggestion("programing", &["ProgrammerHumor", "prog"], 0.8), None);
+	assert_eq!(pick_suggestion("pic", &["pics", "pici"], 0.7), None);
+	assert_eq!(pick_suggestion("rust", &["Rust"], 0.8), None);
+
+	assert!(is_missing(&Ok(Subreddit::default())));
+	assert!(is_missing(&Err("Reddit error 404 \"\": \"Not Found\" | /r/x/about.json".to_string())));
+	assert!(!is_missing(&Err("private".to_string())));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_count_new_posts() {
+	let post = |id: &str, sub: &str, created: u64, nsfw: bool| {
+		let post = serde_json::json!({ "kind": "t3", "data": { "id": id, "subreddit": sub, "is_self": true, "created_utc": created, "over_18": nsfw } });
+		async move { utils::parse_post(&post, true).await }
+	};
+	let page = vec![
+		post("a", "rust", 300, false).await,
+		post("b", "filtered", 250, false).await,
+		post("c", "rust", 200, true).await,
+		post("d", "rust", 100, false).await,
+	];
+	let filters = HashSet::from(["filtered".to_string()]);
+	assert_eq!(count_new_posts(page, 150, &filters, true), (1, false));
+
+	let mut full = Vec::new();
+	for i in 0..NEW_COUNT_PAGE_SIZE as u64 {
+		full.push(post("p", "rust", 1000 + i, false).await);
+	}
+	assert_eq!(count_new_posts(full, 500, &HashSet::new(), false), (NEW_COUNT_PAGE_SIZE, true));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_trim_subreddit_details() {
+	let post = |id: &str, sub: &str| {
+		let post = serde_json::json!({ "kind": "t3", "data": { "id": id, "subreddit": sub, "sr_detail": { "display_name": sub, "title": sub } } });
+		async move { utils::parse_post(&post, true).await }
+	};
+	let mut posts = vec![post("a", "rust").await, post("b", "RUST").await, post("c", "crosspost_source").await];
+	let rust = Subreddit {
+		name: "rust".to_string(),
+		..Subreddit::default()
+	};
+	trim_subreddit_details(&mut posts, &rust);
+	let details = posts.iter().map(|post| post.subreddit_detail.is_some()).collect::<Vec<_>>();
+	assert_eq!(details, vec![false, false, true]);
+	assert_eq!(posts[0].community, "rust");
+
+	// Front page listings carry no subreddit of their own
+	let mut posts = vec![post("a", "rust").await];
+	trim_subreddit_details(&mut posts, &Subreddit::default());
+	assert!(posts[0].subreddit_detail.is_some());
+}
@@ -1,15 +1,23 @@
 #![allow(clippy::cmp_owned)]
 
 // CRATES
-use crate::client::json;
-use crate::json::{json_error, json_response, truncate_posts, UserResponse, DEFAULT_BODY_LIMIT};
+use crate::client::{json, UpstreamError};
+use crate::json::{
+	add_body_text, json_error, json_response, listing_error, ndjson_stream, truncate_posts, upstream_error, ExportSummary, ListingPage, NdjsonWriter, SubredditActivity,
+	UserMulti, UserMultisResponse, UserResponse, UserStatsResponse, UserSubredditsResponse, DEFAULT_BODY_LIMIT,
+};
 use crate::server::RequestExt;
-use crate::utils::{error, filter_posts, format_url, get_filters, nsfw_landing, param, setting, template, Post, Preferences, User};
-use crate::{config, utils};
+use crate::utils::{
+	bad_request, error, fetch_listing, filter_listing, filter_posts, format_url, get_filters, nsfw_landing, param, setting, template, Listing, ListingParams, Post, Preferences,
+	SeenPosts, User,
+};
+use crate::{config, cursor, utils};
 use askama::Template;
 use chrono::DateTime;
 use htmlescape::decode_html;
 use hyper::{Body, Request, Response};
+use serde_json::Value;
+use std::future::Future;
 use time::{macros::format_description, OffsetDateTime};
 
 // STRUCTS
@@ -20,6 +28,8 @@ struct UserTemplate {
 	posts: Vec<Post>,
 	sort: (String, String),
 	ends: (String, String),
+	/// Listing items seen so far, passed back to Reddit as `count`
+	count: u64,
 	/// "overview", "comments", or "submitted"
 	listing: String,
 	prefs: Preferences,
@@ -35,16 +45,17 @@ struct UserTemplate {
 	no_posts: bool,
 }
 
+/// Reddit has no `best` ordering for user listings.
+const BEST_SORT_UNSUPPORTED: &str = "Sorting by best is not available on user pages";
+
 // FUNCTIONS
 pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 	let listing = req.param("listing").unwrap_or_else(|| "overview".to_string());
 
 	// Build the Reddit JSON API path
-	let path = format!(
-		"/user/{}/{listing}.json?{}&raw_json=1",
-		req.param("name").unwrap_or_else(|| "reddit".to_string()),
-		req.uri().query().unwrap_or_default(),
-	);
+	let base = format!("/user/{}/{listing}.json", req.param("name").unwrap_or_else(|| "reddit".to_string()));
+	let params = ListingParams::clamped(req.uri().query().unwrap_or_default());
+	let path = params.path(&base);
 	let url = String::from(req.uri().path_and_query().map_or("", |val| val.as_str()));
 	let redirect_url = url[1..].replace('?', "%3F").replace('&', "%26");
 
@@ -52,6 +63,10 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 	let sort = param(&path, "sort").unwrap_or_default();
 	let username = req.param("name").unwrap_or_default();
 
+	if sort == "best" {
+		return bad_request(req, BEST_SORT_UNSUPPORTED).await;
+	}
+
 	// Retrieve info from user about page.
 	let user = user(&username).await.unwrap_or_default();
 
@@ -70,6 +85,7 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 			posts: Vec::new(),
 			sort: (sort, param(&path, "t").unwrap_or_default()),
 			ends: (param(&path, "after").unwrap_or_default(), String::new()),
+			count: params.count,
 			listing,
 			prefs: Preferences::new(&req),
 			url,
@@ -81,8 +97,9 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 		}))
 	} else {
 		// Request user posts/comments from Reddit
-		match Post::fetch(&path, false, false).await {
-			Ok((mut posts, after)) => {
+		match fetch_listing(&base, &params, false, false, false).await {
+			Ok(Listing { mut posts, after, count, .. }) => {
+				filter_listing(&mut posts, &path);
 				let (_, all_posts_filtered) = filter_posts(&mut posts, &filters);
 				let no_posts = posts.is_empty();
 				let all_posts_hidden_nsfw = !no_posts && (posts.iter().all(|p| p.flags.nsfw) && setting(&req, "show_nsfw") != "on");
@@ -90,7 +107,8 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 					user,
 					posts,
 					sort: (sort, param(&path, "t").unwrap_or_default()),
-					ends: (param(&path, "after").unwrap_or_default(), after),
+					ends: (param(&path, "after").unwrap_or_default(), after.unwrap_or_default()),
+					count,
 					listing,
 					prefs: Preferences::new(&req),
 					url,
@@ -111,11 +129,21 @@ pub async fn profile(req: Request<Body>) -> Result<Response<Body>, String> {
 pub async fn profile_json(req: Request<Body>) -> Result<Response<Body>, String> {
 	let listing = req.param("listing").unwrap_or_else(|| "overview".to_string());
 
-	let path = format!(
-		"/user/{}/{listing}.json?{}&raw_json=1",
-		req.param("name").unwrap_or_else(|| "reddit".to_string()),
-		req.uri().query().unwrap_or_default(),
-	);
+	let query = match cursor::open_query(req.uri().query().unwrap_or_default()) {
+		Ok(query) => query,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	let base = format!("/user/{}/{listing}.json", req.param("name").unwrap_or_else(|| "reddit".to_string()));
+	let params = match ListingParams::parse(&query) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let path = params.path(&base);
+
+	if param(&path, "sort").is_some_and(|sort| sort == "best") {
+		return Ok(json_error(BEST_SORT_UNSUPPORTED.to_string(), 400));
+	}
 
 	// Parse body_limit param (default: 400 chars)
 	let body_limit: Option<usize> = param(&path, "body_limit")
@@ -130,23 +158,316 @@ pub async fn profile_json(req: Request<Body>) -> Result<Response<Body>, String>
 		return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
 	}
 
-	match Post::fetch(&path, false, true).await {
-		Ok((mut posts, after)) => {
+	let mut seen = match SeenPosts::from_query(&path) {
+		Ok(seen) => seen,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	match fetch_listing(&base, &params, false, false, true).await {
+		Ok(Listing { mut posts, after, count, skipped }) => {
+			filter_listing(&mut posts, &path);
+			if let Some(seen) = seen.as_mut() {
+				seen.dedup(&mut posts);
+			}
+			add_body_text(&mut posts, &path);
 			// Truncate post bodies for list response
 			truncate_posts(&mut posts, body_limit);
+			if utils::wants_emojis(&path) {
+				utils::replace_listing_emojis(&mut posts, true).await;
+			}
 			let response = UserResponse {
 				user,
-				posts,
-				after: if after.is_empty() { None } else { Some(after) },
+				page: ListingPage::new(Listing { posts, after, count, skipped }, seen, &path),
 			};
 			Ok(json_response(response))
 		}
-		Err(msg) => Ok(json_error(msg, 500)),
+		Err(msg) => Ok(listing_error(msg, &format!("u/{username}"))),
+	}
+}
+
+/// Number of recent overview items sampled when computing user statistics.
+const STATS_SAMPLE_SIZE: u64 = 100;
+
+/// JSON API endpoint for approximate user statistics.
+pub async fn stats_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let username = req.param("name").unwrap_or_default();
+
+	let about = match json(format!("/user/{username}/about.json?raw_json=1"), false).await {
+		Ok(about) => about,
+		Err(err) => return Ok(upstream_error(err, 500)),
+	};
+
+	// Check NSFW gating (server-side SFW_ONLY only)
+	if about["data"]["subreddit"]["over_18"].as_bool().unwrap_or_default() && utils::sfw_only() {
+		return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
+	}
+
+	let overview = match json(format!("/user/{username}/overview.json?limit={STATS_SAMPLE_SIZE}&raw_json=1"), false).await {
+		Ok(overview) => overview,
+		Err(err) => return Ok(upstream_error(err, 500)),
+	};
+
+	let (sampled_posts, sampled_comments) = count_activity(&overview, utils::sfw_only());
+	let created = about["data"]["created_utc"].as_f64().unwrap_or_default().round() as i64;
+
+	Ok(json_response(UserStatsResponse {
+		name: about["data"]["name"].as_str().unwrap_or(&username).to_owned(),
+		link_karma: about["data"]["link_karma"].as_i64().unwrap_or_default(),
+		comment_karma: about["data"]["comment_karma"].as_i64().unwrap_or_default(),
+		account_age_days: (OffsetDateTime::now_utc().unix_timestamp() - created).max(0) / 86_400,
+		sampled_posts,
+		sampled_comments,
+		comment_to_post_ratio: if sampled_posts == 0 {
+			None
+		} else {
+			Some(sampled_comments as f64 / sampled_posts as f64)
+		},
+		note: format!("Post and comment counts are approximations based on the last {STATS_SAMPLE_SIZE} items of public activity"),
+	}))
+}
+
+/// Count the submissions and comments in a user overview listing. NSFW
+/// items are left out when `skip_nsfw` is set.
+fn count_activity(overview: &Value, skip_nsfw: bool) -> (u64, u64) {
+	overview["data"]["children"]
+		.as_array()
+		.unwrap_or(&Vec::new())
+		.iter()
+		.filter(|item| !(skip_nsfw && item["data"]["over_18"].as_bool().unwrap_or_default()))
+		.fold((0, 0), |(posts, comments), item| match item["kind"].as_str() {
+			Some("t3") => (posts + 1, comments),
+			Some("t1") => (posts, comments + 1),
+			_ => (posts, comments),
+		})
+}
+
+/// Number of overview pages sampled when aggregating a user's subreddits.
+const SUBREDDITS_SAMPLE_PAGES: usize = 2;
+
+/// JSON API endpoint listing the subreddits a user was recently active in.
+pub async fn subreddits_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let username = req.param("name").unwrap_or_default();
+	let user = match user(&username).await {
+		Ok(user) => user,
+		Err(err) => return Ok(upstream_error(err, 500)),
+	};
+
+	// Check NSFW gating (server-side SFW_ONLY only)
+	if user.nsfw && utils::sfw_only() {
+		return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
+	}
+
+	let mut items: Vec<Value> = Vec::new();
+	let mut after = String::new();
+	for _ in 0..SUBREDDITS_SAMPLE_PAGES {
+		let overview = match json(format!("/user/{username}/overview.json?limit={STATS_SAMPLE_SIZE}&after={after}&raw_json=1"), false).await {
+			Ok(overview) => overview,
+			Err(err) => return Ok(upstream_error(err, 500)),
+		};
+		items.extend(overview["data"]["children"].as_array().cloned().unwrap_or_default());
+		after = overview["data"]["after"].as_str().unwrap_or_default().to_string();
+		if after.is_empty() {
+			break;
+		}
 	}
+
+	let subreddits = aggregate_subreddits(&items, utils::sfw_only());
+	Ok(json_response(UserSubredditsResponse {
+		name: user.name,
+		sampled_items: items.len(),
+		subreddits,
+		note: format!("Based on the last {} items of public activity only", items.len()),
+	}))
+}
+
+/// JSON API endpoint for the public multireddits of a user.
+pub async fn multis_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let username = req.param("name").unwrap_or_default();
+	if username.is_empty() || !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+		return Ok(json_error(format!("\"{username}\" is not a valid username"), 400));
+	}
+
+	match json(format!("/api/multi/user/{username}.json?raw_json=1"), false).await {
+		Ok(res) => Ok(json_response(UserMultisResponse {
+			name: username,
+			multireddits: parse_user_multis(&res, utils::sfw_only()),
+		})),
+		Err(msg) if msg.starts_with("Reddit error 404") => Ok(json_error(format!("u/{username} does not exist"), 404)),
+		Err(msg) => Ok(listing_error(msg, &format!("u/{username}"))),
+	}
+}
+
+/// Read the multireddits Reddit lists for a user. Only public ones are
+/// listed to anonymous clients, but anything else that slips through is
+/// left out, as are NSFW multireddits when `skip_nsfw` is set.
+fn parse_user_multis(res: &Value, skip_nsfw: bool) -> Vec<UserMulti> {
+	res
+		.as_array()
+		.into_iter()
+		.flatten()
+		.map(|multi| &multi["data"])
+		.filter(|data| data["visibility"] == "public" && !(skip_nsfw && data["over_18"].as_bool().unwrap_or_default()))
+		.map(|data| UserMulti {
+			name: data["name"].as_str().unwrap_or_default().to_string(),
+			title: data["display_name"].as_str().unwrap_or_default().to_string(),
+			subreddit_count: data["subreddits"].as_array().map_or(0, Vec::len),
+			description: data["description_md"].as_str().unwrap_or_default().to_string(),
+		})
+		.collect()
+}
+
+/// Tally posts and comments per subreddit, most active subreddit first.
+/// NSFW items are left out when `skip_nsfw` is set.
+fn aggregate_subreddits(items: &[Value], skip_nsfw: bool) -> Vec<SubredditActivity> {
+	let mut subreddits: Vec<SubredditActivity> = Vec::new();
+
+	for item in items.iter().filter(|item| !(skip_nsfw && item["data"]["over_18"].as_bool().unwrap_or_default())) {
+		let Some(name) = item["data"]["subreddit"].as_str() else {
+			continue;
+		};
+		let index = match subreddits.iter().position(|s| s.subreddit == name) {
+			Some(index) => index,
+			None => {
+				subreddits.push(SubredditActivity {
+					subreddit: name.to_string(),
+					posts: 0,
+					comments: 0,
+				});
+				subreddits.len() - 1
+			}
+		};
+		match item["kind"].as_str() {
+			Some("t3") => subreddits[index].posts += 1,
+			Some("t1") => subreddits[index].comments += 1,
+			_ => {}
+		}
+	}
+
+	subreddits.sort_by(|a, b| (b.posts + b.comments).cmp(&(a.posts + a.comments)).then_with(|| a.subreddit.cmp(&b.subreddit)));
+	subreddits
+}
+
+/// Pages `/api/u/:name/export` fetches at most when `REDLIB_EXPORT_MAX_PAGES`
+/// isn't set.
+const DEFAULT_EXPORT_MAX_PAGES: usize = 10;
+
+/// Items requested per export page, the most Reddit hands out at once.
+pub(crate) const EXPORT_PAGE_SIZE: usize = 100;
+
+/// Export endpoint streaming a user's submissions or comments as NDJSON, one
+/// item per line, paging through their history up to the instance's cap. The
+/// final line is an [`ExportSummary`].
+pub async fn export_json(req: Request<Body>) -> Result<Response<Body>, String> {
+	let username = req.param("name").unwrap_or_default();
+	let query = match cursor::open_query(req.uri().query().unwrap_or_default()) {
+		Ok(query) => format!("?{query}"),
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+	let (listing, max_pages) = match export_params(&query, export_page_cap()) {
+		Ok(params) => params,
+		Err(msg) => return Ok(json_error(msg, 400)),
+	};
+
+	let user = match user(&username).await {
+		Ok(user) => user,
+		Err(err) => return Ok(upstream_error(err, 500)),
+	};
+
+	// Check NSFW gating (server-side SFW_ONLY only)
+	if user.nsfw && utils::sfw_only() {
+		return Ok(json_error("NSFW content is disabled on this instance".to_string(), 403));
+	}
+
+	let base = format!("/user/{username}/{listing}.json");
+	let after = param(&query, "after").unwrap_or_default();
+	Ok(ndjson_stream(move |writer| {
+		export_pages(writer, max_pages, after, move |after| {
+			let base = base.clone();
+			async move { fetch_export_page(&base, &ListingParams::default(), after, false).await }
+		})
+	}))
+}
+
+/// Fetch the page of an export or archive after the `after` cursor, along
+/// with the cursor of the page following it, empty on the last page.
+pub(crate) async fn fetch_export_page(base: &str, params: &ListingParams, after: String, quarantine: bool) -> Result<(Vec<Post>, String), String> {
+	let params = ListingParams {
+		limit: Some(EXPORT_PAGE_SIZE as u32),
+		after: (!after.is_empty()).then_some(after),
+		..params.clone()
+	};
+	let page = fetch_listing(base, &params, quarantine, false, true).await?;
+	Ok((page.posts, page.after.unwrap_or_default()))
+}
+
+/// Stream up to `max_pages` pages of a user's history, starting after
+/// `after`, to `writer`, ending with an [`ExportSummary`]. `fetch_page`
+/// fetches the page after a cursor.
+async fn export_pages<F, Fut>(mut writer: NdjsonWriter, max_pages: usize, mut after: String, mut fetch_page: F)
+where
+	F: FnMut(String) -> Fut,
+	Fut: Future<Output = Result<(Vec<Post>, String), String>>,
+{
+	let mut summary = ExportSummary {
+		pages: 0,
+		items: 0,
+		more: false,
+		after: None,
+		error: None,
+	};
+
+	while summary.pages < max_pages {
+		let (mut posts, next) = match fetch_page(after.clone()).await {
+			Ok(page) => page,
+			Err(msg) => {
+				summary.error = Some(msg);
+				break;
+			}
+		};
+		summary.pages += 1;
+		if utils::sfw_only() {
+			posts.retain(|post| !post.flags.nsfw);
+		}
+		if !writer.write_all(&posts).await {
+			return;
+		}
+		summary.items += posts.len();
+		after = next;
+		if after.is_empty() {
+			break;
+		}
+	}
+
+	summary.more = summary.error.is_some() || !after.is_empty();
+	summary.after = (!after.is_empty()).then(|| cursor::seal(&after));
+	writer.write(&summary).await;
+}
+
+/// Most pages a single export or archive may fetch, from
+/// `REDLIB_EXPORT_MAX_PAGES`.
+pub(crate) fn export_page_cap() -> usize {
+	config::get_setting("REDLIB_EXPORT_MAX_PAGES")
+		.and_then(|pages| pages.parse().ok())
+		.filter(|pages| *pages > 0)
+		.unwrap_or(DEFAULT_EXPORT_MAX_PAGES)
+}
+
+/// Listing and page count an export asked for, clamping `max_pages` to `cap`.
+fn export_params(query: &str, cap: usize) -> Result<(&'static str, usize), String> {
+	let listing = match param(query, "type").as_deref() {
+		None | Some("submitted") => "submitted",
+		Some("comments") => "comments",
+		Some(other) => return Err(format!("Unknown export type {other:?}, expected submitted or comments")),
+	};
+	let pages = match param(query, "max_pages") {
+		Some(pages) => pages.parse::<usize>().ok().filter(|pages| *pages > 0).ok_or("max_pages must be a positive number")?,
+		None => cap,
+	};
+	Ok((listing, pages.min(cap)))
 }
 
 // USER
-async fn user(name: &str) -> Result<User, String> {
+async fn user(name: &str) -> Result<User, UpstreamError> {
 	// Build the Reddit JSON API path
 	let path: String = format!("/user/{name}/about.json?raw_json=1");
 
@@ -204,6 +525,8 @@ pub async fn rss(req: Request<Body>) -> Result<Response<Body>, String> {
 				.into_iter()
 				.map(|post| Item {
 					title: Some(post.title.to_string()),
+					description: Some(utils::rss_description(&post, &path)),
+					enclosure: utils::rss_enclosure(&post),
 					link: Some(format_url(&utils::get_post_url(&post))),
 					author: Some(post.author.name),
 					pub_date: Some(DateTime::from_timestamp(post.created_ts as i64, 0).unwrap_or_default().to_rfc2822()),
@@ -230,3 +553,108 @@ async fn test_fetching_user() {
 	assert!(user.is_ok());
 	assert!(user.unwrap().karma > 100);
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_export_stream() {
+	let page = |ids: &'static [&'static str], next: &'static str| async move {
+		let mut posts = Vec::new();
+		for id in ids {
+			posts.push(utils::parse_post(&serde_json::json!({ "kind": "t3", "data": { "id": id, "title": id, "is_self": true } }), true).await);
+		}
+		Ok((posts, next.to_string()))
+	};
+	let fetch = move |after: String| async move {
+		match after.as_str() {
+			"" => page(&["a", "b"], "t3_b").await,
+			"t3_b" => page(&["c"], "").await,
+			_ => Err("Reddit is down".to_string()),
+		}
+	};
+	let lines = |res: Response<Body>| async move {
+		let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		String::from_utf8(body.to_vec())
+			.unwrap()
+			.lines()
+			.map(|line| serde_json::from_str::<Value>(line).unwrap())
+			.collect::<Vec<_>>()
+	};
+
+	// Every page is streamed, then the summary
+	let all = lines(ndjson_stream(move |writer| export_pages(writer, 5, String::new(), fetch))).await;
+	assert_eq!(
+		all.iter().map(|line| line["id"].as_str().unwrap_or("summary")).collect::<Vec<_>>(),
+		vec!["a", "b", "c", "summary"]
+	);
+	assert_eq!(all[3], serde_json::json!({ "pages": 2, "items": 3, "more": false }));
+
+	// Stopping at the page cap hands out a cursor to resume from
+	let capped = lines(ndjson_stream(move |writer| export_pages(writer, 1, String::new(), fetch))).await;
+	assert_eq!((capped.len(), &capped[2]["more"]), (3, &Value::Bool(true)));
+	assert_eq!(cursor::open(capped[2]["after"].as_str().unwrap()).as_deref(), Some("t3_b"));
+
+	// So does a page that couldn't be fetched
+	let failed = lines(ndjson_stream(move |writer| export_pages(writer, 5, "t3_c".to_string(), fetch))).await;
+	assert_eq!(failed.len(), 1);
+	assert_eq!((&failed[0]["error"], failed[0]["pages"].as_u64()), (&Value::from("Reddit is down"), Some(0)));
+	assert_eq!(cursor::open(failed[0]["after"].as_str().unwrap()).as_deref(), Some("t3_c"));
+}
+
+#[test]
+fn test_counting_activity() {
+	let overview: Value = serde_json::from_str(
+		r#"{"data":{"children":[{"kind":"t3","data":{"over_18":false}},{"kind":"t1","data":{"over_18":false}},{"kind":"t1","data":{"over_18":true}},{"kind":"more","data":{}}]}}"#,
+	)
+	.unwrap();
+	assert_eq!(count_activity(&overview, false), (1, 2));
+	assert_eq!(count_activity(&overview, true), (1, 1));
+}
+
+#[test]
+fn test_aggregating_subreddits() {
+	let items: Vec<Value> = serde_json::from_str(
+		r#"[{"kind":"t1","data":{"subreddit":"rust","over_18":false}},{"kind":"t3","data":{"subreddit":"linux","over_18":false}},{"kind":"t3","data":{"subreddit":"rust","over_18":false}},{"kind":"t1","data":{"subreddit":"nsfw","over_18":true}}]"#,
+	)
+	.unwrap();
+
+	let subreddits = aggregate_subreddits(&items, false);
+	let names: Vec<&str> = subreddits.iter().map(|s| s.subreddit.as_str()).collect();
+	assert_eq!(names, vec!["rust", "linux", "nsfw"]);
+	assert_eq!((subreddits[0].posts, subreddits[0].comments), (1, 1));
+
+	let subreddits = aggregate_subreddits(&items, true);
+	assert!(subreddits.iter().all(|s| s.subreddit != "nsfw"));
+}
+
+#[test]
+fn test_export_params() {
+	assert_eq!(export_params("?", 10), Ok(("submitted", 10)));
+	assert_eq!(export_params("?type=comments&max_pages=3", 10), Ok(("comments", 3)));
+	assert_eq!(export_params("?type=submitted&max_pages=500", 10), Ok(("submitted", 10)));
+	assert_eq!(export_params("?max_pages=0", 10), Err("max_pages must be a positive number".to_string()));
+	assert_eq!(export_params("?max_pages=all", 10), Err("max_pages must be a positive number".to_string()));
+	assert_eq!(
+		export_params("?type=saved", 10),
+		Err("Unknown export type \"saved\", expected submitted or comments".to_string())
+	);
+}
+
+#[test]
+fn test_parsing_user_multis() {
+	let multi = |name: &str, visibility: &str, nsfw: bool| {
+		serde_json::json!({ "kind": "LabeledMulti", "data": {
+			"name": name,
+			"display_name": name.to_uppercase(),
+			"description_md": "",
+			"subreddits": [{ "name": "rust" }, { "name": "golang" }],
+			"visibility": visibility,
+			"over_18": nsfw
+		} })
+	};
+	let res = serde_json::json!([multi("langs", "public", false), multi("drafts", "private", false), multi("after_dark", "public", true)]);
+
+	let multis = parse_user_multis(&res, false);
+	assert_eq!(multis.iter().map(|multi| multi.name.as_str()).collect::<Vec<_>>(), vec!["langs", "after_dark"]);
+	assert_eq!((multis[0].title.as_str(), multis[0].subreddit_count), ("LANGS", 2));
+	assert_eq!(parse_user_multis(&res, true).len(), 1);
+	assert!(parse_user_multis(&serde_json::json!([]), false).is_empty());
+}
@@ -5,12 +5,19 @@ use crate::config::{self, get_setting};
 //
 // CRATES
 //
-use crate::{client::json, server::RequestExt};
+use crate::client::{json, json_gated, UpstreamError};
+use crate::cursor;
+use crate::server::{RequestContext, RequestExt, REQUEST_CONTEXT};
 use askama::Template;
+use cached::proc_macro::cached;
+use cached::Cached;
+use chrono_tz::Tz;
 use cookie::Cookie;
+use futures_util::future::join_all;
+use htmlescape::{decode_html, encode_minimal};
 use hyper::{Body, Request, Response};
 use libflate::deflate::{Decoder, Encoder};
-use log::error;
+use log::{error, warn};
 use regex::Regex;
 use revision::revisioned;
 use rust_embed::RustEmbed;
@@ -19,6 +26,7 @@ use serde_json::Value;
 use serde_json_path::{JsonPath, JsonPathExt};
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::future::Future;
 use std::io::{Read, Write};
 use std::str::FromStr;
 use std::string::ToString;
@@ -30,6 +38,8 @@ use url::Url;
 
 static HTML_TAG_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
 static HTML_COMMENT_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<!--.*?-->").unwrap());
+/// Reddit's `>!spoiler!<` markup, which can't span paragraphs.
+static SPOILER_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r">!((?:[^\n]|\n[^\n])+?)!<").unwrap());
 
 /// Strip HTML tags from a string, returning plain text.
 pub fn strip_html(s: &str) -> String {
@@ -70,6 +80,74 @@ pub fn truncate_body(s: &str, limit: usize) -> (String, bool) {
 	}
 }
 
+/// Part of a markdown body hidden behind spoiler markup, as byte offsets
+/// of the hidden text itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SpoilerSpan {
+	pub start: usize,
+	pub end: usize,
+}
+
+/// Find the `>!spoilers!<` of a markdown body. HTML bodies have them
+/// rendered already and never match.
+pub fn inline_spoilers(md: &str) -> Vec<SpoilerSpan> {
+	SPOILER_REGEX
+		.captures_iter(md)
+		.filter_map(|caps| caps.get(1))
+		.map(|hidden| SpoilerSpan {
+			start: hidden.start(),
+			end: hidden.end(),
+		})
+		.collect()
+}
+
+/// Convert markdown to plain text for clients that cannot render it. Markup
+/// is dropped, links and images keep only their text, whitespace inside a
+/// block is collapsed and each block ends up on its own line. Code blocks
+/// keep their line breaks.
+pub fn markdown_to_text(md: &str) -> String {
+	use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+
+	let mut blocks: Vec<String> = Vec::new();
+	let mut current = String::new();
+	let mut in_code_block = false;
+
+	let mut flush = |current: &mut String, in_code_block: bool| {
+		let block = if in_code_block {
+			current.lines().map(str::trim_end).collect::<Vec<_>>().join("\n").trim_matches('\n').to_string()
+		} else {
+			current.split_whitespace().collect::<Vec<_>>().join(" ")
+		};
+		if !block.is_empty() {
+			blocks.push(block);
+		}
+		current.clear();
+	};
+
+	for event in Parser::new_ext(md, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES) {
+		match event {
+			Event::Text(text) | Event::Code(text) => current.push_str(&text),
+			Event::SoftBreak | Event::HardBreak => current.push(' '),
+			Event::End(TagEnd::TableCell) => current.push(' '),
+			Event::Start(Tag::CodeBlock(_)) => {
+				flush(&mut current, false);
+				in_code_block = true;
+			}
+			Event::End(TagEnd::CodeBlock) => {
+				flush(&mut current, true);
+				in_code_block = false;
+			}
+			Event::Start(Tag::Paragraph | Tag::Heading { .. } | Tag::BlockQuote(_) | Tag::List(_) | Tag::Item | Tag::TableRow | Tag::TableHead)
+			| Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::BlockQuote(_) | TagEnd::List(_) | TagEnd::Item | TagEnd::TableRow | TagEnd::TableHead)
+			| Event::Rule => flush(&mut current, in_code_block),
+			_ => {}
+		}
+	}
+	flush(&mut current, in_code_block);
+
+	blocks.join("\n")
+}
+
 /// Write a message to stderr on debug mode. This function is a no-op on
 /// release code.
 #[macro_export]
@@ -96,7 +174,7 @@ pub enum ResourceType {
 }
 
 /// Post flair with content, background color and foreground color
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Flair {
 	pub flair_parts: Vec<FlairPart>,
 	pub text: String,
@@ -147,14 +225,14 @@ impl FlairPart {
 	}
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Author {
 	pub name: String,
 	pub flair: Flair,
 	pub distinguished: String,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Poll {
 	pub poll_options: Vec<PollOption>,
 	pub voting_end_timestamp: (String, String),
@@ -182,7 +260,7 @@ impl Poll {
 	}
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct PollOption {
 	pub id: u64,
 	pub text: String,
@@ -212,14 +290,14 @@ impl PollOption {
 }
 
 /// Post flags with NSFW and stickied
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Flags {
 	pub spoiler: bool,
 	pub nsfw: bool,
 	pub stickied: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Media {
 	pub url: String,
 	pub alt_url: String,
@@ -227,6 +305,85 @@ pub struct Media {
 	pub height: i64,
 	pub poster: String,
 	pub download_name: String,
+	/// Separate DASH audio track of a v.redd.it video, if it has sound
+	pub audio_url: Option<String>,
+}
+
+/// One resolution of a post's preview image
+#[derive(Debug, Clone, Serialize)]
+pub struct PreviewImage {
+	pub url: String,
+	pub width: i64,
+	pub height: i64,
+}
+
+impl PreviewImage {
+	/// Collect every resolution Reddit generated for the post's preview,
+	/// smallest first and ending with the original upload.
+	pub fn parse(data: &Value) -> Vec<Self> {
+		let preview = &data["preview"]["images"][0];
+		preview["resolutions"]
+			.as_array()
+			.into_iter()
+			.flatten()
+			.chain(Some(&preview["source"]))
+			.filter_map(|image| {
+				Some(Self {
+					url: format_url(image["url"].as_str()?),
+					width: image["width"].as_i64().unwrap_or_default(),
+					height: image["height"].as_i64().unwrap_or_default(),
+				})
+			})
+			.filter(|image| !image.url.is_empty())
+			.collect()
+	}
+}
+
+/// Widths and upstream URLs of an image's preview resolutions, smallest
+/// first, next to the width of the original.
+type PreviewVariants = (i64, Vec<(i64, String)>);
+
+/// Smaller preview resolutions of images seen in recent posts, keyed by the
+/// image's file name, so `/img/:path?res=` can pick one without refetching
+/// the post.
+static PREVIEW_VARIANTS: LazyLock<std::sync::Mutex<cached::SizedCache<String, PreviewVariants>>> =
+	LazyLock::new(|| std::sync::Mutex::new(cached::SizedCache::with_size(10_000)));
+
+/// Remember the preview resolutions of the post `data` is of, for
+/// [`preview_variant`] to pick from.
+fn remember_preview_variants(data: &Value) {
+	let preview = &data["preview"]["images"][0];
+	let source = preview["source"]["url"].as_str().and_then(|url| Url::parse(url).ok());
+	let Some(name) = source.map(|url| url.path().trim_start_matches('/').to_string()) else {
+		return;
+	};
+	let variants: Vec<(i64, String)> = preview["resolutions"]
+		.as_array()
+		.into_iter()
+		.flatten()
+		.filter_map(|image| Some((image["width"].as_i64()?, image["url"].as_str()?.to_string())))
+		.collect();
+	if name.is_empty() || variants.is_empty() {
+		return;
+	}
+
+	let width = preview["source"]["width"].as_i64().unwrap_or(i64::MAX);
+	PREVIEW_VARIANTS.lock().unwrap_or_else(|e| e.into_inner()).cache_set(name, (width, variants));
+}
+
+/// Upstream URL of the preview resolution of image `name` closest to
+/// `width`. `None` when the image is unknown or the original is the closest
+/// match, in which case the original should be served.
+pub fn preview_variant(name: &str, width: i64) -> Option<String> {
+	let mut variants = PREVIEW_VARIANTS.lock().unwrap_or_else(|e| e.into_inner());
+	let (source, variants) = variants.cache_get(name)?;
+	closest_variant(*source, variants, width).map(ToString::to_string)
+}
+
+fn closest_variant(source: i64, variants: &[(i64, String)], width: i64) -> Option<&str> {
+	// On a tie the larger image wins, so the result never looks blurry
+	let (best, url) = variants.iter().min_by_key(|(w, _)| ((w - width).abs(), -w))?;
+	((best - width).abs() < (source - width).abs()).then_some(url.as_str())
 }
 
 impl Media {
@@ -301,6 +458,16 @@ impl Media {
 
 		let alt_url = alt_url_val.map_or(String::new(), |val| format_url(val.as_str().unwrap_or_default()));
 
+		// v.redd.it serves video and audio as separate DASH streams
+		let audio_url = if post_type == "video" {
+			[data_preview, secure_media, crosspost_parent_media]
+				.into_iter()
+				.find(|video| video["fallback_url"].is_string())
+				.and_then(Self::audio_url)
+		} else {
+			None
+		};
+
 		let download_name = if post_type == "image" || post_type == "gif" || post_type == "video" {
 			let permalink_base = url_path_basename(data["permalink"].as_str().unwrap_or_default());
 			let media_url_base = url_path_basename(url_val.as_str().unwrap_or_default());
@@ -321,13 +488,44 @@ impl Media {
 				height: source["height"].as_i64().unwrap_or_default(),
 				poster: format_url(source["url"].as_str().unwrap_or_default()),
 				download_name,
+				audio_url,
 			},
 			gallery,
 		)
 	}
+
+	/// Guess the audio track of a `reddit_video` object. Reddit never lists it
+	/// directly, but names it after the video stream: `DASH_AUDIO_128.mp4` next
+	/// to `DASH_720.mp4`, or `DASH_audio` next to the older `DASH_720`.
+	fn audio_url(video: &Value) -> Option<String> {
+		if !video["has_audio"].as_bool().unwrap_or_default() {
+			return None;
+		}
+
+		let fallback = video["fallback_url"].as_str()?;
+		let fallback = fallback.split('?').next().unwrap_or_default();
+		let (base, stream) = fallback.rsplit_once("/DASH_")?;
+		let track = if stream.ends_with(".mp4") { "DASH_AUDIO_128.mp4" } else { "DASH_audio" };
+
+		Some(format_url(&format!("{base}/{track}"))).filter(|url| !url.is_empty())
+	}
+
+	/// Where the download link of `post` points. Videos proxied through
+	/// `/vid/` are downloaded muxed with their audio track.
+	pub fn download_url(&self, post: &str) -> String {
+		download_url_with(&self.url, post, &MEDIA_BASE_URL)
+	}
 }
 
-#[derive(Serialize)]
+fn download_url_with(url: &str, post: &str, media_base: &str) -> String {
+	if url.strip_prefix(media_base).is_some_and(|path| path.starts_with("/vid/")) {
+		format!("{url}?download=true&post={post}")
+	} else {
+		url.to_string()
+	}
+}
+
+#[derive(Clone, Serialize)]
 pub struct GalleryMedia {
 	pub url: String,
 	pub width: i64,
@@ -367,8 +565,45 @@ impl GalleryMedia {
 	}
 }
 
+/// What a post is at a glance, so API clients don't each have to redo the
+/// classification from Reddit's many overlapping fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostKind {
+	Image,
+	Gallery,
+	Video,
+	Gif,
+	Link,
+	#[serde(rename = "self")]
+	SelfPost,
+	Poll,
+	Crosspost,
+}
+
+impl PostKind {
+	/// Classify a post from its data and the `post_type` its media was
+	/// parsed as. Polls and crossposts win over what they contain.
+	pub fn classify(data: &Value, post_type: &str) -> Self {
+		if data["poll_data"].is_object() {
+			return Self::Poll;
+		}
+		if data["crosspost_parent_list"].as_array().is_some_and(|parents| !parents.is_empty()) {
+			return Self::Crosspost;
+		}
+		match post_type {
+			"image" => Self::Image,
+			"gallery" => Self::Gallery,
+			"video" => Self::Video,
+			"gif" => Self::Gif,
+			"self" => Self::SelfPost,
+			_ => Self::Link,
+		}
+	}
+}
+
 /// Post containing content, metadata and media
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Post {
 	pub id: String,
 	pub title: String,
@@ -377,13 +612,21 @@ pub struct Post {
 	/// Only present in JSON API responses when body was truncated
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub body_truncated: Option<bool>,
+	/// Plain-text body, only present with `?translate_markdown=false`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub body_text: Option<String>,
 	pub author: Author,
 	pub permalink: String,
+	/// Absolute URL of the post on this instance, only present with
+	/// `?absolute_urls=true`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub instance_permalink: Option<String>,
 	pub link_title: String,
 	pub poll: Option<Poll>,
 	pub score: (String, String),
 	pub upvote_ratio: i64,
 	pub post_type: String,
+	pub kind: PostKind,
 	pub flair: Flair,
 	pub flags: Flags,
 	pub thumbnail: Media,
@@ -391,12 +634,38 @@ pub struct Post {
 	pub domain: String,
 	pub rel_time: String,
 	pub created: String,
+	/// `created` in the timezone asked for with `?tz=`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub created_local: Option<String>,
 	pub created_ts: u64,
 	pub num_duplicates: u64,
+	/// Number of times the post was shared to other communities
+	pub num_crossposts: u32,
+	pub is_crosspostable: bool,
 	pub comments: (String, String),
 	pub gallery: Vec<GalleryMedia>,
 	pub awards: Awards,
 	pub nsfw: bool,
+	/// Tagged as a spoiler, so its media and body should start out hidden
+	pub spoiler: bool,
+	/// Spoilers inside the markdown body, only present when it has any
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub spoilers: Vec<SpoilerSpan>,
+	/// Pinned by its author to the top of their profile. `flags.stickied`
+	/// marks these and moderator announcements alike, so this tells them apart.
+	pub pinned: bool,
+	/// Comment sort the subreddit suggests for this post (e.g. `qa`)
+	pub suggested_sort: Option<String>,
+	/// `moderator` or `admin` when posted in an official capacity
+	pub distinguished: Option<String>,
+	/// Only served through `?preview_only=true`
+	#[serde(skip)]
+	pub preview_images: Vec<PreviewImage>,
+	/// Subreddit the post was made in, when requested with `?sr_detail=true`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub subreddit_detail: Option<Subreddit>,
+	/// First `REDLIB_BLUR_KEYWORDS` entry found in the title or flair
+	pub content_warning: Option<String>,
 	pub out_url: Option<String>,
 	pub ws_url: String,
 }
@@ -404,7 +673,7 @@ pub struct Post {
 impl Post {
 	/// Fetch posts of a user or subreddit and return a vector of posts and the "after" value.
 	/// If `use_markdown` is true, the body will contain raw markdown instead of HTML.
-	pub async fn fetch(path: &str, quarantine: bool, use_markdown: bool) -> Result<(Vec<Self>, String), String> {
+	pub async fn fetch(path: &str, quarantine: bool, use_markdown: bool) -> Result<(Vec<Self>, String), UpstreamError> {
 		// Send a request to the url
 		let res = match json(path.to_string(), quarantine).await {
 			// If success, receive JSON in response
@@ -413,25 +682,42 @@ impl Post {
 			Err(msg) => return Err(msg),
 		};
 
+		let (posts, _) = Self::parse_listing(&res, use_markdown).await?;
+		Ok((posts, res["data"]["after"].as_str().unwrap_or_default().to_string()))
+	}
+
+	/// Parse the posts of a listing response, along with how many of its
+	/// items were skipped for not looking like posts at all. One item
+	/// Reddit changed the shape of shouldn't take the whole page down.
+	pub async fn parse_listing(res: &Value, use_markdown: bool) -> Result<(Vec<Self>, usize), String> {
 		// Fetch the list of posts from the JSON response
 		let Some(post_list) = res["data"]["children"].as_array() else {
 			return Err("No posts found".to_string());
 		};
 
 		let mut posts: Vec<Self> = Vec::new();
+		let mut skipped = 0;
 
 		// For each post from posts list
-		for post in post_list {
+		for (index, post) in post_list.iter().enumerate() {
 			let data = &post["data"];
+			if !data.is_object() || !data["id"].is_string() {
+				warn!("Skipping malformed item {index} of a listing, of kind {}", post["kind"]);
+				skipped += 1;
+				continue;
+			}
 
 			let (rel_time, created) = time(data["created_utc"].as_f64().unwrap_or_default());
 			let created_ts = data["created_utc"].as_f64().unwrap_or_default().round() as u64;
 			let score = data["score"].as_i64().unwrap_or_default();
 			let ratio: f64 = data["upvote_ratio"].as_f64().unwrap_or(1.0) * 100.0;
 			let title = val(post, "title");
+			let content_warning = content_warning(&title, &val(post, "link_flair_text"));
 
 			// Determine the type of media along with the media URL
 			let (post_type, media, gallery) = Media::parse(data).await;
+			remember_preview_variants(data);
+			let kind = PostKind::classify(data, &post_type);
 			let awards = Awards::parse(&data["all_awardings"]);
 
 			// For JSON API (use_markdown=true), use raw markdown; for HTML UI, use HTML with URL rewriting
@@ -442,6 +728,7 @@ impl Post {
 				let html = rewrite_urls(&val(post, "selftext_html"));
 				if html.is_empty() { rewrite_urls(&val(post, "body_html")) } else { html }
 			};
+			let spoilers = inline_spoilers(&body);
 
 			posts.push(Self {
 				id: val(post, "id"),
@@ -449,6 +736,7 @@ impl Post {
 				community: val(post, "subreddit"),
 				body,
 				body_truncated: None,
+				body_text: None,
 				author: Author {
 					name: val(post, "author"),
 					flair: Flair {
@@ -470,6 +758,7 @@ impl Post {
 				},
 				upvote_ratio: ratio as i64,
 				post_type,
+				kind,
 				thumbnail: Media {
 					url: format_url(val(post, "thumbnail").as_str()),
 					alt_url: String::new(),
@@ -477,6 +766,7 @@ impl Post {
 					height: data["thumbnail_height"].as_i64().unwrap_or_default(),
 					poster: String::new(),
 					download_name: String::new(),
+					audio_url: None,
 				},
 				media,
 				domain: val(post, "domain"),
@@ -500,21 +790,33 @@ impl Post {
 					stickied: data["stickied"].as_bool().unwrap_or_default() || data["pinned"].as_bool().unwrap_or_default(),
 				},
 				permalink: val(post, "permalink"),
+				instance_permalink: instance_url(&val(post, "permalink")),
 				link_title: val(post, "link_title"),
 				poll: Poll::parse(&data["poll_data"]),
 				rel_time,
 				created,
+				created_local: local_time(created_ts),
 				created_ts,
 				num_duplicates: post["data"]["num_duplicates"].as_u64().unwrap_or(0),
+				num_crossposts: data["num_crossposts"].as_u64().unwrap_or(0) as u32,
+				is_crosspostable: data["is_crosspostable"].as_bool().unwrap_or_default(),
 				comments: format_num(data["num_comments"].as_i64().unwrap_or_default()),
 				gallery,
 				awards,
 				nsfw: post["data"]["over_18"].as_bool().unwrap_or_default(),
+				spoiler: data["spoiler"].as_bool().unwrap_or_default(),
+				spoilers,
+				pinned: data["pinned"].as_bool().unwrap_or_default(),
+				suggested_sort: data["suggested_sort"].as_str().filter(|sort| !sort.is_empty()).map(ToString::to_string),
+				distinguished: data["distinguished"].as_str().filter(|role| !role.is_empty()).map(ToString::to_string),
+				preview_images: PreviewImage::parse(data),
+				subreddit_detail: data["sr_detail"].is_object().then(|| Subreddit::parse(&data["sr_detail"])),
+				content_warning,
 				ws_url: val(post, "websocket_url"),
 				out_url: post["data"]["url_overridden_by_dest"].as_str().map(|a| a.to_string()),
 			});
 		}
-		Ok((posts, res["data"]["after"].as_str().unwrap_or_default().to_string()))
+		Ok((posts, skipped))
 	}
 }
 
@@ -527,23 +829,63 @@ pub struct Comment {
 	pub parent_id: String,
 	pub parent_kind: String,
 	pub post_link: String,
+	/// Absolute URL of the comment on this instance, only present with
+	/// `?absolute_urls=true`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub instance_permalink: Option<String>,
 	pub post_author: String,
 	pub body: String,
+	/// Only present in JSON API responses when body was truncated
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub body_truncated: Option<bool>,
+	/// Plain-text body, only present with `?translate_markdown=false`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub body_text: Option<String>,
 	pub author: Author,
 	pub score: (String, String),
 	pub rel_time: String,
 	pub created: String,
+	/// `created` in the timezone asked for with `?tz=`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub created_local: Option<String>,
+	pub created_ts: u64,
 	pub edited: (String, String),
 	pub replies: Vec<Comment>,
 	pub highlighted: bool,
 	pub awards: Awards,
+	/// Whether the comment starts out collapsed, either because Reddit
+	/// collapses it or because it is a filtered or stickied mod comment
 	pub collapsed: bool,
+	/// Why Reddit collapses the comment, such as a score below the threshold
+	pub collapsed_reason: Option<String>,
+	/// Score not shown yet, for new comments and contest mode threads
+	pub score_hidden: bool,
+	/// Written by the author of the post
+	pub is_submitter: bool,
+	/// `moderator` or `admin` when written in an official capacity
+	pub distinguished: Option<String>,
+	/// Whether the author has Reddit Premium
+	pub author_premium: bool,
 	pub is_filtered: bool,
+	/// Spoilers inside the markdown body, only present when it has any
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub spoilers: Vec<SpoilerSpan>,
 	pub more_count: i64,
+	/// Comments Reddit left out of the thread, only present on "load more" stubs
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub load_more: Option<MoreComments>,
 	#[serde(skip)]
 	pub prefs: Preferences,
 }
 
+/// Comments behind a "load more" stub: how many there are, and the ids of
+/// those Reddit named, which may be fewer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MoreComments {
+	pub count: i64,
+	pub children: Vec<String>,
+}
+
 #[derive(Default, Clone, Serialize)]
 pub struct Award {
 	pub name: String,
@@ -558,7 +900,7 @@ impl std::fmt::Display for Award {
 	}
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct Awards(pub Vec<Award>);
 
 impl std::ops::Deref for Awards {
@@ -569,6 +911,15 @@ impl std::ops::Deref for Awards {
 	}
 }
 
+impl IntoIterator for Awards {
+	type Item = Award;
+	type IntoIter = std::vec::IntoIter<Award>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.0.into_iter()
+	}
+}
+
 impl std::fmt::Display for Awards {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		self.iter().try_fold((), |_, award| writeln!(f, "{award}"))
@@ -648,7 +999,7 @@ pub struct User {
 	pub nsfw: bool,
 }
 
-#[derive(Default, Serialize)]
+#[derive(Default, Clone, Serialize)]
 /// Subreddit struct containing metadata about community
 pub struct Subreddit {
 	pub name: String,
@@ -661,6 +1012,66 @@ pub struct Subreddit {
 	pub active: (String, String),
 	pub wiki: bool,
 	pub nsfw: bool,
+	/// Theme colors set by the moderators, as `#rrggbb`
+	pub primary_color: Option<String>,
+	pub key_color: Option<String>,
+	pub banner_background_color: Option<String>,
+}
+
+impl Subreddit {
+	/// Parse subreddit metadata from the `data` of an `about.json` response,
+	/// or from the `sr_detail` Reddit embeds in posts on request.
+	pub fn parse(data: &Value) -> Self {
+		let field = |name: &str| data[name].as_str().unwrap_or_default().to_string();
+		let color = |name: &str| data[name].as_str().map(str::trim).filter(|color| !color.is_empty()).map(ToString::to_string);
+
+		// Metadata regarding the subreddit
+		let members: i64 = data["subscribers"].as_u64().unwrap_or_default() as i64;
+		let active: i64 = data["accounts_active"].as_u64().unwrap_or_default() as i64;
+
+		// Fetch subreddit icon either from the community_icon or icon_img value
+		let community_icon = field("community_icon");
+		let icon = if community_icon.is_empty() { field("icon_img") } else { community_icon };
+
+		Self {
+			name: field("display_name"),
+			title: field("title"),
+			description: field("public_description"),
+			info: rewrite_urls(&field("description_html")),
+			icon: format_url(&icon),
+			members: format_num(members),
+			active: format_num(active),
+			wiki: data["wiki_enabled"].as_bool().unwrap_or_default(),
+			nsfw: data["over18"].as_bool().unwrap_or_default(),
+			primary_color: color("primary_color"),
+			key_color: color("key_color"),
+			banner_background_color: color("banner_background_color"),
+		}
+	}
+}
+
+#[derive(Clone, Serialize)]
+/// Moderator of a community along with the permissions they hold
+pub struct Moderator {
+	pub name: String,
+	pub permissions: Vec<String>,
+}
+
+/// Sidebar widget of a community, normalized from Reddit's widget schema.
+/// Kinds that are not understood yet only name Reddit's kind for them, as
+/// their raw data would point clients straight at Reddit.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Widget {
+	Text { title: String, text: String },
+	Button { title: String, description: String, buttons: Vec<WidgetButton> },
+	Unknown { kind: String, title: String },
+}
+
+#[derive(Serialize)]
+pub struct WidgetButton {
+	pub text: String,
+	pub url: String,
 }
 
 /// Parser for query params, used in sorting (eg. /r/rust/?sort=hot)
@@ -840,6 +1251,28 @@ pub fn filter_posts(posts: &mut Vec<Post>, filters: &HashSet<String>) -> (u64, b
 	}
 }
 
+/// Drops posts that don't match what the listing's query string asks for:
+/// at least `min_comments` comments, or only one `post_type` of post.
+/// Unrecognised values are ignored.
+pub fn filter_listing(posts: &mut Vec<Post>, path: &str) {
+	if let Some(min_comments) = param(path, "min_comments").and_then(|value| value.parse::<u64>().ok()) {
+		posts.retain(|post| post.comments.1.parse::<u64>().unwrap_or_default() >= min_comments);
+	}
+	if let Some(kind) = param(path, "post_type").filter(|kind| ["link", "self", "media"].contains(&kind.as_str())) {
+		posts.retain(|post| post_kind(&post.post_type) == kind);
+	}
+}
+
+/// Broad kind of a post for `?post_type=`: `self` for text posts, `media` for
+/// images, GIFs, videos and galleries, and `link` for everything else.
+fn post_kind(post_type: &str) -> &'static str {
+	match post_type {
+		"self" => "self",
+		"image" | "gif" | "video" | "gallery" => "media",
+		_ => "link",
+	}
+}
+
 /// Creates a [`Post`] from a provided JSON.
 /// Parse a single post from Reddit's JSON. If `use_markdown` is true, the body will contain raw markdown.
 pub async fn parse_post(post: &Value, use_markdown: bool) -> Post {
@@ -848,9 +1281,11 @@ pub async fn parse_post(post: &Value, use_markdown: bool) -> Post {
 	// Parse post score and upvote ratio
 	let score = post["data"]["score"].as_i64().unwrap_or_default();
 	let ratio: f64 = post["data"]["upvote_ratio"].as_f64().unwrap_or(1.0) * 100.0;
+	remember_preview_variants(&post["data"]);
 
 	// Determine the type of media along with the media URL
 	let (post_type, media, gallery) = Media::parse(&post["data"]).await;
+	let kind = PostKind::classify(&post["data"], &post_type);
 
 	let created_ts = post["data"]["created_utc"].as_f64().unwrap_or_default().round() as u64;
 
@@ -883,6 +1318,7 @@ pub async fn parse_post(post: &Value, use_markdown: bool) -> Post {
 			rewrite_urls(&val(post, "selftext_html"))
 		}
 	};
+	let spoilers = inline_spoilers(&body);
 
 	// Build a post using data parsed from Reddit post API
 	Post {
@@ -891,6 +1327,7 @@ pub async fn parse_post(post: &Value, use_markdown: bool) -> Post {
 		community: val(post, "subreddit"),
 		body,
 		body_truncated: None,
+		body_text: None,
 		author: Author {
 			name: val(post, "author"),
 			flair: Flair {
@@ -905,12 +1342,14 @@ pub async fn parse_post(post: &Value, use_markdown: bool) -> Post {
 			},
 			distinguished: val(post, "distinguished"),
 		},
+		instance_permalink: instance_url(&permalink),
 		permalink,
 		link_title: val(post, "link_title"),
 		poll,
 		score: format_num(score),
 		upvote_ratio: ratio as i64,
 		post_type,
+		kind,
 		media,
 		thumbnail: Media {
 			url: format_url(val(post, "thumbnail").as_str()),
@@ -919,6 +1358,7 @@ pub async fn parse_post(post: &Value, use_markdown: bool) -> Post {
 			height: post["data"]["thumbnail_height"].as_i64().unwrap_or_default(),
 			poster: String::new(),
 			download_name: String::new(),
+			audio_url: None,
 		},
 		flair: Flair {
 			flair_parts: FlairPart::parse(
@@ -942,12 +1382,23 @@ pub async fn parse_post(post: &Value, use_markdown: bool) -> Post {
 		domain: val(post, "domain"),
 		rel_time,
 		created,
+		created_local: local_time(created_ts),
 		created_ts,
 		num_duplicates: post["data"]["num_duplicates"].as_u64().unwrap_or(0),
+		num_crossposts: post["data"]["num_crossposts"].as_u64().unwrap_or(0) as u32,
+		is_crosspostable: post["data"]["is_crosspostable"].as_bool().unwrap_or_default(),
 		comments: format_num(post["data"]["num_comments"].as_i64().unwrap_or_default()),
 		gallery,
 		awards,
 		nsfw: post["data"]["over_18"].as_bool().unwrap_or_default(),
+		spoiler: post["data"]["spoiler"].as_bool().unwrap_or_default(),
+		spoilers,
+		pinned: post["data"]["pinned"].as_bool().unwrap_or_default(),
+		suggested_sort: post["data"]["suggested_sort"].as_str().filter(|sort| !sort.is_empty()).map(ToString::to_string),
+		distinguished: post["data"]["distinguished"].as_str().filter(|role| !role.is_empty()).map(ToString::to_string),
+		preview_images: PreviewImage::parse(&post["data"]),
+		subreddit_detail: post["data"]["sr_detail"].is_object().then(|| Subreddit::parse(&post["data"]["sr_detail"])),
+		content_warning: content_warning(&val(post, "title"), &val(post, "link_flair_text")),
 		ws_url: val(post, "websocket_url"),
 		out_url: post["data"]["url_overridden_by_dest"].as_str().map(|a| a.to_string()),
 	}
@@ -1070,6 +1521,7 @@ static REGEX_URL_OLD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://o
 static REGEX_URL_NP: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://np\.reddit\.com/(.*)").unwrap());
 static REGEX_URL_PLAIN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://reddit\.com/(.*)").unwrap());
 static REGEX_URL_VIDEOS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://v\.redd\.it/(.*)/DASH_([0-9]{2,4}(\.mp4|$|\?source=fallback))").unwrap());
+static REGEX_URL_VIDEOS_AUDIO: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://v\.redd\.it/([^/]+)/DASH_(AUDIO_[0-9]{2,3}\.mp4|audio(?:\.mp4)?)$").unwrap());
 static REGEX_URL_VIDEOS_HLS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://v\.redd\.it/(.+)/(HLSPlaylist\.m3u8.*)$").unwrap());
 static REGEX_URL_IMAGES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://i\.redd\.it/(.*)").unwrap());
 static REGEX_URL_THUMBS_A: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://a\.thumbs\.redditmedia\.com/(.*)").unwrap());
@@ -1080,8 +1532,86 @@ static REGEX_URL_EXTERNAL_PREVIEW: LazyLock<Regex> = LazyLock::new(|| Regex::new
 static REGEX_URL_STYLES: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://styles\.redditmedia\.com/(.*)").unwrap());
 static REGEX_URL_STATIC_MEDIA: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://www\.redditstatic\.com/(.*)").unwrap());
 
+/// Whether media URLs should be left unproxied while handling this request.
+pub fn raw_media_urls() -> bool {
+	RequestContext::with(|context| context.raw_media_urls).unwrap_or(false)
+}
+
+/// Run `task` under the settings of the request being handled, which tasks
+/// spawned by a handler don't inherit.
+pub fn with_request_settings<F: Future>(task: F) -> impl Future<Output = F::Output> {
+	REQUEST_CONTEXT.scope(RequestContext::inherited(), task)
+}
+
+/// Parse an IANA timezone name such as `America/New_York`, falling back to
+/// UTC for names that aren't one.
+pub fn parse_timezone(name: &str) -> Tz {
+	name.parse().unwrap_or_else(|_| {
+		warn!("Unknown timezone {name:?} requested, using UTC");
+		Tz::UTC
+	})
+}
+
+/// A unix timestamp in the timezone the request asked for, if it did.
+pub fn local_time(created: u64) -> Option<String> {
+	let tz = RequestContext::with(|context| context.display_tz).flatten()?;
+	Some(format_local_time(created, tz))
+}
+
+fn format_local_time(created: u64, tz: Tz) -> String {
+	let time = chrono::DateTime::from_timestamp(created as i64, 0).unwrap_or_default();
+	time.with_timezone(&tz).format("%b %d %Y, %H:%M:%S %Z").to_string()
+}
+
+/// Origin of the instance as configured through `REDLIB_FULL_URL`.
+pub fn configured_origin() -> Option<String> {
+	get_setting("REDLIB_FULL_URL")
+		.map(|url| url.trim_end_matches('/').to_string())
+		.filter(|url| !url.is_empty())
+}
+
+/// Absolute origin of the instance, preferring `REDLIB_FULL_URL` over the
+/// `Host` the request was made to.
+pub fn instance_origin(req: &Request<Body>) -> String {
+	configured_origin()
+		.or_else(|| {
+			let host = req.headers().get(hyper::header::HOST).and_then(|host| host.to_str().ok())?;
+			Some(format!("{}://{}", req.client().scheme, host.trim_end_matches('/')))
+		})
+		.unwrap_or_default()
+}
+
+/// `path` on this instance as an absolute URL, when the request asked for
+/// absolute URLs.
+pub fn instance_url(path: &str) -> Option<String> {
+	let origin = RequestContext::with(|context| context.instance_origin.clone()).flatten()?;
+	(!path.is_empty()).then(|| format!("{origin}{path}"))
+}
+
+/// Origin media URLs are rewritten to, from `REDLIB_MEDIA_BASE_URL`; empty to
+/// keep them relative to this instance.
+static MEDIA_BASE_URL: LazyLock<String> = LazyLock::new(|| parse_media_base_url(get_setting("REDLIB_MEDIA_BASE_URL")).ok().flatten().unwrap_or_default());
+
+/// Validate `REDLIB_MEDIA_BASE_URL`, which must be an absolute http(s) URL.
+/// Returns it without a trailing slash so paths can be appended as is.
+pub fn parse_media_base_url(value: Option<String>) -> Result<Option<String>, String> {
+	let Some(value) = value.filter(|value| !value.trim().is_empty()) else {
+		return Ok(None);
+	};
+	match Url::parse(value.trim()) {
+		Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() && url.query().is_none() && url.fragment().is_none() => {
+			Ok(Some(url.as_str().trim_end_matches('/').to_string()))
+		}
+		_ => Err(format!("\"{value}\" is not an absolute http(s) URL")),
+	}
+}
+
 /// Direct urls to proxy if proxy is enabled
 pub fn format_url(url: &str) -> String {
+	format_url_with(url, &MEDIA_BASE_URL)
+}
+
+fn format_url_with(url: &str, media_base: &str) -> String {
 	if url.is_empty() || url == "self" || url == "default" || url == "nsfw" || url == "spoiler" {
 		String::new()
 	} else {
@@ -1117,20 +1647,31 @@ pub fn format_url(url: &str) -> String {
 				};
 			}
 
+			// Links to Reddit itself are still made relative
+			if raw_media_urls() && !matches!(domain, "www.reddit.com" | "old.reddit.com" | "np.reddit.com" | "reddit.com") {
+				return url.to_string();
+			}
+
+			// Proxied media may be served from another host
+			let media = |path: String| if path.is_empty() { path } else { format!("{media_base}{path}") };
+
 			match domain {
 				"www.reddit.com" => capture(&REGEX_URL_WWW, "/", 1),
 				"old.reddit.com" => capture(&REGEX_URL_OLD, "/", 1),
 				"np.reddit.com" => capture(&REGEX_URL_NP, "/", 1),
 				"reddit.com" => capture(&REGEX_URL_PLAIN, "/", 1),
-				"v.redd.it" => chain!(capture(&REGEX_URL_VIDEOS, "/vid/", 2), capture(&REGEX_URL_VIDEOS_HLS, "/hls/", 2)),
-				"i.redd.it" => capture(&REGEX_URL_IMAGES, "/img/", 1),
-				"a.thumbs.redditmedia.com" => capture(&REGEX_URL_THUMBS_A, "/thumb/a/", 1),
-				"b.thumbs.redditmedia.com" => capture(&REGEX_URL_THUMBS_B, "/thumb/b/", 1),
-				"emoji.redditmedia.com" => capture(&REGEX_URL_EMOJI, "/emoji/", 2),
-				"preview.redd.it" => capture(&REGEX_URL_PREVIEW, "/preview/pre/", 1),
-				"external-preview.redd.it" => capture(&REGEX_URL_EXTERNAL_PREVIEW, "/preview/external-pre/", 1),
-				"styles.redditmedia.com" => capture(&REGEX_URL_STYLES, "/style/", 1),
-				"www.redditstatic.com" => capture(&REGEX_URL_STATIC_MEDIA, "/static/", 1),
+				"v.redd.it" => media(chain!(
+					chain!(capture(&REGEX_URL_VIDEOS, "/vid/", 2), capture(&REGEX_URL_VIDEOS_AUDIO, "/vid/", 2)),
+					capture(&REGEX_URL_VIDEOS_HLS, "/hls/", 2)
+				)),
+				"i.redd.it" => media(capture(&REGEX_URL_IMAGES, "/img/", 1)),
+				"a.thumbs.redditmedia.com" => media(capture(&REGEX_URL_THUMBS_A, "/thumb/a/", 1)),
+				"b.thumbs.redditmedia.com" => media(capture(&REGEX_URL_THUMBS_B, "/thumb/b/", 1)),
+				"emoji.redditmedia.com" => media(capture(&REGEX_URL_EMOJI, "/emoji/", 2)),
+				"preview.redd.it" => media(capture(&REGEX_URL_PREVIEW, "/preview/pre/", 1)),
+				"external-preview.redd.it" => media(capture(&REGEX_URL_EXTERNAL_PREVIEW, "/preview/external-pre/", 1)),
+				"styles.redditmedia.com" => media(capture(&REGEX_URL_STYLES, "/style/", 1)),
+				"www.redditstatic.com" => media(capture(&REGEX_URL_STATIC_MEDIA, "/static/", 1)),
 				_ => url.to_string(),
 			}
 		})
@@ -1157,6 +1698,10 @@ static REDLIB_PREVIEW_TEXT_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(
 
 /// Rewrite Reddit links to Redlib in body of text
 pub fn rewrite_urls(input_text: &str) -> String {
+	rewrite_urls_with(input_text, &MEDIA_BASE_URL)
+}
+
+fn rewrite_urls_with(input_text: &str, media_base: &str) -> String {
 	let mut text1 =
 		// Rewrite Reddit links to Redlib
 		REDDIT_REGEX.replace_all(input_text, r#"href="/"#).to_string();
@@ -1166,7 +1711,7 @@ pub fn rewrite_urls(input_text: &str) -> String {
 			break;
 		} else {
 			text1 = REDDIT_EMOJI_REGEX
-				.replace_all(&text1, format_url(REDDIT_EMOJI_REGEX.find(&text1).map(|x| x.as_str()).unwrap_or_default()))
+				.replace_all(&text1, format_url_with(REDDIT_EMOJI_REGEX.find(&text1).map(|x| x.as_str()).unwrap_or_default(), media_base))
 				.to_string()
 		}
 	}
@@ -1179,10 +1724,13 @@ pub fn rewrite_urls(input_text: &str) -> String {
 		if REDDIT_PREVIEW_REGEX.find(&text1).is_none() {
 			return text1;
 		} else {
-			let formatted_url = format_url(REDDIT_PREVIEW_REGEX.find(&text1).map(|x| x.as_str()).unwrap_or_default());
+			let formatted_url = format_url_with(REDDIT_PREVIEW_REGEX.find(&text1).map(|x| x.as_str()).unwrap_or_default(), media_base);
+			let formatted_url = formatted_url.strip_prefix(media_base).unwrap_or(&formatted_url);
 
-			let image_url = REDLIB_PREVIEW_LINK_REGEX.find(&formatted_url).map_or("", |m| m.as_str());
-			let mut image_caption = REDLIB_PREVIEW_TEXT_REGEX.find(&formatted_url).map_or("", |m| m.as_str());
+			let image_url = REDLIB_PREVIEW_LINK_REGEX
+				.find(formatted_url)
+				.map_or(String::new(), |m| format!("{media_base}{}", m.as_str()));
+			let mut image_caption = REDLIB_PREVIEW_TEXT_REGEX.find(formatted_url).map_or("", |m| m.as_str());
 
 			/* As long as image_caption isn't empty remove first and last four characters of image_text to leave us with just the text in the caption without any HTML.
 			This makes it possible to enclose it in a <figcaption> later on without having stray HTML breaking it */
@@ -1217,7 +1765,7 @@ pub fn rewrite_urls(input_text: &str) -> String {
 			};
 
 			text1 = REDDIT_PREVIEW_REGEX
-				.replace(&text1, format!("{_preview_type}$2"))
+				.replace(&text1, format!("{media_base}{_preview_type}$2"))
 				.replace(&image_to_replace, &_image_replacement)
 		}
 	}
@@ -1226,6 +1774,148 @@ pub fn rewrite_urls(input_text: &str) -> String {
 // These links all follow a pattern of "https://reddit-econ-prod-assets-permanent.s3.amazonaws.com/asset-manager/SUBREDDIT_ID/RANDOM_FILENAME.png"
 static REDDIT_EMOTE_LINK_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"https://reddit-econ-prod-assets-permanent.s3.amazonaws.com/asset-manager/(.*)"#).unwrap());
 
+/// Matches `:name:` emoji tokens, and HTML tags so that tokens inside
+/// attributes can be left alone.
+static EMOJI_TOKEN_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>|:([A-Za-z0-9_-]+):").unwrap());
+
+/// Whether the page was requested with `?emoji=true`, asking for custom
+/// subreddit emojis to be resolved. Off by default to save a request.
+pub fn wants_emojis(path: &str) -> bool {
+	param(path, "emoji").is_some_and(|emoji| emoji == "true")
+}
+
+/// Custom emojis of a subreddit, mapping each name to its proxied image.
+#[cached(size = 100, time = 3600, result = true)]
+pub async fn subreddit_emojis(sub: String) -> Result<HashMap<String, String>, UpstreamError> {
+	json(format!("/api/v1/{sub}/emojis/all"), false).await.map(|response| parse_emojis(&response))
+}
+
+/// Drop every cached emoji map, returning how many were evicted.
+pub async fn flush_emojis_cache() -> usize {
+	let mut cache = SUBREDDIT_EMOJIS.lock().await;
+	let evicted = cache.cache_size();
+	cache.cache_clear();
+	evicted
+}
+
+/// Flatten Reddit's emoji listing, which groups them by owner (`snoomojis`
+/// and the subreddit's own `t5_` id).
+fn parse_emojis(response: &Value) -> HashMap<String, String> {
+	response
+		.as_object()
+		.into_iter()
+		.flat_map(|owners| owners.values())
+		.filter_map(Value::as_object)
+		.flatten()
+		.filter_map(|(name, emoji)| Some((name.clone(), format_url(emoji["url"].as_str()?))))
+		.collect()
+}
+
+/// Swap known `:name:` tokens in rendered HTML for their emoji images.
+pub fn replace_emojis(html: &str, emojis: &HashMap<String, String>) -> String {
+	replace_emoji_tokens(html, emojis, |name, url| {
+		format!("<img loading=\"lazy\" src=\"{url}\" alt=\":{name}:\" title=\":{name}:\" width=\"20\" height=\"20\" style=\"vertical-align:text-bottom\">")
+	})
+}
+
+/// Swap known `:name:` tokens in a markdown body for image links, as the
+/// JSON API serves bodies as markdown.
+pub fn replace_markdown_emojis(markdown: &str, emojis: &HashMap<String, String>) -> String {
+	replace_emoji_tokens(markdown, emojis, |name, url| format!("![:{name}:]({url})"))
+}
+
+fn replace_emoji_tokens(text: &str, emojis: &HashMap<String, String>, render: impl Fn(&str, &str) -> String) -> String {
+	if emojis.is_empty() {
+		return text.to_string();
+	}
+
+	EMOJI_TOKEN_REGEX
+		.replace_all(text, |caps: &regex::Captures| match caps.get(1).and_then(|name| emojis.get_key_value(name.as_str())) {
+			Some((name, url)) => render(name, url),
+			None => caps[0].to_string(),
+		})
+		.to_string()
+}
+
+/// Split the text parts of a flair around known emoji tokens.
+fn replace_flair_emojis(flair: &mut Flair, emojis: &HashMap<String, String>) {
+	if emojis.is_empty() {
+		return;
+	}
+
+	let text_part = |value: &str| FlairPart {
+		flair_part_type: "text".to_string(),
+		value: value.to_string(),
+	};
+
+	let mut parts = Vec::new();
+	for part in flair.flair_parts.drain(..) {
+		if part.flair_part_type != "text" {
+			parts.push(part);
+			continue;
+		}
+
+		let mut rest = 0;
+		for caps in EMOJI_TOKEN_REGEX.captures_iter(&part.value) {
+			let Some(url) = caps.get(1).and_then(|name| emojis.get(name.as_str())) else {
+				continue;
+			};
+			let token = caps.get(0).unwrap();
+			if token.start() > rest {
+				parts.push(text_part(&part.value[rest..token.start()]));
+			}
+			parts.push(FlairPart {
+				flair_part_type: "emoji".to_string(),
+				value: url.clone(),
+			});
+			rest = token.end();
+		}
+		if rest < part.value.len() {
+			parts.push(text_part(&part.value[rest..]));
+		}
+	}
+	flair.flair_parts = parts;
+}
+
+/// Resolve custom emojis in a post's body and flairs. `markdown` is set
+/// for bodies kept as markdown, as the JSON API serves them.
+pub fn replace_post_emojis(post: &mut Post, emojis: &HashMap<String, String>, markdown: bool) {
+	post.body = replace_body_emojis(&post.body, emojis, markdown);
+	replace_flair_emojis(&mut post.flair, emojis);
+	replace_flair_emojis(&mut post.author.flair, emojis);
+}
+
+/// Resolve custom emojis in a comment thread's bodies and author flairs.
+pub fn replace_comment_emojis(comment: &mut Comment, emojis: &HashMap<String, String>, markdown: bool) {
+	comment.body = replace_body_emojis(&comment.body, emojis, markdown);
+	replace_flair_emojis(&mut comment.author.flair, emojis);
+	for reply in &mut comment.replies {
+		replace_comment_emojis(reply, emojis, markdown);
+	}
+}
+
+fn replace_body_emojis(body: &str, emojis: &HashMap<String, String>, markdown: bool) -> String {
+	if markdown {
+		replace_markdown_emojis(body, emojis)
+	} else {
+		replace_emojis(body, emojis)
+	}
+}
+
+/// Resolve custom emojis for every post of a listing, fetching the emojis
+/// of each subreddit in it once and all at the same time.
+pub async fn replace_listing_emojis(posts: &mut [Post], markdown: bool) {
+	let mut communities: Vec<String> = posts.iter().map(|post| post.community.clone()).collect();
+	communities.sort_unstable();
+	communities.dedup();
+
+	let fetched = join_all(communities.iter().map(|sub| subreddit_emojis(sub.clone()))).await;
+	let emojis: HashMap<String, HashMap<String, String>> = communities.into_iter().zip(fetched).map(|(sub, emojis)| (sub, emojis.unwrap_or_default())).collect();
+	for post in posts {
+		replace_post_emojis(post, &emojis[&post.community], markdown);
+	}
+}
+
 // These all follow a pattern of '"emote|SUBREDDIT_IT|NUMBER"', we want the number
 static REDDIT_EMOTE_ID_NUMBER_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""emote\|.*\|(.*)""#).unwrap());
 
@@ -1319,6 +2009,192 @@ pub fn format_num(num: i64) -> (String, String) {
 	(truncated, num.to_string())
 }
 
+/// Listing items seen once the current page has been consumed: the `count`
+/// the client sent for this page plus the items Reddit returned on it. Sent
+/// back to Reddit with the next `after` so pages don't overlap.
+pub fn next_count(path: &str, page_len: usize) -> u64 {
+	param(path, "count").and_then(|count| count.parse::<u64>().ok()).unwrap_or_default() + page_len as u64
+}
+
+/// Time ranges Reddit accepts as `t` on top and controversial listings and
+/// searches.
+const LISTING_TIMES: [&str; 6] = ["hour", "day", "week", "month", "year", "all"];
+
+/// Most items Reddit returns for a single listing page.
+const MAX_LISTING_LIMIT: u32 = 100;
+
+/// Query parameters of a Reddit listing, checked once for every listing
+/// route. Parameters Reddit doesn't know about stay in `extra` so that handlers
+/// can still read their own options off the listing's path.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ListingParams {
+	pub sort: Option<String>,
+	/// Time range, sent to Reddit as `t`
+	pub time: Option<String>,
+	pub limit: Option<u32>,
+	pub after: Option<String>,
+	pub before: Option<String>,
+	/// Listing items seen before this page, see [`next_count`]
+	pub count: u64,
+	pub extra: Vec<(String, String)>,
+}
+
+impl ListingParams {
+	/// Parse and validate a request's query string. Empty values count as
+	/// unset.
+	pub fn parse(query: &str) -> Result<Self, String> {
+		Self::parse_with(query, true)
+	}
+
+	/// Parse the query string of a page, where a bad value shouldn't stand in
+	/// the way of the listing: `limit` is clamped to what Reddit allows, and
+	/// unknown time ranges and malformed counts are ignored.
+	pub fn clamped(query: &str) -> Self {
+		Self::parse_with(query, false).unwrap_or_default()
+	}
+
+	fn parse_with(query: &str, strict: bool) -> Result<Self, String> {
+		let mut params = Self::default();
+		for (name, value) in url::form_urlencoded::parse(query.trim_start_matches('?').as_bytes()) {
+			let value = value.into_owned();
+			match name.as_ref() {
+				"sort" | "t" | "limit" | "after" | "before" | "count" if value.is_empty() => {}
+				"sort" => params.sort = Some(value),
+				"t" if LISTING_TIMES.contains(&value.as_str()) => params.time = Some(value),
+				"t" if strict => return Err(format!("Unknown time range \"{value}\", expected one of {}", LISTING_TIMES.join(", "))),
+				"t" => {}
+				"limit" => match value.parse::<u32>() {
+					Ok(limit) if (1..=MAX_LISTING_LIMIT).contains(&limit) => params.limit = Some(limit),
+					_ if strict => return Err(format!("limit must be a number between 1 and {MAX_LISTING_LIMIT}")),
+					Ok(limit) => params.limit = Some(limit.clamp(1, MAX_LISTING_LIMIT)),
+					Err(_) => {}
+				},
+				"after" => params.after = Some(value),
+				"before" => params.before = Some(value),
+				"count" => match value.parse() {
+					Ok(count) => params.count = count,
+					Err(_) if strict => return Err("count must be a number".to_string()),
+					Err(_) => {}
+				},
+				// Always added back when the query is built
+				"raw_json" => {}
+				_ => params.extra.push((name.into_owned(), value)),
+			}
+		}
+		Ok(params)
+	}
+
+	/// Add a parameter of the handler's own to send to Reddit.
+	pub fn set(&mut self, name: &str, value: &str) {
+		self.extra.retain(|(existing, _)| existing != name);
+		self.extra.push((name.to_string(), value.to_string()));
+	}
+
+	/// The query string to send to Reddit, always asking for unescaped JSON.
+	pub fn to_query(&self) -> String {
+		let mut query = self.serializer();
+		query.append_pair("raw_json", "1");
+		query.finish()
+	}
+
+	/// The query string of a page showing the listing.
+	pub fn page_query(&self) -> String {
+		self.serializer().finish()
+	}
+
+	fn serializer(&self) -> url::form_urlencoded::Serializer<'static, String> {
+		let mut query = url::form_urlencoded::Serializer::new(String::new());
+		let typed = [
+			("sort", self.sort.clone()),
+			("t", self.time.clone()),
+			("limit", self.limit.map(|limit| limit.to_string())),
+			("after", self.after.clone()),
+			("before", self.before.clone()),
+			("count", (self.count > 0).then(|| self.count.to_string())),
+		];
+		for (name, value) in typed {
+			if let Some(value) = value {
+				query.append_pair(name, &value);
+			}
+		}
+		for (name, value) in &self.extra {
+			query.append_pair(name, value);
+		}
+		query
+	}
+
+	/// Full Reddit path of the listing at `base`, such as `/r/rust/hot.json`.
+	pub fn path(&self, base: &str) -> String {
+		format!("{base}?{}", self.to_query())
+	}
+}
+
+/// One page of a listing.
+pub struct Listing {
+	pub posts: Vec<Post>,
+	/// Reddit's cursor for the next page, if there is one
+	pub after: Option<String>,
+	/// Listing items seen once this page is consumed
+	pub count: u64,
+	/// Items of the page that were dropped for being malformed
+	pub skipped: usize,
+}
+
+/// Fetch the page of the listing at `base` that `params` asks for. `over18`
+/// accepts Reddit's over-18 gate alone, see [`json_gated`].
+pub async fn fetch_listing(base: &str, params: &ListingParams, quarantine: bool, over18: bool, use_markdown: bool) -> Result<Listing, UpstreamError> {
+	let path = params.path(base);
+	let res = json_gated(path.clone(), quarantine, over18).await?;
+	let (posts, skipped) = Post::parse_listing(&res, use_markdown).await?;
+	Ok(Listing {
+		count: next_count(&path, posts.len() + skipped),
+		after: res["data"]["after"].as_str().filter(|after| !after.is_empty()).map(ToString::to_string),
+		posts,
+		skipped,
+	})
+}
+
+/// Most post ids a `seen` token remembers; the oldest are forgotten first.
+pub const MAX_SEEN_IDS: usize = 250;
+
+/// Post ids a paginating client was already shown, carried from page to
+/// page in `?seen=` once asked for with `?dedup_seen=true`. Reddit may move a
+/// post onto the next page as scores change, and this keeps it from being
+/// shown twice.
+#[derive(Debug, Default, PartialEq)]
+pub struct SeenPosts(Vec<String>);
+
+impl SeenPosts {
+	/// Read the posts seen so far off a listing request, or `None` when it
+	/// didn't ask for duplicates to be left out.
+	pub fn from_query(path: &str) -> Result<Option<Self>, String> {
+		match param(path, "seen").filter(|token| !token.is_empty()) {
+			Some(token) => {
+				let ids = cursor::open(&token).ok_or("Invalid seen token")?;
+				Ok(Some(Self(ids.split(',').filter(|id| !id.is_empty()).map(ToString::to_string).collect())))
+			}
+			None if param(path, "dedup_seen").is_some_and(|dedup| dedup == "true") => Ok(Some(Self::default())),
+			None => Ok(None),
+		}
+	}
+
+	/// Leave out the posts shown before, or earlier on the same page, and
+	/// remember the others.
+	pub fn dedup(&mut self, posts: &mut Vec<Post>) {
+		let mut seen: HashSet<String> = self.0.iter().cloned().collect();
+		posts.retain(|post| seen.insert(post.id.clone()));
+		self.0.extend(posts.iter().map(|post| post.id.clone()));
+		if self.0.len() > MAX_SEEN_IDS {
+			self.0.drain(..self.0.len() - MAX_SEEN_IDS);
+		}
+	}
+
+	/// Token to send back as `?seen=` with the next page.
+	pub fn seal(&self) -> String {
+		cursor::seal(&self.0.join(","))
+	}
+}
+
 /// Parse a relative and absolute time from a UNIX timestamp
 pub fn time(created: f64) -> (String, String) {
 	let time = OffsetDateTime::from_unix_timestamp(created.round() as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
@@ -1383,17 +2259,60 @@ pub fn redirect(path: &str) -> Response<Body> {
 
 /// Renders a generic error landing page.
 pub async fn error(req: Request<Body>, msg: &str) -> Result<Response<Body>, String> {
-	error!("Error page rendered: {}", msg.split('|').next().unwrap_or_default());
-	let url = req.uri().to_string();
-	let body = ErrorTemplate {
-		msg: msg.to_string(),
-		prefs: Preferences::new(&req),
-		url,
-	}
-	.render()
-	.unwrap_or_default();
+	error_page(req, msg, 404)
+}
+
+/// Renders the error page for a request that cannot be served as asked.
+pub async fn bad_request(req: Request<Body>, msg: &str) -> Result<Response<Body>, String> {
+	error_page(req, msg, 400)
+}
+
+/// Renders the error page with `status`, from `REDLIB_ERROR_TEMPLATE` when
+/// one is configured.
+pub fn error_page(req: Request<Body>, msg: &str, status: u16) -> Result<Response<Body>, String> {
+	// The id ties what the visitor sees to the log line
+	let request_id = uuid::Uuid::new_v4().simple().to_string();
+	error!("Error page rendered ({request_id}): {}", msg.split('|').next().unwrap_or_default());
 
-	Ok(Response::builder().status(404).header("content-type", "text/html").body(body.into()).unwrap_or_default())
+	let body = match ERROR_TEMPLATE.as_deref() {
+		Some(custom) => render_error_template(custom, status, msg, &request_id),
+		None => ErrorTemplate {
+			msg: msg.to_string(),
+			prefs: Preferences::new(&req),
+			url: req.uri().to_string(),
+		}
+		.render()
+		.unwrap_or_default(),
+	};
+
+	Ok(
+		Response::builder()
+			.status(status)
+			.header("content-type", "text/html")
+			.header("X-Request-Id", &request_id)
+			.body(body.into())
+			.unwrap_or_default(),
+	)
+}
+
+/// Operator-provided error page from `REDLIB_ERROR_TEMPLATE`, read once.
+static ERROR_TEMPLATE: LazyLock<Option<String>> = LazyLock::new(|| load_error_template(get_setting("REDLIB_ERROR_TEMPLATE")).ok().flatten());
+
+/// Read the error page template at `path`, if one is configured.
+pub fn load_error_template(path: Option<String>) -> Result<Option<String>, String> {
+	let Some(path) = path.filter(|path| !path.is_empty()) else {
+		return Ok(None);
+	};
+	std::fs::read_to_string(&path).map(Some).map_err(|e| format!("{path}: {e}"))
+}
+
+/// Fill in the placeholders of a custom error page. The message is escaped,
+/// as it may echo parts of the request.
+fn render_error_template(template: &str, status: u16, msg: &str, request_id: &str) -> String {
+	template
+		.replace("{{status}}", &status.to_string())
+		.replace("{{request_id}}", request_id)
+		.replace("{{message}}", &encode_minimal(msg))
 }
 
 /// Renders a generic info landing page.
@@ -1410,6 +2329,34 @@ pub async fn info(req: Request<Body>, msg: &str) -> Result<Response<Body>, Strin
 	Ok(Response::builder().status(200).header("content-type", "text/html").body(body.into()).unwrap_or_default())
 }
 
+/// Keywords that earn a post a content warning, paired with the pattern that
+/// finds them as whole words.
+static BLUR_KEYWORDS: LazyLock<Vec<(String, Regex)>> = LazyLock::new(|| parse_blur_keywords(&get_setting("REDLIB_BLUR_KEYWORDS").unwrap_or_default()));
+
+fn parse_blur_keywords(value: &str) -> Vec<(String, Regex)> {
+	value
+		.split(',')
+		.map(str::trim)
+		.filter(|keyword| !keyword.is_empty())
+		.filter_map(|keyword| {
+			let pattern = Regex::new(&format!(r"(?i)(?:^|\W){}(?:\W|$)", regex::escape(keyword))).ok()?;
+			Some((keyword.to_string(), pattern))
+		})
+		.collect()
+}
+
+fn match_keyword(keywords: &[(String, Regex)], texts: &[&str]) -> Option<String> {
+	keywords
+		.iter()
+		.find(|(_, pattern)| texts.iter().any(|text| pattern.is_match(text)))
+		.map(|(keyword, _)| keyword.clone())
+}
+
+/// The configured blur keyword a post's title or flair mentions, if any.
+pub fn content_warning(title: &str, flair: &str) -> Option<String> {
+	match_keyword(&BLUR_KEYWORDS, &[title, flair])
+}
+
 /// Returns true if the config/env variable `REDLIB_SFW_ONLY` carries the
 /// value `on`.
 ///
@@ -1509,6 +2456,94 @@ pub fn get_post_url(post: &Post) -> String {
 	}
 }
 
+/// Build the description of a post's RSS item for `?summary=`: the whole
+/// body (`full`), the body cut to `?body_limit=` characters (`short`, the
+/// default) or nothing but the comments link (`none`). Link posts get their
+/// thumbnail and target instead of a body.
+pub fn rss_description(post: &Post, path: &str) -> String {
+	let full_url = config::get_setting("REDLIB_FULL_URL").unwrap_or_default();
+	let absolute = |url: &str| if url.starts_with('/') { format!("{full_url}{url}") } else { url.to_string() };
+	let summary = param(path, "summary").unwrap_or_else(|| "short".to_string());
+
+	let mut description = String::new();
+	if summary != "none" {
+		if post.post_type == "self" {
+			if summary == "full" {
+				description = rewrite_urls(&decode_html(&post.body).unwrap_or_else(|_| post.body.clone()));
+			} else {
+				let limit = param(path, "body_limit").and_then(|limit| limit.parse().ok()).unwrap_or(crate::json::DEFAULT_BODY_LIMIT);
+				description = format!("<p>{}</p>", encode_minimal(&truncate_body(&post.body, limit).0));
+			}
+		} else if !post.media.url.is_empty() {
+			let target = encode_minimal(&absolute(&post.media.url));
+			if !post.thumbnail.url.is_empty() {
+				description.push_str(&format!(
+					"<a href=\"{target}\"><img src=\"{}\" alt=\"{}\"></a><br>",
+					encode_minimal(&absolute(&post.thumbnail.url)),
+					encode_minimal(&post.title)
+				));
+			}
+			description.push_str(&format!("<a href=\"{target}\">{}</a>", encode_minimal(&post.domain)));
+		}
+		// The first image of a gallery is its enclosure, the rest are linked
+		for (index, image) in post.gallery.iter().enumerate().skip(1) {
+			if !description.is_empty() {
+				description.push_str("<br>");
+			}
+			description.push_str(&format!("<a href=\"{}\">Image {}</a>", encode_minimal(&absolute(&image.url)), index + 1));
+		}
+		if !description.is_empty() {
+			description.push_str("<br>");
+		}
+	}
+
+	description.push_str(&format!("<a href=\"{}\">Comments</a>", encode_minimal(&format!("{full_url}{}", post.permalink))));
+	description
+}
+
+/// Primary media of a post as an RSS enclosure: the image or video itself,
+/// or the first image of a gallery. Feed readers need an absolute URL, so
+/// proxied media only gets one with `REDLIB_FULL_URL` set. Reddit doesn't
+/// tell how big media is, so the length is always 0.
+pub fn rss_enclosure(post: &Post) -> Option<rss::Enclosure> {
+	let url = match post.post_type.as_str() {
+		"image" | "gif" | "video" => post.media.url.clone(),
+		"gallery" => post.gallery.first()?.url.clone(),
+		_ => return None,
+	};
+	let url = if url.starts_with('/') {
+		format!("{}{url}", config::get_setting("REDLIB_FULL_URL").unwrap_or_default())
+	} else {
+		url
+	};
+	if !url.starts_with("http") {
+		return None;
+	}
+
+	Some(rss::Enclosure {
+		mime_type: media_mime_type(&url, &post.post_type).to_string(),
+		url,
+		length: "0".to_string(),
+	})
+}
+
+/// MIME type of a media URL going by its extension, or by what kind of post
+/// it belongs to when it has none.
+fn media_mime_type(url: &str, post_type: &str) -> &'static str {
+	let file = url.split(['?', '#']).next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+	match file.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).as_deref() {
+		Some("jpg" | "jpeg") => "image/jpeg",
+		Some("png") => "image/png",
+		Some("gif") => "image/gif",
+		Some("webp") => "image/webp",
+		Some("mp4") => "video/mp4",
+		Some("webm") => "video/webm",
+		Some("m3u8") => "application/vnd.apple.mpegurl",
+		_ if post_type == "video" || post_type == "gif" => "video/mp4",
+		_ => "image/jpeg",
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::{format_num, format_url, rewrite_urls, Preferences};
@@ -1748,3 +2783,587 @@ fn test_round_trip(input: &Preferences, compression: bool) {
 	let deserialized: Preferences = bincode::deserialize(&decompressed).unwrap();
 	assert_eq!(*input, deserialized);
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_parsing_stickied_post() {
+	let stickied: Value = serde_json::from_str(r#"{"kind":"t3","data":{"id":"a","title":"Rules","is_self":true,"stickied":true,"pinned":false}}"#).unwrap();
+	let normal: Value = serde_json::from_str(r#"{"kind":"t3","data":{"id":"b","title":"Hello","is_self":true}}"#).unwrap();
+
+	let stickied = parse_post(&stickied, true).await;
+	assert!(!stickied.pinned);
+	assert!(stickied.flags.stickied);
+
+	// Posts pinned to a profile are highlighted too, but aren't announcements
+	let pinned: Value = serde_json::from_str(r#"{"kind":"t3","data":{"id":"c","title":"About me","is_self":true,"stickied":false,"pinned":true}}"#).unwrap();
+	let pinned = parse_post(&pinned, true).await;
+	assert!(pinned.pinned);
+	assert!(pinned.flags.stickied);
+
+	let normal = parse_post(&normal, true).await;
+	assert!(!normal.pinned);
+	assert!(!normal.flags.stickied);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_parsing_suggested_sort() {
+	let qa: Value = serde_json::from_str(r#"{"kind":"t3","data":{"id":"a","title":"AMA","is_self":true,"suggested_sort":"qa"}}"#).unwrap();
+	let unset: Value = serde_json::from_str(r#"{"kind":"t3","data":{"id":"b","title":"Hello","is_self":true,"suggested_sort":null}}"#).unwrap();
+
+	assert_eq!(parse_post(&qa, true).await.suggested_sort.as_deref(), Some("qa"));
+	assert_eq!(parse_post(&unset, true).await.suggested_sort, None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_parsing_subreddit_detail() {
+	let detailed: Value = serde_json::from_str(
+		r##"{"kind":"t3","data":{"id":"a","title":"Hello","is_self":true,"sr_detail":{"display_name":"rust","title":"The Rust Programming Language","public_description":"A place for all things Rust","subscribers":350000,"community_icon":"","icon_img":"https://b.thumbs.redditmedia.com/icon.png","over18":false,"primary_color":"#dea584","key_color":"","banner_background_color":null}}}"##,
+	)
+	.unwrap();
+	let plain: Value = serde_json::from_str(r#"{"kind":"t3","data":{"id":"b","title":"Hello","is_self":true}}"#).unwrap();
+
+	let detailed = parse_post(&detailed, true).await;
+	let detail = detailed.subreddit_detail.as_ref().unwrap();
+	assert_eq!(detail.name, "rust");
+	assert_eq!(detail.description, "A place for all things Rust");
+	assert_eq!(detail.members.0, "350.0k");
+	assert_eq!(detail.icon, "/thumb/b/icon.png");
+	assert_eq!(detail.primary_color.as_deref(), Some("#dea584"));
+	assert_eq!(detail.key_color, None);
+	assert_eq!(detail.banner_background_color, None);
+
+	let plain = parse_post(&plain, true).await;
+	assert!(plain.subreddit_detail.is_none());
+	assert!(serde_json::to_value(&plain).unwrap().get("subreddit_detail").is_none());
+}
+
+#[test]
+fn test_blur_keyword_matching() {
+	let keywords = parse_blur_keywords("Spoiler, gore,, #nsfl ");
+	assert_eq!(keywords.len(), 3);
+
+	assert_eq!(match_keyword(&keywords, &["Finale SPOILER thread", ""]), Some("Spoiler".to_string()));
+	assert_eq!(match_keyword(&keywords, &["Weekly discussion", "Gore"]), Some("gore".to_string()));
+	assert_eq!(match_keyword(&keywords, &["Tagged #NSFL", ""]), Some("#nsfl".to_string()));
+	assert_eq!(match_keyword(&keywords, &["Spoilers are fine", "Gorey details"]), None);
+	assert_eq!(match_keyword(&[], &["Spoiler"]), None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_min_comments_filter() {
+	let mut posts = Vec::new();
+	for (i, count) in [0, 9, 10, 250].into_iter().enumerate() {
+		let post = serde_json::json!({ "kind": "t3", "data": { "id": format!("p{i}"), "title": "Post", "is_self": true, "num_comments": count } });
+		posts.push(parse_post(&post, true).await);
+	}
+	let ids = |posts: &[Post]| posts.iter().map(|post| post.id.clone()).collect::<Vec<_>>();
+
+	filter_listing(&mut posts, "/r/rust/hot.json?min_comments=lots");
+	assert_eq!(ids(&posts), vec!["p0", "p1", "p2", "p3"]);
+
+	filter_listing(&mut posts, "/r/rust/hot.json?min_comments=10&raw_json=1");
+	assert_eq!(ids(&posts), vec!["p2", "p3"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_crosspost_fields() {
+	let shared = serde_json::json!({ "kind": "t3", "data": { "id": "a", "title": "Post", "is_self": true, "num_crossposts": 4, "is_crosspostable": true } });
+	let post = parse_post(&shared, true).await;
+	assert_eq!((post.num_crossposts, post.is_crosspostable), (4, true));
+
+	let plain = serde_json::json!({ "kind": "t3", "data": { "id": "b", "title": "Post", "is_self": true } });
+	let post = parse_post(&plain, true).await;
+	assert_eq!((post.num_crossposts, post.is_crosspostable), (0, false));
+	let serialized = serde_json::to_value(&post).unwrap();
+	assert_eq!(serialized["num_crossposts"], 0);
+	assert_eq!(serialized["is_crosspostable"], false);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_listing_skips_malformed_items() {
+	let res = serde_json::json!({ "kind": "Listing", "data": { "after": "t3_c", "children": [
+		{ "kind": "t3", "data": { "id": "a", "title": "First", "is_self": true } },
+		{ "kind": "t3", "data": "unexpected" },
+		"not an item",
+		{ "kind": "t3", "data": { "id": "c", "title": "Last", "is_self": true } },
+	] } });
+	let (posts, skipped) = Post::parse_listing(&res, true).await.unwrap();
+	assert_eq!(posts.iter().map(|post| post.id.as_str()).collect::<Vec<_>>(), vec!["a", "c"]);
+	assert_eq!(skipped, 2);
+
+	assert!(Post::parse_listing(&serde_json::json!({ "kind": "Listing" }), true).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_post_kinds() {
+	let kind = |data: serde_json::Value| async move { parse_post(&serde_json::json!({ "kind": "t3", "data": data }), true).await.kind };
+
+	assert_eq!(kind(serde_json::json!({ "id": "a", "is_self": true })).await, PostKind::SelfPost);
+	assert_eq!(
+		kind(serde_json::json!({ "id": "a", "post_hint": "image", "url": "https://i.redd.it/a.png", "preview": { "images": [{ "source": { "url": "https://preview.redd.it/a.png", "width": 10, "height": 10 } }] } })).await,
+		PostKind::Image
+	);
+	assert_eq!(
+		kind(serde_json::json!({ "id": "a", "is_video": true, "secure_media": { "reddit_video": { "fallback_url": "https://v.redd.it/a/DASH_720.mp4" } } })).await,
+		PostKind::Video
+	);
+	assert_eq!(
+		kind(serde_json::json!({ "id": "a", "secure_media": { "reddit_video": { "fallback_url": "https://v.redd.it/a/DASH_720.mp4", "is_gif": true } } })).await,
+		PostKind::Gif
+	);
+	assert_eq!(
+		kind(serde_json::json!({ "id": "a", "is_gallery": true, "url": "https://www.reddit.com/gallery/a", "gallery_data": { "items": [] } })).await,
+		PostKind::Gallery
+	);
+	assert_eq!(
+		kind(serde_json::json!({ "id": "a", "domain": "example.com", "url": "https://example.com/a" })).await,
+		PostKind::Link
+	);
+	assert_eq!(
+		kind(serde_json::json!({ "id": "a", "is_self": true, "poll_data": { "total_vote_count": 3, "options": [] } })).await,
+		PostKind::Poll
+	);
+	assert_eq!(
+		kind(serde_json::json!({ "id": "a", "crosspost_parent_list": [{ "id": "b", "is_self": true }], "url": "/r/rust/comments/b/" })).await,
+		PostKind::Crosspost
+	);
+
+	let post = parse_post(&serde_json::json!({ "kind": "t3", "data": { "id": "a", "is_self": true } }), true).await;
+	assert_eq!(serde_json::to_value(&post).unwrap()["kind"], "self");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_post_type_filter() {
+	let listing = [
+		serde_json::json!({ "kind": "t3", "data": { "id": "text", "title": "Post", "is_self": true, "num_comments": 12 } }),
+		serde_json::json!({ "kind": "t3", "data": { "id": "pic", "title": "Post", "post_hint": "image", "domain": "i.redd.it", "url": "https://i.redd.it/a.png", "num_comments": 3 } }),
+		serde_json::json!({ "kind": "t3", "data": { "id": "article", "title": "Post", "domain": "example.com", "url": "https://example.com/a", "num_comments": 40 } }),
+	];
+	let filtered = |path: &'static str| async {
+		let mut posts = Vec::new();
+		for post in &listing {
+			posts.push(parse_post(post, true).await);
+		}
+		filter_listing(&mut posts, path);
+		posts.into_iter().map(|post| post.id).collect::<Vec<_>>()
+	};
+
+	assert_eq!(filtered("/r/rust/hot.json?post_type=self").await, vec!["text"]);
+	assert_eq!(filtered("/r/rust/hot.json?post_type=media").await, vec!["pic"]);
+	assert_eq!(filtered("/r/rust/hot.json?post_type=link").await, vec!["article"]);
+	assert_eq!(filtered("/r/rust/hot.json?post_type=all").await, vec!["text", "pic", "article"]);
+	assert_eq!(filtered("/r/rust/hot.json?post_type=polls").await, vec!["text", "pic", "article"]);
+	assert!(filtered("/r/rust/hot.json?post_type=link&min_comments=50").await.is_empty());
+	assert_eq!(filtered("/r/rust/hot.json?min_comments=10&post_type=self").await, vec!["text"]);
+}
+
+#[test]
+fn test_listing_params() {
+	let params = ListingParams::parse("?sort=top&t=week&limit=25&after=t3_abc&count=25&q=rust+lang&raw_json=1&body_limit=").unwrap();
+	assert_eq!(params.sort.as_deref(), Some("top"));
+	assert_eq!(params.time.as_deref(), Some("week"));
+	assert_eq!(params.limit, Some(25));
+	assert_eq!(params.count, 25);
+	assert_eq!(params.to_query(), "sort=top&t=week&limit=25&after=t3_abc&count=25&q=rust+lang&body_limit=&raw_json=1");
+	assert_eq!(params.path("/r/rust/search.json"), format!("/r/rust/search.json?{}", params.to_query()));
+
+	// Empty values are left out, and so is a count of zero
+	let mut params = ListingParams::parse("after=&t=&count=0").unwrap();
+	assert_eq!(params, ListingParams::default());
+	params.set("restrict_sr", "on");
+	params.set("restrict_sr", "on");
+	assert_eq!(params.to_query(), "restrict_sr=on&raw_json=1");
+
+	assert_eq!(
+		ListingParams::parse("t=decade").unwrap_err(),
+		"Unknown time range \"decade\", expected one of hour, day, week, month, year, all"
+	);
+	assert_eq!(ListingParams::parse("limit=500").unwrap_err(), "limit must be a number between 1 and 100");
+	assert_eq!(ListingParams::parse("limit=0").unwrap_err(), "limit must be a number between 1 and 100");
+	assert_eq!(ListingParams::parse("count=many").unwrap_err(), "count must be a number");
+
+	// Pages make do with what they can use
+	let clamped = ListingParams::clamped("limit=500&t=decade&count=many&sort=top");
+	assert_eq!((clamped.limit, clamped.time, clamped.count, clamped.sort.as_deref()), (Some(100), None, 0, Some("top")));
+	assert_eq!(ListingParams::clamped("limit=0").limit, Some(1));
+	assert_eq!(ListingParams::clamped("limit=lots").limit, None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_next_count_across_pages() {
+	// Reddit's answers for two pages of a listing, seeded into the response cache
+	let base = "/r/countfixture/hot.json";
+	let page = |ids: std::ops::Range<u32>, after: &str| {
+		let children: Vec<Value> = ids
+			.map(|id| serde_json::json!({ "kind": "t3", "data": { "id": format!("p{id}"), "title": "Post", "is_self": true, "permalink": format!("/r/countfixture/comments/p{id}/post/") } }))
+			.collect();
+		serde_json::json!({ "kind": "Listing", "data": { "after": after, "children": children } })
+	};
+	let seed = |params: &ListingParams, listing: Value| {
+		let key = (params.path(base), false, false);
+		async move { crate::client::JSON_GATED.lock().await.cache_set(key, listing) }
+	};
+
+	// First page: no count sent yet, Reddit returns 25 items
+	let first = ListingParams::parse("limit=25").unwrap();
+	seed(&first, page(0..25, "t3_p24")).await;
+	let listing = fetch_listing(base, &first, false, false, true).await.unwrap();
+	assert_eq!((listing.posts.len(), listing.count, listing.after.as_deref()), (25, 25, Some("t3_p24")));
+
+	// Second page follows `after` and carries the count forward
+	let second = ListingParams {
+		after: listing.after,
+		count: listing.count,
+		..first
+	};
+	assert!(second.path(base).contains("after=t3_p24&count=25"));
+	seed(&second, page(25..50, "")).await;
+	let listing = fetch_listing(base, &second, false, false, true).await.unwrap();
+	assert_eq!((listing.posts[0].id.as_str(), listing.count, listing.after), ("p25", 50, None));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_parsing_video_audio_track() {
+	let post: Value = serde_json::from_str(
+		r#"{"kind":"t3","data":{"id":"1abc2d","title":"Video","domain":"v.redd.it","url":"https://v.redd.it/xyz789","secure_media":{"reddit_video":{"fallback_url":"https://v.redd.it/xyz789/DASH_720.mp4?source=fallback","hls_url":"https://v.redd.it/xyz789/HLSPlaylist.m3u8?a=1","has_audio":true,"is_gif":false}}}}"#,
+	)
+	.unwrap();
+	let post = parse_post(&post, true).await;
+	assert_eq!(post.post_type, "video");
+	assert_eq!(post.media.url, "/vid/xyz789/720.mp4");
+	assert_eq!(post.media.audio_url.as_deref(), Some("/vid/xyz789/AUDIO_128.mp4"));
+
+	let silent: Value = serde_json::from_str(
+		r#"{"kind":"t3","data":{"id":"1abc2e","title":"Silent","secure_media":{"reddit_video":{"fallback_url":"https://v.redd.it/old456/DASH_480?source=fallback","has_audio":false}}}}"#,
+	)
+	.unwrap();
+	assert_eq!(parse_post(&silent, true).await.media.audio_url, None);
+
+	let legacy = serde_json::json!({ "fallback_url": "https://v.redd.it/old456/DASH_480?source=fallback", "has_audio": true });
+	assert_eq!(Media::audio_url(&legacy).as_deref(), Some("/vid/old456/audio"));
+}
+
+#[test]
+fn test_replacing_subreddit_emojis() {
+	let emojis = parse_emojis(&serde_json::json!({
+		"snoomojis": { "snoo": { "url": "https://emoji.redditmedia.com/abc/snoo", "user_flair_allowed": true } },
+		"t5_2qh1i": { "ferris": { "url": "https://emoji.redditmedia.com/def/ferris" } }
+	}));
+	assert_eq!(emojis.get("snoo").map(String::as_str), Some("/emoji/abc/snoo"));
+	assert_eq!(emojis.len(), 2);
+
+	let html = replace_emojis(r#"<p><a href="https://x.com/:snoo:">link</a> hi :ferris: and :unknown:</p>"#, &emojis);
+	assert!(html.starts_with(r#"<p><a href="https://x.com/:snoo:">link</a> hi <img loading="lazy" src="/emoji/def/ferris" alt=":ferris:""#));
+	assert!(html.ends_with(" and :unknown:</p>"));
+	assert_eq!(
+		replace_markdown_emojis("hi :ferris: and :unknown:", &emojis),
+		"hi ![:ferris:](/emoji/def/ferris) and :unknown:"
+	);
+
+	let mut flair = Flair {
+		flair_parts: FlairPart::parse("text", None, Some(":snoo: Contributor")),
+		text: String::new(),
+		background_color: String::new(),
+		foreground_color: String::new(),
+	};
+	replace_flair_emojis(&mut flair, &emojis);
+	let parts: Vec<_> = flair.flair_parts.iter().map(|part| (part.flair_part_type.as_str(), part.value.as_str())).collect();
+	assert_eq!(parts, vec![("emoji", "/emoji/abc/snoo"), ("text", " Contributor")]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rss_description() {
+	let self_post = serde_json::json!({ "kind": "t3", "data": {
+		"id": "abc", "title": "Post", "is_self": true, "permalink": "/r/rust/comments/abc/post/",
+		"selftext_html": "<div class=\"md\"><p>Fish & chips are great food</p></div>"
+	} });
+	let post = parse_post(&self_post, false).await;
+
+	assert_eq!(
+		rss_description(&post, "/r/rust.rss?body_limit=14"),
+		"<p>Fish &amp; chips...</p><br><a href=\"/r/rust/comments/abc/post/\">Comments</a>"
+	);
+	assert_eq!(rss_description(&post, "/r/rust.rss?summary=none"), "<a href=\"/r/rust/comments/abc/post/\">Comments</a>");
+	assert!(rss_description(&post, "/r/rust.rss?summary=full").contains("great food"));
+
+	let link_post = serde_json::json!({ "kind": "t3", "data": {
+		"id": "def", "title": "A \"quoted\" link", "permalink": "/r/rust/comments/def/link/",
+		"url": "https://example.com/article", "domain": "example.com", "thumbnail": "https://b.thumbs.redditmedia.com/thumb.jpg"
+	} });
+	let post = parse_post(&link_post, false).await;
+	let description = rss_description(&post, "/r/rust.rss");
+	assert!(description.starts_with("<a href=\"https://example.com/article\"><img"), "{description}");
+	assert!(description.contains("alt=\"A &quot;quoted&quot; link\""), "{description}");
+	assert!(description.contains(">example.com</a>"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rss_enclosures() {
+	let image = serde_json::json!({ "kind": "t3", "data": {
+		"id": "abc", "title": "Picture", "permalink": "/r/pics/comments/abc/picture/", "post_hint": "image",
+		"url": "https://i.redd.it/picture.png",
+		"preview": { "images": [{ "source": { "url": "https://i.redd.it/picture.png", "width": 640, "height": 480 }, "resolutions": [] }] }
+	} });
+	// Proxied media is relative, which feed readers can't fetch
+	assert!(rss_enclosure(&parse_post(&image, false).await).is_none());
+	let enclosure = rss_enclosure(&REQUEST_CONTEXT.scope(raw_urls(), parse_post(&image, false)).await).unwrap();
+	assert_eq!((enclosure.url.as_str(), enclosure.mime_type.as_str()), ("https://i.redd.it/picture.png", "image/png"));
+	assert_eq!(enclosure.length, "0");
+
+	let gallery = serde_json::json!({ "kind": "t3", "data": {
+		"id": "gal", "title": "Gallery", "permalink": "/r/pics/comments/gal/gallery/", "url": "https://www.reddit.com/gallery/gal", "is_gallery": true,
+		"gallery_data": { "items": [{ "media_id": "one" }, { "media_id": "two" }, { "media_id": "three" }] },
+		"media_metadata": {
+			"one": { "m": "image/jpg", "s": { "u": "https://i.redd.it/one.jpg", "x": 100, "y": 100 } },
+			"two": { "m": "image/jpg", "s": { "u": "https://i.redd.it/two.jpg", "x": 100, "y": 100 } },
+			"three": { "m": "image/gif", "s": { "gif": "https://i.redd.it/three.gif", "x": 100, "y": 100 } }
+		}
+	} });
+	let post = REQUEST_CONTEXT.scope(raw_urls(), parse_post(&gallery, false)).await;
+	let enclosure = rss_enclosure(&post).unwrap();
+	assert_eq!((enclosure.url.as_str(), enclosure.mime_type.as_str()), ("https://i.redd.it/one.jpg", "image/jpeg"));
+	let description = rss_description(&post, "/r/pics.rss");
+	assert!(description.contains("<a href=\"https://i.redd.it/two.jpg\">Image 2</a><br>"), "{description}");
+	assert!(description.contains("<a href=\"https://i.redd.it/three.gif\">Image 3</a>"), "{description}");
+
+	assert_eq!(media_mime_type("https://v.redd.it/abc/DASH_720.mp4?source=fallback", "video"), "video/mp4");
+	assert_eq!(media_mime_type("https://v.redd.it/abc/HLSPlaylist.m3u8", "video"), "application/vnd.apple.mpegurl");
+	assert_eq!(media_mime_type("https://example.com/watch", "video"), "video/mp4");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_raw_media_urls() {
+	let post = serde_json::json!({ "kind": "t3", "data": {
+		"id": "abc", "title": "Picture", "permalink": "/r/pics/comments/abc/picture/", "post_hint": "image",
+		"url": "https://i.redd.it/picture.jpg", "thumbnail": "https://b.thumbs.redditmedia.com/thumb.jpg",
+		"preview": { "images": [{ "source": { "url": "https://preview.redd.it/picture.jpg?width=640", "width": 640, "height": 480 }, "resolutions": [] }] }
+	} });
+
+	let proxied = parse_post(&post, false).await;
+	assert_eq!(proxied.thumbnail.url, "/thumb/b/thumb.jpg");
+	assert!(proxied.media.url.starts_with("/preview/pre/picture.jpg"));
+	assert!(proxied.preview_images.iter().all(|image| image.url.starts_with("/preview/pre/")));
+
+	let raw = REQUEST_CONTEXT.scope(raw_urls(), parse_post(&post, false)).await;
+	assert_eq!(raw.thumbnail.url, "https://b.thumbs.redditmedia.com/thumb.jpg");
+	assert!(raw.media.url.starts_with("https://preview.redd.it/picture.jpg"));
+	assert!(raw.preview_images.iter().all(|image| image.url.starts_with("https://preview.redd.it/")));
+	assert_eq!(REQUEST_CONTEXT.sync_scope(raw_urls(), || format_url("https://www.reddit.com/r/rust")), "/r/rust");
+	assert_eq!(
+		REQUEST_CONTEXT.sync_scope(raw_urls(), || format_url("https://v.redd.it/abc/DASH_720.mp4")),
+		"https://v.redd.it/abc/DASH_720.mp4"
+	);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_instance_permalinks() {
+	let post = serde_json::json!({ "kind": "t3", "data": { "id": "abc", "title": "Post", "is_self": true, "permalink": "/r/rust/comments/abc/post/" } });
+
+	assert_eq!(parse_post(&post, true).await.instance_permalink, None);
+	let context = RequestContext {
+		instance_origin: Some("https://redlib.example.com".to_string()),
+		..Default::default()
+	};
+	let absolute = REQUEST_CONTEXT.scope(context, parse_post(&post, true)).await;
+	assert_eq!(absolute.instance_permalink.as_deref(), Some("https://redlib.example.com/r/rust/comments/abc/post/"));
+	assert_eq!(absolute.permalink, "/r/rust/comments/abc/post/");
+
+	let mut req = Request::new(Body::empty());
+	req.headers_mut().insert(hyper::header::HOST, "localhost:8080".parse().unwrap());
+	assert_eq!(instance_origin(&req), "http://localhost:8080");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_local_times() {
+	// 2024-01-15 17:30:00 UTC
+	let post = serde_json::json!({ "kind": "t3", "data": { "id": "abc", "is_self": true, "created_utc": 1_705_339_800.0 } });
+
+	assert_eq!(parse_post(&post, true).await.created_local, None);
+	let context = RequestContext {
+		display_tz: Some(parse_timezone("America/New_York")),
+		..Default::default()
+	};
+	let local = REQUEST_CONTEXT.scope(context, parse_post(&post, true)).await;
+	assert_eq!(local.created_local.as_deref(), Some("Jan 15 2024, 12:30:00 EST"));
+	assert_eq!(local.created, "Jan 15 2024, 17:30:00 UTC");
+
+	assert_eq!(format_local_time(1_721_064_600, parse_timezone("Europe/Berlin")), "Jul 15 2024, 19:30:00 CEST");
+	assert_eq!(format_local_time(1_705_339_800, parse_timezone("Mars/Olympus_Mons")), "Jan 15 2024, 17:30:00 UTC");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spoilers() {
+	let post = serde_json::json!({ "kind": "t3", "data": { "id": "a", "is_self": true, "spoiler": true, "selftext": "The ending: >!they all die!< and >!a\nsequel!<." } });
+	let post = parse_post(&post, true).await;
+	assert!(post.spoiler);
+	assert!(post.flags.spoiler);
+	let hidden: Vec<&str> = post.spoilers.iter().map(|span| &post.body[span.start..span.end]).collect();
+	assert_eq!(hidden, vec!["they all die", "a\nsequel"]);
+
+	assert_eq!(inline_spoilers("Not >!across\n\nparagraphs!<"), vec![]);
+	assert_eq!(inline_spoilers("<p>Rendered <span class=\"md-spoiler-text\">already</span></p>"), vec![]);
+	assert_eq!(inline_spoilers("So >!Rosebud!< was the sled"), vec![SpoilerSpan { start: 5, end: 12 }]);
+}
+
+#[test]
+fn test_markdown_to_text() {
+	assert_eq!(
+		markdown_to_text("# Release notes\n\nSome **bold**   and *italic*\ntext."),
+		"Release notes\nSome bold and italic text."
+	);
+	assert_eq!(
+		markdown_to_text("* first\n* second with `code`\n    * nested\n\n1. one\n2. two"),
+		"first\nsecond with code\nnested\none\ntwo"
+	);
+	assert_eq!(
+		markdown_to_text("See [the docs](https://doc.rust-lang.org) and ![a crab](https://i.redd.it/crab.png)."),
+		"See the docs and a crab."
+	);
+	assert_eq!(
+		markdown_to_text("Before:\n\n```\nfn main() {\n    println!(\"hi\");\n}\n```\n\n> quoted ~~old~~"),
+		"Before:\nfn main() {\n    println!(\"hi\");\n}\nquoted old"
+	);
+	assert_eq!(markdown_to_text("| a | b |\n|---|---|\n| 1 | 2 |"), "a b\n1 2");
+}
+
+#[test]
+fn test_closest_preview_variant() {
+	let variants = vec![(108, "s108".to_string()), (320, "s320".to_string()), (640, "s640".to_string())];
+	assert_eq!(closest_variant(1920, &variants, 600), Some("s640"));
+	assert_eq!(closest_variant(1920, &variants, 100), Some("s108"));
+	assert_eq!(closest_variant(1920, &variants, 480), Some("s640"));
+	assert_eq!(closest_variant(1920, &variants, 1500), None);
+	assert_eq!(closest_variant(1920, &[], 640), None);
+
+	let data = |name: &str| {
+		serde_json::json!({ "preview": { "images": [{
+			"source": { "url": format!("https://preview.redd.it/{name}?auto=webp&s=src"), "width": 1920 },
+			"resolutions": [
+				{ "url": format!("https://preview.redd.it/{name}?width=320&crop=smart&auto=webp&s=sig320"), "width": 320 },
+				{ "url": format!("https://preview.redd.it/{name}?width=640&crop=smart&auto=webp&s=sig640"), "width": 640 }
+			]
+		}] } })
+	};
+	// Parsing the preview alone leaves the cache be
+	assert_eq!(PreviewImage::parse(&data("parsed.jpg")).len(), 3);
+	assert_eq!(preview_variant("parsed.jpg", 300), None);
+
+	remember_preview_variants(&data("abc123.jpg"));
+	assert_eq!(
+		preview_variant("abc123.jpg", 300).as_deref(),
+		Some("https://preview.redd.it/abc123.jpg?width=320&crop=smart&auto=webp&s=sig320")
+	);
+	assert_eq!(preview_variant("abc123.jpg", 4000), None);
+	assert_eq!(preview_variant("unknown.jpg", 300), None);
+}
+
+/// Context of a JSON API request made with `?raw_url=true`.
+#[cfg(test)]
+fn raw_urls() -> RequestContext {
+	RequestContext {
+		raw_media_urls: true,
+		..Default::default()
+	}
+}
+
+#[tokio::test]
+async fn test_request_settings_in_spawned_tasks() {
+	let context = RequestContext {
+		instance_origin: Some("https://redlib.example.com".to_string()),
+		..Default::default()
+	};
+	let spawned = REQUEST_CONTEXT
+		.scope(context, async {
+			tokio::spawn(with_request_settings(async { (instance_url("/r/rust"), raw_media_urls()) })).await
+		})
+		.await
+		.unwrap();
+	assert_eq!(spawned, (Some("https://redlib.example.com/r/rust".to_string()), false));
+}
+
+#[test]
+fn test_media_base_url() {
+	assert_eq!(parse_media_base_url(None), Ok(None));
+	assert_eq!(parse_media_base_url(Some(" ".into())), Ok(None));
+	assert_eq!(parse_media_base_url(Some("https://cdn.example.com/".into())), Ok(Some("https://cdn.example.com".into())));
+	assert_eq!(
+		parse_media_base_url(Some("https://cdn.example.com/media/".into())),
+		Ok(Some("https://cdn.example.com/media".into()))
+	);
+	assert!(parse_media_base_url(Some("cdn.example.com".into())).is_err());
+	assert!(parse_media_base_url(Some("/media".into())).is_err());
+	assert!(parse_media_base_url(Some("ftp://cdn.example.com".into())).is_err());
+
+	let base = "https://cdn.example.com";
+	assert_eq!(format_url_with("https://i.redd.it/abc.jpg", ""), "/img/abc.jpg");
+	assert_eq!(format_url_with("https://i.redd.it/abc.jpg", base), "https://cdn.example.com/img/abc.jpg");
+	assert_eq!(format_url_with("https://v.redd.it/abc/DASH_720.mp4", base), "https://cdn.example.com/vid/abc/720.mp4");
+	// Links to Reddit pages stay on this instance
+	assert_eq!(format_url_with("https://www.reddit.com/r/rust/", base), "/r/rust/");
+	assert_eq!(format_url_with("https://example.com/page", base), "https://example.com/page");
+
+	// Videos are downloaded with their audio wherever they're proxied from
+	let video = format_url_with("https://v.redd.it/abc/DASH_720.mp4", base);
+	assert_eq!(download_url_with(&video, "xyz", base), "https://cdn.example.com/vid/abc/720.mp4?download=true&post=xyz");
+	assert_eq!(download_url_with("/vid/abc/720.mp4", "xyz", ""), "/vid/abc/720.mp4?download=true&post=xyz");
+	assert_eq!(download_url_with("https://i.redd.it/abc.jpg", "xyz", base), "https://i.redd.it/abc.jpg");
+
+	let input = r#"<p><a href="https://preview.redd.it/abc.png?width=2560&amp;s=sig">caption</a></p>"#;
+	assert_eq!(
+		rewrite_urls_with(input, base),
+		r#"<figure><a href="https://cdn.example.com/preview/pre/abc.png?width=2560&amp;s=sig"><img loading="lazy" src="https://cdn.example.com/preview/pre/abc.png?width=2560&amp;s=sig"></a><figcaption>caption</figcaption></figure>"#
+	);
+	assert_eq!(
+		rewrite_urls_with(input, ""),
+		r#"<figure><a href="/preview/pre/abc.png?width=2560&amp;s=sig"><img loading="lazy" src="/preview/pre/abc.png?width=2560&amp;s=sig"></a><figcaption>caption</figcaption></figure>"#
+	);
+}
+
+#[test]
+fn test_custom_error_template() {
+	let template = "<h1>{{status}}</h1><p>{{message}}</p><small>{{request_id}}</small>";
+	assert_eq!(
+		render_error_template(template, 404, "r/<script> has been banned", "abc123"),
+		"<h1>404</h1><p>r/&lt;script&gt; has been banned</p><small>abc123</small>"
+	);
+
+	assert_eq!(load_error_template(None), Ok(None));
+	assert!(load_error_template(Some("/nonexistent/error.html".into())).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_seen_posts() {
+	let page = |ids: &[&str]| {
+		let posts: Vec<_> = ids
+			.iter()
+			.map(|id| serde_json::json!({ "kind": "t3", "data": { "id": id, "title": id, "is_self": true } }))
+			.collect();
+		async move {
+			let mut page = Vec::new();
+			for post in &posts {
+				page.push(parse_post(post, true).await);
+			}
+			page
+		}
+	};
+	let ids = |posts: &[Post]| posts.iter().map(|post| post.id.clone()).collect::<Vec<_>>();
+
+	assert_eq!(SeenPosts::from_query("/r/rust/hot.json?limit=25"), Ok(None));
+	let mut seen = SeenPosts::from_query("/r/rust/hot.json?dedup_seen=true").unwrap().unwrap();
+	let mut first = page(&["a", "b", "c"]).await;
+	seen.dedup(&mut first);
+	assert_eq!(ids(&first), vec!["a", "b", "c"]);
+
+	// A post that slid onto the next page, or turns up twice on it, is shown once
+	let mut seen = SeenPosts::from_query(&format!("/r/rust/hot.json?seen={}", seen.seal())).unwrap().unwrap();
+	let mut second = page(&["c", "d", "e", "d"]).await;
+	seen.dedup(&mut second);
+	assert_eq!(ids(&second), vec!["d", "e"]);
+	assert_eq!(seen.seal(), "a,b,c,d,e");
+
+	// Only the latest ids are remembered
+	let numbers: Vec<String> = (0..MAX_SEEN_IDS + 10).map(|i| i.to_string()).collect();
+	let mut many = page(&numbers.iter().map(String::as_str).collect::<Vec<_>>()).await;
+	seen.dedup(&mut many);
+	assert_eq!(seen.0.len(), MAX_SEEN_IDS);
+	assert_eq!(seen.0.first().map(String::as_str), Some("10"));
+}